@@ -0,0 +1,1457 @@
+//! Building the `tar.zst` (or `tar.gz` / plain `tar`) archives.
+
+use crate::database::DatabaseDump;
+use crate::encryption::{self, SegmentedWriter};
+use anyhow::{Context, Result};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// zstd level used unless an item asks for something else.
+pub const DEFAULT_LEVEL: i32 = 10;
+
+/// gzip level used unless an item asks for something else.
+pub const DEFAULT_GZIP_LEVEL: i32 = 6;
+
+/// Compression wrapped around the tar stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[default]
+    Zstd,
+    Gzip,
+    /// Uncompressed, for restore targets without zstd or gzip.
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 3] = [Self::Zstd, Self::Gzip, Self::Tar];
+
+    /// File extension without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => "tar.zst",
+            Self::Gzip => "tar.gz",
+            Self::Tar => "tar",
+        }
+    }
+
+    /// Format of a file named by `build_archive_path`, and whether it is
+    /// age-encrypted.
+    pub fn from_file_name(name: &str) -> Option<(Self, bool)> {
+        let (name, encrypted) = match name.strip_suffix(".age") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        Self::ALL
+            .into_iter()
+            .find(|format| {
+                name.strip_suffix(format.extension())
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+            .map(|format| (format, encrypted))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Tar => "tar",
+        }
+    }
+}
+
+/// How a `source_path` that is itself a symlink is archived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkMode {
+    /// Archive whatever the link currently points to.
+    #[default]
+    Follow,
+    /// Archive the link itself as a symlink entry.
+    Store,
+}
+
+pub struct ArchiveOptions {
+    pub symlink_mode: SymlinkMode,
+    /// Archive what symlinks below a directory source point to instead of
+    /// the links themselves.
+    pub follow_symlinks: bool,
+    pub format: ArchiveFormat,
+    /// Compression level for `format`; ignored for plain tar.
+    pub level: i32,
+    /// zstd worker threads. With `1` the stream is compressed on the
+    /// calling thread; other formats always are.
+    pub threads: u32,
+    /// Encrypts the compressed stream with age when set.
+    pub recipient: Option<age::x25519::Recipient>,
+    /// With `recipient`, starts a new age stream after every this many
+    /// compressed bytes instead of encrypting them all as one, for archives
+    /// that are split into parts (see [`crate::encryption`]).
+    pub segment_size: Option<u64>,
+    pub filter: Option<PathFilter>,
+    /// Only files modified after this time are taken from directory sources,
+    /// for incremental backups. Directories are kept only when they lead to
+    /// such a file.
+    pub modified_since: Option<SystemTime>,
+    /// Stored as the owner of every entry instead of the file's own uid and
+    /// gid, so a restore does not depend on the ids of the backed-up host.
+    pub owner_uid: Option<u64>,
+    pub owner_gid: Option<u64>,
+}
+
+/// zstd at [`DEFAULT_LEVEL`] on one thread, without encryption, filters or
+/// owner changes.
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            symlink_mode: SymlinkMode::Follow,
+            follow_symlinks: false,
+            format: ArchiveFormat::Zstd,
+            level: DEFAULT_LEVEL,
+            threads: 1,
+            recipient: None,
+            segment_size: None,
+            filter: None,
+            modified_since: None,
+            owner_uid: None,
+            owner_gid: None,
+        }
+    }
+}
+
+/// `include` and `exclude` globs for directory sources. Patterns without a
+/// `/` match the file name at any depth; the others match the path relative
+/// to the source directory, with `*` stopping at `/` and `**` crossing it.
+pub struct PathFilter {
+    include: Option<Patterns>,
+    exclude: Option<Patterns>,
+}
+
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+    /// `dir/**` patterns with the trailing `/**` removed, so the directory
+    /// itself can be pruned instead of testing every file below it.
+    dirs: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String], kind: &str) -> Result<Option<Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        let mut dirs = GlobSetBuilder::new();
+        for pattern in patterns {
+            let anchored = pattern.trim_start_matches('/');
+            let build = |glob: &str| {
+                GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid {} pattern: {}", kind, pattern))
+            };
+            if let Some(dir) = anchored.strip_suffix("/**")
+                && !dir.is_empty()
+            {
+                dirs.add(build(dir)?);
+            }
+            if anchored.contains('/') {
+                paths.add(build(anchored)?);
+            } else {
+                names.add(build(anchored)?);
+            }
+        }
+        let context = || format!("Failed to build {} patterns", kind);
+        Ok(Some(Self {
+            names: names.build().with_context(context)?,
+            paths: paths.build().with_context(context)?,
+            dirs: dirs.build().with_context(context)?,
+        }))
+    }
+
+    fn is_match(&self, relative: &Path) -> bool {
+        let name_matches = relative
+            .file_name()
+            .is_some_and(|name| self.names.is_match(Path::new(name)));
+        name_matches || self.paths.is_match(relative)
+    }
+}
+
+impl PathFilter {
+    /// Returns `None` when both lists are empty, which keeps every file.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Option<Self>> {
+        let include = Patterns::new(include, "include")?;
+        let exclude = Patterns::new(exclude, "exclude")?;
+        if include.is_none() && exclude.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { include, exclude }))
+    }
+
+    /// Whether the file at `relative` (relative to the source directory)
+    /// should be archived: it matches `include` (if set) and not `exclude`.
+    pub fn matches(&self, relative: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(relative))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(relative))
+    }
+
+    /// Whether the directory at `relative` is excluded along with everything
+    /// below it.
+    pub fn prunes_dir(&self, relative: &Path) -> bool {
+        self.exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(relative) || exclude.dirs.is_match(relative))
+    }
+
+    /// With only `exclude` patterns, directories are kept even when no file
+    /// in them is; `include` keeps just the directories leading to a match.
+    fn keeps_empty_dirs(&self) -> bool {
+        self.include.is_none()
+    }
+}
+
+/// File count and size of a source tree, gathered by [`measure_source`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceSummary {
+    pub files: u64,
+    pub bytes: u64,
+    /// Bytes in files whose extension marks them as already compressed.
+    pub compressed_bytes: u64,
+    pub largest: Option<(PathBuf, u64)>,
+    pub oldest_mtime: Option<SystemTime>,
+    pub newest_mtime: Option<SystemTime>,
+}
+
+/// Walks every source path and sums up their regular files, skipping those
+/// `filter` rejects. Symlinks are not followed, so neither what they point
+/// to nor its size is counted, even for items that archive it.
+pub fn measure_source(paths: &[PathBuf], filter: Option<&PathFilter>) -> Result<SourceSummary> {
+    let mut summary = SourceSummary::default();
+    for path in paths {
+        measure_path(&mut summary, path, filter)?;
+    }
+    Ok(summary)
+}
+
+fn measure_path(
+    summary: &mut SourceSummary,
+    path: &Path,
+    filter: Option<&PathFilter>,
+) -> Result<()> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("Failed to read source path: {}", path.display()))?;
+    if metadata.is_file() {
+        summary.add_file(path, &metadata);
+        return Ok(());
+    }
+
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+        for entry in entries {
+            let entry =
+                entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+            let entry_path = entry.path();
+            if let Some(filter) = filter
+                && let Ok(relative) = entry_path.strip_prefix(path)
+            {
+                let excluded = if entry_path.is_dir() {
+                    filter.prunes_dir(relative)
+                } else {
+                    !filter.matches(relative)
+                };
+                if excluded {
+                    continue;
+                }
+            }
+            let file_type = entry
+                .file_type()
+                .with_context(|| format!("Failed to read file type: {}", entry_path.display()))?;
+            // Links below the source are archived as links, so what they
+            // point to is not counted.
+            if file_type.is_dir() {
+                pending.push(entry_path);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().with_context(|| {
+                    format!("Failed to read metadata: {}", entry_path.display())
+                })?;
+                summary.add_file(&entry_path, &metadata);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl SourceSummary {
+    fn add_file(&mut self, path: &Path, metadata: &fs::Metadata) {
+        let len = metadata.len();
+        self.files += 1;
+        self.bytes += len;
+        if is_compressed_file(path) {
+            self.compressed_bytes += len;
+        }
+        if self
+            .largest
+            .as_ref()
+            .is_none_or(|(_, largest)| len > *largest)
+        {
+            self.largest = Some((path.to_path_buf(), len));
+        }
+        if let Ok(mtime) = metadata.modified() {
+            self.oldest_mtime = Some(self.oldest_mtime.map_or(mtime, |oldest| oldest.min(mtime)));
+            self.newest_mtime = Some(self.newest_mtime.map_or(mtime, |newest| newest.max(mtime)));
+        }
+    }
+}
+
+fn is_compressed_file(path: &Path) -> bool {
+    const EXTENSIONS: &[&str] = &[
+        "7z", "avi", "br", "bz2", "gz", "heic", "jpeg", "jpg", "lz4", "mkv", "mov", "mp3", "mp4",
+        "png", "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+    ];
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| {
+            EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+        .unwrap_or(false)
+}
+
+/// zstd's own limit on worker threads.
+const MAX_THREADS: u32 = 200;
+
+/// Checks `compression_threads` and turns `0` into one thread per CPU.
+pub fn compression_threads(threads: u32) -> Result<u32> {
+    if threads > MAX_THREADS {
+        anyhow::bail!(
+            "compression_threads {} is out of range (1 to {}, or 0 for one per CPU)",
+            threads,
+            MAX_THREADS
+        );
+    }
+    if threads == 0 {
+        return Ok(std::thread::available_parallelism()
+            .map_or(1, |cpus| cpus.get().min(MAX_THREADS as usize) as u32));
+    }
+    Ok(threads)
+}
+
+/// Checks that `level` is one `format` accepts: 1 to 22 or a negative "fast"
+/// level for zstd, 1 to 9 for gzip. Plain tar takes no level at all.
+pub fn check_level(format: ArchiveFormat, level: i32) -> Result<i32> {
+    match format {
+        ArchiveFormat::Zstd => {
+            let range = zstd::compression_level_range();
+            if level == 0 || !range.contains(&level) {
+                anyhow::bail!(
+                    "compression_level {} is out of range (1 to {}, or a negative fast level down to {})",
+                    level,
+                    range.end(),
+                    range.start()
+                );
+            }
+        }
+        ArchiveFormat::Gzip => {
+            if !(1..=9).contains(&level) {
+                anyhow::bail!(
+                    "compression_level {} is out of range for gzip (1 to 9)",
+                    level
+                );
+            }
+        }
+        ArchiveFormat::Tar => {
+            anyhow::bail!("compression_level cannot be used with archive_format = \"tar\"")
+        }
+    }
+    Ok(level)
+}
+
+/// Picks a zstd level for `auto_level` items, returning it with a short
+/// reason for the log.
+pub fn choose_level(summary: &SourceSummary) -> (i32, &'static str) {
+    const LARGE_SOURCE_BYTES: u64 = 8 << 30;
+    const SMALL_FILE_BYTES: u64 = 64 << 10;
+
+    if summary.bytes > 0 && summary.compressed_bytes * 10 >= summary.bytes * 8 {
+        (3, "mostly already-compressed data")
+    } else if summary.bytes >= LARGE_SOURCE_BYTES {
+        (3, "large source, favouring speed")
+    } else if summary.files >= 1000 && summary.bytes / summary.files < SMALL_FILE_BYTES {
+        (15, "many small files")
+    } else {
+        (DEFAULT_LEVEL, "no strong signal, keeping the default")
+    }
+}
+
+/// Refuses to write an archive into the directory tree it is archiving, which
+/// would make the in-progress file part of its own contents.
+pub fn ensure_archive_outside_source(source_path: &Path, archive_path: &Path) -> Result<()> {
+    if !source_path.is_dir() {
+        return Ok(());
+    }
+    let source = fs::canonicalize(source_path)
+        .with_context(|| format!("Failed to resolve source path: {}", source_path.display()))?;
+    let archive_dir = archive_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let archive_dir = fs::canonicalize(archive_dir).with_context(|| {
+        format!(
+            "Failed to resolve archive directory: {}",
+            archive_dir.display()
+        )
+    })?;
+    if archive_dir.starts_with(&source) {
+        anyhow::bail!(
+            "Archive {} would be written inside the source directory {}; run from a directory outside the source",
+            archive_path.display(),
+            source.display()
+        );
+    }
+    Ok(())
+}
+
+/// Where the compressed bytes go: the output (the archive file, or a stream
+/// being uploaded), or one or a sequence of age streams in front of it.
+enum Sink<W: Write> {
+    Plain(W),
+    Age(age::stream::StreamWriter<W>),
+    Segmented(SegmentedWriter<W>),
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(output) => output.write(buf),
+            Self::Age(writer) => writer.write(buf),
+            Self::Segmented(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(output) => output.flush(),
+            Self::Age(writer) => writer.flush(),
+            Self::Segmented(writer) => writer.flush(),
+        }
+    }
+}
+
+impl<W: Write> Sink<W> {
+    fn finish(self) -> Result<()> {
+        let mut output = match self {
+            Self::Plain(output) => output,
+            Self::Age(writer) => writer.finish().context("Failed to finish age encryption")?,
+            Self::Segmented(writer) => {
+                writer.finish().context("Failed to finish age encryption")?
+            }
+        };
+        output.flush().context("Failed to write archive file")
+    }
+}
+
+/// The sink under the tar stream, compressed as `ArchiveFormat` says.
+enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<'static, Sink<W>>),
+    Gzip(GzEncoder<Sink<W>>),
+    Tar(Sink<W>),
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Tar(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Tar(file) => file.flush(),
+        }
+    }
+}
+
+type ArchiveBuilder<W> = tar::Builder<Encoder<W>>;
+
+fn create_archive_file(output_path: &Path) -> Result<File> {
+    File::create(output_path)
+        .with_context(|| format!("Failed to create archive file: {}", output_path.display()))
+}
+
+fn open_archive<W: Write>(output: W, options: &ArchiveOptions) -> Result<ArchiveBuilder<W>> {
+    let sink = match (&options.recipient, options.segment_size) {
+        (Some(recipient), Some(segment_size)) => Sink::Segmented(SegmentedWriter::new(
+            output,
+            recipient.clone(),
+            segment_size,
+        )?),
+        (Some(recipient), None) => {
+            let encryptor =
+                age::Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))
+                    .context("Failed to initialize age encryption")?;
+            Sink::Age(
+                encryptor
+                    .wrap_output(output)
+                    .context("Failed to initialize age encryption")?,
+            )
+        }
+        (None, _) => Sink::Plain(output),
+    };
+    let encoder = match options.format {
+        ArchiveFormat::Zstd => {
+            let mut encoder = zstd::Encoder::new(sink, options.level)
+                .context("Failed to initialize zstd encoder")?;
+            // The workers' output is only complete once `finish` returned,
+            // which `finish_archive` waits for like with a single thread.
+            if options.threads > 1 {
+                encoder
+                    .multithread(options.threads)
+                    .context("Failed to enable zstd multithreading")?;
+            }
+            Encoder::Zstd(encoder)
+        }
+        ArchiveFormat::Gzip => Encoder::Gzip(GzEncoder::new(
+            sink,
+            Compression::new(options.level.clamp(1, 9) as u32),
+        )),
+        ArchiveFormat::Tar => Encoder::Tar(sink),
+    };
+    Ok(tar::Builder::new(encoder))
+}
+
+/// The header of an entry for a file with `metadata`: its mode bits
+/// (executables stay executable), owner and mtime, with the owner replaced
+/// as `options` say.
+fn entry_header(metadata: &fs::Metadata, options: &ArchiveOptions) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    set_owner(&mut header, options);
+    header
+}
+
+fn set_owner(header: &mut tar::Header, options: &ArchiveOptions) {
+    if let Some(uid) = options.owner_uid {
+        header.set_uid(uid);
+    }
+    if let Some(gid) = options.owner_gid {
+        header.set_gid(gid);
+    }
+}
+
+/// Appends the regular file at `path` (or what a link there points to)
+/// under `name`.
+fn append_file<W: Write>(
+    builder: &mut ArchiveBuilder<W>,
+    path: &Path,
+    name: &Path,
+    options: &ArchiveOptions,
+) -> Result<()> {
+    let context = || format!("Failed to append file: {}", path.display());
+    let file = File::open(path).with_context(context)?;
+    let metadata = file.metadata().with_context(context)?;
+    let mut header = entry_header(&metadata, options);
+    builder
+        .append_data(&mut header, name, file)
+        .with_context(context)
+}
+
+/// Appends the directory at `path` under `name`, without its contents.
+fn append_dir<W: Write>(
+    builder: &mut ArchiveBuilder<W>,
+    name: &Path,
+    path: &Path,
+    options: &ArchiveOptions,
+) -> Result<()> {
+    let context = || format!("Failed to append directory: {}", path.display());
+    let metadata = fs::metadata(path).with_context(context)?;
+    let mut header = entry_header(&metadata, options);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, name, io::empty())
+        .with_context(context)
+}
+
+fn finish_archive<W: Write>(mut builder: ArchiveBuilder<W>) -> Result<()> {
+    builder.finish().context("Failed to finish tar archive")?;
+    let encoder = builder
+        .into_inner()
+        .context("Failed to finalize tar builder")?;
+    let sink = match encoder {
+        Encoder::Zstd(encoder) => encoder.finish().context("Failed to finish zstd encoding")?,
+        Encoder::Gzip(encoder) => encoder.finish().context("Failed to finish gzip encoding")?,
+        Encoder::Tar(sink) => sink,
+    };
+    sink.finish()
+}
+
+/// Archives `source_paths` into `output_path` and returns a summary of the
+/// regular files that went in, counted as they are appended.
+pub fn create_archive(
+    source_paths: &[PathBuf],
+    output_path: &Path,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    write_archive(source_paths, create_archive_file(output_path)?, options)
+}
+
+/// Like [`create_archive`], writing the archive to `output`.
+pub fn write_archive<W: Write>(
+    source_paths: &[PathBuf],
+    output: W,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let mut builder = open_archive(output, options)?;
+    let mut summary = SourceSummary::default();
+    for (source_path, base_name) in source_paths.iter().zip(entry_names(source_paths)) {
+        append_source(&mut builder, source_path, &base_name, options, &mut summary)?;
+    }
+    finish_archive(builder)?;
+    Ok(summary)
+}
+
+/// The top-level entry name of each source: its file name, with `-1`, `-2`,
+/// ... appended when an earlier source already uses it. Names are taken from
+/// the OS path as-is; tar stores them as raw bytes, so names that are not
+/// valid UTF-8 survive the round trip.
+fn entry_names(source_paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut taken: HashSet<OsString> = HashSet::new();
+    source_paths
+        .iter()
+        .map(|source_path| {
+            let base = source_path
+                .file_name()
+                .filter(|name| !name.is_empty())
+                .unwrap_or(OsStr::new("backup"));
+            let mut name = base.to_os_string();
+            let mut counter = 1;
+            while !taken.insert(name.clone()) {
+                name = base.to_os_string();
+                name.push(format!("-{counter}"));
+                counter += 1;
+            }
+            PathBuf::from(name)
+        })
+        .collect()
+}
+
+/// Appends one source path under `base_name`, adding its files to
+/// `summary`.
+fn append_source<W: Write>(
+    builder: &mut ArchiveBuilder<W>,
+    source_path: &Path,
+    base_name: &Path,
+    options: &ArchiveOptions,
+    summary: &mut SourceSummary,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(source_path)
+        .with_context(|| format!("Failed to read source path: {}", source_path.display()))?;
+    if options.symlink_mode == SymlinkMode::Store && metadata.file_type().is_symlink() {
+        append_symlink(builder, source_path, base_name, &metadata, options)?;
+    } else if source_path.is_dir() {
+        let matched = append_dir_walk(builder, base_name, source_path, options, summary)?;
+        if matched == 0 && options.modified_since.is_some() {
+            info!("No files changed in {}", source_path.display());
+        } else if matched == 0 && options.filter.is_some() {
+            warn!(
+                "include/exclude patterns matched no files in {}",
+                source_path.display()
+            );
+        }
+    } else if source_path.is_file() {
+        append_file(builder, source_path, base_name, options)?;
+        let metadata = fs::metadata(source_path)
+            .with_context(|| format!("Failed to read metadata: {}", source_path.display()))?;
+        summary.add_file(source_path, &metadata);
+    } else {
+        anyhow::bail!(
+            "Source path is not a file or directory: {}",
+            source_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Stores the symlink at `path` itself, with its own target and metadata.
+fn append_symlink<W: Write>(
+    builder: &mut ArchiveBuilder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &fs::Metadata,
+    options: &ArchiveOptions,
+) -> Result<()> {
+    let target = fs::read_link(path)
+        .with_context(|| format!("Failed to read symlink: {}", path.display()))?;
+    let mut header = entry_header(metadata, options);
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, name, &target)
+        .with_context(|| format!("Failed to append symlink: {}", path.display()))
+}
+
+/// Appends `source_dir` under `base_name`, skipping what the `filter` of
+/// `options` rejects, and returns how many files were added; regular files
+/// also go into `summary`. Without `include` patterns every
+/// directory that is not excluded is kept; with them only the directories
+/// leading to an added file are, and the same goes for `modified_since`,
+/// which skips files not modified after it. Symlinks below `source_dir` are
+/// stored as links (and count as files here) rather than followed, so a
+/// restore recreates them instead of duplicating what they point to, unless
+/// `follow_symlinks` is set. Then the file or directory a link points to is
+/// archived under the link's name; dangling links, and links back to a
+/// directory the walk is already inside, are still stored as links.
+fn append_dir_walk<W: Write>(
+    builder: &mut ArchiveBuilder<W>,
+    base_name: &Path,
+    source_dir: &Path,
+    options: &ArchiveOptions,
+    summary: &mut SourceSummary,
+) -> Result<u64> {
+    let filter = options.filter.as_ref();
+    let modified_since = options.modified_since;
+    let keep_empty_dirs =
+        modified_since.is_none() && filter.is_none_or(PathFilter::keeps_empty_dirs);
+    append_dir(builder, base_name, source_dir, options)?;
+    let mut appended_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut matched = 0;
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative_dir) = pending.pop() {
+        let dir = source_dir.join(&relative_dir);
+        let mut entries = fs::read_dir(&dir)
+            .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let relative = relative_dir.join(entry.file_name());
+            let mut metadata = fs::symlink_metadata(&path)
+                .with_context(|| format!("Failed to read metadata: {}", path.display()))?;
+            let mut is_symlink = metadata.file_type().is_symlink();
+            if is_symlink
+                && options.follow_symlinks
+                && let Ok(target) = fs::metadata(&path)
+            {
+                if target.is_dir() && leads_back(source_dir, &relative_dir, &path) {
+                    warn!(
+                        "Storing {} as a link: following it would loop back into {}",
+                        path.display(),
+                        dir.display()
+                    );
+                } else {
+                    metadata = target;
+                    is_symlink = false;
+                }
+            }
+            if metadata.is_dir() {
+                if filter.is_some_and(|filter| filter.prunes_dir(&relative)) {
+                    continue;
+                }
+                if keep_empty_dirs {
+                    append_dir(builder, &base_name.join(&relative), &path, options)?;
+                    appended_dirs.insert(relative.clone());
+                }
+                pending.push(relative);
+                continue;
+            }
+            if !(metadata.is_file() || is_symlink)
+                || filter.is_some_and(|filter| !filter.matches(&relative))
+            {
+                continue;
+            }
+            // A file whose mtime can't be read is always taken.
+            if let Some(since) = modified_since
+                && metadata.modified().is_ok_and(|modified| modified <= since)
+            {
+                continue;
+            }
+
+            let mut parents: Vec<&Path> = relative
+                .ancestors()
+                .skip(1)
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .collect();
+            parents.reverse();
+            for parent in parents {
+                if appended_dirs.insert(parent.to_path_buf()) {
+                    append_dir(
+                        builder,
+                        &base_name.join(parent),
+                        &source_dir.join(parent),
+                        options,
+                    )?;
+                }
+            }
+            if is_symlink {
+                append_symlink(
+                    builder,
+                    &path,
+                    &base_name.join(&relative),
+                    &metadata,
+                    options,
+                )?;
+            } else {
+                append_file(builder, &path, &base_name.join(&relative), options)?;
+                summary.add_file(&path, &metadata);
+            }
+            matched += 1;
+        }
+    }
+    Ok(matched)
+}
+
+/// Whether the directory `link` points to contains `source_dir/relative_dir`
+/// or one of the directories above it up to `source_dir`, as they resolve
+/// through the links followed to get there. Walking into it would reach the
+/// link again, forever.
+fn leads_back(source_dir: &Path, relative_dir: &Path, link: &Path) -> bool {
+    let Ok(target) = fs::canonicalize(link) else {
+        return false;
+    };
+    relative_dir.ancestors().any(|ancestor| {
+        fs::canonicalize(source_dir.join(ancestor)).is_ok_and(|real| real.starts_with(&target))
+    })
+}
+
+/// Opens an archive for reading its entries, decrypting it with `identity`
+/// first when one is given. An archive encrypted as several age streams is
+/// decrypted stream by stream.
+pub fn read_archive(
+    path: &Path,
+    format: ArchiveFormat,
+    identity: Option<&age::x25519::Identity>,
+) -> Result<tar::Archive<Box<dyn Read>>> {
+    let input: Box<dyn Read> = match identity {
+        Some(identity) => encryption::open_streams(path, identity)?,
+        None => Box::new(
+            File::open(path)
+                .with_context(|| format!("Failed to open archive: {}", path.display()))?,
+        ),
+    };
+    let reader: Box<dyn Read> = match format {
+        ArchiveFormat::Zstd => {
+            Box::new(zstd::Decoder::new(input).context("Failed to initialize zstd decoder")?)
+        }
+        ArchiveFormat::Gzip => Box::new(GzDecoder::new(input)),
+        ArchiveFormat::Tar => input,
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Reads every entry of the archive to the end and returns how many there
+/// are, failing on a truncated or corrupted stream.
+pub fn count_entries(path: &Path, format: ArchiveFormat) -> Result<u64> {
+    let mut archive = read_archive(path, format, None)?;
+    let mut count = 0;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Failed to read archive: {}", path.display()))?
+    {
+        let mut entry = entry.with_context(|| format!("Corrupted archive: {}", path.display()))?;
+        io::copy(&mut entry, &mut io::sink())
+            .with_context(|| format!("Corrupted archive: {}", path.display()))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Archives the output of a database dump as a single `entry_name` entry.
+///
+/// tar needs an entry's size before its data, so the dump is spooled to an
+/// unnamed temporary file next to the archive rather than held in memory.
+/// The archive itself is only created once the dump has succeeded. The
+/// summary returned counts the dump as the one file.
+pub fn create_database_archive(
+    database: &DatabaseDump,
+    entry_name: &str,
+    output_path: &Path,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let spool_dir = output_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let (spool, size) = spool_dump(database, spool_dir)?;
+    append_dump(
+        spool,
+        size,
+        entry_name,
+        create_archive_file(output_path)?,
+        options,
+    )
+}
+
+/// Like [`create_database_archive`], writing the archive to `output` and
+/// spooling the dump in `spool_dir`.
+pub fn write_database_archive<W: Write>(
+    database: &DatabaseDump,
+    entry_name: &str,
+    spool_dir: &Path,
+    output: W,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let (spool, size) = spool_dump(database, spool_dir)?;
+    append_dump(spool, size, entry_name, output, options)
+}
+
+/// Archives `spool`, a file holding a command's complete output, as a
+/// single `entry_name` entry. The archive is removed again if writing it
+/// fails, so no half-written one is left behind.
+pub fn create_output_archive(
+    spool: File,
+    entry_name: &str,
+    output_path: &Path,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let created = write_output_archive(
+        spool,
+        entry_name,
+        create_archive_file(output_path)?,
+        options,
+    );
+    if created.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    created
+}
+
+/// Like [`create_output_archive`], writing the archive to `output`.
+pub fn write_output_archive<W: Write>(
+    mut spool: File,
+    entry_name: &str,
+    output: W,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let size = spool
+        .metadata()
+        .context("Failed to read command output spool file")?
+        .len();
+    spool
+        .rewind()
+        .context("Failed to rewind command output spool file")?;
+    append_dump(spool, size, entry_name, output, options)
+}
+
+/// Runs the dump into an unnamed file in `spool_dir` and returns it rewound,
+/// with its size.
+fn spool_dump(database: &DatabaseDump, spool_dir: &Path) -> Result<(File, u64)> {
+    let mut spool = tempfile::tempfile_in(spool_dir).with_context(|| {
+        format!(
+            "Failed to create dump spool file in: {}",
+            spool_dir.display()
+        )
+    })?;
+
+    let mut child = database
+        .command()
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {} dump", database.engine.as_str()))?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .context("Failed to capture dump output")?;
+    let copied = io::copy(&mut stdout, &mut spool);
+    let status = child.wait().context("Failed to wait for dump process")?;
+    let size = copied.context("Failed to read dump output")?;
+    if !status.success() {
+        anyhow::bail!("Dump command failed with exit code: {}", status);
+    }
+    spool.rewind().context("Failed to rewind dump spool file")?;
+    Ok((spool, size))
+}
+
+fn append_dump<W: Write>(
+    spool: File,
+    size: u64,
+    entry_name: &str,
+    output: W,
+    options: &ArchiveOptions,
+) -> Result<SourceSummary> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o600);
+    set_owner(&mut header, options);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0),
+    );
+    let mut builder = open_archive(output, options)?;
+    builder
+        .append_data(&mut header, entry_name, spool)
+        .with_context(|| format!("Failed to append archive entry: {}", entry_name))?;
+    finish_archive(builder)?;
+    Ok(SourceSummary {
+        files: 1,
+        bytes: size,
+        ..SourceSummary::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_archive_outside_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(source.join("nested")).unwrap();
+
+        let inside = source.join("nested").join("data-20260211.tar.zst");
+        assert!(ensure_archive_outside_source(&source, &inside).is_err());
+
+        let outside = dir.path().join("data-20260211.tar.zst");
+        assert!(ensure_archive_outside_source(&source, &outside).is_ok());
+    }
+
+    #[test]
+    fn test_choose_level() {
+        let media = SourceSummary {
+            files: 3,
+            bytes: 30 << 30,
+            compressed_bytes: 29 << 30,
+            ..Default::default()
+        };
+        assert_eq!(choose_level(&media).0, 3);
+
+        let configs = SourceSummary {
+            files: 20_000,
+            bytes: 200 << 20,
+            ..Default::default()
+        };
+        assert_eq!(choose_level(&configs).0, 15);
+
+        let mixed = SourceSummary {
+            files: 10,
+            bytes: 1 << 30,
+            ..Default::default()
+        };
+        assert_eq!(choose_level(&mixed).0, DEFAULT_LEVEL);
+    }
+
+    #[test]
+    fn test_check_level() {
+        assert_eq!(check_level(ArchiveFormat::Zstd, 1).unwrap(), 1);
+        assert_eq!(check_level(ArchiveFormat::Zstd, 22).unwrap(), 22);
+        assert_eq!(check_level(ArchiveFormat::Zstd, -5).unwrap(), -5);
+        assert!(check_level(ArchiveFormat::Zstd, 0).is_err());
+        assert!(check_level(ArchiveFormat::Zstd, 23).is_err());
+        assert_eq!(check_level(ArchiveFormat::Gzip, 9).unwrap(), 9);
+        assert!(check_level(ArchiveFormat::Gzip, 10).is_err());
+        assert!(check_level(ArchiveFormat::Tar, 1).is_err());
+    }
+
+    #[test]
+    fn test_create_archive_multithreaded() {
+        assert_eq!(compression_threads(4).unwrap(), 4);
+        assert!(compression_threads(0).unwrap() >= 1);
+        assert!(compression_threads(MAX_THREADS + 1).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(&source).unwrap();
+        // Large enough for zstd to split it into several jobs.
+        let content = "backup-to-cloud ".repeat(1 << 20);
+        fs::write(source.join("big.txt"), &content).unwrap();
+        let output = dir.path().join("data-20260211.tar.zst");
+        let options = ArchiveOptions {
+            threads: 4,
+            ..ArchiveOptions::default()
+        };
+        create_archive(&[source], &output, &options).unwrap();
+
+        let decoder = zstd::Decoder::new(File::open(&output).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.path().unwrap().ends_with("big.txt"))
+            .unwrap();
+        let mut read = String::new();
+        entry.read_to_string(&mut read).unwrap();
+        assert_eq!(read, content);
+    }
+
+    #[test]
+    fn test_write_output_archive() {
+        let mut spool = tempfile::tempfile().unwrap();
+        spool.write_all(b"CREATE TABLE t (id int);\n").unwrap();
+        let options = ArchiveOptions::default();
+        let mut output = Vec::new();
+        let summary = write_output_archive(spool, "app.sql", &mut output, &options).unwrap();
+        assert_eq!((summary.files, summary.bytes), (1, 25));
+
+        let decoder = zstd::Decoder::new(output.as_slice()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("app.sql"));
+        let mut read = String::new();
+        entry.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "CREATE TABLE t (id int);\n");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let include = ["*.conf".to_string(), "/ssl/*.key".to_string()];
+        let filter = PathFilter::new(&include, &[]).unwrap().unwrap();
+        assert!(filter.matches(Path::new("nginx.conf")));
+        assert!(filter.matches(Path::new("nginx/sites/default.conf")));
+        assert!(filter.matches(Path::new("ssl/server.key")));
+        assert!(!filter.matches(Path::new("ssl/private/server.key")));
+        assert!(!filter.matches(Path::new("nginx/mime.types")));
+        assert!(PathFilter::new(&[], &[]).unwrap().is_none());
+        assert!(PathFilter::new(&["[".to_string()], &[]).is_err());
+        assert!(PathFilter::new(&[], &["[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_path_filter_exclude() {
+        let exclude = [
+            "**/node_modules/**".to_string(),
+            "*.tmp".to_string(),
+            ".git".to_string(),
+        ];
+        let filter = PathFilter::new(&[], &exclude).unwrap().unwrap();
+        assert!(filter.matches(Path::new("src/main.rs")));
+        assert!(!filter.matches(Path::new("build/cache.tmp")));
+        assert!(!filter.matches(Path::new("web/node_modules/left-pad/index.js")));
+        assert!(filter.prunes_dir(Path::new("node_modules")));
+        assert!(filter.prunes_dir(Path::new("web/node_modules")));
+        assert!(filter.prunes_dir(Path::new("web/.git")));
+        assert!(!filter.prunes_dir(Path::new("web/src")));
+
+        let include = ["*.conf".to_string()];
+        let exclude = ["old/**".to_string()];
+        let filter = PathFilter::new(&include, &exclude).unwrap().unwrap();
+        assert!(filter.matches(Path::new("nginx.conf")));
+        assert!(!filter.matches(Path::new("old/nginx.conf")));
+    }
+
+    #[test]
+    fn test_create_archive_multiple_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = [
+            dir.path().join("app/data"),
+            dir.path().join("etc/app.toml"),
+            dir.path().join("uploads/data"),
+        ];
+        fs::create_dir_all(&sources[0]).unwrap();
+        fs::write(sources[0].join("db.sqlite"), b"rows").unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        fs::write(&sources[1], b"debug = false").unwrap();
+        fs::create_dir_all(&sources[2]).unwrap();
+        fs::write(sources[2].join("logo.png"), b"png").unwrap();
+        let output = dir.path().join("app-20260211.tar.zst");
+        let options = ArchiveOptions::default();
+        let summary = create_archive(&sources, &output, &options).unwrap();
+        assert_eq!((summary.files, summary.bytes), (3, 20));
+        assert_eq!(summary.compressed_bytes, 3);
+
+        assert_eq!(
+            archive_names(&output),
+            [
+                "data",
+                "data/db.sqlite",
+                "app.toml",
+                "data-1",
+                "data-1/logo.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_archive_with_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("etc");
+        fs::create_dir_all(source.join("nginx")).unwrap();
+        fs::write(source.join("hosts"), b"127.0.0.1 localhost").unwrap();
+        fs::write(source.join("nginx/nginx.conf"), b"events {}").unwrap();
+        let output = dir.path().join("etc-20260211.tar.zst");
+        let options = ArchiveOptions {
+            filter: PathFilter::new(&["*.conf".to_string()], &[]).unwrap(),
+            ..ArchiveOptions::default()
+        };
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+
+        assert_eq!(
+            archive_names(&output),
+            ["etc", "etc/nginx", "etc/nginx/nginx.conf"]
+        );
+    }
+
+    #[test]
+    fn test_create_archive_modified_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(source.join("old")).unwrap();
+        fs::create_dir_all(source.join("new")).unwrap();
+        let since = SystemTime::now() - std::time::Duration::from_secs(3600);
+        for (path, modified) in [
+            ("old/report.csv", since - std::time::Duration::from_secs(60)),
+            ("new/report.csv", SystemTime::now()),
+        ] {
+            let file = File::create(source.join(path)).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+        let output = dir.path().join("data-20260211-incr.tar.zst");
+        let options = ArchiveOptions {
+            modified_since: Some(since),
+            ..ArchiveOptions::default()
+        };
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+
+        assert_eq!(
+            archive_names(&output),
+            ["data", "data/new", "data/new/report.csv"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_archive_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("app.toml"), b"debug = false").unwrap();
+        let uid = u64::from(fs::metadata(&source).unwrap().uid());
+        let output = dir.path().join("app-20260211.tar");
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Tar,
+            ..ArchiveOptions::default()
+        };
+        let owners = |output: &Path| {
+            let mut archive = tar::Archive::new(File::open(output).unwrap());
+            archive
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let header = entry.unwrap().header().clone();
+                    (header.uid().unwrap(), header.gid().unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+        assert!(owners(&output).iter().all(|&(owner, _)| owner == uid));
+
+        let options = ArchiveOptions {
+            owner_uid: Some(0),
+            owner_gid: Some(1000),
+            ..options
+        };
+        create_archive(&[source], &output, &options).unwrap();
+        assert_eq!(owners(&output), [(0, 1000), (0, 1000)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_archive_following_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        let shared = dir.path().join("shared");
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(source.join("a.txt"), b"a").unwrap();
+        fs::write(shared.join("b.txt"), b"b").unwrap();
+        symlink("../shared", source.join("shared")).unwrap();
+        symlink(".", source.join("loop")).unwrap();
+        symlink("missing-target", source.join("gone")).unwrap();
+        symlink("../data", shared.join("back")).unwrap();
+        let output = dir.path().join("data-20260211.tar.zst");
+        let options = ArchiveOptions {
+            follow_symlinks: true,
+            ..ArchiveOptions::default()
+        };
+        let summary = create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+
+        assert_eq!(
+            archive_names(&output),
+            [
+                "data",
+                "data/a.txt",
+                "data/gone",
+                "data/loop",
+                "data/shared",
+                "data/shared/b.txt",
+                "data/shared/back",
+            ]
+        );
+        assert_eq!(summary.files, 2);
+    }
+
+    #[test]
+    fn test_create_archive_with_exclude() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app");
+        fs::create_dir_all(source.join("web/node_modules/left-pad")).unwrap();
+        fs::create_dir_all(source.join("logs")).unwrap();
+        fs::write(source.join("web/index.js"), b"main()").unwrap();
+        fs::write(source.join("web/node_modules/left-pad/index.js"), b"pad").unwrap();
+        fs::write(source.join("web/build.tmp"), b"scratch").unwrap();
+        let output = dir.path().join("app-20260211.tar.zst");
+        let exclude = ["**/node_modules/**".to_string(), "*.tmp".to_string()];
+        let options = ArchiveOptions {
+            filter: PathFilter::new(&[], &exclude).unwrap(),
+            ..ArchiveOptions::default()
+        };
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+        let mut names = archive_names(&output);
+        names.sort();
+        // The empty `logs` directory is kept since nothing excluded it.
+        assert_eq!(names, ["app", "app/logs", "app/web", "app/web/index.js"]);
+
+        // A pattern that matches nothing archives the whole tree.
+        let options = ArchiveOptions {
+            filter: PathFilter::new(&[], &["*.bak".to_string()]).unwrap(),
+            ..options
+        };
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+        assert_eq!(archive_names(&output).len(), 8);
+    }
+
+    #[test]
+    fn test_create_archive_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("notes.txt");
+        fs::write(&source, b"remember the milk").unwrap();
+        for format in [ArchiveFormat::Gzip, ArchiveFormat::Tar] {
+            let output = dir
+                .path()
+                .join(format!("notes-20260211.{}", format.extension()));
+            let options = ArchiveOptions {
+                format,
+                level: DEFAULT_GZIP_LEVEL,
+                ..ArchiveOptions::default()
+            };
+            create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+            let name = output.file_name().unwrap().to_str().unwrap();
+            assert_eq!(ArchiveFormat::from_file_name(name), Some((format, false)));
+            let mut archive = read_archive(&output, format, None).unwrap();
+            let mut entries = archive.entries().unwrap();
+            let mut entry = entries.next().unwrap().unwrap();
+            assert_eq!(entry.path().unwrap(), Path::new("notes.txt"));
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "remember the milk");
+            assert_eq!(count_entries(&output, format).unwrap(), 1);
+        }
+        assert_eq!(
+            ArchiveFormat::from_file_name("notes-20260211.tar.zst.age"),
+            Some((ArchiveFormat::Zstd, true))
+        );
+        assert_eq!(ArchiveFormat::from_file_name("notes.zip"), None);
+    }
+
+    #[test]
+    fn test_create_encrypted_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("secrets.txt");
+        fs::write(&source, b"hunter2").unwrap();
+        let output = dir.path().join("secrets-20260211.tar.zst.age");
+        let identity = age::x25519::Identity::generate();
+        let options = ArchiveOptions {
+            recipient: Some(identity.to_public()),
+            ..ArchiveOptions::default()
+        };
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+
+        let encrypted = fs::read(&output).unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1"));
+        let mut archive = read_archive(&output, ArchiveFormat::Zstd, Some(&identity)).unwrap();
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hunter2");
+    }
+
+    fn archive_names(path: &Path) -> Vec<String> {
+        let decoder = zstd::Decoder::new(File::open(path).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().display().to_string();
+                path.trim_end_matches('/').to_string()
+            })
+            .collect()
+    }
+
+    // macOS refuses file names that are not valid UTF-8.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_create_archive_keeps_non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join(OsStr::from_bytes(b"data-\xff"));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join(OsStr::from_bytes(b"caf\xe9.txt")), b"latin-1").unwrap();
+        let output = dir.path().join("data-20260211.tar.zst");
+        let options = ArchiveOptions::default();
+        create_archive(std::slice::from_ref(&source), &output, &options).unwrap();
+
+        let decoder = zstd::Decoder::new(File::open(&output).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let names: Vec<Vec<u8>> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path_bytes().into_owned())
+            .collect();
+        assert!(names.iter().any(|name| name == b"data-\xff/caf\xe9.txt"));
+    }
+
+    #[test]
+    fn test_measure_source() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/notes.txt"), b"hello").unwrap();
+        fs::write(dir.path().join("a/b/photo.JPG"), b"0123456789").unwrap();
+
+        let summary = measure_source(&[dir.path().to_path_buf()], None).unwrap();
+        assert_eq!(summary.files, 2);
+        assert_eq!(summary.bytes, 15);
+        assert_eq!(summary.compressed_bytes, 10);
+        assert_eq!(
+            summary.largest,
+            Some((dir.path().join("a/b/photo.JPG"), 10))
+        );
+        assert!(summary.oldest_mtime.is_some() && summary.oldest_mtime <= summary.newest_mtime);
+    }
+}
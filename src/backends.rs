@@ -0,0 +1,667 @@
+//! Setting up the enabled uploaders from `[app]`, with their tokens,
+//! credentials and HTTP settings.
+
+use crate::config::AppConfig;
+use crate::uploader::gdrive::{GDriveConfig, GDriveUploader};
+use crate::uploader::local::LocalUploader;
+use crate::uploader::onedrive::{self, OneDriveConfig, OneDriveUploader};
+use crate::uploader::s3::{ObjectLock, ObjectLockMode, S3Config, S3Uploader};
+use crate::uploader::sftp::{SftpAuth, SftpConfig, SftpUploader};
+use crate::uploader::webdav::WebDavUploader;
+use crate::uploader::{
+    self, Backoff, EstanUploader, HttpConfig, ImmutableUploader, Throttle, Timeouts, Uploader,
+};
+use anyhow::{Context, Result};
+use estan::uploader::{BaiduPanUploader, Cloud189Uploader};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Makes a token file written by estan readable by its owner only. estan
+/// creates it with the default umask; rewriting it in place later keeps the
+/// mode.
+#[cfg(unix)]
+pub fn restrict_token_file(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Ok(metadata) = fs::metadata(path) else {
+        return;
+    };
+    if metadata.permissions().mode() & 0o077 != 0 {
+        match fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            Ok(()) => info!("Restricted token file {} to mode 600", path.display()),
+            Err(err) => warn!(
+                "Token file {} is accessible by other users and could not be restricted: {err}",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn restrict_token_file(_path: &Path) {}
+
+/// Where estan keeps the Baidu and Cloud189 tokens when given no path,
+/// relative to the home directory.
+pub const LEGACY_BAIDU_CONFIG: &str = ".baidu/baidu_pan_config.json";
+pub const LEGACY_CLOUD189_CONFIG: &str = ".cloud189/config.json";
+
+/// The token file used when the config names none:
+/// `backup-to-cloud/<name>` in the platform's config directory
+/// (`$XDG_CONFIG_HOME` or `~/.config` on Linux, `~/Library/Application
+/// Support` on macOS, `%APPDATA%` on Windows). The first time, a token
+/// estan saved at its own `legacy` location is moved there, so upgrading
+/// keeps the login. `None` if the platform has no config directory, which
+/// leaves the location to estan as before.
+pub fn default_token_path(name: &str, legacy: &str) -> Option<PathBuf> {
+    let dir = dirs::config_dir()?.join(env!("CARGO_PKG_NAME"));
+    let path = dir.join(name);
+    if path.exists() {
+        return Some(path);
+    }
+    if let Err(err) = fs::create_dir_all(&dir) {
+        warn!("Cannot create {}: {err}", dir.display());
+        return None;
+    }
+    let Some(legacy) = dirs::home_dir()
+        .map(|home| home.join(legacy))
+        .filter(|legacy| legacy.is_file())
+    else {
+        return Some(path);
+    };
+    // A rename fails across file systems; copy the token then.
+    let moved = fs::rename(&legacy, &path)
+        .or_else(|_| fs::copy(&legacy, &path).and_then(|_| fs::remove_file(&legacy)));
+    match moved {
+        Ok(()) => {
+            restrict_token_file(&path);
+            info!(
+                "Moved token file {} to {}",
+                legacy.display(),
+                path.display()
+            );
+            Some(path)
+        }
+        Err(err) => {
+            warn!(
+                "Cannot move token file {} to {}, still using it there: {err}",
+                legacy.display(),
+                path.display()
+            );
+            Some(legacy)
+        }
+    }
+}
+
+/// Environment variable `auth` takes the Baidu authorization code from.
+pub const BAIDU_AUTH_CODE_ENV: &str = "BAIDU_AUTH_CODE";
+
+/// Where a Baidu Netdisk user grants the app access; the page shows the
+/// authorization code that estan asks for.
+pub const BAIDU_AUTHORIZE_URL: &str = "https://openapi.baidu.com/oauth/2.0/authorize";
+
+/// The authorization page for the app with `app_key`, for the out-of-band
+/// code flow estan uses.
+pub fn baidu_authorize_url(app_key: &str) -> String {
+    format!(
+        "{BAIDU_AUTHORIZE_URL}?response_type=code&client_id={}&redirect_uri=oob&scope=basic,netdisk",
+        app_key.trim()
+    )
+}
+
+/// Retries when Baidu rate limits an upload, unless
+/// `baidu_rate_limit_retries` is set: 10s, 20s, 40s, 80s and 160s apart.
+pub const DEFAULT_BAIDU_RATE_LIMIT_RETRIES: u32 = 5;
+pub const DEFAULT_BAIDU_RATE_LIMIT_DELAY_SECS: u64 = 10;
+
+/// An uploader has no saved token and stdin is not a terminal, so its
+/// login could only fail or block: estan's Baidu prompt waits for a code on
+/// stdin, and the Google Drive and OneDrive codes have to be entered in a
+/// browser by someone who sees them.
+#[derive(Debug)]
+pub enum AuthRequired {
+    Baidu { app_key: String },
+    GDrive,
+    OneDrive,
+}
+
+impl fmt::Display for AuthRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthRequired::Baidu { app_key } => write!(
+                f,
+                "Baidu Netdisk is not authorized yet and there is no terminal to ask for the code; \
+                 run `backup-to-cloud auth <config>` once interactively, or open {} and run it \
+                 with {} set to the code shown there",
+                baidu_authorize_url(app_key),
+                BAIDU_AUTH_CODE_ENV
+            ),
+            AuthRequired::GDrive => write!(
+                f,
+                "Google Drive is not authorized yet and there is no terminal to show the code on; \
+                 run `backup-to-cloud auth <config>` once interactively"
+            ),
+            AuthRequired::OneDrive => write!(
+                f,
+                "OneDrive is not authorized yet and there is no terminal to show the code on; \
+                 run `backup-to-cloud auth <config>` once interactively"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuthRequired {}
+
+/// Builds the Baidu uploader, which logs in when it has no saved token by
+/// prompting for an authorization code on stdin. Unless `can_prompt`, a
+/// missing token file fails with `AuthRequired` instead.
+pub fn new_baidu_uploader(app: &AppConfig, can_prompt: bool) -> Result<BaiduPanUploader> {
+    let has_baidu_key = app
+        .baidu_app_key
+        .as_deref()
+        .map(|value| !value.trim().is_empty())
+        .unwrap_or(false);
+    let has_baidu_secret = app
+        .baidu_app_secret
+        .as_deref()
+        .map(|value| !value.trim().is_empty())
+        .unwrap_or(false);
+    if !(has_baidu_key && has_baidu_secret) {
+        anyhow::bail!("Baidu uploader enabled but baidu_app_key/baidu_app_secret are incomplete");
+    }
+    let app_key = app
+        .baidu_app_key
+        .clone()
+        .context("Missing baidu_app_key (or app_key)")?;
+    let app_secret = app
+        .baidu_app_secret
+        .clone()
+        .context("Missing baidu_app_secret (or app_secret)")?;
+    let baidu_config = baidu_token_path(app);
+    if !can_prompt && baidu_config.as_deref().is_some_and(|path| !path.exists()) {
+        return Err(AuthRequired::Baidu { app_key }.into());
+    }
+    let uploader = BaiduPanUploader::new(app_key, app_secret, baidu_config.clone())
+        .map_err(|err| uploader::baidu::explain(err.into()))?;
+    // Construction may have logged in and written fresh tokens.
+    if let Some(path) = &baidu_config {
+        restrict_token_file(path);
+    }
+    Ok(uploader)
+}
+
+pub fn baidu_token_path(app: &AppConfig) -> Option<PathBuf> {
+    app.baidu_config
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| default_token_path("baidu.json", LEGACY_BAIDU_CONFIG))
+}
+
+/// Builds the Google Drive uploader, which runs the device authorization
+/// when `gdrive_config` does not exist yet. Unless `can_prompt`, that fails
+/// with `AuthRequired` instead.
+pub fn new_gdrive_uploader(app: &AppConfig, can_prompt: bool) -> Result<GDriveUploader> {
+    let (Some(client_id), Some(client_secret)) = (
+        app.gdrive_client_id
+            .clone()
+            .filter(|value| !value.trim().is_empty()),
+        app.gdrive_client_secret
+            .clone()
+            .filter(|value| !value.trim().is_empty()),
+    ) else {
+        anyhow::bail!(
+            "GDrive uploader enabled but gdrive_client_id/gdrive_client_secret are incomplete"
+        );
+    };
+    let token_path = app
+        .gdrive_config
+        .as_deref()
+        .filter(|value| !value.trim().is_empty())
+        .map(PathBuf::from)
+        .context("GDrive uploader enabled but gdrive_config is missing")?;
+    if !can_prompt && !token_path.exists() {
+        return Err(AuthRequired::GDrive.into());
+    }
+    let uploader = GDriveUploader::new(
+        GDriveConfig {
+            client_id,
+            client_secret,
+            token_path: token_path.clone(),
+        },
+        &http_config(app),
+    )?;
+    restrict_token_file(&token_path);
+    Ok(uploader)
+}
+
+/// Builds the OneDrive uploader, which runs the device authorization when
+/// `onedrive_config` does not exist yet. Unless `can_prompt`, that fails
+/// with `AuthRequired` instead.
+pub fn new_onedrive_uploader(app: &AppConfig, can_prompt: bool) -> Result<OneDriveUploader> {
+    let client_id = app
+        .onedrive_client_id
+        .clone()
+        .filter(|value| !value.trim().is_empty())
+        .context("OneDrive uploader enabled but onedrive_client_id is missing")?;
+    let token_path = app
+        .onedrive_config
+        .as_deref()
+        .filter(|value| !value.trim().is_empty())
+        .map(PathBuf::from)
+        .context("OneDrive uploader enabled but onedrive_config is missing")?;
+    if !can_prompt && !token_path.exists() {
+        return Err(AuthRequired::OneDrive.into());
+    }
+    let uploader = OneDriveUploader::new(
+        OneDriveConfig {
+            client_id,
+            client_secret: app
+                .onedrive_client_secret
+                .clone()
+                .filter(|value| !value.trim().is_empty()),
+            tenant: app
+                .onedrive_tenant
+                .clone()
+                .filter(|value| !value.trim().is_empty())
+                .unwrap_or_else(|| onedrive::DEFAULT_TENANT.to_string()),
+            token_path: token_path.clone(),
+        },
+        &http_config(app),
+    )?;
+    restrict_token_file(&token_path);
+    Ok(uploader)
+}
+
+/// The client settings shared by S3, WebDAV, Google Drive and OneDrive.
+pub fn http_config(app: &AppConfig) -> HttpConfig {
+    let defaults = Timeouts::default();
+    HttpConfig {
+        timeouts: Timeouts {
+            connect: app
+                .connect_timeout_secs
+                .map_or(defaults.connect, Duration::from_secs),
+            request: app
+                .upload_timeout_secs
+                .map_or(defaults.request, Duration::from_secs),
+        },
+        proxy: app
+            .http_proxy
+            .clone()
+            .filter(|value| !value.trim().is_empty()),
+        user_agent: app
+            .user_agent
+            .clone()
+            .filter(|value| !value.trim().is_empty()),
+    }
+}
+
+pub fn build_uploaders(app: &AppConfig) -> Result<Vec<Box<dyn Uploader>>> {
+    build_selected_uploaders(app, None)
+}
+
+/// Builds the enabled uploaders, or with `only` just the one of that name
+/// (as in `enabled_uploader_names`).
+pub fn build_selected_uploaders(
+    app: &AppConfig,
+    only: Option<&str>,
+) -> Result<Vec<Box<dyn Uploader>>> {
+    let enabled = |flag: Option<bool>, name: &str| {
+        flag.unwrap_or(false) && only.is_none_or(|only| only == name)
+    };
+
+    let baidu_enabled = enabled(app.baidu_enabled, "Baidu");
+    let baidu_uploader = if baidu_enabled {
+        Some(Box::new(
+            EstanUploader::new(new_baidu_uploader(app, io::stdin().is_terminal())?)
+                .with_absolute_paths()
+                .with_baidu_errnos()
+                .with_max_file_size(app.baidu_max_file_size_mb.map(|mb| mb << 20))
+                .with_backoff(app.baidu_max_retries.map(|max_retries| Backoff {
+                    max_retries,
+                    base_delay: Duration::from_millis(
+                        app.baidu_retry_base_delay_ms.unwrap_or(1000),
+                    ),
+                }))
+                .with_baidu_rate_limit(Some(Backoff {
+                    max_retries: app
+                        .baidu_rate_limit_retries
+                        .unwrap_or(DEFAULT_BAIDU_RATE_LIMIT_RETRIES),
+                    base_delay: Duration::from_secs(
+                        app.baidu_rate_limit_delay_secs
+                            .unwrap_or(DEFAULT_BAIDU_RATE_LIMIT_DELAY_SECS),
+                    ),
+                })),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let cloud189_enabled = enabled(app.cloud189_enabled, "Cloud189");
+    let cloud189_uploader = if cloud189_enabled {
+        let (username, password, use_qr) = resolve_cloud189_credentials(
+            app.cloud189_username.clone(),
+            app.cloud189_password.clone(),
+            app.cloud189_use_qr,
+        );
+        let username_present = username
+            .as_deref()
+            .map(|value| !value.trim().is_empty())
+            .unwrap_or(false);
+        let password_present = password
+            .as_deref()
+            .map(|value| !value.trim().is_empty())
+            .unwrap_or(false);
+        if !use_qr {
+            if username_present ^ password_present {
+                anyhow::bail!(
+                    "Cloud189 uploader enabled with password login, but username/password are incomplete"
+                );
+            }
+            if !username_present && !password_present {
+                anyhow::bail!(
+                    "Cloud189 uploader enabled requires either cloud189_use_qr=true or both username/password"
+                );
+            }
+        }
+        let cloud189_config = app
+            .cloud189_config
+            .as_ref()
+            .map(PathBuf::from)
+            .or_else(|| default_token_path("cloud189.json", LEGACY_CLOUD189_CONFIG));
+        Some(Box::new(
+            EstanUploader::new(Cloud189Uploader::new(
+                cloud189_config,
+                username,
+                password,
+                use_qr,
+            )?)
+            .with_max_file_size(app.cloud189_max_file_size_mb.map(|mb| mb << 20)),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    // One bucket for every backend, so the cap holds for the whole run even
+    // while items upload to several backends at once.
+    let throttle = Throttle::new(
+        app.max_upload_bytes_per_sec.unwrap_or(0),
+        app.stall_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    );
+    if throttle.is_some() && (baidu_enabled || cloud189_enabled) {
+        warn!(
+            "max_upload_bytes_per_sec and stall_timeout_secs do not apply to Baidu Pan or Cloud189: \
+             estan sends the whole file in a single call"
+        );
+    }
+
+    let http = http_config(app);
+    if http.timeouts != Timeouts::default() && (baidu_enabled || cloud189_enabled) {
+        warn!(
+            "connect_timeout_secs and upload_timeout_secs do not apply to Baidu Pan or Cloud189: \
+             estan uses its own HTTP client"
+        );
+    }
+    if (http.proxy.is_some() || http.user_agent.is_some()) && (baidu_enabled || cloud189_enabled) {
+        warn!(
+            "http_proxy and user_agent do not apply to Baidu Pan or Cloud189: \
+             estan uses its own HTTP client"
+        );
+    }
+
+    let s3_uploader = if enabled(app.s3_enabled, "S3") {
+        Some(Box::new(
+            build_s3_uploader(app)?
+                .with_throttle(throttle.clone())
+                .with_http(&http)?,
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let webdav_uploader = if enabled(app.webdav_enabled, "WebDAV") {
+        let url = app
+            .webdav_url
+            .as_deref()
+            .context("WebDAV uploader enabled but webdav_url is missing")?;
+        let (username, password) =
+            resolve_webdav_credentials(app.webdav_username.clone(), app.webdav_password.clone());
+        let (Some(username), Some(password)) = (
+            username.filter(|value| !value.trim().is_empty()),
+            password.filter(|value| !value.trim().is_empty()),
+        ) else {
+            anyhow::bail!(
+                "WebDAV uploader enabled but webdav_username/webdav_password are incomplete"
+            );
+        };
+        Some(Box::new(
+            WebDavUploader::new(url, username, password)?
+                .with_throttle(throttle.clone())
+                .with_http(&http)?,
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let local_uploader = if enabled(app.local_enabled, "Local") {
+        let base_dir = app
+            .local_base_dir
+            .as_deref()
+            .filter(|value| !value.trim().is_empty())
+            .context("Local uploader enabled but local_base_dir is missing")?;
+        Some(Box::new(
+            LocalUploader::new(base_dir, app.local_fsync.unwrap_or(true))?
+                .with_throttle(throttle.clone()),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let gdrive_uploader = if enabled(app.gdrive_enabled, "GDrive") {
+        Some(Box::new(
+            new_gdrive_uploader(app, io::stdin().is_terminal())?.with_throttle(throttle.clone()),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let onedrive_uploader = if enabled(app.onedrive_enabled, "OneDrive") {
+        Some(Box::new(
+            new_onedrive_uploader(app, io::stdin().is_terminal())?.with_throttle(throttle.clone()),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let sftp_uploader = if enabled(app.sftp_enabled, "SFTP") {
+        Some(Box::new(
+            SftpUploader::new(build_sftp_config(app)?)
+                .with_throttle(throttle)
+                .with_timeouts(&http.timeouts),
+        ) as Box<dyn Uploader>)
+    } else {
+        None
+    };
+
+    let mut uploaders: Vec<Box<dyn Uploader>> = Vec::new();
+    for (uploader, immutable) in [
+        (baidu_uploader, app.baidu_immutable),
+        (cloud189_uploader, app.cloud189_immutable),
+        (s3_uploader, app.s3_immutable),
+        (webdav_uploader, app.webdav_immutable),
+        (local_uploader, app.local_immutable),
+        (sftp_uploader, app.sftp_immutable),
+        (gdrive_uploader, app.gdrive_immutable),
+        (onedrive_uploader, app.onedrive_immutable),
+    ] {
+        if let Some(uploader) = uploader {
+            if immutable.unwrap_or(false) {
+                uploaders.push(Box::new(ImmutableUploader::new(uploader)));
+            } else {
+                uploaders.push(uploader);
+            }
+        }
+    }
+
+    if uploaders.is_empty() {
+        anyhow::bail!("No cloud uploader enabled");
+    }
+    Ok(uploaders)
+}
+
+pub fn build_s3_uploader(app: &AppConfig) -> Result<S3Uploader> {
+    let endpoint = app
+        .s3_endpoint
+        .clone()
+        .context("S3 uploader enabled but s3_endpoint is missing")?;
+    let bucket = app
+        .s3_bucket
+        .clone()
+        .context("S3 uploader enabled but s3_bucket is missing")?;
+    let (access_key, secret_key) =
+        resolve_s3_credentials(app.s3_access_key.clone(), app.s3_secret_key.clone());
+    let (Some(access_key), Some(secret_key)) = (
+        access_key.filter(|value| !value.trim().is_empty()),
+        secret_key.filter(|value| !value.trim().is_empty()),
+    ) else {
+        anyhow::bail!("S3 uploader enabled but s3_access_key/s3_secret_key are incomplete");
+    };
+    let object_lock = match (app.s3_immutable.unwrap_or(false), app.s3_object_lock_days) {
+        (true, Some(days)) => Some(ObjectLock {
+            mode: app
+                .s3_object_lock_mode
+                .unwrap_or(ObjectLockMode::Compliance),
+            days,
+        }),
+        (false, Some(_)) => anyhow::bail!("s3_object_lock_days requires s3_immutable = true"),
+        (_, None) => None,
+    };
+    S3Uploader::new(S3Config {
+        endpoint,
+        region: app
+            .s3_region
+            .clone()
+            .unwrap_or_else(|| "us-east-1".to_string()),
+        bucket,
+        access_key,
+        secret_key,
+        object_lock,
+    })
+}
+
+pub fn resolve_cloud189_credentials(
+    username: Option<String>,
+    password: Option<String>,
+    use_qr: Option<bool>,
+) -> (Option<String>, Option<String>, bool) {
+    let username = username.or_else(|| env::var("CLOUD189_USERNAME").ok());
+    let password = password.or_else(|| env::var("CLOUD189_PASSWORD").ok());
+    let use_qr = use_qr
+        .or_else(|| env::var("CLOUD189_USE_QR").ok().and_then(parse_env_bool))
+        .unwrap_or(false);
+    (username, password, use_qr)
+}
+
+pub fn resolve_s3_credentials(
+    access_key: Option<String>,
+    secret_key: Option<String>,
+) -> (Option<String>, Option<String>) {
+    let access_key = access_key.or_else(|| env::var("S3_ACCESS_KEY").ok());
+    let secret_key = secret_key.or_else(|| env::var("S3_SECRET_KEY").ok());
+    (access_key, secret_key)
+}
+
+pub fn resolve_webdav_credentials(
+    username: Option<String>,
+    password: Option<String>,
+) -> (Option<String>, Option<String>) {
+    let username = username.or_else(|| env::var("WEBDAV_USERNAME").ok());
+    let password = password.or_else(|| env::var("WEBDAV_PASSWORD").ok());
+    (username, password)
+}
+
+pub fn build_sftp_config(app: &AppConfig) -> Result<SftpConfig> {
+    let host = app
+        .sftp_host
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .context("SFTP uploader enabled but sftp_host is missing")?;
+    let username = app
+        .sftp_username
+        .clone()
+        .filter(|value| !value.trim().is_empty())
+        .context("SFTP uploader enabled but sftp_username is missing")?;
+    let password = app
+        .sftp_password
+        .clone()
+        .or_else(|| env::var("SFTP_PASSWORD").ok())
+        .filter(|value| !value.is_empty());
+    let auth = match (&app.sftp_private_key, password) {
+        (Some(key), _) => SftpAuth::PrivateKey {
+            path: PathBuf::from(key),
+            passphrase: app
+                .sftp_private_key_passphrase
+                .clone()
+                .or_else(|| env::var("SFTP_PRIVATE_KEY_PASSPHRASE").ok()),
+        },
+        (None, Some(password)) => SftpAuth::Password(password),
+        (None, None) => anyhow::bail!(
+            "SFTP uploader enabled but neither sftp_private_key nor sftp_password is set"
+        ),
+    };
+    let known_hosts = match &app.sftp_known_hosts {
+        Some(path) => PathBuf::from(path),
+        None => env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".ssh/known_hosts"))
+            .context("Cannot locate known_hosts: HOME is not set, set sftp_known_hosts")?,
+    };
+    Ok(SftpConfig {
+        host: host.to_string(),
+        port: app.sftp_port.unwrap_or(22),
+        username,
+        auth,
+        known_hosts,
+        host_key_policy: app.sftp_host_key_policy,
+    })
+}
+
+pub fn parse_env_bool(value: String) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_bool() {
+        assert_eq!(parse_env_bool("1".to_string()), Some(true));
+        assert_eq!(parse_env_bool("off".to_string()), Some(false));
+        assert_eq!(parse_env_bool("invalid".to_string()), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_restrict_token_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baidu_pan_config.json");
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        restrict_token_file(&path);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        restrict_token_file(&dir.path().join("missing.json"));
+    }
+}
@@ -0,0 +1,1774 @@
+//! The `backup-to-cloud` command line: argument parsing, logging, the
+//! `--daemon` loop and the subcommands, on top of the backup engine in the
+//! crate root.
+
+use crate::archive::{self, ArchiveFormat, PathFilter, measure_source};
+use crate::backends::{
+    AuthRequired, BAIDU_AUTH_CODE_ENV, baidu_token_path, build_selected_uploaders, build_uploaders,
+    new_baidu_uploader, new_gdrive_uploader, new_onedrive_uploader,
+};
+use crate::config::{
+    AppConfig, BackupItem, Config, STDIN_CONFIG, config_anchor, config_names,
+    enabled_uploader_names, load_config, read_config_table,
+};
+use crate::estimate::{self, Estimate, HISTORY_RUNS, History, Pricing};
+use crate::restore::{RestoreOptions, extract_archive};
+use crate::retention::{ArchiveNaming, item_archives};
+use crate::schedule::Schedule;
+use crate::signing::{self, Verifier};
+use crate::state::StateFile;
+use crate::uploader::{RemoteEntry, StorageUsage, Uploader};
+use crate::{
+    DEFAULT_DATE_FORMAT, RunOptions, RunReport, Stamp, archive_extension, build_archive_path,
+    dedup, encryption, expand_placeholders, format_bytes, normalize_archive_name, parse_date,
+    prepare_work_dir, resolve_source_paths, run_config, shutdown, sidecar_path, split,
+    work_dir_path,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CliCommand {
+    Backup,
+    Usage,
+    Probe {
+        uploader: String,
+    },
+    List {
+        item: String,
+    },
+    Verify {
+        item: String,
+        date: Option<String>,
+        checksum_only: bool,
+    },
+    Restore(RestoreRequest),
+    Auth,
+    Check,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RestoreRequest {
+    item: String,
+    date: String,
+    destination: PathBuf,
+    force: bool,
+    identity: Option<PathBuf>,
+}
+
+struct CliOptions {
+    command: CliCommand,
+    config_paths: Vec<String>,
+    summary_only: bool,
+    resume: bool,
+    restart: bool,
+    scheduled: bool,
+    daemon: bool,
+    dry_run: bool,
+    validate: bool,
+    force: bool,
+}
+
+/// Back up directories, files and database dumps to cloud storage.
+///
+/// Without a subcommand the arguments are those of `backup`, so
+/// `backup-to-cloud prod.toml` keeps working.
+#[derive(Debug, Parser)]
+#[command(
+    name = "backup-to-cloud",
+    version,
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+    #[command(flatten)]
+    backup: BackupArgs,
+}
+
+#[derive(Debug, Args)]
+struct BackupArgs {
+    /// Config files, merged in order; `-` reads one from stdin
+    #[arg(default_value = DEFAULT_CONFIG)]
+    config: Vec<String>,
+    /// Print a single RESULT line to stdout and only warnings and errors to stderr
+    #[arg(long)]
+    summary_only: bool,
+    /// Skip items that already succeeded today
+    #[arg(long)]
+    resume: bool,
+    /// Run every item again, also those an interrupted run today completed
+    #[arg(long, conflicts_with = "resume")]
+    restart: bool,
+    /// Only run items whose schedule is due; items without one always run
+    #[arg(long)]
+    scheduled: bool,
+    /// Stay running and back up each item whenever its schedule is due
+    #[arg(long, conflicts_with_all = ["resume", "restart", "dry_run", "validate"])]
+    daemon: bool,
+    /// Log what would be done without running commands, archiving or uploading
+    #[arg(long)]
+    dry_run: bool,
+    /// Check the config file, report every problem found and exit
+    #[arg(long, conflicts_with = "dry_run")]
+    validate: bool,
+    /// Run even if the lock file shows another run in progress
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Run every backup item (the default)
+    Backup(BackupArgs),
+    /// Show how much space each item's archives take on every uploader
+    #[command(alias = "du")]
+    Usage {
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// Log in to Baidu Netdisk, Google Drive and OneDrive interactively and
+    /// save their tokens, so unattended runs never have to prompt
+    Auth {
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// Log in to every enabled uploader and make one harmless request to
+    /// test its credentials, without uploading or deleting anything
+    Check {
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// Upload, list, download and delete a small file to test an uploader
+    Probe {
+        /// Uploader name, e.g. `cloud189`
+        uploader: String,
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// List an item's archives on every uploader
+    List {
+        /// The item's `archive_name`
+        item: String,
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// Download an item's archive from every uploader and check it against
+    /// its checksum and signature and that it reads back
+    Verify {
+        /// The item's `archive_name`
+        item: String,
+        /// Archive date as written in its name (`YYYYMMDD` unless the item
+        /// sets `date_format`); the newest archive by default
+        #[arg(long)]
+        date: Option<String>,
+        /// Only compare the checksum and signature, without decompressing
+        /// the archive
+        #[arg(long)]
+        checksum_only: bool,
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+    /// Download an item's archive from the first uploader that has it and
+    /// extract it
+    Restore {
+        /// The item's `archive_name`
+        item: String,
+        /// Archive date as written in its name (`YYYYMMDD` unless the item
+        /// sets `date_format`)
+        date: String,
+        /// Directory to extract into; created if missing
+        destination: PathBuf,
+        /// Overwrite files that already exist in the destination
+        #[arg(long)]
+        force: bool,
+        /// age identity file (from `age-keygen`) for encrypted archives
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        #[arg(default_value = DEFAULT_CONFIG)]
+        config: Vec<String>,
+    },
+}
+
+const DEFAULT_CONFIG: &str = "backup.toml";
+
+/// Exit status of a run that needs a login nobody is there to answer.
+const EXIT_AUTH_REQUIRED: u8 = 3;
+
+/// The `backup-to-cloud` command line: parses the arguments, sets up
+/// logging and runs the command they name.
+pub fn run_cli() -> ExitCode {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|err| err.exit());
+    let log_format = match configured_log_format(&options.config_paths) {
+        Ok(log_format) => log_format,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    init_logging(log_format, options.summary_only);
+
+    match dispatch(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_status(&err))
+        }
+    }
+}
+
+/// The exit status for a command that failed with `err`.
+fn exit_status(err: &anyhow::Error) -> u8 {
+    if err.downcast_ref::<AuthRequired>().is_some() {
+        EXIT_AUTH_REQUIRED
+    } else {
+        1
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+/// `[app] log_format`, read on its own before the config is loaded so the
+/// warnings from loading it already come out in that format. A config that
+/// cannot be read or parsed yields the default here and is reported by the
+/// command itself; only an unknown `log_format` fails.
+fn configured_log_format(config_paths: &[String]) -> Result<LogFormat> {
+    let Some(value) = read_config_table(config_paths)
+        .ok()
+        .and_then(|mut table| table.get_mut("app")?.as_table_mut()?.remove("log_format"))
+    else {
+        return Ok(LogFormat::default());
+    };
+    value
+        .try_into()
+        .context("Invalid log_format in [app], expected \"text\" or \"json\"")
+}
+
+/// The level comes from `RUST_LOG` (e.g. `debug` or `warn,backup_to_cloud=info`)
+/// and defaults to `info`.
+fn init_logging(format: LogFormat, summary_only: bool) {
+    let filter = if summary_only {
+        EnvFilter::new("warn")
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    // Keep stdout for the RESULT line; only warnings and errors reach stderr.
+    match (format, summary_only) {
+        (LogFormat::Text, false) => builder.init(),
+        (LogFormat::Text, true) => builder.with_writer(io::stderr).init(),
+        (LogFormat::Json, false) => builder.json().flatten_event(true).init(),
+        (LogFormat::Json, true) => builder
+            .json()
+            .flatten_event(true)
+            .with_writer(io::stderr)
+            .init(),
+    }
+}
+
+fn dispatch(options: &CliOptions) -> Result<()> {
+    match &options.command {
+        CliCommand::Backup if options.validate => return run_validate(&options.config_paths),
+        CliCommand::Backup if options.dry_run => return run_dry_run(&options.config_paths),
+        CliCommand::Backup => {}
+        CliCommand::Usage => return run_usage(&options.config_paths),
+        CliCommand::Probe { uploader } => return run_probe(&options.config_paths, uploader),
+        CliCommand::List { item } => return run_list(&options.config_paths, item),
+        CliCommand::Verify {
+            item,
+            date,
+            checksum_only,
+        } => {
+            return run_verify(&options.config_paths, item, date.as_deref(), *checksum_only);
+        }
+        CliCommand::Restore(request) => return run_restore(&options.config_paths, request),
+        CliCommand::Auth => return run_auth(&options.config_paths),
+        CliCommand::Check => return run_check(&options.config_paths),
+    }
+    if options.daemon {
+        return run_daemon(options);
+    }
+    run_once(options)
+}
+
+/// One backup run, followed by the `--summary-only` line.
+fn run_once(options: &CliOptions) -> Result<()> {
+    let started = Instant::now();
+    let mut report = RunReport::default();
+    let result = run(options, &mut report);
+    if options.summary_only {
+        println!("{}", format_summary_line(&report, started.elapsed()));
+    }
+    result
+}
+
+impl CliOptions {
+    /// `command` with every flag off.
+    fn new(command: CliCommand, config_paths: Vec<String>) -> Self {
+        Self {
+            command,
+            config_paths,
+            summary_only: false,
+            resume: false,
+            restart: false,
+            scheduled: false,
+            daemon: false,
+            dry_run: false,
+            validate: false,
+            force: false,
+        }
+    }
+
+    /// The flags the backup run itself reads.
+    fn run_options(&self) -> RunOptions {
+        RunOptions {
+            resume: self.resume,
+            restart: self.restart,
+            scheduled: self.scheduled,
+            force: self.force,
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<CliOptions, clap::Error> {
+    let cli = Cli::try_parse_from(std::iter::once("backup-to-cloud".to_string()).chain(args))?;
+    let backup = |command, args: BackupArgs| CliOptions {
+        command,
+        config_paths: args.config,
+        summary_only: args.summary_only,
+        resume: args.resume,
+        restart: args.restart,
+        scheduled: args.scheduled || args.daemon,
+        daemon: args.daemon,
+        dry_run: args.dry_run,
+        validate: args.validate,
+        force: args.force,
+    };
+    let other = CliOptions::new;
+    Ok(match cli.command {
+        None => backup(CliCommand::Backup, cli.backup),
+        Some(Commands::Backup(args)) => backup(CliCommand::Backup, args),
+        Some(Commands::Usage { config }) => other(CliCommand::Usage, config),
+        Some(Commands::Auth { config }) => other(CliCommand::Auth, config),
+        Some(Commands::Check { config }) => other(CliCommand::Check, config),
+        Some(Commands::Probe { uploader, config }) => other(CliCommand::Probe { uploader }, config),
+        Some(Commands::List { item, config }) => other(CliCommand::List { item }, config),
+        Some(Commands::Verify {
+            item,
+            date,
+            checksum_only,
+            config,
+        }) => other(
+            CliCommand::Verify {
+                item,
+                date,
+                checksum_only,
+            },
+            config,
+        ),
+        Some(Commands::Restore {
+            item,
+            date,
+            destination,
+            force,
+            identity,
+            config,
+        }) => other(
+            CliCommand::Restore(RestoreRequest {
+                item,
+                date,
+                destination,
+                force,
+                identity,
+            }),
+            config,
+        ),
+    })
+}
+
+/// Renders the `--summary-only` line. The field order and names are part of
+/// the CLI contract; append new fields at the end only.
+fn format_summary_line(report: &RunReport, elapsed: Duration) -> String {
+    format!(
+        "RESULT {} items={} failed={} bytes={} duration={}s",
+        if report.succeeded() { "ok" } else { "failed" },
+        report.items.len(),
+        report.failed_items(),
+        report.bytes,
+        elapsed.as_secs()
+    )
+}
+
+/// How long the daemon waits before retrying items that are still due
+/// after a cycle, unless `daemon_retry_secs` is set.
+const DEFAULT_DAEMON_RETRY: Duration = Duration::from_secs(15 * 60);
+
+/// `--daemon`: runs the scheduled items, sleeps until the next one is due
+/// and repeats, reloading the config every cycle. Every item needs a
+/// schedule, at the start and in every cycle after. A cycle that fails is
+/// logged and the daemon carries on; only a missing login ends it, since
+/// nobody is there to give one. SIGTERM or SIGINT lets the current item
+/// finish and then exits.
+fn run_daemon(options: &CliOptions) -> Result<()> {
+    daemon_schedules(&load_config(&options.config_paths)?)?;
+    shutdown::install()?;
+    info!("Daemon started for {}", config_names(&options.config_paths));
+    while !shutdown::requested() {
+        info!("Daemon cycle started");
+        let cycle = match load_config(&options.config_paths) {
+            Ok(config) => daemon_schedules(&config).map(drop),
+            // The run reports an unreadable config itself.
+            Err(_) => Ok(()),
+        }
+        .and_then(|()| run_once(options));
+        match cycle {
+            Ok(()) => info!("Daemon cycle finished"),
+            Err(err) if err.downcast_ref::<AuthRequired>().is_some() => return Err(err),
+            Err(err) => error!("Daemon cycle failed: {err:#}"),
+        }
+        if shutdown::requested() {
+            break;
+        }
+        let wait = match load_config(&options.config_paths)
+            .and_then(|config| daemon_wait(&config, &options.config_paths))
+        {
+            Ok(wait) => wait,
+            Err(err) => {
+                warn!("Cannot plan the next cycle from the config: {err:#}");
+                DEFAULT_DAEMON_RETRY
+            }
+        };
+        let wake =
+            Local::now() + chrono::Duration::from_std(wait).unwrap_or(chrono::Duration::zero());
+        info!("Next daemon cycle at {}", wake.format("%Y-%m-%d %H:%M:%S"));
+        shutdown::sleep(wait);
+    }
+    info!("Daemon stopped");
+    Ok(())
+}
+
+/// The name and schedule of every item; the daemon has nothing to time an
+/// item without a schedule by, so any such item fails the config.
+fn daemon_schedules(config: &Config) -> Result<Vec<(&str, &Schedule)>> {
+    let mut schedules = Vec::new();
+    let mut unscheduled = Vec::new();
+    for item in &config.backups {
+        let name = normalize_archive_name(&item.archive_name);
+        match &item.schedule {
+            Some(schedule) => schedules.push((name, schedule)),
+            None => unscheduled.push(name),
+        }
+    }
+    if !unscheduled.is_empty() {
+        anyhow::bail!(
+            "--daemon runs items on their schedule, but {} has none",
+            unscheduled.join(", ")
+        );
+    }
+    Ok(schedules)
+}
+
+/// Time until the earliest item becomes due. Items that are due already
+/// were just tried and failed; they are tried again after
+/// `daemon_retry_secs`. Fails like [`run_daemon`] does when an edit left an
+/// item without a schedule.
+fn daemon_wait(config: &Config, config_paths: &[String]) -> Result<Duration> {
+    let schedules = daemon_schedules(config)?;
+    let retry = config
+        .app
+        .daemon_retry_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_DAEMON_RETRY);
+    let state = StateFile::load(StateFile::path_for_config(&config_anchor(config_paths)))
+        .unwrap_or_else(|err| {
+            warn!("Ignoring unreadable state file: {err:#}");
+            StateFile::empty(PathBuf::new())
+        });
+    let now = Local::now().naive_local();
+    Ok(schedules
+        .into_iter()
+        .filter_map(|(name, schedule)| {
+            let last_run = state
+                .last_run(name)
+                .map(|time| DateTime::<Local>::from(time).naive_local());
+            if schedule.is_due(last_run, now) {
+                return Some(retry);
+            }
+            let due = schedule.next_due(last_run?)?;
+            (due - now).to_std().ok()
+        })
+        .min()
+        .unwrap_or(retry))
+}
+
+/// Loads the config, runs the backup and sends the end-of-run notifications.
+/// Without a readable config there is nobody to notify.
+fn run(options: &CliOptions, report: &mut RunReport) -> Result<()> {
+    let result = load_config(&options.config_paths)
+        .and_then(|config| run_config(&config, &options.run_options(), report));
+    if let Err(err) = &result {
+        report.error.get_or_insert_with(|| format!("{err:#}"));
+    }
+    result
+}
+
+/// Loads and validates the config without running anything.
+fn run_validate(config_paths: &[String]) -> Result<()> {
+    let config = load_config(config_paths)?;
+    println!(
+        "{} is valid: {} item(s), uploaders: {}",
+        config_names(config_paths),
+        config.backups.len(),
+        enabled_uploader_names(&config.app).join(", ")
+    );
+    Ok(())
+}
+
+/// Logs what a backup run would do, item by item, without running commands,
+/// creating archives, uploading or deleting anything. Uploaders are only
+/// named, not built, since building one can already log in or refresh a
+/// token.
+fn run_dry_run(config_paths: &[String]) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let uploaders = enabled_uploader_names(&config.app);
+    let run_stamp = Stamp::now(DEFAULT_DATE_FORMAT);
+    let history = config
+        .app
+        .report_file
+        .as_deref()
+        .map(|path| {
+            let path = PathBuf::from(run_stamp.expand_run_command(path));
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            History::load(dir, HISTORY_RUNS)
+        })
+        .unwrap_or_default();
+    let s3_pricing = s3_pricing(&config.app);
+    let mut estimates = Vec::new();
+    if let Some(command) = &config.app.pre_run_command {
+        info!(
+            "[dry-run] would run pre_run_command: {}",
+            run_stamp.expand_run_command(command)
+        );
+    }
+    for item in &config.backups {
+        let base_name = normalize_archive_name(&item.archive_name);
+        let stamp = Stamp::now(item.date_format());
+        if let Some(database) = &item.database {
+            info!(
+                "[dry-run] [{base_name}] source: {} dump as {}",
+                database.engine.as_str(),
+                database.entry_name(base_name)
+            );
+        } else if let Some(entry_name) = &item.command_output_name {
+            info!(
+                "[dry-run] [{base_name}] source: command output as {}",
+                expand_placeholders(entry_name, &stamp, base_name).trim()
+            );
+        } else {
+            match resolve_source_paths(item, &stamp, base_name) {
+                Ok(paths) => {
+                    for path in paths {
+                        info!("[dry-run] [{base_name}] source: {}", path.display());
+                    }
+                }
+                Err(err) => warn!("[dry-run] [{base_name}] source: {err}"),
+            }
+        }
+        if let Some(argv) = &item.command_argv {
+            let argv: Vec<String> = argv
+                .iter()
+                .map(|arg| expand_placeholders(arg, &stamp, base_name))
+                .collect();
+            info!("[dry-run] [{base_name}] would run command_argv: {:?}", argv);
+        } else if let Some(command) = &item.command {
+            info!(
+                "[dry-run] [{base_name}] would run command: {}",
+                expand_placeholders(command, &stamp, base_name)
+            );
+        }
+        let encrypted = item.encryption.is_some() || config.app.encryption.is_some();
+        let archive_path = build_archive_path(
+            &work_dir_path(item.work_dir.as_deref().or(config.app.work_dir.as_deref()))?,
+            &item.archive_naming(),
+            &stamp.date,
+            &archive_extension(item.archive_format, encrypted),
+        )?;
+        if item.stream_upload.unwrap_or(false) {
+            info!(
+                "[dry-run] [{base_name}] archive: {} (streamed to the uploaders that support it)",
+                archive_path.display()
+            );
+        } else {
+            info!(
+                "[dry-run] [{base_name}] archive: {}",
+                archive_path.display()
+            );
+        }
+        if let Some(mb) = item.max_part_size_mb {
+            info!(
+                "[dry-run] [{base_name}] would split the archive into parts if it is over {}",
+                format_bytes(mb << 20)
+            );
+        }
+        info!(
+            "[dry-run] [{base_name}] remote_dir: {}",
+            expand_placeholders(&item.remote_dir, &stamp, base_name)
+        );
+        let item_uploaders: Vec<&str> = uploaders
+            .iter()
+            .copied()
+            .filter(|name| item.uses_uploader(name))
+            .collect();
+        info!(
+            "[dry-run] [{base_name}] uploaders: {}",
+            item_uploaders.join(", ")
+        );
+        let bytes = estimated_archive_size(item, &stamp, base_name, &history);
+        for name in &item_uploaders {
+            // estan sends the whole file itself, past the rate cap.
+            let cap = config
+                .app
+                .max_upload_bytes_per_sec
+                .filter(|_| !matches!(*name, "Baidu" | "Cloud189"));
+            estimates.push(Estimate {
+                item: base_name.to_string(),
+                uploader: name.to_string(),
+                bytes,
+                duration: bytes
+                    .and_then(|bytes| estimate::upload_time(bytes, history.rate(name), cap)),
+                cost: s3_pricing
+                    .filter(|_| *name == "S3")
+                    .zip(bytes)
+                    .map(|(pricing, bytes)| {
+                        pricing.cost(bytes, item.keep_remote.unwrap_or(1) as u64)
+                    }),
+            });
+        }
+        if let Some(keep) = item.keep_remote {
+            info!("[dry-run] [{base_name}] would keep the newest {keep} remote archive(s)");
+        }
+        if let Some(keep) = item.keep_local {
+            info!("[dry-run] [{base_name}] would keep the newest {keep} local archive(s)");
+        }
+        if let Some(command) = &item.post_command {
+            info!(
+                "[dry-run] [{base_name}] would run post_command after the uploads: {}",
+                expand_placeholders(command, &stamp, base_name)
+            );
+        }
+    }
+    if let Some(command) = &config.app.post_run_command {
+        info!(
+            "[dry-run] would run post_run_command: {}",
+            run_stamp.expand_run_command(command)
+        );
+    }
+    if !estimates.is_empty() {
+        info!("[dry-run] estimates:");
+        for line in estimate::render_table(&estimates) {
+            info!("[dry-run] {line}");
+        }
+    }
+    Ok(())
+}
+
+/// Archive size `--dry-run` expects for `item`: its measured source at the
+/// ratio its last recorded run reached, or that run's archive size for
+/// items without one to measure, such as database dumps and command output.
+fn estimated_archive_size(
+    item: &BackupItem,
+    stamp: &Stamp,
+    base_name: &str,
+    history: &History,
+) -> Option<u64> {
+    let recorded = history.archive(base_name);
+    let summary = resolve_source_paths(item, stamp, base_name)
+        .ok()
+        .filter(|_| item.database.is_none() && item.command_output_name.is_none())
+        .and_then(|paths| {
+            let filter = PathFilter::new(&item.include, &item.exclude).ok()?;
+            measure_source(&paths, filter.as_ref()).ok()
+        });
+    match summary {
+        Some(summary) => Some(estimate::archive_size(
+            &summary,
+            item.archive_format,
+            recorded.and_then(|(_, ratio)| ratio),
+        )),
+        None => recorded.map(|(bytes, _)| bytes),
+    }
+}
+
+/// Prices for the `--dry-run` cost estimate: the configured ones, with AWS
+/// S3 Standard filling in the other, or AWS S3 Standard for an AWS endpoint.
+/// Other endpoints have no known pricing.
+fn s3_pricing(app: &AppConfig) -> Option<Pricing> {
+    let aws = app
+        .s3_endpoint
+        .as_deref()
+        .is_some_and(|endpoint| endpoint.contains(".amazonaws.com"));
+    let configured = app.s3_price_per_gb_month.is_some() || app.s3_egress_price_per_gb.is_some();
+    (aws || configured).then(|| Pricing {
+        storage_per_month: app
+            .s3_price_per_gb_month
+            .unwrap_or(Pricing::AWS_S3_STANDARD.storage_per_month),
+        egress: app
+            .s3_egress_price_per_gb
+            .unwrap_or(Pricing::AWS_S3_STANDARD.egress),
+    })
+}
+
+fn find_item<'a>(config: &'a Config, name: &str) -> Result<&'a BackupItem> {
+    config
+        .backups
+        .iter()
+        .find(|item| normalize_archive_name(&item.archive_name) == name)
+        .with_context(|| {
+            let names: Vec<&str> = config
+                .backups
+                .iter()
+                .map(|item| normalize_archive_name(&item.archive_name))
+                .collect();
+            format!(
+                "No backup item with archive_name {}; configured: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+/// Lists the item's archives on every uploader, oldest first, as
+/// `uploader<TAB>name<TAB>size` lines.
+fn run_list(config_paths: &[String], item_name: &str) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let item = find_item(&config, item_name)?;
+    let base_name = normalize_archive_name(&item.archive_name);
+    let stamp = Stamp::now(item.date_format());
+    let remote_dir = expand_placeholders(&item.remote_dir, &stamp, base_name);
+    let mut uploaders = build_uploaders(&config.app)?;
+    let mut failures: Vec<String> = Vec::new();
+    for uploader in uploaders
+        .iter_mut()
+        .filter(|uploader| item.uses_uploader(uploader.name()))
+    {
+        match uploader
+            .normalize_remote_dir(&remote_dir)
+            .and_then(|remote_dir| uploader.list(&remote_dir))
+        {
+            Ok(entries) => {
+                for entry in item_archives(entries, &item.archive_naming()) {
+                    println!(
+                        "{}\t{}\t{}",
+                        uploader.name(),
+                        entry.name,
+                        format_bytes(entry.size)
+                    );
+                }
+            }
+            Err(err) => {
+                println!("{}\t-\tfailed: {err:#}", uploader.name());
+                failures.push(format!("{}: {err:#}", uploader.name()));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("Listing failed on:\n{}", failures.join("\n"));
+    }
+    Ok(())
+}
+
+/// Checks the item's archive on every uploader: its size, its `.sha256` and
+/// `.sig` if uploaded, and unless `checksum_only` that every entry reads back.
+/// Fails if any uploader's copy does not pass.
+fn run_verify(
+    config_paths: &[String],
+    item_name: &str,
+    date: Option<&str>,
+    checksum_only: bool,
+) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let item = find_item(&config, item_name)?;
+    let base_name = normalize_archive_name(&item.archive_name);
+    let stamp = match date {
+        Some(date) => Stamp::with_date(date),
+        None => Stamp::now(item.date_format()),
+    };
+    let remote_dir = expand_placeholders(&item.remote_dir, &stamp, base_name);
+    let verifier = config
+        .app
+        .signing_public_key_file
+        .as_deref()
+        .map(|path| Verifier::from_pem_file(Path::new(path)))
+        .transpose()?;
+    let checks = ArchiveChecks {
+        verifier: verifier.as_ref(),
+        decompress: !checksum_only,
+        fallback_format: item
+            .custom_name_format(item.encryption.is_some() || config.app.encryption.is_some()),
+    };
+    let mut uploaders = build_uploaders(&config.app)?;
+    let work_dir = tempfile::tempdir_in(prepare_work_dir(config.app.work_dir.as_deref())?)
+        .context("Failed to create a download directory")?;
+    let mut failures: Vec<String> = Vec::new();
+    for uploader in uploaders
+        .iter_mut()
+        .filter(|uploader| item.uses_uploader(uploader.name()))
+    {
+        let name = uploader.name().to_string();
+        let result = verify_remote_archive(
+            uploader.as_mut(),
+            &remote_dir,
+            &item.archive_naming(),
+            date,
+            work_dir.path(),
+            &checks,
+        );
+        match result {
+            Ok(message) => println!("{}\tok\t{}", name, message),
+            Err(err) => {
+                println!("{}\tfailed\t{err:#}", name);
+                failures.push(format!("{}: {err:#}", name));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("Verification failed on:\n{}", failures.join("\n"));
+    }
+    Ok(())
+}
+
+/// What `verify` checks beyond the size.
+struct ArchiveChecks<'a> {
+    /// From `signing_public_key_file`; an archive without `.sig` fails.
+    verifier: Option<&'a Verifier>,
+    /// Read every entry back, for unencrypted archives.
+    decompress: bool,
+    /// Format of archives whose `archive_filename` extension does not tell.
+    fallback_format: Option<(ArchiveFormat, bool)>,
+}
+
+fn verify_remote_archive(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    naming: &ArchiveNaming,
+    date: Option<&str>,
+    work_dir: &Path,
+    checks: &ArchiveChecks,
+) -> Result<String> {
+    let base_name = naming.archive_name;
+    let remote_dir = uploader.normalize_remote_dir(remote_dir)?;
+    let entries = uploader.list(&remote_dir)?;
+    let archives = item_archives(entries.clone(), naming);
+    let entry = archives
+        .into_iter()
+        .rev()
+        .find(|entry| {
+            date.is_none_or(|date| {
+                naming
+                    .date_of(&entry.name)
+                    .is_some_and(|(found, _, _)| found == date)
+            })
+        })
+        .with_context(|| match date {
+            Some(date) => format!("No {} archive from {} in {}", base_name, date, remote_dir),
+            None => format!("No {} archive in {}", base_name, remote_dir),
+        })?;
+    let local_path = work_dir.join(&entry.name);
+    let result = download_archive(uploader, &remote_dir, &entry.name, &entries, &local_path)
+        .and_then(|size| {
+            // A reassembled archive is checked against the size its recipe
+            // or parts add up to rather than a listed one.
+            let entry = RemoteEntry { size, ..entry };
+            check_archive(uploader, &remote_dir, &entry, &entries, &local_path, checks)
+        });
+    for path in [
+        local_path.clone(),
+        sidecar_path(&local_path, "sha256"),
+        sidecar_path(&local_path, "sig"),
+    ] {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// Runs `checks` on the archive downloaded to `local_path`, fetching its
+/// sidecars from `remote_dir` as needed, and describes what passed.
+fn check_archive(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    entry: &RemoteEntry,
+    entries: &[RemoteEntry],
+    local_path: &Path,
+    checks: &ArchiveChecks,
+) -> Result<String> {
+    let listed = |extension: &str| {
+        let sidecar = format!("{}.{extension}", entry.name);
+        entries
+            .iter()
+            .any(|other| other.name == sidecar)
+            .then_some(sidecar)
+    };
+    let size = fs::metadata(local_path)
+        .with_context(|| format!("Failed to read file size: {}", local_path.display()))?
+        .len();
+    if size != entry.size {
+        anyhow::bail!(
+            "{} is listed as {} bytes but downloaded as {}",
+            entry.name,
+            entry.size,
+            size
+        );
+    }
+    let mut passed = vec![format_bytes(size)];
+
+    match listed("sha256") {
+        Some(name) => {
+            let checksum_path = sidecar_path(local_path, "sha256");
+            uploader.download(remote_dir, &name, &checksum_path)?;
+            let expected = signing::read_checksum(&checksum_path)?;
+            let actual = signing::sha256_file(local_path)?;
+            if actual != expected {
+                anyhow::bail!(
+                    "{} has SHA-256 {}, its .sha256 lists {}",
+                    entry.name,
+                    actual,
+                    expected
+                );
+            }
+            passed.push("sha256 ok".to_string());
+        }
+        None => passed.push("no .sha256".to_string()),
+    }
+
+    if let Some(verifier) = checks.verifier {
+        let name = listed("sig").with_context(|| {
+            format!(
+                "{} has no .sig but signing_public_key_file is set",
+                entry.name
+            )
+        })?;
+        let sig_path = sidecar_path(local_path, "sig");
+        uploader.download(remote_dir, &name, &sig_path)?;
+        verifier.verify_file(local_path, &sig_path)?;
+        passed.push("signature ok".to_string());
+    }
+
+    if checks.decompress {
+        match ArchiveFormat::from_file_name(&entry.name).or(checks.fallback_format) {
+            Some((_, true)) => passed.push("encrypted, not decompressed".to_string()),
+            Some((format, false)) => {
+                let count = archive::count_entries(local_path, format)?;
+                passed.push(format!("{count} entries"));
+            }
+            None => anyhow::bail!("Unknown archive format: {}", entry.name),
+        }
+    }
+    Ok(format!("{} ({})", entry.name, passed.join(", ")))
+}
+
+/// Restores one archive: the first enabled uploader that has the item's
+/// archive from `date` wins. With `signing_public_key_file` set, the archive's
+/// `.sig` is downloaded and checked before anything is extracted.
+fn run_restore(config_paths: &[String], request: &RestoreRequest) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let item = find_item(&config, &request.item)?;
+    if parse_date(&request.date, item.date_format()).is_none() {
+        anyhow::bail!(
+            "Restore date must match the item's date_format {}, got {}",
+            item.date_format(),
+            request.date
+        );
+    }
+    let base_name = normalize_archive_name(&item.archive_name);
+    let remote_dir = expand_placeholders(
+        &item.remote_dir,
+        &Stamp::with_date(&request.date),
+        base_name,
+    );
+    let verifier = config
+        .app
+        .signing_public_key_file
+        .as_deref()
+        .map(|path| Verifier::from_pem_file(Path::new(path)))
+        .transpose()?;
+    let identity = request
+        .identity
+        .as_deref()
+        .map(encryption::load_identity)
+        .transpose()?;
+    let mut uploaders = build_uploaders(&config.app)?;
+    let work_dir = tempfile::tempdir_in(prepare_work_dir(config.app.work_dir.as_deref())?)
+        .context("Failed to create a download directory")?;
+
+    let mut tried: Vec<String> = Vec::new();
+    for uploader in uploaders
+        .iter_mut()
+        .filter(|uploader| item.uses_uploader(uploader.name()))
+    {
+        let name = uploader.name().to_string();
+        let found = uploader.normalize_remote_dir(&remote_dir).and_then(|dir| {
+            let entries = uploader.list(&dir)?;
+            let has_signature = |archive: &str| {
+                entries
+                    .iter()
+                    .any(|entry| entry.name == format!("{archive}.sig"))
+            };
+            let naming = item.archive_naming();
+            let archive = item_archives(entries.clone(), &naming)
+                .into_iter()
+                .rev()
+                .find(|entry| {
+                    naming
+                        .date_of(&entry.name)
+                        .is_some_and(|(date, _, _)| date == request.date)
+                });
+            let signed = archive
+                .as_ref()
+                .is_some_and(|entry| has_signature(&entry.name));
+            Ok(archive.map(|entry| (dir, entry.name, signed, entries)))
+        });
+        let (dir, archive_name, signed, entries) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                tried.push(format!("{name}: not found"));
+                continue;
+            }
+            Err(err) => {
+                warn!("Cannot list {} on {}: {err:#}", remote_dir, name);
+                tried.push(format!("{name}: {err:#}"));
+                continue;
+            }
+        };
+
+        let (format, encrypted) = ArchiveFormat::from_file_name(&archive_name)
+            .or(item
+                .custom_name_format(item.encryption.is_some() || config.app.encryption.is_some()))
+            .with_context(|| format!("Unknown archive format: {}", archive_name))?;
+        if encrypted && identity.is_none() {
+            anyhow::bail!(
+                "{} is encrypted; pass --identity with the age key file it was encrypted to",
+                archive_name
+            );
+        }
+        let local_path = work_dir.path().join(&archive_name);
+        info!("Downloading {} from {}", archive_name, name);
+        download_archive(
+            uploader.as_mut(),
+            &dir,
+            &archive_name,
+            &entries,
+            &local_path,
+        )?;
+        if let Some(verifier) = &verifier {
+            if !signed {
+                anyhow::bail!(
+                    "{} has no .sig on {} but signing_public_key_file is set; refusing to restore",
+                    archive_name,
+                    name
+                );
+            }
+            let sig_path = sidecar_path(&local_path, "sig");
+            uploader.download(&dir, &format!("{archive_name}.sig"), &sig_path)?;
+            verifier.verify_file(&local_path, &sig_path)?;
+            info!("Signature of {} is valid", archive_name);
+        }
+        let entries = extract_archive(
+            &local_path,
+            &request.destination,
+            &RestoreOptions {
+                format,
+                identity: identity.as_ref(),
+                force: request.force,
+            },
+        )?;
+        info!(
+            "Restored {} ({} entries) from {} into {}",
+            archive_name,
+            entries,
+            name,
+            request.destination.display()
+        );
+        return Ok(());
+    }
+    anyhow::bail!(
+        "No {} archive from {} in {} on any uploader ({})",
+        base_name,
+        request.date,
+        remote_dir,
+        tried.join("; ")
+    )
+}
+
+/// Prints how much space each item's archives occupy on every uploader.
+/// Placeholders in `remote_dir` are expanded with today's date.
+fn run_usage(config_paths: &[String]) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let mut uploaders = build_uploaders(&config.app)?;
+    let mut failures: Vec<String> = Vec::new();
+    let mut grand_total = 0u64;
+
+    for uploader in uploaders.iter_mut() {
+        let mut total = 0u64;
+        for item in &config.backups {
+            if !item.uses_uploader(uploader.name()) {
+                continue;
+            }
+            let base_name = normalize_archive_name(&item.archive_name);
+            let stamp = Stamp::now(item.date_format());
+            let remote_dir = expand_placeholders(&item.remote_dir, &stamp, base_name);
+            match uploader
+                .normalize_remote_dir(&remote_dir)
+                .and_then(|remote_dir| uploader.list(&remote_dir))
+            {
+                Ok(entries) => {
+                    let archives = item_archives(entries, &item.archive_naming());
+                    let bytes: u64 = archives.iter().map(|entry| entry.size).sum();
+                    total += bytes;
+                    println!(
+                        "{}\t{}\t{} archive(s)\t{}",
+                        uploader.name(),
+                        base_name,
+                        archives.len(),
+                        format_bytes(bytes)
+                    );
+                }
+                Err(err) => {
+                    let message = format!(
+                        "[{base_name}] listing {} failed on {}: {}",
+                        remote_dir,
+                        uploader.name(),
+                        err
+                    );
+                    error!("{}", message);
+                    failures.push(message);
+                }
+            }
+        }
+        let usage = uploader.storage_usage().unwrap_or_else(|err| {
+            warn!(
+                "Usage query failed on {}, summing its listings instead: {:#}",
+                uploader.name(),
+                err
+            );
+            None
+        });
+        let (line, bytes) = usage_total(uploader.name(), total, usage);
+        println!("{}", line);
+        grand_total += bytes;
+    }
+    println!("all\ttotal\t{}", format_bytes(grand_total));
+
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Usage report incomplete, {} listing(s) failed:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// The per-uploader total line of `usage` and the bytes it counts: what the
+/// provider reports for the whole account when it has a usage API, otherwise
+/// the sum of the listed archives.
+fn usage_total(name: &str, listed: u64, usage: Option<StorageUsage>) -> (String, u64) {
+    match usage {
+        Some(StorageUsage { used, limit }) => {
+            let limit = match limit {
+                Some(limit) => format!(" of {}", format_bytes(limit)),
+                None => String::new(),
+            };
+            (
+                format!(
+                    "{}\ttotal\t{} used{} (account, {} in archives)",
+                    name,
+                    format_bytes(used),
+                    limit,
+                    format_bytes(listed)
+                ),
+                used,
+            )
+        }
+        None => (format!("{}\ttotal\t{}", name, format_bytes(listed)), listed),
+    }
+}
+
+/// Authorizes every enabled uploader that keeps an OAuth token, so later
+/// unattended runs find one saved: Google Drive and OneDrive through their
+/// device code flow, Baidu Netdisk by the code estan reads from stdin. With
+/// `BAIDU_AUTH_CODE` set, the Baidu step runs this command again with the
+/// code piped in, which scripts the first-time setup.
+fn run_auth(config_paths: &[String]) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let baidu_enabled = config.app.baidu_enabled.unwrap_or(false);
+    let gdrive_enabled = config.app.gdrive_enabled.unwrap_or(false);
+    let onedrive_enabled = config.app.onedrive_enabled.unwrap_or(false);
+    if !baidu_enabled && !gdrive_enabled && !onedrive_enabled {
+        anyhow::bail!(
+            "None of baidu_enabled, gdrive_enabled and onedrive_enabled is set in {}; \
+             only Baidu Netdisk, Google Drive and OneDrive need `auth`",
+            config_names(config_paths)
+        );
+    }
+    // Google Drive and OneDrive first: the Baidu authorization with
+    // BAIDU_AUTH_CODE runs `auth` again in a child, which then finds their
+    // tokens in place.
+    if gdrive_enabled {
+        new_gdrive_uploader(&config.app, true)?;
+        if let Some(path) = &config.app.gdrive_config {
+            println!("Google Drive is authorized; token saved in {path}");
+        }
+    }
+    if onedrive_enabled {
+        new_onedrive_uploader(&config.app, true)?;
+        if let Some(path) = &config.app.onedrive_config {
+            println!("OneDrive is authorized; token saved in {path}");
+        }
+    }
+    if baidu_enabled {
+        run_baidu_auth(config_paths, &config.app)?;
+    }
+    Ok(())
+}
+
+fn run_baidu_auth(config_paths: &[String], app: &AppConfig) -> Result<()> {
+    if let Ok(code) = env::var(BAIDU_AUTH_CODE_ENV)
+        && !code.trim().is_empty()
+    {
+        // The child reads the code from stdin, so it cannot read the config
+        // from there as well.
+        if config_paths.iter().any(|path| path == STDIN_CONFIG) {
+            anyhow::bail!("{BAIDU_AUTH_CODE_ENV} cannot be used with a config read from stdin");
+        }
+        let program = env::current_exe().context("Failed to locate the running executable")?;
+        let mut child = Command::new(program)
+            .arg("auth")
+            .args(config_paths)
+            .env_remove(BAIDU_AUTH_CODE_ENV)
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to start the Baidu authorization")?;
+        let mut stdin = child.stdin.take().context("Authorization has no stdin")?;
+        writeln!(stdin, "{}", code.trim()).context("Failed to pass the authorization code")?;
+        drop(stdin);
+        let status = child.wait().context("Baidu authorization did not finish")?;
+        if !status.success() {
+            anyhow::bail!("Baidu authorization with {BAIDU_AUTH_CODE_ENV} failed ({status})");
+        }
+        return Ok(());
+    }
+    new_baidu_uploader(app, true)?;
+    match baidu_token_path(app) {
+        Some(path) => println!(
+            "Baidu Netdisk is authorized; token saved in {}",
+            path.display()
+        ),
+        None => println!("Baidu Netdisk is authorized"),
+    }
+    Ok(())
+}
+
+const PROBE_SIZE: usize = 1024;
+
+/// Runs a small file through upload, list, download and delete on a single
+/// uploader, printing one line per step.
+fn run_probe(config_paths: &[String], uploader_name: &str) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let uploaders = build_uploaders(&config.app)?;
+    let available: Vec<String> = uploaders
+        .iter()
+        .map(|uploader| uploader.name().to_string())
+        .collect();
+    let mut uploader = uploaders
+        .into_iter()
+        .find(|uploader| uploader.name().eq_ignore_ascii_case(uploader_name))
+        .with_context(|| {
+            format!(
+                "No enabled uploader named {}; enabled: {}",
+                uploader_name,
+                available.join(", ")
+            )
+        })?;
+    let name = uploader.name().to_string();
+    if uploader.is_immutable() {
+        anyhow::bail!(
+            "{} is immutable and probe has to delete its probe file; refusing to run",
+            name
+        );
+    }
+    let remote_dir = uploader.normalize_remote_dir(
+        config
+            .app
+            .probe_remote_dir
+            .as_deref()
+            .unwrap_or("backup-to-cloud-probe"),
+    )?;
+
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let mut content = format!("backup-to-cloud probe {seed}\n").into_bytes();
+    content.resize(PROBE_SIZE, b'.');
+    let file_name = format!("probe-{seed}.bin");
+    let work_dir = tempfile::tempdir().context("Failed to create probe directory")?;
+    let local_path = work_dir.path().join(&file_name);
+    fs::write(&local_path, &content)
+        .with_context(|| format!("Failed to write probe file: {}", local_path.display()))?;
+    let downloaded_path = work_dir.path().join("downloaded.bin");
+
+    let mut result = probe_step(&name, "upload", || {
+        uploader.upload(&local_path, &remote_dir)
+    });
+    if result.is_ok() {
+        result = probe_step(&name, "verify", || {
+            let entries = uploader.list(&remote_dir)?;
+            let entry = entries
+                .iter()
+                .find(|entry| entry.name == file_name)
+                .with_context(|| format!("{} is not listed in {}", file_name, remote_dir))?;
+            if entry.size != PROBE_SIZE as u64 {
+                anyhow::bail!(
+                    "listed size is {} bytes, expected {}",
+                    entry.size,
+                    PROBE_SIZE
+                );
+            }
+            Ok(())
+        })
+        .and_then(|()| {
+            probe_step(&name, "download", || {
+                uploader.download(&remote_dir, &file_name, &downloaded_path)?;
+                let downloaded = fs::read(&downloaded_path).with_context(|| {
+                    format!(
+                        "Failed to read downloaded file: {}",
+                        downloaded_path.display()
+                    )
+                })?;
+                if downloaded != content {
+                    anyhow::bail!("downloaded content does not match the uploaded probe file");
+                }
+                Ok(())
+            })
+        });
+        // Clean up even when a middle step failed.
+        let deleted = probe_step(&name, "delete", || uploader.delete(&remote_dir, &file_name));
+        result = result.and(deleted);
+    }
+
+    result.with_context(|| format!("Probe of {} failed", name))?;
+    println!("{name}\tprobe ok");
+    Ok(())
+}
+
+fn probe_step(uploader: &str, step: &str, action: impl FnOnce() -> Result<()>) -> Result<()> {
+    let started = Instant::now();
+    let result = action().with_context(|| format!("{step} failed"));
+    match &result {
+        Ok(()) => println!(
+            "{}\t{}\tok\t{}ms",
+            uploader,
+            step,
+            started.elapsed().as_millis()
+        ),
+        Err(err) => println!("{}\t{}\tfailed\t{:#}", uploader, step, err),
+    }
+    result
+}
+
+/// Builds each enabled uploader on its own and makes its `check_access`
+/// request, printing one line per uploader with what it tells about the
+/// account and the free space where the backend reports it. One uploader
+/// failing does not stop the others from being checked.
+fn run_check(config_paths: &[String]) -> Result<()> {
+    let config = load_config(config_paths)?;
+    let names = enabled_uploader_names(&config.app);
+    if names.is_empty() {
+        anyhow::bail!("No cloud uploader enabled");
+    }
+    let mut failed = Vec::new();
+    for name in names {
+        let started = Instant::now();
+        match check_uploader(&config.app, name) {
+            Ok(details) => {
+                println!(
+                    "{}\tcheck\tok\t{}ms{}",
+                    name,
+                    started.elapsed().as_millis(),
+                    details
+                        .map(|details| format!("\t{details}"))
+                        .unwrap_or_default()
+                );
+            }
+            Err(err) => {
+                println!("{name}\tcheck\tfailed\t{err:#}");
+                failed.push(name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Credential check failed for {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+fn check_uploader(app: &AppConfig, name: &str) -> Result<Option<String>> {
+    let mut uploader = build_selected_uploaders(app, Some(name))?
+        .pop()
+        .with_context(|| format!("{name} uploader was not built"))?;
+    let account = uploader.check_access()?;
+    // Free space is extra information; the check passed already.
+    let free = match uploader.available_space() {
+        Ok(free) => free.map(|bytes| format!("{} free", format_bytes(bytes))),
+        Err(err) => {
+            warn!("{name} did not report its free space: {err:#}");
+            None
+        }
+    };
+    let details: Vec<String> = account.into_iter().chain(free).collect();
+    Ok((!details.is_empty()).then(|| details.join(", ")))
+}
+
+/// Downloads one of the item's archives to `local_path`, reassembling it
+/// from its parts if it was uploaded split, or from its chunks if it was
+/// uploaded with `dedup`. Returns the size the archive should have.
+fn download_archive(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    name: &str,
+    entries: &[RemoteEntry],
+    local_path: &Path,
+) -> Result<u64> {
+    if let Some(entry) = entries.iter().find(|entry| entry.name == name) {
+        uploader.download(remote_dir, name, local_path)?;
+        return Ok(entry.size);
+    }
+    let recipe = dedup::recipe_name(name);
+    if entries.iter().any(|entry| entry.name == recipe) {
+        let recipe_path = sidecar_path(local_path, dedup::RECIPE_EXTENSION);
+        uploader.download(remote_dir, &recipe, &recipe_path)?;
+        let chunks = dedup::read_recipe(&recipe_path);
+        let _ = fs::remove_file(&recipe_path);
+        let chunks = chunks?;
+        info!("Reassembling {} from {} chunks", name, chunks.len());
+        let chunk_dir = format!("{}/{}", remote_dir.trim_end_matches('/'), dedup::CHUNK_DIR);
+        dedup::join_chunks(&chunks, local_path, |chunk, path| {
+            uploader.download(&chunk_dir, chunk, path)
+        })?;
+        return Ok(chunks.iter().map(|chunk| chunk.size).sum());
+    }
+    let manifest_path = sidecar_path(local_path, split::MANIFEST_EXTENSION);
+    uploader.download(remote_dir, &split::manifest_name(name), &manifest_path)?;
+    let parts = split::read_manifest(&manifest_path, name);
+    let _ = fs::remove_file(&manifest_path);
+    let parts = parts?;
+    info!("Reassembling {} from {} parts", name, parts.len());
+    split::join_parts(&parts, local_path, |part, path| {
+        uploader.download(remote_dir, part, path)
+    })?;
+    Ok(entries
+        .iter()
+        .filter(|entry| parts.iter().any(|part| part.name == entry.name))
+        .map(|entry| entry.size)
+        .sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ItemReport, ItemStatus};
+
+    #[test]
+    fn test_configured_log_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("backup.toml");
+        let path = &[config.to_str().unwrap().to_string()];
+        assert_eq!(configured_log_format(path).unwrap(), LogFormat::Text);
+        fs::write(&config, "[app]\nlog_format = \"json\"\n").unwrap();
+        assert_eq!(configured_log_format(path).unwrap(), LogFormat::Json);
+        fs::write(&config, "[app]\n").unwrap();
+        assert_eq!(configured_log_format(path).unwrap(), LogFormat::Text);
+        fs::write(&config, "[app]\nlog_format = \"xml\"\n").unwrap();
+        assert!(configured_log_format(path).is_err());
+    }
+
+    #[test]
+    fn test_format_summary_line() {
+        let item = |name: &str, failures: Vec<String>| ItemReport {
+            name: name.to_string(),
+            status: if failures.is_empty() {
+                ItemStatus::Ok
+            } else {
+                ItemStatus::Failed
+            },
+            bytes: 0,
+            duration_secs: 0,
+            failures,
+            archive: None,
+            uploads: Vec::new(),
+        };
+        let mut report = RunReport {
+            items: vec![item("docs", Vec::new()), item("photos", Vec::new())],
+            bytes: 12345678,
+            ..RunReport::default()
+        };
+        assert_eq!(
+            format_summary_line(&report, Duration::from_millis(42_900)),
+            "RESULT ok items=2 failed=0 bytes=12345678 duration=42s"
+        );
+        assert!(report.outcome().is_ok());
+
+        report
+            .items
+            .push(item("mail", vec!["[mail] upload to s3 failed".to_string()]));
+        report.error = report.outcome().err().map(|err| err.to_string());
+        assert_eq!(
+            format_summary_line(&report, Duration::from_millis(42_900)),
+            "RESULT failed items=3 failed=1 bytes=12345678 duration=42s"
+        );
+        assert_eq!(
+            report.outcome().unwrap_err().to_string(),
+            "Backup finished with 1 failure(s):\n[mail] upload to s3 failed"
+        );
+    }
+
+    #[test]
+    fn test_parse_args_probe() {
+        let args = |list: &[&str]| parse_args(list.iter().map(|arg| arg.to_string()));
+
+        let options = args(&["auth", "prod.toml"]).unwrap();
+        assert_eq!(options.command, CliCommand::Auth);
+        assert_eq!(args(&["check"]).unwrap().command, CliCommand::Check);
+        assert_eq!(options.config_paths, ["prod.toml"]);
+
+        let options = args(&["probe", "cloud189", "prod.toml"]).unwrap();
+        assert!(!options.dry_run);
+        assert_eq!(
+            options.command,
+            CliCommand::Probe {
+                uploader: "cloud189".to_string()
+            }
+        );
+        assert_eq!(options.config_paths, ["prod.toml"]);
+        assert!(args(&["probe"]).is_err());
+        assert_eq!(args(&["du"]).unwrap().config_paths, ["backup.toml"]);
+        assert_eq!(
+            args(&["usage", "base.toml", "-"]).unwrap().config_paths,
+            ["base.toml", "-"]
+        );
+        assert!(args(&["--dry-run", "prod.toml"]).unwrap().dry_run);
+    }
+
+    #[test]
+    fn test_parse_args_subcommands() {
+        let args = |list: &[&str]| parse_args(list.iter().map(|arg| arg.to_string()));
+
+        let options = args(&[]).unwrap();
+        assert_eq!(options.command, CliCommand::Backup);
+        assert_eq!(options.config_paths, ["backup.toml"]);
+
+        let bare = args(&["--summary-only", "prod.toml"]).unwrap();
+        assert_eq!(bare.command, CliCommand::Backup);
+        assert_eq!(bare.config_paths, ["prod.toml"]);
+        assert!(bare.summary_only);
+        let explicit = args(&["backup", "--resume", "prod.toml"]).unwrap();
+        assert_eq!(explicit.config_paths, ["prod.toml"]);
+        assert!(explicit.resume);
+        assert!(!explicit.restart);
+        assert!(!explicit.force);
+        assert!(args(&["--restart", "prod.toml"]).unwrap().restart);
+        assert!(args(&["--scheduled", "prod.toml"]).unwrap().scheduled);
+        let daemon = args(&["--daemon", "prod.toml"]).unwrap();
+        assert!(daemon.daemon && daemon.scheduled);
+        assert!(args(&["--daemon", "--dry-run", "prod.toml"]).is_err());
+        assert!(args(&["--restart", "--resume", "prod.toml"]).is_err());
+        assert!(args(&["--force", "prod.toml"]).unwrap().force);
+
+        assert_eq!(
+            args(&["list", "project"]).unwrap().command,
+            CliCommand::List {
+                item: "project".to_string()
+            }
+        );
+        let verify = args(&["verify", "project", "--date", "20260211", "prod.toml"]).unwrap();
+        assert_eq!(
+            verify.command,
+            CliCommand::Verify {
+                item: "project".to_string(),
+                date: Some("20260211".to_string()),
+                checksum_only: false,
+            }
+        );
+        assert!(matches!(
+            args(&["verify", "project", "--checksum-only"])
+                .unwrap()
+                .command,
+            CliCommand::Verify {
+                checksum_only: true,
+                ..
+            }
+        ));
+        assert_eq!(verify.config_paths, ["prod.toml"]);
+        assert!(args(&["list"]).is_err());
+        let restore = args(&["restore", "project", "20260211", "/srv/restore", "--force"]).unwrap();
+        assert_eq!(
+            restore.command,
+            CliCommand::Restore(RestoreRequest {
+                item: "project".to_string(),
+                date: "20260211".to_string(),
+                destination: PathBuf::from("/srv/restore"),
+                force: true,
+                identity: None,
+            })
+        );
+        assert!(args(&["restore", "project", "20260211"]).is_err());
+        assert!(args(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_baidu_auth_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let app: AppConfig = toml::from_str(&format!(
+            r#"
+            baidu_enabled = true
+            baidu_app_key = "abc123"
+            baidu_app_secret = "secret"
+            baidu_config = "{}"
+            "#,
+            dir.path().join("baidu.json").display()
+        ))
+        .unwrap();
+        let err = new_baidu_uploader(&app, false).err().unwrap();
+        assert_eq!(exit_status(&err), EXIT_AUTH_REQUIRED);
+        assert!(
+            err.to_string().contains(
+                "open https://openapi.baidu.com/oauth/2.0/authorize?response_type=code&client_id=abc123&redirect_uri=oob&scope=basic,netdisk and run it with BAIDU_AUTH_CODE set"
+            ),
+            "{err}"
+        );
+        assert_eq!(exit_status(&anyhow::anyhow!("upload failed")), 1);
+    }
+
+    #[test]
+    fn test_daemon_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = [dir.path().join("backup.toml").display().to_string()];
+        let scheduled = r#"
+            [app]
+            daemon_retry_secs = 60
+            [[backups]]
+            source_dir = "/etc"
+            remote_dir = "/backups/etc"
+            archive_name = "etc"
+            schedule = "@daily"
+            "#;
+        let config: Config = toml::from_str(scheduled).unwrap();
+        // Never backed up, so due now and retried after daemon_retry_secs.
+        assert_eq!(
+            daemon_wait(&config, &paths).unwrap(),
+            Duration::from_secs(60)
+        );
+
+        let config: Config = toml::from_str(&format!(
+            "{scheduled}\n[[backups]]\nsource_dir = \"/home\"\nremote_dir = \"/backups/home\"\narchive_name = \"home\"\n"
+        ))
+        .unwrap();
+        assert_eq!(
+            daemon_wait(&config, &paths).unwrap_err().to_string(),
+            "--daemon runs items on their schedule, but home has none"
+        );
+    }
+
+    #[test]
+    fn test_dry_run_estimates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "x".repeat(3000)).unwrap();
+        fs::write(dir.path().join("photo.jpg"), "x".repeat(1000)).unwrap();
+        let item: BackupItem = toml::from_str(&format!(
+            "source_dir = {:?}\nremote_dir = \"nightly\"\narchive_name = \"data\"\nexclude = [\"*.jpg\"]\n",
+            dir.path().display().to_string()
+        ))
+        .unwrap();
+        let stamp = Stamp::now(DEFAULT_DATE_FORMAT);
+        let history = History::default();
+        assert_eq!(
+            estimated_archive_size(&item, &stamp, "data", &history),
+            Some(1000)
+        );
+        let database: BackupItem = toml::from_str(
+            "remote_dir = \"db\"\narchive_name = \"db\"\n[database]\nengine = \"postgres\"\ndatabase = \"app\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            estimated_archive_size(&database, &stamp, "db", &history),
+            None
+        );
+
+        let app = |toml: &str| toml::from_str::<AppConfig>(toml).unwrap();
+        assert_eq!(
+            s3_pricing(&app("s3_endpoint = \"https://s3.eu-west-1.amazonaws.com\"")),
+            Some(Pricing::AWS_S3_STANDARD)
+        );
+        assert_eq!(
+            s3_pricing(&app("s3_endpoint = \"https://minio.lan:9000\"")),
+            None
+        );
+        assert_eq!(
+            s3_pricing(&app(
+                "s3_endpoint = \"https://minio.lan:9000\"\ns3_price_per_gb_month = 0.005"
+            )),
+            Some(Pricing {
+                storage_per_month: 0.005,
+                egress: Pricing::AWS_S3_STANDARD.egress,
+            })
+        );
+    }
+
+    #[test]
+    fn test_usage_total() {
+        assert_eq!(
+            usage_total("webdav", 3 << 30, None),
+            ("webdav\ttotal\t3.0 GiB".to_string(), 3 << 30)
+        );
+        let usage = StorageUsage {
+            used: 5 << 30,
+            limit: Some(15 << 30),
+        };
+        assert_eq!(
+            usage_total("gdrive", 3 << 30, Some(usage)),
+            (
+                "gdrive\ttotal\t5.0 GiB used of 15.0 GiB (account, 3.0 GiB in archives)"
+                    .to_string(),
+                5 << 30
+            )
+        );
+        let unlimited = StorageUsage {
+            used: 1024,
+            limit: None,
+        };
+        assert_eq!(
+            usage_total("onedrive", 0, Some(unlimited)).0,
+            "onedrive\ttotal\t1.0 KiB used (account, 0 B in archives)"
+        );
+    }
+}
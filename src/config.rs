@@ -0,0 +1,1180 @@
+//! Loading and validating the configuration: merging config files,
+//! resolving `${VAR}` references and `*_file` secrets, and checking every
+//! item before a run starts.
+
+use crate::archive::{self, ArchiveFormat, SymlinkMode, check_level};
+use crate::backends::build_uploaders;
+use crate::database::DatabaseDump;
+use crate::encryption::EncryptionConfig;
+use crate::gpg::{SignConfig, SignTarget};
+use crate::notify::{NotifyConfig, SmtpConfig, TelegramConfig};
+use crate::retention::ArchiveNaming;
+use crate::schedule::Schedule;
+use crate::uploader::s3::ObjectLockMode;
+use crate::uploader::sftp::HostKeyPolicy;
+use crate::uploader::{Uploader, parse_proxy};
+use crate::verify::VerifyMode;
+use crate::{DEFAULT_DATE_FORMAT, check_date_format, hostname, normalize_archive_name};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// A loaded and validated configuration.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub(crate) app: AppConfig,
+    pub(crate) backups: Vec<BackupItem>,
+    /// The files it was loaded from; the run state is kept next to the
+    /// last of them.
+    #[serde(skip)]
+    pub(crate) paths: Vec<String>,
+}
+
+impl Config {
+    /// Loads config files as the command line does: merged in order, with
+    /// `-` for one read from stdin, `${VAR}` and `*_file` settings resolved,
+    /// and the result validated.
+    pub fn load(paths: &[String]) -> Result<Self> {
+        load_config(paths)
+    }
+
+    /// Parses and validates a config from TOML text. Its run state is kept
+    /// in the working directory, as for a config read from stdin.
+    pub fn parse(text: &str) -> Result<Self> {
+        let table = toml::from_str(text).context("Failed to parse config file")?;
+        config_from_table(table, Vec::new())
+    }
+
+    pub fn items(&self) -> &[BackupItem] {
+        &self.backups
+    }
+
+    /// Sets up every enabled uploader, as a run does before its first item.
+    pub fn uploaders(&self) -> Result<Vec<Box<dyn Uploader>>> {
+        build_uploaders(&self.app)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AppConfig {
+    #[serde(default)]
+    pub baidu_enabled: Option<bool>,
+    #[serde(alias = "app_key")]
+    pub baidu_app_key: Option<String>,
+    #[serde(alias = "app_secret")]
+    pub baidu_app_secret: Option<String>,
+    pub baidu_config: Option<String>,
+    #[serde(default)]
+    pub cloud189_enabled: Option<bool>,
+    pub cloud189_config: Option<String>,
+    pub cloud189_username: Option<String>,
+    pub cloud189_password: Option<String>,
+    pub cloud189_use_qr: Option<bool>,
+    pub upload_attempts: Option<u32>,
+    pub upload_retry_delay_secs: Option<u64>,
+    pub provider_failure_threshold: Option<usize>,
+    pub max_upload_bytes_per_sec: Option<u64>,
+    pub stall_timeout_secs: Option<u64>,
+    pub connect_timeout_secs: Option<u64>,
+    pub upload_timeout_secs: Option<u64>,
+    pub http_proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub stop_on_first_failure: Option<bool>,
+    pub daemon_retry_secs: Option<u64>,
+    pub work_dir: Option<String>,
+    pub lock_file: Option<String>,
+    pub signing_key_file: Option<String>,
+    pub signing_public_key_file: Option<String>,
+    #[serde(default)]
+    pub verify_mode: VerifyMode,
+    pub probe_remote_dir: Option<String>,
+    pub baidu_max_file_size_mb: Option<u64>,
+    pub baidu_immutable: Option<bool>,
+    pub baidu_max_retries: Option<u32>,
+    pub baidu_retry_base_delay_ms: Option<u64>,
+    pub baidu_rate_limit_retries: Option<u32>,
+    pub baidu_rate_limit_delay_secs: Option<u64>,
+    pub cloud189_max_file_size_mb: Option<u64>,
+    pub cloud189_immutable: Option<bool>,
+    #[serde(default)]
+    pub s3_enabled: Option<bool>,
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    pub s3_immutable: Option<bool>,
+    pub s3_object_lock_days: Option<u32>,
+    pub s3_object_lock_mode: Option<ObjectLockMode>,
+    /// USD per GiB and month, for the `--dry-run` cost estimate.
+    pub s3_price_per_gb_month: Option<f64>,
+    /// USD per GiB downloaded, for the `--dry-run` cost estimate.
+    pub s3_egress_price_per_gb: Option<f64>,
+    #[serde(default)]
+    pub webdav_enabled: Option<bool>,
+    pub webdav_url: Option<String>,
+    pub webdav_username: Option<String>,
+    pub webdav_password: Option<String>,
+    pub webdav_immutable: Option<bool>,
+    #[serde(default)]
+    pub local_enabled: Option<bool>,
+    pub local_base_dir: Option<String>,
+    pub local_fsync: Option<bool>,
+    pub local_immutable: Option<bool>,
+    #[serde(default)]
+    pub sftp_enabled: Option<bool>,
+    pub sftp_host: Option<String>,
+    pub sftp_port: Option<u16>,
+    pub sftp_username: Option<String>,
+    pub sftp_password: Option<String>,
+    pub sftp_private_key: Option<String>,
+    pub sftp_private_key_passphrase: Option<String>,
+    pub sftp_known_hosts: Option<String>,
+    #[serde(default)]
+    pub sftp_host_key_policy: HostKeyPolicy,
+    pub sftp_immutable: Option<bool>,
+    #[serde(default)]
+    pub gdrive_enabled: Option<bool>,
+    pub gdrive_client_id: Option<String>,
+    pub gdrive_client_secret: Option<String>,
+    pub gdrive_config: Option<String>,
+    pub gdrive_immutable: Option<bool>,
+    #[serde(default)]
+    pub onedrive_enabled: Option<bool>,
+    pub onedrive_client_id: Option<String>,
+    pub onedrive_client_secret: Option<String>,
+    pub onedrive_tenant: Option<String>,
+    pub onedrive_config: Option<String>,
+    pub onedrive_immutable: Option<bool>,
+    pub compression_level: Option<i32>,
+    pub compression_threads: Option<u32>,
+    pub encryption: Option<EncryptionConfig>,
+    pub sign: Option<SignConfig>,
+    pub pre_run_command: Option<String>,
+    pub post_run_command: Option<String>,
+    pub report_file: Option<String>,
+    pub notify: Option<NotifyConfig>,
+    pub smtp: Option<SmtpConfig>,
+    pub telegram: Option<TelegramConfig>,
+}
+
+/// One `[[backups]]` entry.
+#[derive(Debug, Deserialize)]
+pub struct BackupItem {
+    pub(crate) source_dir: Option<String>,
+    pub(crate) source_path: Option<String>,
+    #[serde(default)]
+    pub(crate) source_paths: Vec<String>,
+    pub(crate) command: Option<String>,
+    pub(crate) command_argv: Option<Vec<String>>,
+    pub(crate) command_workdir: Option<String>,
+    pub(crate) command_timeout_secs: Option<u64>,
+    pub(crate) command_output_name: Option<String>,
+    pub(crate) keep_command_source: Option<bool>,
+    pub(crate) post_command: Option<String>,
+    pub(crate) post_command_workdir: Option<String>,
+    pub(crate) remote_dir: String,
+    pub(crate) remote_date_subdir: Option<bool>,
+    pub(crate) archive_name: String,
+    pub(crate) archive_filename: Option<String>,
+    pub(crate) keep_archive: Option<bool>,
+    pub(crate) stream_upload: Option<bool>,
+    pub(crate) skip_if_unchanged: Option<bool>,
+    pub(crate) max_part_size_mb: Option<u64>,
+    pub(crate) work_dir: Option<String>,
+    pub(crate) keep_remote: Option<usize>,
+    pub(crate) keep_local: Option<usize>,
+    pub(crate) write_checksum: Option<bool>,
+    pub(crate) incremental: Option<bool>,
+    pub(crate) schedule: Option<Schedule>,
+    pub(crate) date_format: Option<String>,
+    pub(crate) database: Option<DatabaseDump>,
+    #[serde(default)]
+    pub(crate) source_is_symlink: SymlinkMode,
+    pub(crate) follow_symlinks: Option<bool>,
+    pub(crate) owner_uid: Option<u64>,
+    pub(crate) owner_gid: Option<u64>,
+    pub(crate) atomic_remote: Option<bool>,
+    pub(crate) auto_level: Option<bool>,
+    pub(crate) compression_level: Option<i32>,
+    #[serde(default)]
+    pub(crate) archive_format: ArchiveFormat,
+    pub(crate) encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    pub(crate) collect_stats: Option<bool>,
+    pub(crate) dedup: Option<bool>,
+    /// Names of the uploaders this item goes to; empty means all enabled.
+    #[serde(default)]
+    pub(crate) uploaders: Vec<String>,
+}
+
+impl BackupItem {
+    /// `archive_name`, which the item goes by in logs and reports.
+    pub fn name(&self) -> &str {
+        normalize_archive_name(&self.archive_name)
+    }
+
+    pub(crate) fn has_command(&self) -> bool {
+        self.command_argv.is_some() || self.command.is_some()
+    }
+
+    pub(crate) fn date_format(&self) -> &str {
+        self.date_format.as_deref().unwrap_or(DEFAULT_DATE_FORMAT)
+    }
+
+    pub(crate) fn archive_naming(&self) -> ArchiveNaming<'_> {
+        let archive_name = normalize_archive_name(&self.archive_name);
+        ArchiveNaming {
+            template: self.archive_filename.as_ref().map(|template| {
+                template
+                    .replace("{archive_name}", archive_name)
+                    .replace("{hostname}", hostname())
+            }),
+            ..ArchiveNaming::new(archive_name, self.date_format())
+        }
+    }
+
+    /// What the item's archives are when `archive_filename` gives them an
+    /// extension that does not tell, as a fallback for reading one back.
+    pub(crate) fn custom_name_format(&self, encrypted: bool) -> Option<(ArchiveFormat, bool)> {
+        // Chunks are cut from a plain tar, see backup_item.
+        let format = if self.dedup.unwrap_or(false) {
+            ArchiveFormat::Tar
+        } else {
+            self.archive_format
+        };
+        self.archive_filename
+            .is_some()
+            .then_some((format, encrypted))
+    }
+
+    /// `command_timeout_secs`; `0` means none, like leaving it unset.
+    pub(crate) fn command_timeout(&self) -> Option<Duration> {
+        self.command_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Whether the uploader called `name` receives this item. Names match
+    /// case-insensitively, as for `probe`.
+    pub(crate) fn uses_uploader(&self, name: &str) -> bool {
+        self.uploaders.is_empty()
+            || self
+                .uploaders
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Names of the uploaders `build_uploaders` would create, in upload order.
+pub fn enabled_uploader_names(app: &AppConfig) -> Vec<&'static str> {
+    [
+        ("Baidu", app.baidu_enabled),
+        ("Cloud189", app.cloud189_enabled),
+        ("S3", app.s3_enabled),
+        ("WebDAV", app.webdav_enabled),
+        ("Local", app.local_enabled),
+        ("SFTP", app.sftp_enabled),
+        ("GDrive", app.gdrive_enabled),
+        ("OneDrive", app.onedrive_enabled),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| enabled.unwrap_or(false))
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// The config path that reads the config from stdin.
+pub const STDIN_CONFIG: &str = "-";
+
+/// Loads the config files in order, merged as described at
+/// `read_config_table`.
+pub fn load_config(paths: &[String]) -> Result<Config> {
+    config_from_table(read_config_table(paths)?, paths.to_vec())
+}
+
+/// Builds the config from the merged files, which came from `paths`.
+pub fn config_from_table(mut table: toml::Table, paths: Vec<String>) -> Result<Config> {
+    let mut problems: Vec<String> = Vec::new();
+    for (key, value) in table.iter_mut() {
+        expand_env_vars(value, key, &mut problems);
+    }
+    read_secret_files(&mut table, &mut problems);
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid backup configuration:\n{}", problems.join("\n"));
+    }
+    let mut config: Config = toml::Value::Table(table)
+        .try_into()
+        .context("Failed to parse config file")?;
+    config.paths = paths;
+    // Everything else keys off the archive name, so it is expanded here
+    // rather than with the other placeholders. The date subfolder is added
+    // here too, so listing, retention, verify and restore find it just like
+    // a `{date}` written into `remote_dir`.
+    for item in &mut config.backups {
+        item.archive_name = item.archive_name.replace("{hostname}", hostname());
+        if item.remote_date_subdir.unwrap_or(false) {
+            if item.remote_dir.contains("{date}") {
+                warn!(
+                    "[{}] remote_dir {} already contains {{date}}, so remote_date_subdir adds no subfolder",
+                    normalize_archive_name(&item.archive_name),
+                    item.remote_dir
+                );
+            } else {
+                item.remote_dir = format!("{}/{{date}}", item.remote_dir.trim_end_matches('/'));
+            }
+        }
+    }
+    validate_config(&config)?;
+    Ok(config)
+}
+
+/// Reads and merges the config files: a later file overrides the `[app]`
+/// settings (and any other table, key by key) of the earlier ones and adds
+/// its `[[backups]]` to theirs. Whatever cannot be merged that way fails the
+/// load: an item defined in two files, or a key that is a table in one file
+/// and a value in another.
+pub fn read_config_table(paths: &[String]) -> Result<toml::Table> {
+    if paths.iter().filter(|path| *path == STDIN_CONFIG).count() > 1 {
+        anyhow::bail!("The config can be read from stdin (`-`) only once");
+    }
+    let mut merged = toml::Table::new();
+    let mut item_sources: Vec<(String, &str)> = Vec::new();
+    let mut problems = Vec::new();
+    for path in paths {
+        let contents = read_config_file(path)?;
+        let table: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config file: {}", config_name(path)))?;
+        if let Some(items) = table.get("backups").and_then(toml::Value::as_array) {
+            let names = items
+                .iter()
+                .filter_map(|item| item.get("archive_name")?.as_str());
+            for name in names {
+                if let Some((_, earlier)) = item_sources
+                    .iter()
+                    .find(|(seen, source)| seen == name && source != path)
+                {
+                    problems.push(format!(
+                        "Item {name} is defined in both {} and {}; backups from several config files are added up, not merged, so define each item in one file only",
+                        config_name(earlier),
+                        config_name(path)
+                    ));
+                }
+            }
+            item_sources.extend(
+                items
+                    .iter()
+                    .filter_map(|item| item.get("archive_name")?.as_str())
+                    .map(|name| (name.to_string(), path.as_str())),
+            );
+        }
+        merge_config_table(&mut merged, table, "", config_name(path), &mut problems);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "Cannot merge config files {}:\n{}",
+            config_names(paths),
+            problems.join("\n")
+        );
+    }
+    Ok(merged)
+}
+
+/// Merges `overlay`, read from `source`, into `base`, whose tables are at
+/// `prefix`. A later `<field>` of `SECRET_FIELDS` replaces an earlier
+/// `<field>_file` and the other way round, since setting both is an error.
+pub fn merge_config_table(
+    base: &mut toml::Table,
+    overlay: toml::Table,
+    prefix: &str,
+    source: &str,
+    problems: &mut Vec<String>,
+) {
+    let overlay_keys: Vec<String> = overlay.keys().cloned().collect();
+    for (key, value) in overlay {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Array(earlier)), toml::Value::Array(items)) if name == "backups" => {
+                earlier.extend(items);
+            }
+            (Some(toml::Value::Table(earlier)), toml::Value::Table(table)) => {
+                merge_config_table(earlier, table, &name, source, problems);
+            }
+            (Some(earlier), value)
+                if earlier.is_table() || value.is_table() || name == "backups" =>
+            {
+                problems.push(format!(
+                    "{name} is a {} in {source} but a {} in an earlier config file; a later file can only override values with values and merge tables into tables",
+                    value.type_str(),
+                    earlier.type_str()
+                ));
+            }
+            (_, value) => {
+                let alternative = match key.strip_suffix("_file") {
+                    Some(field) => field.to_string(),
+                    None => format!("{key}_file"),
+                };
+                let secret = SECRET_FIELDS.iter().any(|(section, field)| {
+                    *section == prefix && (*field == key || *field == alternative)
+                });
+                if secret && !overlay_keys.contains(&alternative) {
+                    base.remove(&alternative);
+                }
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Reads one config file, or stdin for `-`. Stdin is read once and kept,
+/// since the config is loaded more than once per run.
+pub fn read_config_file(path: &str) -> Result<String> {
+    if path != STDIN_CONFIG {
+        return fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path));
+    }
+    static STDIN: OnceLock<Result<String, String>> = OnceLock::new();
+    STDIN
+        .get_or_init(|| {
+            if io::stdin().is_terminal() {
+                return Err("stdin is a terminal; pipe the config in".to_string());
+            }
+            let mut contents = String::new();
+            io::stdin()
+                .read_to_string(&mut contents)
+                .map(|_| contents)
+                .map_err(|err| err.to_string())
+        })
+        .clone()
+        .map_err(|err| anyhow::anyhow!("Failed to read config from stdin: {err}"))
+}
+
+pub fn config_name(path: &str) -> &str {
+    if path == STDIN_CONFIG { "stdin" } else { path }
+}
+
+/// The config files for messages, e.g. `base.toml + host.toml`.
+pub fn config_names(paths: &[String]) -> String {
+    paths
+        .iter()
+        .map(|path| config_name(path))
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+/// The config file the state, chunk index and such are kept next to: the
+/// last one named, which with a shared base config is the host's own. A
+/// config read only from stdin keeps them in the current directory, as
+/// `stdin.state.json` and so on.
+pub fn config_anchor(paths: &[String]) -> PathBuf {
+    paths
+        .iter()
+        .rev()
+        .find(|path| *path != STDIN_CONFIG)
+        .map_or_else(|| PathBuf::from("stdin"), PathBuf::from)
+}
+
+/// Secrets that can be read from the file named by `<field>_file` instead,
+/// e.g. a Docker secret or a systemd credential, by table.
+pub const SECRET_FIELDS: &[(&str, &str)] = &[
+    ("app", "baidu_app_secret"),
+    ("app", "cloud189_password"),
+    ("app", "s3_access_key"),
+    ("app", "s3_secret_key"),
+    ("app", "webdav_password"),
+    ("app", "sftp_password"),
+    ("app", "sftp_private_key_passphrase"),
+    ("app", "gdrive_client_secret"),
+    ("app", "onedrive_client_secret"),
+    ("app.smtp", "password"),
+    ("app.telegram", "bot_token"),
+];
+
+/// Replaces each `<field>_file` of `SECRET_FIELDS` with the field itself,
+/// set to the file's contents without trailing whitespace. Since the field
+/// is then set inline, it also wins over the environment variable fallback.
+/// Setting both the field and its file, or naming a file that cannot be read
+/// or is empty, is a problem.
+pub fn read_secret_files(config: &mut toml::Table, problems: &mut Vec<String>) {
+    for (section, field) in SECRET_FIELDS {
+        let Some(table) = section.split('.').try_fold(&mut *config, |table, key| {
+            table.get_mut(key)?.as_table_mut()
+        }) else {
+            continue;
+        };
+        let file_field = format!("{field}_file");
+        let Some(path) = table.remove(&file_field) else {
+            continue;
+        };
+        let Some(path) = path.as_str() else {
+            problems.push(format!("[{section}] {file_field} must be a path"));
+            continue;
+        };
+        if table.contains_key(*field) {
+            problems.push(format!(
+                "[{section}] set either {field} or {file_field}, not both"
+            ));
+            continue;
+        }
+        match read_secret_file(Path::new(path)) {
+            Ok(secret) => {
+                table.insert(field.to_string(), toml::Value::String(secret));
+            }
+            Err(err) => problems.push(format!("[{section}] {file_field}: {err:#}")),
+        }
+    }
+}
+
+pub fn read_secret_file(path: &Path) -> Result<String> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let secret = contents.trim_end();
+    if secret.is_empty() {
+        anyhow::bail!("{} is empty", path.display());
+    }
+    Ok(secret.to_string())
+}
+
+/// Config fields run through the shell, which expands variables itself (and
+/// may set them inside the command).
+pub const SHELL_FIELDS: &[&str] = &[
+    "command",
+    "post_command",
+    "pre_run_command",
+    "post_run_command",
+];
+
+/// Expands `$VAR` / `${VAR}` in every string below `value`, pushing a problem
+/// naming the field for each variable that is not set.
+pub fn expand_env_vars(value: &mut toml::Value, field: &str, problems: &mut Vec<String>) {
+    match value {
+        toml::Value::String(text) => match expand_env(text, |name| env::var(name).ok()) {
+            Ok(expanded) => *text = expanded,
+            Err(name) => problems.push(format!(
+                "{field}: environment variable {name} is not set (write $$ for a literal $)"
+            )),
+        },
+        toml::Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                expand_env_vars(item, &format!("{field}[{index}]"), problems);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                if !SHELL_FIELDS.contains(&key.as_str()) {
+                    expand_env_vars(item, &format!("{field}.{key}"), problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces `$NAME` and `${NAME}` with `lookup(NAME)`; `$$` is a literal `$`
+/// and a `$` not followed by a name is kept, so `{date}` style placeholders
+/// pass through untouched. Fails with the first name `lookup` doesn't know.
+pub fn expand_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{')
+            && let Some(end) = braced.find('}')
+            && !braced[..end].is_empty()
+            && braced[..end].chars().all(is_name_char)
+        {
+            (&braced[..end], &braced[end + 1..])
+        } else if after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        } else if let Some(remainder) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = remainder;
+            continue;
+        } else {
+            expanded.push('$');
+            rest = after;
+            continue;
+        };
+        expanded.push_str(&lookup(name).ok_or_else(|| name.to_string())?);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Checks everything that can be checked without building the uploaders or
+/// touching the sources, and reports all problems together so a config can be
+/// fixed in one go. Checks that need the uploaders are in `validate_items`.
+pub fn validate_config(config: &Config) -> Result<()> {
+    let mut problems: Vec<String> = Vec::new();
+    if config.backups.is_empty() {
+        problems.push("No backups configured".to_string());
+    }
+    if enabled_uploader_names(&config.app).is_empty() {
+        problems
+            .push("[app] no uploader is enabled (set one of the *_enabled options)".to_string());
+    }
+    let enabled = enabled_uploader_names(&config.app);
+    for item in &config.backups {
+        check_item(item, &mut problems);
+        for name in &item.uploaders {
+            if !enabled
+                .iter()
+                .any(|enabled| enabled.eq_ignore_ascii_case(name))
+            {
+                problems.push(format!(
+                    "[{}] uploaders names {}, which is not enabled (enabled: {})",
+                    normalize_archive_name(&item.archive_name),
+                    name,
+                    enabled.join(", ")
+                ));
+            }
+        }
+    }
+    for (key, value) in [
+        ("connect_timeout_secs", config.app.connect_timeout_secs),
+        ("upload_timeout_secs", config.app.upload_timeout_secs),
+        ("daemon_retry_secs", config.app.daemon_retry_secs),
+    ] {
+        if value == Some(0) {
+            problems.push(format!(
+                "[app] {key} must be at least 1, or left unset for the default"
+            ));
+        }
+    }
+    if let Some(proxy) = &config.app.http_proxy
+        && let Err(err) = parse_proxy(proxy)
+    {
+        problems.push(format!("[app] {err}"));
+    }
+    check_compression_levels(config, &mut problems);
+    check_encryption(config, &mut problems);
+    check_sign(config, &mut problems);
+    check_dedup(config, &enabled, &mut problems);
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid backup configuration:\n{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+/// Placeholders `expand_placeholders` replaces in paths and commands.
+pub const PLACEHOLDERS: &[&str] = &["date", "time", "archive_name", "hostname"];
+
+/// `archive_filename` has to name a file and bring back its date, which
+/// `{time}` would make ambiguous.
+pub fn check_archive_filename(template: &str, base_name: &str, problems: &mut Vec<String>) {
+    if template.matches("{date}").count() != 1 {
+        problems.push(format!(
+            "[{base_name}] archive_filename must contain {{date}} exactly once"
+        ));
+    }
+    if template.contains(['/', '\\']) {
+        problems.push(format!(
+            "[{base_name}] archive_filename is a file name and cannot contain / or \\"
+        ));
+    }
+    if template.contains("{time}") {
+        problems.push(format!(
+            "[{base_name}] archive_filename cannot use {{time}}; a second archive on the same date gets a -<n> suffix"
+        ));
+    }
+    for token in unknown_placeholders(template) {
+        problems.push(format!(
+            "[{base_name}] archive_filename uses unknown placeholder {{{token}}} (known: date, archive_name, hostname)"
+        ));
+    }
+}
+
+pub fn check_item(item: &BackupItem, problems: &mut Vec<String>) {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let has_source =
+        item.source_path.is_some() || item.source_dir.is_some() || !item.source_paths.is_empty();
+    if item.database.is_some() {
+        if item.has_command() || has_source || item.command_output_name.is_some() {
+            problems.push(format!(
+                "[{base_name}] database cannot be combined with source_path/source_dir/source_paths/command/command_output_name"
+            ));
+        }
+        if item.incremental.unwrap_or(false) {
+            problems.push(format!(
+                "[{base_name}] incremental only works for files, not database dumps"
+            ));
+        }
+    } else if let Some(entry_name) = &item.command_output_name {
+        if !item.has_command() || has_source {
+            problems.push(format!(
+                "[{base_name}] command_output_name archives the stdout of command or command_argv, so it needs one of them and no source_path/source_dir/source_paths"
+            ));
+        }
+        if entry_name.trim().is_empty() {
+            problems.push(format!("[{base_name}] command_output_name cannot be empty"));
+        }
+        if item.incremental.unwrap_or(false) {
+            problems.push(format!(
+                "[{base_name}] incremental only works for files, not command output"
+            ));
+        }
+    } else if !has_source {
+        problems.push(format!(
+            "[{base_name}] needs source_path, source_dir or source_paths (or a database block)"
+        ));
+    }
+    if item.remote_dir.trim().is_empty() {
+        problems.push(format!("[{base_name}] remote_dir cannot be empty"));
+    }
+    if item.stream_upload.unwrap_or(false) && item.keep_archive.unwrap_or(false) {
+        problems.push(format!(
+            "[{base_name}] stream_upload writes no local archive, so keep_archive cannot be set"
+        ));
+    }
+    if item.skip_if_unchanged.unwrap_or(false) {
+        if item.stream_upload.unwrap_or(false) {
+            problems.push(format!(
+                "[{base_name}] stream_upload uploads while archiving, so skip_if_unchanged has nothing to compare beforehand"
+            ));
+        }
+        if !item.write_checksum.unwrap_or(true) {
+            problems.push(format!(
+                "[{base_name}] skip_if_unchanged compares .sha256 files, so write_checksum cannot be false"
+            ));
+        }
+    }
+    if item.max_part_size_mb == Some(0) {
+        problems.push(format!(
+            "[{base_name}] max_part_size_mb must be at least 1, or left unset to upload archives whole"
+        ));
+    }
+    if item.stream_upload.unwrap_or(false) && item.max_part_size_mb.is_some() {
+        problems.push(format!(
+            "[{base_name}] stream_upload writes no local archive to split, so max_part_size_mb cannot be set"
+        ));
+    }
+    if item.keep_remote == Some(0) {
+        problems.push(format!(
+            "[{base_name}] keep_remote must be at least 1, or left unset to keep everything"
+        ));
+    }
+    if item.keep_local == Some(0) {
+        problems.push(format!(
+            "[{base_name}] keep_local must be at least 1; without keep_archive the archive is removed after the upload anyway"
+        ));
+    }
+    if item.stream_upload.unwrap_or(false) && item.keep_local.is_some() {
+        problems.push(format!(
+            "[{base_name}] stream_upload writes no local archive, so keep_local cannot be set"
+        ));
+    }
+    if let Some(date_format) = &item.date_format
+        && let Err(err) = check_date_format(date_format)
+    {
+        problems.push(format!("[{base_name}] date_format {err}"));
+    }
+    if let Some(template) = &item.archive_filename {
+        check_archive_filename(template, base_name, problems);
+    }
+    let paths = [
+        ("remote_dir", Some(item.remote_dir.as_str())),
+        ("source_path", item.source_path.as_deref()),
+        ("source_dir", item.source_dir.as_deref()),
+        ("command_workdir", item.command_workdir.as_deref()),
+        ("command_output_name", item.command_output_name.as_deref()),
+        ("post_command_workdir", item.post_command_workdir.as_deref()),
+    ]
+    .into_iter()
+    .chain(
+        item.source_paths
+            .iter()
+            .map(|path| ("source_paths", Some(path.as_str()))),
+    );
+    for (field, value) in paths {
+        for token in value.map(unknown_placeholders).unwrap_or_default() {
+            problems.push(format!(
+                "[{base_name}] {field} uses unknown placeholder {{{token}}} (known: {})",
+                PLACEHOLDERS
+                    .iter()
+                    .map(|name| format!("{{{name}}}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+}
+
+/// `{name}` tokens in `value` that are not in `PLACEHOLDERS`. `${VAR}` is
+/// left alone since it is shell syntax.
+pub fn unknown_placeholders(value: &str) -> Vec<&str> {
+    let mut unknown = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = value[offset..].find('{').map(|index| offset + index) {
+        let Some(len) = value[start + 1..].find('}') else {
+            break;
+        };
+        let token = &value[start + 1..start + 1 + len];
+        let is_name =
+            !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if is_name && !value[..start].ends_with('$') && !PLACEHOLDERS.contains(&token) {
+            unknown.push(token);
+        }
+        offset = start + 1;
+    }
+    unknown
+}
+
+/// Rejects levels the archive format does not support before anything is
+/// archived. The `[app]` default only applies to zstd items.
+pub fn check_compression_levels(config: &Config, problems: &mut Vec<String>) {
+    if let Some(level) = config.app.compression_level
+        && let Err(err) = check_level(ArchiveFormat::Zstd, level)
+    {
+        problems.push(format!("[app] {err}"));
+    }
+    if let Some(threads) = config.app.compression_threads
+        && let Err(err) = archive::compression_threads(threads)
+    {
+        problems.push(format!("[app] {err}"));
+    }
+    for item in &config.backups {
+        let base_name = normalize_archive_name(&item.archive_name);
+        if let Some(level) = item.compression_level {
+            if let Err(err) = check_level(item.archive_format, level) {
+                problems.push(format!("[{base_name}] {err}"));
+            }
+            if item.auto_level.unwrap_or(false) {
+                problems.push(format!(
+                    "[{base_name}] compression_level cannot be combined with auto_level"
+                ));
+            }
+        }
+        if item.auto_level.unwrap_or(false) && item.archive_format != ArchiveFormat::Zstd {
+            problems.push(format!(
+                "[{base_name}] auto_level picks zstd levels and cannot be used with archive_format = \"{}\"",
+                item.archive_format.as_str()
+            ));
+        }
+    }
+}
+
+/// Loads every configured age recipient once, so a typo in a key fails the
+/// run before any archive is built.
+pub fn check_encryption(config: &Config, problems: &mut Vec<String>) {
+    if let Some(encryption) = &config.app.encryption
+        && let Err(err) = encryption.load_recipient()
+    {
+        problems.push(format!("[app] {err:#}"));
+    }
+    for item in &config.backups {
+        if let Some(encryption) = &item.encryption
+            && let Err(err) = encryption.load_recipient()
+        {
+            let base_name = normalize_archive_name(&item.archive_name);
+            problems.push(format!("[{base_name}] {err:#}"));
+        }
+    }
+}
+
+/// `[app.sign]` writes the same `.sig` as `signing_key_file`, and needs
+/// the file it signs on disk.
+pub fn check_sign(config: &Config, problems: &mut Vec<String>) {
+    let Some(sign) = &config.app.sign else {
+        return;
+    };
+    if let Err(err) = sign.check() {
+        problems.push(format!("[app] {err:#}"));
+    }
+    if config.app.signing_key_file.is_some() {
+        problems.push(
+            "[app] sign and signing_key_file both write <archive>.sig; set only one".to_string(),
+        );
+    }
+    for item in &config.backups {
+        let base_name = normalize_archive_name(&item.archive_name);
+        match sign.target {
+            SignTarget::Archive if item.stream_upload.unwrap_or(false) => problems.push(format!(
+                "[{base_name}] stream_upload writes no local archive for sign to sign; set target = \"checksum\" in [app.sign]"
+            )),
+            SignTarget::Checksum if !item.write_checksum.unwrap_or(true) => {
+                problems.push(format!(
+                    "[{base_name}] sign signs the .sha256 file (target = \"checksum\"), so write_checksum cannot be false"
+                ))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// `dedup` items go to a single uploader, into a fixed `remote_dir` their
+/// chunks are shared in, and leave out what would defeat the chunking or
+/// orphan chunks.
+pub fn check_dedup(config: &Config, enabled: &[&str], problems: &mut Vec<String>) {
+    for item in config
+        .backups
+        .iter()
+        .filter(|item| item.dedup.unwrap_or(false))
+    {
+        let base_name = normalize_archive_name(&item.archive_name);
+        let targets = enabled
+            .iter()
+            .filter(|name| item.uses_uploader(name))
+            .count();
+        if targets != 1 {
+            problems.push(format!(
+                "[{base_name}] dedup works with a single uploader; set uploaders to one of: {}",
+                enabled.join(", ")
+            ));
+        }
+        if ["{date}", "{time}"]
+            .iter()
+            .any(|placeholder| item.remote_dir.contains(placeholder))
+        {
+            problems.push(format!(
+                "[{base_name}] dedup shares chunks within one remote_dir, so it cannot contain {{date}} or {{time}} nor set remote_date_subdir"
+            ));
+        }
+        let conflicts = [
+            ("stream_upload", item.stream_upload.unwrap_or(false)),
+            ("skip_if_unchanged", item.skip_if_unchanged.unwrap_or(false)),
+            ("max_part_size_mb", item.max_part_size_mb.is_some()),
+            ("keep_remote", item.keep_remote.is_some()),
+            (
+                "archive_format = \"gzip\"",
+                item.archive_format == ArchiveFormat::Gzip,
+            ),
+            (
+                "encryption",
+                item.encryption.is_some() || config.app.encryption.is_some(),
+            ),
+        ];
+        for (field, _) in conflicts.iter().filter(|(_, set)| *set) {
+            problems.push(format!(
+                "[{base_name}] dedup cannot be combined with {field}"
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_config_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let base = write(
+            "base.toml",
+            "[app]\nwork_dir = \"/tmp\"\ns3_secret_key = \"inline\"\n[app.smtp]\nhost = \"mail\"\nport = 25\n\n\
+             [[backups]]\narchive_name = \"etc\"\n",
+        );
+        let host = write(
+            "host.toml",
+            "[app]\nwork_dir = \"/srv/tmp\"\ns3_secret_key_file = \"/run/secrets/s3\"\n[app.smtp]\nport = 587\n\n\
+             [[backups]]\narchive_name = \"db\"\n",
+        );
+        let table = read_config_table(&[base.clone(), host.clone()]).unwrap();
+        let app = &table["app"];
+        assert_eq!(app["work_dir"].as_str(), Some("/srv/tmp"));
+        assert!(app.get("s3_secret_key").is_none());
+        assert_eq!(app["s3_secret_key_file"].as_str(), Some("/run/secrets/s3"));
+        assert_eq!(app["smtp"]["host"].as_str(), Some("mail"));
+        assert_eq!(app["smtp"]["port"].as_integer(), Some(587));
+        let names: Vec<_> = table["backups"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["archive_name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["etc", "db"]);
+
+        let again = write("again.toml", "[[backups]]\narchive_name = \"etc\"\n");
+        let err = read_config_table(&[base.clone(), again]).unwrap_err();
+        assert!(format!("{err:#}").contains("Item etc is defined in both"));
+        let flat = write("flat.toml", "[app]\nsmtp = \"mail\"\n");
+        let err = read_config_table(&[base, flat]).unwrap_err();
+        assert!(format!("{err:#}").contains("app.smtp is a string in"));
+        assert!(read_config_table(&["-".to_string(), "-".to_string()]).is_err());
+        assert_eq!(
+            config_anchor(&[host.clone(), "-".to_string()]),
+            PathBuf::from(&host)
+        );
+        assert_eq!(config_anchor(&["-".to_string()]), PathBuf::from("stdin"));
+    }
+
+    #[test]
+    fn test_check_command_output_name() {
+        let config: Config = toml::from_str(
+            r#"
+            [app]
+            [[backups]]
+            source_dir = "/srv/db"
+            remote_dir = "/backups/db"
+            archive_name = "db"
+            command_output_name = "db.sql"
+            incremental = true
+            "#,
+        )
+        .unwrap();
+        let mut problems = Vec::new();
+        check_item(&config.backups[0], &mut problems);
+        assert_eq!(
+            problems,
+            [
+                "[db] command_output_name archives the stdout of command or command_argv, so it needs one of them and no source_path/source_dir/source_paths",
+                "[db] incremental only works for files, not command output",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_secret_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("webdav_password");
+        fs::write(&secret, "s3cr3t$x \n").unwrap();
+        let empty = dir.path().join("empty");
+        fs::write(&empty, "\n").unwrap();
+        let mut config: toml::Table = toml::from_str(&format!(
+            "[app]\nwebdav_password_file = {:?}\n[app.smtp]\npassword_file = {:?}\n",
+            secret.display().to_string(),
+            empty.display().to_string()
+        ))
+        .unwrap();
+        let mut problems = Vec::new();
+        read_secret_files(&mut config, &mut problems);
+        assert_eq!(config["app"]["webdav_password"].as_str(), Some("s3cr3t$x"));
+        assert!(config["app"].get("webdav_password_file").is_none());
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("[app.smtp] password_file: "));
+
+        let mut config: toml::Table = toml::from_str(
+            "[app]\ns3_secret_key = \"inline\"\ns3_secret_key_file = \"/missing\"\n",
+        )
+        .unwrap();
+        let mut problems = Vec::new();
+        read_secret_files(&mut config, &mut problems);
+        assert_eq!(
+            problems,
+            ["[app] set either s3_secret_key or s3_secret_key_file, not both"]
+        );
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/backup".to_string()),
+            "HOSTNAME" => Some("nas".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env("$HOME/data/{date}", lookup).unwrap(),
+            "/home/backup/data/{date}"
+        );
+        assert_eq!(
+            expand_env("/backups/${HOSTNAME}-{archive_name}", lookup).unwrap(),
+            "/backups/nas-{archive_name}"
+        );
+        assert_eq!(
+            expand_env("cost $$5, $1 and ${}", lookup).unwrap(),
+            "cost $5, $1 and ${}"
+        );
+        assert_eq!(expand_env("$HOME_DIR", lookup).unwrap_err(), "HOME_DIR");
+
+        let mut value: toml::Value = toml::Value::Table(
+            toml::from_str(
+                r#"
+                [app]
+                post_run_command = "echo $UNSET_IN_SHELL"
+                [[backups]]
+                source_path = "${BACKUP_TEST_UNSET_VAR}/data"
+                "#,
+            )
+            .unwrap(),
+        );
+        let mut problems = Vec::new();
+        expand_env_vars(&mut value, "config", &mut problems);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(
+            "config.backups[0].source_path: environment variable BACKUP_TEST_UNSET_VAR is not set"
+        ));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [app]
+            [[backups]]
+            remote_dir = " "
+            archive_name = "web"
+            keep_remote = 0
+            [[backups]]
+            source_dir = "/srv/{host}/data"
+            remote_dir = "/backups/{archive_name}/{date}"
+            archive_name = "data"
+            compression_level = 40
+            [[backups]]
+            source_dir = "/srv/db"
+            remote_dir = "/backups/db"
+            archive_name = "db"
+            database = { engine = "postgres", database = "app" }
+            "#,
+        )
+        .unwrap();
+        let err = validate_config(&config).unwrap_err().to_string();
+        let problems: Vec<&str> = err.lines().skip(1).collect();
+        assert_eq!(problems.len(), 7, "{err}");
+        assert!(problems[0].starts_with("[app] no uploader is enabled"));
+        assert_eq!(
+            problems[1],
+            "[web] needs source_path, source_dir or source_paths (or a database block)"
+        );
+        assert!(problems[4].starts_with("[data] source_dir uses unknown placeholder {host}"));
+        assert!(problems[6].starts_with("[data] "), "{err}");
+
+        assert_eq!(unknown_placeholders("/srv/{date}/${HOME}/{x}"), ["x"]);
+        assert!(unknown_placeholders("awk '{print $1}' {").is_empty());
+    }
+
+    #[test]
+    fn test_item_uploaders() {
+        let config: Config = toml::from_str(
+            r#"
+            [app]
+            s3_enabled = true
+            webdav_enabled = true
+            [[backups]]
+            source_dir = "/srv/db"
+            remote_dir = "/backups/db"
+            archive_name = "db"
+            uploaders = ["s3", "Baidu"]
+            "#,
+        )
+        .unwrap();
+        let err = validate_config(&config).unwrap_err().to_string();
+        assert!(
+            err.ends_with("[db] uploaders names Baidu, which is not enabled (enabled: S3, WebDAV)"),
+            "{err}"
+        );
+        let item = &config.backups[0];
+        assert!(item.uses_uploader("S3"));
+        assert!(!item.uses_uploader("WebDAV"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Built-in database dumps configured through `[backups.database]`.
+
+use serde::Deserialize;
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseEngine {
+    #[serde(alias = "postgresql")]
+    Postgres,
+    #[serde(alias = "mariadb")]
+    Mysql,
+}
+
+impl DatabaseEngine {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DatabaseEngine::Postgres => "postgres",
+            DatabaseEngine::Mysql => "mysql",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DatabaseDump {
+    pub engine: DatabaseEngine,
+    pub database: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Dump tool to run instead of `pg_dump` / `mysqldump`.
+    pub program: Option<String>,
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl DatabaseDump {
+    /// Name of the dump entry inside the archive.
+    pub fn entry_name(&self, archive_name: &str) -> String {
+        format!("{archive_name}.sql")
+    }
+
+    /// Builds the dump command writing SQL to stdout. The password is passed
+    /// through the environment (`PGPASSWORD` / `MYSQL_PWD`) so it never shows
+    /// up in the process list or in logs.
+    pub fn command(&self) -> Command {
+        match self.engine {
+            DatabaseEngine::Postgres => {
+                let mut cmd = Command::new(self.program.as_deref().unwrap_or("pg_dump"));
+                cmd.arg("--no-password");
+                if let Some(host) = &self.host {
+                    cmd.arg("--host").arg(host);
+                }
+                if let Some(port) = self.port {
+                    cmd.arg("--port").arg(port.to_string());
+                }
+                if let Some(username) = &self.username {
+                    cmd.arg("--username").arg(username);
+                }
+                cmd.args(&self.extra_args);
+                cmd.arg("--dbname").arg(&self.database);
+                if let Some(password) = &self.password {
+                    cmd.env("PGPASSWORD", password);
+                }
+                cmd
+            }
+            DatabaseEngine::Mysql => {
+                let mut cmd = Command::new(self.program.as_deref().unwrap_or("mysqldump"));
+                if let Some(host) = &self.host {
+                    cmd.arg(format!("--host={host}"));
+                }
+                if let Some(port) = self.port {
+                    cmd.arg(format!("--port={port}"));
+                }
+                if let Some(username) = &self.username {
+                    cmd.arg(format!("--user={username}"));
+                }
+                cmd.args(&self.extra_args);
+                cmd.arg(&self.database);
+                if let Some(password) = &self.password {
+                    cmd.env("MYSQL_PWD", password);
+                }
+                cmd
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn dump(engine: DatabaseEngine) -> DatabaseDump {
+        DatabaseDump {
+            engine,
+            database: "appdb".to_string(),
+            host: Some("db.local".to_string()),
+            port: Some(5433),
+            username: Some("backup".to_string()),
+            password: Some("s3cret".to_string()),
+            program: None,
+            extra_args: vec!["--no-owner".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_password_is_passed_via_env_only() {
+        for (engine, env_key) in [
+            (DatabaseEngine::Postgres, "PGPASSWORD"),
+            (DatabaseEngine::Mysql, "MYSQL_PWD"),
+        ] {
+            let cmd = dump(engine).command();
+            assert!(
+                !cmd.get_args()
+                    .any(|arg| arg.to_string_lossy().contains("s3cret"))
+            );
+            assert!(
+                cmd.get_envs()
+                    .any(|(key, value)| key == env_key && value == Some(OsStr::new("s3cret")))
+            );
+        }
+    }
+
+    #[test]
+    fn test_postgres_command_args() {
+        let cmd = dump(DatabaseEngine::Postgres).command();
+        assert_eq!(cmd.get_program(), "pg_dump");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "--no-password",
+                "--host",
+                "db.local",
+                "--port",
+                "5433",
+                "--username",
+                "backup",
+                "--no-owner",
+                "--dbname",
+                "appdb"
+            ]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+//! Deduplicated storage of archives as content-defined chunks.
+//!
+//! The plain tar stream is cut wherever a rolling hash over its content hits
+//! a fixed pattern, so an edit only changes the chunks around it and the
+//! rest of a snapshot repeats the chunks of the previous one. Each chunk is
+//! stored once, zstd-compressed, as `chunks/<sha256>.zst` below the item's
+//! `remote_dir`, and `x.tar` itself is replaced by `x.tar.recipe`:
+//!
+//! ```text
+//! backup-to-cloud recipe 1
+//! <sha256 of chunk 1> <uncompressed size>
+//! <sha256 of chunk 2> <uncompressed size>
+//! ```
+//!
+//! Restoring concatenates the decompressed chunks in recipe order. Which
+//! chunks an uploader already has is tracked in a local index next to the
+//! config, so a chunk is uploaded the first time it is seen only.
+
+use crate::signing::to_hex;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const RECIPE_EXTENSION: &str = "recipe";
+/// Subdirectory of `remote_dir` the chunks are stored in.
+pub const CHUNK_DIR: &str = "chunks";
+const CHUNK_EXTENSION: &str = "zst";
+const RECIPE_HEADER: &str = "backup-to-cloud recipe 1";
+
+/// No cut is made before this many bytes, so chunks do not get tiny.
+const MIN_CHUNK_SIZE: usize = 256 << 10;
+/// A chunk is cut here even if the content gives no cut point.
+const MAX_CHUNK_SIZE: usize = 4 << 20;
+/// A cut is made where the low 20 bits of the rolling hash are zero, about
+/// once per MiB past the minimum.
+const CUT_MASK: u64 = (1 << 20) - 1;
+
+/// Random values the rolling hash adds per byte value, from a fixed seed
+/// so that cut points never change between versions.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6261_636b_7570_3263;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+}
+
+/// One line of a recipe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// Lowercase hex SHA-256 of the uncompressed chunk.
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Chunk {
+    /// File name of the stored chunk in [`CHUNK_DIR`].
+    pub fn file_name(&self) -> String {
+        format!("{}.{CHUNK_EXTENSION}", self.sha256)
+    }
+}
+
+pub fn recipe_name(archive_name: &str) -> String {
+    format!("{archive_name}.{RECIPE_EXTENSION}")
+}
+
+/// The result of [`chunk_file`].
+pub struct ChunkedArchive {
+    pub recipe: PathBuf,
+    /// Every chunk of the archive, in order.
+    pub chunks: Vec<Chunk>,
+    /// Compressed chunks written because `is_known` did not have them, each
+    /// once.
+    pub new_chunks: Vec<PathBuf>,
+}
+
+/// Cuts the archive at `path` into chunks and writes its recipe next to it.
+/// Chunks `is_known` rejects are compressed with zstd at `level` into
+/// `chunk_dir`. Nothing is left behind if it fails.
+pub fn chunk_file(
+    path: &Path,
+    chunk_dir: &Path,
+    level: i32,
+    is_known: impl Fn(&str) -> bool,
+) -> Result<ChunkedArchive> {
+    let mut written = Vec::new();
+    let result = write_chunks(path, chunk_dir, level, is_known, &mut written);
+    if result.is_err() {
+        for file in &written {
+            let _ = fs::remove_file(file);
+        }
+    }
+    result
+}
+
+fn write_chunks(
+    path: &Path,
+    chunk_dir: &Path,
+    level: i32,
+    is_known: impl Fn(&str) -> bool,
+    written: &mut Vec<PathBuf>,
+) -> Result<ChunkedArchive> {
+    let archive_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Archive file name is not valid UTF-8")?;
+    fs::create_dir_all(chunk_dir)
+        .with_context(|| format!("Failed to create directory: {}", chunk_dir.display()))?;
+    let input =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut chunks = Vec::new();
+    let mut new_chunks = Vec::new();
+    for_each_chunk(BufReader::new(input), |data| {
+        let chunk = Chunk {
+            sha256: to_hex(&Sha256::digest(data)),
+            size: data.len() as u64,
+        };
+        if !is_known(&chunk.sha256) && seen.insert(chunk.sha256.clone()) {
+            let chunk_path = chunk_dir.join(chunk.file_name());
+            written.push(chunk_path.clone());
+            let compressed = zstd::encode_all(data, level).context("Failed to compress chunk")?;
+            fs::write(&chunk_path, compressed)
+                .with_context(|| format!("Failed to write chunk: {}", chunk_path.display()))?;
+            new_chunks.push(chunk_path);
+        }
+        chunks.push(chunk);
+        Ok(())
+    })
+    .with_context(|| format!("Failed to read archive: {}", path.display()))?;
+
+    let mut recipe = format!("{RECIPE_HEADER}\n");
+    for chunk in &chunks {
+        recipe.push_str(&format!("{} {}\n", chunk.sha256, chunk.size));
+    }
+    let recipe_path = path.with_file_name(recipe_name(archive_name));
+    written.push(recipe_path.clone());
+    fs::write(&recipe_path, recipe)
+        .with_context(|| format!("Failed to write recipe: {}", recipe_path.display()))?;
+    Ok(ChunkedArchive {
+        recipe: recipe_path,
+        chunks,
+        new_chunks,
+    })
+}
+
+/// Calls `chunk` with each content-defined chunk of `input`, in order.
+fn for_each_chunk(mut input: impl Read, mut chunk: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+    let mut buf = vec![0u8; 64 << 10];
+    let mut current: Vec<u8> = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    loop {
+        let read = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        for &byte in &buf[..read] {
+            current.push(byte);
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if (current.len() >= MIN_CHUNK_SIZE && hash & CUT_MASK == 0)
+                || current.len() >= MAX_CHUNK_SIZE
+            {
+                chunk(&current)?;
+                current.clear();
+                hash = 0;
+            }
+        }
+    }
+    if !current.is_empty() {
+        chunk(&current)?;
+    }
+    Ok(())
+}
+
+/// Reads a recipe written by [`chunk_file`].
+pub fn read_recipe(path: &Path) -> Result<Vec<Chunk>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read recipe: {}", path.display()))?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    if lines.next() != Some(RECIPE_HEADER) {
+        anyhow::bail!(
+            "{} is not a recipe this version can read (expected {:?} on the first line)",
+            path.display(),
+            RECIPE_HEADER
+        );
+    }
+    lines
+        .map(|line| {
+            let (sha256, size) = line.split_once(' ').with_context(|| {
+                format!("Malformed recipe line in {}: {}", path.display(), line)
+            })?;
+            let size = size.parse().with_context(|| {
+                format!("Malformed recipe line in {}: {}", path.display(), line)
+            })?;
+            if sha256.len() != 64 || !sha256.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                anyhow::bail!("Malformed recipe line in {}: {}", path.display(), line);
+            }
+            Ok(Chunk {
+                sha256: sha256.to_ascii_lowercase(),
+                size,
+            })
+        })
+        .collect()
+}
+
+/// Reassembles `chunks` into `output`. `fetch(name, path)` has to put the
+/// stored chunk `name` at `path`; every chunk is checked against the recipe
+/// and removed once appended. A chunk repeated back to back is fetched once.
+pub fn join_chunks(
+    chunks: &[Chunk],
+    output: &Path,
+    mut fetch: impl FnMut(&str, &Path) -> Result<()>,
+) -> Result<()> {
+    let dir = output.parent().unwrap_or(Path::new(""));
+    let mut joined = File::create(output)
+        .with_context(|| format!("Failed to create file: {}", output.display()))?;
+    let mut previous: Option<(&str, Vec<u8>)> = None;
+    for chunk in chunks {
+        if let Some((sha256, data)) = &previous
+            && *sha256 == chunk.sha256
+        {
+            joined
+                .write_all(data)
+                .with_context(|| format!("Failed to write file: {}", output.display()))?;
+            continue;
+        }
+        let chunk_path = dir.join(chunk.file_name());
+        fetch(&chunk.file_name(), &chunk_path)?;
+        let data = read_chunk(&chunk_path, chunk);
+        let _ = fs::remove_file(&chunk_path);
+        let data = data?;
+        joined
+            .write_all(&data)
+            .with_context(|| format!("Failed to write file: {}", output.display()))?;
+        previous = Some((&chunk.sha256, data));
+    }
+    joined
+        .sync_all()
+        .with_context(|| format!("Failed to flush file: {}", output.display()))
+}
+
+fn read_chunk(path: &Path, chunk: &Chunk) -> Result<Vec<u8>> {
+    let compressed =
+        fs::read(path).with_context(|| format!("Failed to read chunk: {}", path.display()))?;
+    let data = zstd::decode_all(compressed.as_slice())
+        .with_context(|| format!("Corrupted chunk: {}", chunk.file_name()))?;
+    let sha256 = to_hex(&Sha256::digest(&data));
+    if sha256 != chunk.sha256 || data.len() as u64 != chunk.size {
+        anyhow::bail!(
+            "Chunk {} holds {} bytes with SHA-256 {}, the recipe lists {} bytes",
+            chunk.file_name(),
+            data.len(),
+            sha256,
+            chunk.size
+        );
+    }
+    Ok(data)
+}
+
+/// The chunks each uploader is known to have, per chunk directory.
+pub struct ChunkIndex {
+    path: PathBuf,
+    stored: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl ChunkIndex {
+    /// `backup.toml` -> `backup.chunks.json` in the same directory.
+    pub fn path_for_config(config_path: &Path) -> PathBuf {
+        let mut name = config_path
+            .file_stem()
+            .map(|stem| stem.to_os_string())
+            .unwrap_or_else(|| "backup".into());
+        name.push(".chunks.json");
+        config_path.with_file_name(name)
+    }
+
+    /// Loads the index, treating a missing file as empty.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let stored = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse chunk index: {}", path.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read chunk index: {}", path.display()));
+            }
+        };
+        Ok(Self { path, stored })
+    }
+
+    fn key(uploader: &str, chunk_dir: &str) -> String {
+        format!("{uploader}:{chunk_dir}")
+    }
+
+    pub fn contains(&self, uploader: &str, chunk_dir: &str, sha256: &str) -> bool {
+        self.stored
+            .get(&Self::key(uploader, chunk_dir))
+            .is_some_and(|stored| stored.contains(sha256))
+    }
+
+    /// Records `chunks` as uploaded and writes the file right away.
+    pub fn record(&mut self, uploader: &str, chunk_dir: &str, chunks: &[Chunk]) -> Result<()> {
+        self.stored
+            .entry(Self::key(uploader, chunk_dir))
+            .or_default()
+            .extend(chunks.iter().map(|chunk| chunk.sha256.clone()));
+        let content =
+            serde_json::to_string(&self.stored).context("Failed to serialize chunk index")?;
+        let mut tmp_name = self.path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        fs::write(&tmp_path, content)
+            .with_context(|| format!("Failed to write chunk index: {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace chunk index: {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic bytes that do not compress or repeat.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_and_join() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunk_dir = dir.path().join("chunks");
+        let archive = dir.path().join("data-20260211.tar");
+        let mut content = noise(6 << 20, 1);
+        fs::write(&archive, &content).unwrap();
+
+        let first = chunk_file(&archive, &chunk_dir, 3, |_| false).unwrap();
+        assert!(first.chunks.len() >= 2);
+        assert_eq!(first.new_chunks.len(), first.chunks.len());
+        assert!(
+            first
+                .chunks
+                .iter()
+                .all(|chunk| chunk.size <= MAX_CHUNK_SIZE as u64)
+        );
+        assert_eq!(read_recipe(&first.recipe).unwrap(), first.chunks);
+
+        // A change in the middle leaves the chunks before and after it alone.
+        content[3 << 20] ^= 0xff;
+        fs::write(&archive, &content).unwrap();
+        let known: HashSet<String> = first
+            .chunks
+            .iter()
+            .map(|chunk| chunk.sha256.clone())
+            .collect();
+        let second = chunk_file(&archive, &chunk_dir, 3, |sha256| known.contains(sha256)).unwrap();
+        assert!(!second.new_chunks.is_empty());
+        assert!(second.new_chunks.len() < second.chunks.len());
+
+        let joined = dir.path().join("joined.tar");
+        let fetch = |name: &str, path: &Path| {
+            fs::copy(chunk_dir.join(name), path)?;
+            Ok(())
+        };
+        join_chunks(&second.chunks, &joined, fetch).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), content);
+
+        let tampered = &second.chunks[0];
+        fs::write(
+            chunk_dir.join(tampered.file_name()),
+            zstd::encode_all(&b"tampered"[..], 3).unwrap(),
+        )
+        .unwrap();
+        assert!(join_chunks(&second.chunks, &joined, fetch).is_err());
+    }
+
+    #[test]
+    fn test_chunk_index() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            ChunkIndex::path_for_config(Path::new("/etc/backup/nightly.toml")),
+            Path::new("/etc/backup/nightly.chunks.json")
+        );
+        let path = dir.path().join("backup.chunks.json");
+        let mut index = ChunkIndex::load(path.clone()).unwrap();
+        let chunk = Chunk {
+            sha256: "ab".repeat(32),
+            size: 1,
+        };
+        index
+            .record("S3", "backups/chunks", std::slice::from_ref(&chunk))
+            .unwrap();
+        let index = ChunkIndex::load(path).unwrap();
+        assert!(index.contains("S3", "backups/chunks", &chunk.sha256));
+        assert!(!index.contains("SFTP", "backups/chunks", &chunk.sha256));
+    }
+}
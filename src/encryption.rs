@@ -0,0 +1,443 @@
+//! Client-side encryption of archives with age.
+//!
+//! The compressed tar stream is encrypted to an X25519 recipient before it
+//! reaches the disk, so neither the local archive nor the uploaded copy is
+//! ever plaintext. Only the matching identity can decrypt it.
+//!
+//! An archive that will be split into parts (`max_part_size_mb`) is not one
+//! age stream cut at byte boundaries but a sequence of age streams, each
+//! encrypting the next [`segment_size`] bytes of the compressed stream, so
+//! that each part holds exactly one stream and decrypts on its own:
+//!
+//! ```text
+//! x.tar.zst.age.part001  = age(compressed[0 .. S])
+//! x.tar.zst.age.part002  = age(compressed[S .. 2S])
+//! ...
+//! ```
+//!
+//! `for p in x.tar.zst.age.part*; do age -d -i key.txt "$p"; done | tar --zstd -xf -`
+//! restores it, and a lost or damaged part only loses the files in it.
+//! Every stream is an ordinary age file with its own header: age draws a new
+//! random file key for each one and derives its payload key from that key
+//! and a fresh random 16-byte nonce, and STREAM numbers the 64 KiB chunks
+//! within a stream from zero. No key and nonce pair is therefore used
+//! twice, and no nonce has to be carried from one part to the next.
+//!
+//! `S` is a multiple of the 64 KiB chunk size, so a stream that is not the
+//! last ends exactly after a whole number of chunks. That is where
+//! [`open_streams`] looks for the next header when it reads the streams back
+//! from the concatenated archive.
+
+use age::stream::StreamWriter;
+use age::x25519::{Identity, Recipient};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Extension appended to the archive name once it is encrypted.
+pub const EXTENSION: &str = "age";
+
+/// First line of every age header.
+const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+/// Start of the header's last line, the MAC.
+const MAC_PREFIX: &[u8] = b"--- ";
+const NONCE_SIZE: u64 = 16;
+/// Plaintext bytes per STREAM chunk, and what each chunk adds to them.
+const CHUNK_SIZE: u64 = 64 << 10;
+const TAG_SIZE: u64 = 16;
+/// Room left in each part for the header, which takes about 200 bytes for
+/// one X25519 recipient.
+const HEADER_ALLOWANCE: u64 = 4 << 10;
+/// Headers longer than this are not taken for age headers.
+const MAX_HEADER_SIZE: usize = 64 << 10;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EncryptionConfig {
+    /// An `age1...` public key, or the path to a file containing one (as
+    /// written by `age-keygen -y key.txt > key.pub`).
+    pub recipient: String,
+}
+
+impl EncryptionConfig {
+    pub fn load_recipient(&self) -> Result<Recipient> {
+        let value = self.recipient.trim();
+        if value.starts_with("age1") {
+            return parse_recipient(value, "recipient");
+        }
+        let path = Path::new(value);
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read age recipient file: {}", path.display()))?;
+        let key = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .with_context(|| format!("No age recipient found in {}", path.display()))?;
+        parse_recipient(key, &path.display().to_string())
+    }
+}
+
+/// Reads the X25519 identity from an `age-keygen` key file, for restoring
+/// encrypted archives.
+pub fn load_identity(path: &Path) -> Result<Identity> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read age identity file: {}", path.display()))?;
+    let key = content
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .with_context(|| format!("No AGE-SECRET-KEY found in {}", path.display()))?;
+    Identity::from_str(key)
+        .map_err(|err| anyhow::anyhow!("Invalid age identity in {}: {}", path.display(), err))
+}
+
+/// Plaintext bytes per age stream for parts of at most `max_part_size`: the
+/// most whole chunks that fit once the header and the chunk tags are added,
+/// and at least one chunk.
+pub fn segment_size(max_part_size: u64) -> u64 {
+    let chunks =
+        max_part_size.saturating_sub(HEADER_ALLOWANCE + NONCE_SIZE) / (CHUNK_SIZE + TAG_SIZE);
+    chunks.max(1) * CHUNK_SIZE
+}
+
+/// Encrypts what is written to it to `recipient` as a sequence of age
+/// streams of `segment_size` plaintext bytes each, the last one shorter.
+/// A new stream is only started once there is more to write, so the
+/// output never ends with an empty one.
+pub struct SegmentedWriter<W: Write> {
+    recipient: Recipient,
+    segment_size: u64,
+    /// Plaintext bytes in the current stream.
+    written: u64,
+    /// `None` only while one stream is finished and the next started.
+    stream: Option<StreamWriter<W>>,
+}
+
+impl<W: Write> SegmentedWriter<W> {
+    pub fn new(output: W, recipient: Recipient, segment_size: u64) -> Result<Self> {
+        let stream = start_stream(output, &recipient)?;
+        Ok(Self {
+            recipient,
+            segment_size,
+            written: 0,
+            stream: Some(stream),
+        })
+    }
+
+    /// Finishes the last stream and returns the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.current()?.finish()
+    }
+
+    fn current(&mut self) -> io::Result<StreamWriter<W>> {
+        self.stream
+            .take()
+            .ok_or_else(|| io::Error::other("age stream was not restarted after an error"))
+    }
+}
+
+impl<W: Write> Write for SegmentedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.segment_size {
+            let output = self.current()?.finish()?;
+            self.stream = Some(start_stream(output, &self.recipient).map_err(io::Error::other)?);
+            self.written = 0;
+        }
+        let room = (self.segment_size - self.written).min(buf.len() as u64) as usize;
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| io::Error::other("age stream was not restarted after an error"))?;
+        let written = stream.write(&buf[..room])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn start_stream<W: Write>(output: W, recipient: &Recipient) -> Result<StreamWriter<W>> {
+    age::Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))
+        .context("Failed to initialize age encryption")?
+        .wrap_output(output)
+        .context("Failed to initialize age encryption")
+}
+
+/// Where each age stream in `path` starts, in order, the first at `0`. A
+/// file written as one stream has just that one.
+pub fn stream_offsets(path: &Path) -> Result<Vec<u64>> {
+    let mut file = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?,
+    );
+    let len = file
+        .get_ref()
+        .metadata()
+        .with_context(|| format!("Failed to read file size: {}", path.display()))?
+        .len();
+    let mut offsets = vec![0];
+    let mut start = 0;
+    'streams: loop {
+        let payload = start
+            + header_len(&mut file, start).with_context(|| {
+                format!(
+                    "Not an age-encrypted file at offset {start}: {}",
+                    path.display()
+                )
+            })?
+            + NONCE_SIZE;
+        let mut next = payload + CHUNK_SIZE + TAG_SIZE;
+        while next + (MAGIC.len() as u64) < len {
+            if starts_with_magic(&mut file, next)
+                .with_context(|| format!("Failed to read file: {}", path.display()))?
+            {
+                offsets.push(next);
+                start = next;
+                continue 'streams;
+            }
+            next += CHUNK_SIZE + TAG_SIZE;
+        }
+        return Ok(offsets);
+    }
+}
+
+/// Length of the age header at `offset`, up to and including the MAC line.
+fn header_len(file: &mut BufReader<File>, offset: u64) -> Result<u64> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header_len = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = file
+            .by_ref()
+            .take((MAX_HEADER_SIZE - header_len) as u64)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 || line.last() != Some(&b'\n') {
+            anyhow::bail!("truncated or oversized age header");
+        }
+        if header_len == 0 && line != MAGIC {
+            anyhow::bail!("no age header");
+        }
+        header_len += read;
+        if line.starts_with(MAC_PREFIX) {
+            return Ok(header_len as u64);
+        }
+    }
+}
+
+fn starts_with_magic(file: &mut BufReader<File>, offset: u64) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut start = [0u8; MAGIC.len()];
+    match file.read_exact(&mut start) {
+        Ok(()) => Ok(start == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Decrypts every age stream in `path` with `identity`, one after the other,
+/// as one plaintext. Each stream is only opened once the one before it has
+/// been read to its end.
+pub fn open_streams(path: &Path, identity: &Identity) -> Result<Box<dyn Read>> {
+    let offsets = stream_offsets(path)?;
+    let len = fs::metadata(path)
+        .with_context(|| format!("Failed to read file size: {}", path.display()))?
+        .len();
+    let ends = offsets.iter().skip(1).copied().chain(std::iter::once(len));
+    let mut streams = StreamsReader {
+        path: path.to_path_buf(),
+        identity: identity.clone(),
+        pending: offsets.iter().copied().zip(ends).collect(),
+        current: Box::new(io::empty()),
+    };
+    // The first stream is opened right away, so a wrong identity fails here
+    // rather than at the first read.
+    streams.current = streams
+        .open_next()?
+        .unwrap_or_else(|| Box::new(io::empty()));
+    Ok(Box::new(streams))
+}
+
+struct StreamsReader {
+    path: PathBuf,
+    identity: Identity,
+    /// Start and end offset of each stream not opened yet.
+    pending: VecDeque<(u64, u64)>,
+    current: Box<dyn Read>,
+}
+
+impl StreamsReader {
+    fn open_next(&mut self) -> Result<Option<Box<dyn Read>>> {
+        let Some((start, end)) = self.pending.pop_front() else {
+            return Ok(None);
+        };
+        let context = || {
+            format!(
+                "Failed to decrypt {} at offset {start}",
+                self.path.display()
+            )
+        };
+        let mut file = File::open(&self.path).with_context(context)?;
+        file.seek(SeekFrom::Start(start)).with_context(context)?;
+        let decryptor =
+            age::Decryptor::new(BufReader::new(file.take(end - start))).with_context(context)?;
+        let reader = decryptor
+            .decrypt(std::iter::once(&self.identity as &dyn age::Identity))
+            .with_context(context)?;
+        Ok(Some(Box::new(reader)))
+    }
+}
+
+impl Read for StreamsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.open_next().map_err(io::Error::other)? {
+                Some(next) => self.current = next,
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+fn parse_recipient(key: &str, source: &str) -> Result<Recipient> {
+    Recipient::from_str(key).map_err(|err| {
+        anyhow::anyhow!(
+            "Invalid age recipient in {}: {}. Expected an X25519 public key (age1...), \
+             e.g. from `age-keygen -y key.txt`; archives encrypted to it are restored with \
+             `age -d -i key.txt <archive>.tar.zst.age | tar --zstd -xf -`",
+            source,
+            err
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    #[test]
+    fn test_load_recipient() {
+        let identity = age::x25519::Identity::generate();
+        let public = identity.to_public().to_string();
+        let inline = EncryptionConfig {
+            recipient: public.clone(),
+        };
+        assert_eq!(inline.load_recipient().unwrap().to_string(), public);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.pub");
+        fs::write(&path, format!("# created: today\n{public}\n")).unwrap();
+        let from_file = EncryptionConfig {
+            recipient: path.display().to_string(),
+        };
+        assert_eq!(from_file.load_recipient().unwrap().to_string(), public);
+
+        let key_path = dir.path().join("backup-key.txt");
+        fs::write(
+            &key_path,
+            format!(
+                "# public key: {public}\n{}\n",
+                identity.to_string().expose_secret()
+            ),
+        )
+        .unwrap();
+        let loaded = load_identity(&key_path).unwrap();
+        assert_eq!(loaded.to_public().to_string(), public);
+        assert!(load_identity(&path).is_err());
+
+        let secret = EncryptionConfig {
+            recipient: identity.to_string().expose_secret().to_string(),
+        };
+        assert!(secret.load_recipient().is_err());
+        let invalid = EncryptionConfig {
+            recipient: "age1notakey".to_string(),
+        };
+        assert!(invalid.load_recipient().is_err());
+    }
+
+    #[test]
+    fn test_segmented_streams() {
+        let identity = Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tar.zst.age");
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let segment = 2 * CHUNK_SIZE;
+        let mut writer =
+            SegmentedWriter::new(File::create(&path).unwrap(), identity.to_public(), segment)
+                .unwrap();
+        // Odd write sizes, so writes straddle the stream boundaries.
+        for piece in content.chunks(10_007) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // 300000 bytes in streams of 131072: two full ones and the rest.
+        let offsets = stream_offsets(&path).unwrap();
+        assert_eq!(offsets.len(), 3);
+        let data = fs::read(&path).unwrap();
+        for window in offsets.windows(2) {
+            let stream = &data[window[0] as usize..window[1] as usize];
+            let mut plain = Vec::new();
+            age::Decryptor::new(stream)
+                .unwrap()
+                .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .unwrap()
+                .read_to_end(&mut plain)
+                .unwrap();
+            assert_eq!(plain.len() as u64, segment);
+        }
+
+        let mut plain = Vec::new();
+        open_streams(&path, &identity)
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, content);
+        assert!(open_streams(&path, &Identity::generate()).is_err());
+
+        // One stream, as without max_part_size_mb.
+        let single = dir.path().join("single.age");
+        let mut writer =
+            start_stream(File::create(&single).unwrap(), &identity.to_public()).unwrap();
+        writer.write_all(&content).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(stream_offsets(&single).unwrap(), [0]);
+        let mut plain = Vec::new();
+        open_streams(&single, &identity)
+            .unwrap()
+            .read_to_end(&mut plain)
+            .unwrap();
+        assert_eq!(plain, content);
+
+        fs::write(&single, b"not encrypted").unwrap();
+        assert!(stream_offsets(&single).is_err());
+    }
+
+    #[test]
+    fn test_segment_size() {
+        assert_eq!(segment_size(1 << 20), 15 * CHUNK_SIZE);
+        assert_eq!(segment_size(1000), CHUNK_SIZE);
+        let max_part_size = 100 << 20;
+        let segment = segment_size(max_part_size);
+        assert_eq!(segment % CHUNK_SIZE, 0);
+        let encrypted =
+            HEADER_ALLOWANCE + NONCE_SIZE + segment / CHUNK_SIZE * (CHUNK_SIZE + TAG_SIZE);
+        assert!(encrypted <= max_part_size);
+        assert!(encrypted + CHUNK_SIZE + TAG_SIZE > max_part_size);
+    }
+}
@@ -0,0 +1,359 @@
+//! Upload estimates for `--dry-run`.
+//!
+//! An item's archive size comes from measuring its source, scaled by the
+//! compression ratio its last recorded run reached (or a guess without one);
+//! upload time comes from the throughput earlier runs recorded per uploader
+//! in their `report_file`; and S3 gets a rough monthly storage and restore
+//! cost. Nothing here is exact: it is meant for weighing a config change
+//! before running it.
+
+use crate::archive::{ArchiveFormat, SourceSummary};
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// How many of the newest reports the throughput is taken from.
+pub const HISTORY_RUNS: usize = 10;
+
+/// Ratio assumed for data that is not already compressed, when the item has
+/// no recorded run yet.
+const GUESSED_RATIO: f64 = 3.0;
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// The parts of a run report the estimates read back.
+#[derive(Deserialize)]
+struct RecordedRun {
+    started_at: DateTime<FixedOffset>,
+    items: Vec<RecordedItem>,
+}
+
+#[derive(Deserialize)]
+struct RecordedItem {
+    name: String,
+    archive: Option<RecordedArchive>,
+    #[serde(default)]
+    uploads: Vec<RecordedUpload>,
+}
+
+#[derive(Deserialize)]
+struct RecordedArchive {
+    archive_bytes: u64,
+    ratio: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RecordedUpload {
+    uploader: String,
+    bytes_per_sec: Option<u64>,
+}
+
+/// Throughput and archive sizes recorded by earlier runs.
+#[derive(Debug, Default)]
+pub struct History {
+    /// Per uploader, the `bytes_per_sec` of its successful uploads.
+    rates: HashMap<String, Vec<u64>>,
+    /// Per item, the newest archive size and compression ratio.
+    archives: HashMap<String, (u64, Option<f64>)>,
+}
+
+impl History {
+    /// Reads the newest `runs` reports among the `.json` files in `dir`.
+    /// Files that are not run reports, or cannot be read, are skipped, and a
+    /// missing directory is an empty history.
+    pub fn load(dir: &Path, runs: usize) -> Self {
+        let mut recorded: Vec<RecordedRun> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| fs::read(path).ok())
+            .filter_map(|json| serde_json::from_slice(&json).ok())
+            .collect();
+        recorded.sort_by_key(|run| std::cmp::Reverse(run.started_at));
+        recorded.truncate(runs);
+
+        let mut history = Self::default();
+        for run in recorded {
+            for item in run.items {
+                for upload in &item.uploads {
+                    if let Some(rate) = upload.bytes_per_sec.filter(|rate| *rate > 0) {
+                        history
+                            .rates
+                            .entry(upload.uploader.clone())
+                            .or_default()
+                            .push(rate);
+                    }
+                }
+                if let Some(archive) = item.archive {
+                    // Runs go newest first, so the first one seen stays.
+                    history
+                        .archives
+                        .entry(item.name)
+                        .or_insert((archive.archive_bytes, archive.ratio));
+                }
+            }
+        }
+        history
+    }
+
+    /// Median recorded throughput of `uploader`.
+    pub fn rate(&self, uploader: &str) -> Option<u64> {
+        let mut rates = self.rates.get(uploader)?.clone();
+        rates.sort_unstable();
+        rates.get(rates.len() / 2).copied()
+    }
+
+    /// Newest recorded archive size and compression ratio of `item`.
+    pub fn archive(&self, item: &str) -> Option<(u64, Option<f64>)> {
+        self.archives.get(item).copied()
+    }
+}
+
+/// Archive size for a source of `summary`: at the item's recorded `ratio`,
+/// or else with already-compressed files kept as they are and the rest
+/// shrunk by [`GUESSED_RATIO`]. Plain tar archives are not compressed.
+pub fn archive_size(summary: &SourceSummary, format: ArchiveFormat, ratio: Option<f64>) -> u64 {
+    match (format, ratio) {
+        (ArchiveFormat::Tar, _) => summary.bytes,
+        (_, Some(ratio)) if ratio > 0.0 => (summary.bytes as f64 / ratio) as u64,
+        _ => {
+            let compressible = summary.bytes - summary.compressed_bytes;
+            summary.compressed_bytes + (compressible as f64 / GUESSED_RATIO) as u64
+        }
+    }
+}
+
+/// Upload time of `bytes` at `rate`, lowered to `cap` when a rate cap is
+/// set. `None` without a recorded rate.
+pub fn upload_time(bytes: u64, rate: Option<u64>, cap: Option<u64>) -> Option<Duration> {
+    let rate = match (rate, cap.filter(|cap| *cap > 0)) {
+        (Some(rate), Some(cap)) => rate.min(cap),
+        (rate, _) => rate?,
+    };
+    Some(Duration::from_secs_f64(bytes as f64 / rate as f64))
+}
+
+/// Prices in USD per GiB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub storage_per_month: f64,
+    /// Downloading it back, e.g. for a restore.
+    pub egress: f64,
+}
+
+impl Pricing {
+    /// AWS S3 Standard, us-east-1, first 50 TB.
+    pub const AWS_S3_STANDARD: Pricing = Pricing {
+        storage_per_month: 0.023,
+        egress: 0.09,
+    };
+
+    /// Monthly cost of storing `copies` archives of `bytes`, and the cost of
+    /// downloading one of them. Uploads themselves are free on S3.
+    pub fn cost(&self, bytes: u64, copies: u64) -> (f64, f64) {
+        let gib = bytes as f64 / GIB;
+        (
+            gib * copies as f64 * self.storage_per_month,
+            gib * self.egress,
+        )
+    }
+}
+
+/// One line of the dry-run table.
+#[derive(Debug, Default)]
+pub struct Estimate {
+    pub item: String,
+    pub uploader: String,
+    pub bytes: Option<u64>,
+    pub duration: Option<Duration>,
+    /// Monthly storage and one restore, in USD.
+    pub cost: Option<(f64, f64)>,
+}
+
+/// Renders `estimates` as an aligned table with a header line, `-` where
+/// something could not be estimated.
+pub fn render_table(estimates: &[Estimate]) -> Vec<String> {
+    let rows: Vec<[String; 5]> = estimates
+        .iter()
+        .map(|estimate| {
+            [
+                estimate.item.clone(),
+                estimate.uploader.clone(),
+                estimate
+                    .bytes
+                    .map_or_else(|| "-".to_string(), crate::format_bytes),
+                estimate
+                    .duration
+                    .map_or_else(|| "-".to_string(), format_duration),
+                estimate.cost.map_or_else(
+                    || "-".to_string(),
+                    |(storage, restore)| format!("${storage:.2}/month, ${restore:.2} per restore"),
+                ),
+            ]
+        })
+        .collect();
+    let header = ["item", "uploader", "size", "upload time", "cost"].map(String::from);
+    let mut widths = header.clone().map(|cell| cell.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect();
+            line.join("  ").trim_end().to_string()
+        })
+        .collect()
+}
+
+/// `45s`, `12m 5s` or `3h 20m`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs().max(1);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_run(dir: &Path, name: &str, started_at: &str, rate: u64, archive_bytes: u64) {
+        let report = serde_json::json!({
+            "status": "ok",
+            "started_at": started_at,
+            "finished_at": started_at,
+            "items": [{
+                "name": "etc",
+                "status": "ok",
+                "bytes": archive_bytes,
+                "duration_secs": 1,
+                "failures": [],
+                "archive": {
+                    "source_bytes": archive_bytes * 4,
+                    "archive_bytes": archive_bytes,
+                    "ratio": 4.0,
+                    "duration_ms": 10,
+                },
+                "uploads": [
+                    {"uploader": "S3", "status": "ok", "duration_ms": 10, "bytes_per_sec": rate, "unchanged": false},
+                    {"uploader": "SFTP", "status": "failed", "duration_ms": 10, "error": "refused", "unchanged": false},
+                ],
+            }],
+            "bytes": archive_bytes,
+            "duration_secs": 1,
+            "failures": [],
+        });
+        fs::write(dir.join(name), report.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        write_run(
+            dir.path(),
+            "run-1.json",
+            "2026-10-01T02:00:00+02:00",
+            1000,
+            100,
+        );
+        write_run(
+            dir.path(),
+            "run-2.json",
+            "2026-10-02T02:00:00+02:00",
+            3000,
+            200,
+        );
+        write_run(
+            dir.path(),
+            "run-3.json",
+            "2026-10-03T02:00:00+02:00",
+            2000,
+            300,
+        );
+        fs::write(dir.path().join("other.json"), "{}").unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a report").unwrap();
+
+        let history = History::load(dir.path(), HISTORY_RUNS);
+        assert_eq!(history.rate("S3"), Some(2000));
+        assert_eq!(history.rate("SFTP"), None);
+        assert_eq!(history.archive("etc"), Some((300, Some(4.0))));
+        assert_eq!(history.archive("home"), None);
+
+        // Only the newest two runs.
+        let history = History::load(dir.path(), 2);
+        assert_eq!(history.rate("S3"), Some(3000));
+
+        assert!(
+            History::load(&dir.path().join("missing"), HISTORY_RUNS)
+                .rate("S3")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_estimates() {
+        let summary = SourceSummary {
+            files: 2,
+            bytes: 4000,
+            compressed_bytes: 1000,
+            ..SourceSummary::default()
+        };
+        assert_eq!(archive_size(&summary, ArchiveFormat::Zstd, None), 2000);
+        assert_eq!(archive_size(&summary, ArchiveFormat::Gzip, Some(2.0)), 2000);
+        assert_eq!(archive_size(&summary, ArchiveFormat::Tar, Some(2.0)), 4000);
+
+        assert_eq!(upload_time(1000, None, Some(100)), None);
+        assert_eq!(
+            upload_time(1000, Some(500), None),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            upload_time(1000, Some(500), Some(100)),
+            Some(Duration::from_secs(10))
+        );
+
+        let (storage, restore) = Pricing::AWS_S3_STANDARD.cost(10 << 30, 3);
+        assert!((storage - 0.69).abs() < 1e-9, "{storage}");
+        assert!((restore - 0.9).abs() < 1e-9, "{restore}");
+    }
+
+    #[test]
+    fn test_render_table() {
+        let lines = render_table(&[
+            Estimate {
+                item: "etc".to_string(),
+                uploader: "S3".to_string(),
+                bytes: Some(2048),
+                duration: Some(Duration::from_secs(150)),
+                cost: Some((0.5, 1.25)),
+            },
+            Estimate {
+                item: "database".to_string(),
+                uploader: "SFTP".to_string(),
+                ..Estimate::default()
+            },
+        ]);
+        assert_eq!(
+            lines,
+            [
+                "item      uploader  size     upload time  cost",
+                "etc       S3        2.0 KiB  2m 30s       $0.50/month, $1.25 per restore",
+                "database  SFTP      -        -            -",
+            ]
+        );
+    }
+}
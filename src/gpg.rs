@@ -0,0 +1,189 @@
+//! Detached OpenPGP signatures made by running `gpg`, configured through
+//! `[app.sign]`.
+//!
+//! For setups that need a signature checkable with standard tools rather
+//! than the built-in Ed25519 one. `gpg` signs either the archive itself or
+//! its `.sha256` file, and writes the binary signature to `<archive>.sig`.
+//! With `key_file`, the key is imported into a temporary keyring for the run
+//! instead of using the one of the user running the backup.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+/// What the `.sig` file covers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignTarget {
+    #[default]
+    Archive,
+    /// The `.sha256` file, which is much faster to sign than a large
+    /// archive and still pins its content.
+    Checksum,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignConfig {
+    /// Key ID, fingerprint or user ID of a secret key, as for
+    /// `gpg --local-user`.
+    pub key: Option<String>,
+    /// An exported secret key (`gpg --export-secret-keys --armor`), imported
+    /// into a keyring of its own.
+    pub key_file: Option<String>,
+    /// File holding the key's passphrase; without it the key must be
+    /// unprotected or unlocked in a running `gpg-agent`.
+    pub passphrase_file: Option<String>,
+    #[serde(default)]
+    pub target: SignTarget,
+    /// `gpg` binary to run instead of `gpg` from `PATH`.
+    pub program: Option<String>,
+}
+
+impl SignConfig {
+    /// The checks that need no `gpg`, for `--validate`.
+    pub fn check(&self) -> Result<()> {
+        let set = |value: &Option<String>| value.as_deref().is_some_and(|v| !v.trim().is_empty());
+        if !set(&self.key) && !set(&self.key_file) {
+            anyhow::bail!("sign needs key (a GPG key ID) or key_file (an exported secret key)");
+        }
+        for path in [&self.key_file, &self.passphrase_file]
+            .into_iter()
+            .flatten()
+        {
+            if !Path::new(path).is_file() {
+                anyhow::bail!("sign: {} does not exist or is not a file", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct GpgSigner {
+    program: String,
+    key: Option<String>,
+    passphrase_file: Option<PathBuf>,
+    target: SignTarget,
+    /// Temporary keyring holding `key_file`, removed when dropped.
+    home: Option<TempDir>,
+}
+
+impl GpgSigner {
+    /// Imports `key_file` if set and makes sure the key can be found, so a
+    /// missing key fails the run before any archive is made.
+    pub fn new(config: &SignConfig) -> Result<Self> {
+        config.check()?;
+        let mut signer = Self {
+            program: config.program.clone().unwrap_or_else(|| "gpg".to_string()),
+            key: config.key.clone().filter(|key| !key.trim().is_empty()),
+            passphrase_file: config.passphrase_file.as_ref().map(PathBuf::from),
+            target: config.target,
+            home: None,
+        };
+        if let Some(key_file) = &config.key_file {
+            let home = tempfile::Builder::new()
+                .prefix("backup-to-cloud-gnupg")
+                .tempdir()
+                .context("Failed to create a temporary GPG keyring")?;
+            signer.home = Some(home);
+            let mut import = signer.command();
+            import.arg("--import").arg(key_file);
+            signer.passphrase_args(&mut import);
+            signer.run(import, &format!("Importing GPG key from {key_file}"))?;
+        }
+        let mut list = signer.command();
+        list.arg("--list-secret-keys");
+        if let Some(key) = &signer.key {
+            list.arg("--").arg(key);
+        }
+        let output = signer.run(list, "Looking up the GPG signing key")?;
+        if output.stdout.is_empty() {
+            anyhow::bail!("No GPG secret key found to sign with");
+        }
+        Ok(signer)
+    }
+
+    pub fn target(&self) -> SignTarget {
+        self.target
+    }
+
+    /// Signs the file at `path` and writes the detached signature to
+    /// `sig_path`, replacing an old one.
+    pub fn sign_file(&self, path: &Path, sig_path: &Path) -> Result<()> {
+        let mut sign = self.command();
+        sign.arg("--yes").arg("--detach-sign");
+        if let Some(key) = &self.key {
+            sign.arg("--local-user").arg(key);
+        }
+        self.passphrase_args(&mut sign);
+        sign.arg("--output").arg(sig_path).arg("--").arg(path);
+        self.run(sign, &format!("GPG signing of {}", path.display()))?;
+        // A gpg that exits 0 without writing anything must not pass for a
+        // signature.
+        let written = fs::metadata(sig_path).map(|meta| meta.len()).unwrap_or(0);
+        if written == 0 {
+            anyhow::bail!(
+                "GPG signing of {} wrote no signature to {}",
+                path.display(),
+                sig_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// `--batch` makes `gpg` fail instead of prompting, e.g. for the
+    /// passphrase of a locked key.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(["--batch", "--no-tty"]);
+        if let Some(home) = &self.home {
+            cmd.arg("--homedir").arg(home.path());
+        }
+        cmd
+    }
+
+    fn passphrase_args(&self, cmd: &mut Command) {
+        if let Some(path) = &self.passphrase_file {
+            cmd.args(["--pinentry-mode", "loopback", "--passphrase-file"])
+                .arg(path);
+        }
+    }
+
+    /// Runs `cmd` and fails with what `gpg` printed unless it succeeded.
+    fn run(&self, mut cmd: Command, what: &str) -> Result<Output> {
+        let output = cmd
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("{what} failed: could not run {}", self.program))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!(
+                "{what} failed ({}): {}",
+                output.status,
+                stderr.trim().lines().collect::<Vec<_>>().join("; ")
+            );
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_config_check() {
+        let config: SignConfig = toml::from_str(r#"key = "backup@example.com""#).unwrap();
+        assert!(config.check().is_ok());
+        assert_eq!(config.target, SignTarget::Archive);
+
+        let config: SignConfig = toml::from_str(r#"target = "checksum""#).unwrap();
+        assert!(config.check().is_err());
+        assert_eq!(config.target, SignTarget::Checksum);
+
+        let config: SignConfig = toml::from_str(r#"key_file = "/nonexistent/key.asc""#).unwrap();
+        assert!(config.check().is_err());
+    }
+}
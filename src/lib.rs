@@ -0,0 +1,1180 @@
+//! The `backup-to-cloud` backup tool as a library.
+//!
+//! The binary is a thin wrapper around [`run_cli`]. To run backups from
+//! other code, load a [`Config`] and hand it to [`run_backup`], which
+//! reports the outcome of every item instead of printing it and exiting.
+//! The upload backends are in [`uploader`], behind the [`Uploader`] trait,
+//! and [`create_archive`] packs files the way backup items do.
+
+pub mod archive;
+mod backends;
+mod cli;
+mod config;
+mod database;
+mod dedup;
+mod encryption;
+mod estimate;
+mod gpg;
+mod lock;
+mod notify;
+mod pipeline;
+mod restore;
+mod retention;
+mod schedule;
+mod shutdown;
+mod signing;
+mod split;
+mod state;
+mod stream;
+mod upload;
+pub mod uploader;
+mod verify;
+
+pub use archive::{ArchiveFormat, ArchiveOptions, SymlinkMode, create_archive};
+pub use cli::run_cli;
+pub use config::{BackupItem, Config};
+pub use notify::{ArchiveReport, ItemReport, UploadReport};
+pub use state::ItemStatus;
+pub use uploader::Uploader;
+
+use anyhow::{Context, Result};
+use backends::build_uploaders;
+use chrono::format::{Item, Parsed, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDateTime};
+use config::{AppConfig, config_anchor};
+use dedup::ChunkIndex;
+use encryption::EncryptionConfig;
+use gpg::GpgSigner;
+use lock::RunLock;
+use notify::RunSummary;
+use pipeline::backup_item;
+use retention::ArchiveNaming;
+use signing::Signer;
+use state::StateFile;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn};
+use upload::{UploadPolicy, UploadTarget};
+
+/// What a backup run did, filled in item by item as it goes. The run's
+/// error, its exit status, the `--summary-only` line and the run report
+/// are all made from it.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// One per item that was run, in config order.
+    pub items: Vec<ItemReport>,
+    /// Archive bytes that were uploaded everywhere.
+    pub bytes: u64,
+    /// Why the run failed: the failures of its items, or a problem outside
+    /// any item, such as an uploader that could not be set up. `None` when
+    /// everything was backed up.
+    pub error: Option<String>,
+    /// Why the run ended before its last item, without the failures.
+    stopped: Option<String>,
+}
+
+impl RunReport {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn failed_items(&self) -> usize {
+        self.items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Failed)
+            .count()
+    }
+
+    /// Failure messages of all items, in the order they happened.
+    fn failures(&self) -> Vec<&str> {
+        self.items
+            .iter()
+            .flat_map(|item| item.failures.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// The error the items leave the run with: how it stopped early, or how
+    /// many failures it finished with, followed by each of them.
+    fn outcome(&self) -> Result<()> {
+        let failures = self.failures();
+        match &self.stopped {
+            Some(stopped) if failures.is_empty() => anyhow::bail!("{stopped}"),
+            Some(stopped) => anyhow::bail!("{stopped}:\n{}", failures.join("\n")),
+            None if failures.is_empty() => Ok(()),
+            None => anyhow::bail!(
+                "Backup finished with {} failure(s):\n{}",
+                failures.len(),
+                failures.join("\n")
+            ),
+        }
+    }
+}
+
+/// How a backup run treats the checkpoint, schedules and run lock; the
+/// command line sets these from its flags.
+#[derive(Debug, Clone, Copy, Default)]
+struct RunOptions {
+    /// Skip items that already succeeded today.
+    resume: bool,
+    /// Run every item again, also those an interrupted run today completed.
+    restart: bool,
+    /// Only run items whose schedule is due.
+    scheduled: bool,
+    /// Run even if the lock file shows another run in progress.
+    force: bool,
+}
+
+/// Backs up every item of `config` once, as `backup-to-cloud` without
+/// flags does: under the run lock, with the configured run commands, run
+/// report and notifications.
+pub fn run_backup(config: &Config) -> RunReport {
+    let mut report = RunReport::default();
+    if let Err(err) = run_config(config, &RunOptions::default(), &mut report) {
+        report.error.get_or_insert_with(|| format!("{err:#}"));
+    }
+    report
+}
+
+/// A backup run of the loaded `config`, with its report file and
+/// notifications. Fails with the run's error, which is also set in
+/// `report` once the run got past the lock.
+fn run_config(config: &Config, options: &RunOptions, report: &mut RunReport) -> Result<()> {
+    let _lock = lock_run(&config.app, options.force)?;
+    let started = Instant::now();
+    let started_at = Local::now();
+    let stamp = Stamp::now(DEFAULT_DATE_FORMAT);
+    let result = run_items(config, options, report).and_then(|()| report.outcome());
+    report.error = result.as_ref().err().map(|err| format!("{err:#}"));
+    let summary = run_summary(report, started_at, started.elapsed());
+    if let Some(path) = &config.app.report_file {
+        let path = PathBuf::from(stamp.expand_run_command(path));
+        match notify::write_report(&path, &summary) {
+            Ok(()) => info!("Wrote run report to {}", path.display()),
+            Err(err) => warn!("Run report failed: {err:#}"),
+        }
+    }
+    send_notifications(&config.app, &summary);
+    result
+}
+
+/// Takes the run lock, or fails if another run holds it. With `force` a
+/// held lock is only warned about and the run goes ahead unlocked.
+fn lock_run(app: &AppConfig, force: bool) -> Result<Option<RunLock>> {
+    let path = match &app.lock_file {
+        Some(path) => PathBuf::from(path),
+        None => prepare_work_dir(app.work_dir.as_deref())?.join(lock::DEFAULT_LOCK_FILE),
+    };
+    if let Some(lock) = RunLock::try_acquire(&path)? {
+        return Ok(Some(lock));
+    }
+    let holder = match lock::holder(&path) {
+        Some(pid) => format!("PID {pid}"),
+        None => "unknown PID".to_string(),
+    };
+    if force {
+        warn!(
+            "Another backup is already running ({holder}, lock file {}); continuing because of --force",
+            path.display()
+        );
+        return Ok(None);
+    }
+    anyhow::bail!(
+        "Another backup is already running ({holder}, lock file {}); pass --force to run anyway",
+        path.display()
+    )
+}
+
+fn run_summary(report: &RunReport, started_at: DateTime<Local>, elapsed: Duration) -> RunSummary {
+    RunSummary {
+        status: if report.succeeded() {
+            ItemStatus::Ok
+        } else {
+            ItemStatus::Failed
+        },
+        started_at,
+        finished_at: Local::now(),
+        items: report.items.clone(),
+        bytes: report.bytes,
+        duration_secs: elapsed.as_secs(),
+        failures: report.failures().into_iter().map(String::from).collect(),
+        error: report.error.clone(),
+    }
+}
+
+fn send_notifications(app: &AppConfig, summary: &RunSummary) {
+    if let Some(webhook) = &app.notify {
+        match notify::send_webhook(webhook, summary) {
+            Ok(()) => info!("Sent webhook notification"),
+            Err(err) => warn!("Webhook notification failed: {err:#}"),
+        }
+    }
+    if let Some(smtp) = &app.smtp {
+        match notify::send_email(smtp, summary) {
+            Ok(()) => info!("Sent email notification to {}", smtp.to.join(", ")),
+            Err(err) => warn!("Email notification failed: {err:#}"),
+        }
+    }
+    if let Some(telegram) = &app.telegram {
+        match notify::send_telegram(telegram, summary) {
+            Ok(()) => info!("Sent Telegram notification"),
+            Err(err) => warn!("Telegram notification failed: {err:#}"),
+        }
+    }
+}
+
+fn run_items(config: &Config, options: &RunOptions, report: &mut RunReport) -> Result<()> {
+    let config_paths = config.paths.as_slice();
+    let uploaders = build_uploaders(&config.app)?;
+    validate_items(&config.backups, &uploaders)?;
+    let signer = config
+        .app
+        .signing_key_file
+        .as_deref()
+        .map(|path| Signer::from_pem_file(Path::new(path)))
+        .transpose()?;
+    let gpg_signer = config.app.sign.as_ref().map(GpgSigner::new).transpose()?;
+    let mut ctx = RunContext {
+        targets: uploaders.into_iter().map(UploadTarget::new).collect(),
+        policy: UploadPolicy::from_app(&config.app),
+        signer,
+        gpg_signer,
+        encryption: config.app.encryption.clone(),
+        default_level: config
+            .app
+            .compression_level
+            .unwrap_or(archive::DEFAULT_LEVEL),
+        compression_threads: archive::compression_threads(
+            config.app.compression_threads.unwrap_or(1),
+        )?,
+        stop_on_first_failure: config.app.stop_on_first_failure.unwrap_or(false),
+        work_dir: config.app.work_dir.clone(),
+        chunk_index_path: ChunkIndex::path_for_config(&config_anchor(config_paths)),
+        uploads: Vec::new(),
+        archive: None,
+    };
+
+    let state_path = StateFile::path_for_config(&config_anchor(config_paths));
+    let mut state = match StateFile::load(state_path.clone()) {
+        Ok(state) => state,
+        Err(err) if !options.resume => {
+            warn!("Ignoring unreadable state file: {err:#}");
+            StateFile::empty(state_path)
+        }
+        Err(err) => return Err(err.context("--resume needs a readable state file")),
+    };
+    if options.restart
+        && let Err(err) = state.clear_checkpoint()
+    {
+        warn!(
+            "Could not clear the checkpoint in {}: {err:#}",
+            state.path().display()
+        );
+    }
+
+    let stamp = Stamp::now(DEFAULT_DATE_FORMAT);
+    if let Some(command) = &config.app.pre_run_command {
+        info!("Running pre_run_command");
+        run_command(&stamp.expand_run_command(command), None, None)
+            .context("pre_run_command failed, no backup item was run")?;
+    }
+
+    let result = backup_items(
+        &config.backups,
+        &mut ctx,
+        &mut state,
+        options,
+        &stamp.date,
+        report,
+    );
+
+    if let Some(command) = &config.app.post_run_command {
+        let status = if result.is_ok() && report.outcome().is_ok() {
+            "ok"
+        } else {
+            "failed"
+        };
+        info!("Running post_run_command (status: {status})");
+        let command = stamp
+            .expand_run_command(command)
+            .replace("{status}", status);
+        // A broken cleanup hook is worth an error line, but the backups
+        // themselves already succeeded or failed on their own.
+        if let Err(err) = run_command(&command, None, None) {
+            error!("post_run_command failed: {err:#}");
+        }
+    }
+    result
+}
+
+/// Backs up every item in order, recording each outcome in `state`. Items
+/// the checkpoint of an interrupted run on `date` names are skipped, with
+/// `--resume` so are all that already succeeded on `date`, and with
+/// `--scheduled` those whose schedule is not due; with
+/// `stop_on_first_failure`, the run ends at the first item that fails,
+/// keeping the checkpoint for the next run.
+fn backup_items(
+    items: &[BackupItem],
+    ctx: &mut RunContext,
+    state: &mut StateFile,
+    options: &RunOptions,
+    date: &str,
+    report: &mut RunReport,
+) -> Result<()> {
+    for (index, item) in items.iter().enumerate() {
+        let base_name = normalize_archive_name(&item.archive_name);
+        if shutdown::requested() {
+            report.stopped = Some(format!(
+                "Backup stopped before [{base_name}] by a signal, {} item(s) not run",
+                items.len() - index
+            ));
+            return Ok(());
+        }
+        if options.scheduled
+            && let Some(schedule) = &item.schedule
+        {
+            let last_run = state
+                .last_run(base_name)
+                .map(|time| DateTime::<Local>::from(time).naive_local());
+            if !schedule.is_due(last_run, Local::now().naive_local()) {
+                let next = last_run
+                    .and_then(|last_run| schedule.next_due(last_run))
+                    .map(|due| due.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| "never".to_string());
+                info!(
+                    "[{base_name}] not due yet (schedule {schedule}, next {next}), skipping (--scheduled)"
+                );
+                continue;
+            }
+        }
+        if options.resume && state.succeeded_on(base_name, date) {
+            info!("[{base_name}] already backed up on {date}, skipping (--resume)");
+            continue;
+        }
+        if state.checkpointed(base_name, date) {
+            info!(
+                "[{base_name}] completed by an interrupted run on {date}, skipping \
+                 (--restart runs it again)"
+            );
+            continue;
+        }
+        let mut failures = Vec::new();
+        let item_started = Instant::now();
+        let incremental = item.incremental.unwrap_or(false);
+        let modified_since = if incremental {
+            state.incremental_since(base_name)
+        } else {
+            None
+        };
+        let archive_started = SystemTime::now();
+        ctx.uploads.clear();
+        ctx.archive = None;
+        let bytes = backup_item(item, ctx, modified_since, &mut failures)?;
+        report.bytes += bytes;
+        let status = if failures.is_empty() {
+            ItemStatus::Ok
+        } else {
+            ItemStatus::Failed
+        };
+        if incremental
+            && status == ItemStatus::Ok
+            && let Err(err) = state.record_incremental(base_name, archive_started)
+        {
+            warn!(
+                "[{base_name}] could not record the incremental base in {}: {err:#}",
+                state.path().display()
+            );
+        }
+        if status == ItemStatus::Ok
+            && let Err(err) = state.record_last_run(base_name, archive_started)
+        {
+            warn!(
+                "[{base_name}] could not record the last run in {}: {err:#}",
+                state.path().display()
+            );
+        }
+        let duration = item_started.elapsed();
+        info!(
+            item = base_name,
+            status = status.as_str(),
+            bytes,
+            duration_ms = duration.as_millis() as u64,
+            "[{base_name}] item finished"
+        );
+        report.items.push(ItemReport {
+            name: base_name.to_string(),
+            status,
+            bytes,
+            duration_secs: duration.as_secs(),
+            failures,
+            archive: ctx.archive.take(),
+            uploads: std::mem::take(&mut ctx.uploads),
+        });
+        if let Err(err) = state.record(base_name, date, status) {
+            warn!(
+                "[{base_name}] could not update {}: {err:#}",
+                state.path().display()
+            );
+        }
+        if status == ItemStatus::Failed && ctx.stop_on_first_failure {
+            report.stopped = Some(format!(
+                "Backup stopped at [{base_name}] (stop_on_first_failure), {} item(s) not run",
+                items.len() - index - 1
+            ));
+            return Ok(());
+        }
+    }
+
+    if let Err(err) = state.clear_checkpoint() {
+        warn!(
+            "Could not clear the checkpoint in {}: {err:#}",
+            state.path().display()
+        );
+    }
+    if report.failed_items() == 0 {
+        info!("Backup uploaded successfully");
+    }
+    Ok(())
+}
+
+/// Checks every item's remote settings against each uploader (path rules,
+/// required capabilities) so a bad combination fails the run before any
+/// archive is built.
+fn validate_items(items: &[BackupItem], uploaders: &[Box<dyn Uploader>]) -> Result<()> {
+    let mut problems: Vec<String> = Vec::new();
+    for item in items {
+        let base_name = normalize_archive_name(&item.archive_name);
+        let stamp = Stamp::now(item.date_format());
+        let remote_dir = expand_placeholders(&item.remote_dir, &stamp, base_name);
+        for uploader in uploaders
+            .iter()
+            .filter(|uploader| item.uses_uploader(uploader.name()))
+        {
+            match uploader.normalize_remote_dir(&remote_dir) {
+                Ok(normalized) if normalized != remote_dir => warn!(
+                    "[{base_name}] remote_dir {} is used as {} on {}",
+                    remote_dir,
+                    normalized,
+                    uploader.name()
+                ),
+                Ok(_) => {}
+                Err(err) => problems.push(format!("[{base_name}] {}: {err}", uploader.name())),
+            }
+            if item.keep_remote.is_some() && uploader.is_immutable() {
+                problems.push(format!(
+                    "[{base_name}] keep_remote deletes old archives, which the immutable {} forbids",
+                    uploader.name()
+                ));
+            }
+            if item.atomic_remote.unwrap_or(false) && uploader.is_immutable() {
+                problems.push(format!(
+                    "[{base_name}] atomic_remote renames the uploaded file, which the immutable {} forbids",
+                    uploader.name()
+                ));
+            } else if item.atomic_remote.unwrap_or(false) && !uploader.supports_rename() {
+                problems.push(format!(
+                    "[{base_name}] atomic_remote requires server-side rename, which {} does not support",
+                    uploader.name()
+                ));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid backup configuration:\n{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+/// Everything `backup_item` needs that outlives a single item.
+struct RunContext {
+    targets: Vec<UploadTarget>,
+    policy: UploadPolicy,
+    signer: Option<Signer>,
+    /// From `[app.sign]`.
+    gpg_signer: Option<GpgSigner>,
+    /// Used by items without their own `encryption` block.
+    encryption: Option<EncryptionConfig>,
+    /// zstd level for items without `compression_level` or `auto_level`.
+    default_level: i32,
+    /// `compression_threads`, with `0` resolved to the number of CPUs.
+    compression_threads: u32,
+    /// Skip the remaining items once one has failed.
+    stop_on_first_failure: bool,
+    /// `[app] work_dir`, for items without their own.
+    work_dir: Option<String>,
+    /// Where `dedup` items keep track of the chunks already uploaded.
+    chunk_index_path: PathBuf,
+    /// Per-uploader results of the current item, for its report.
+    uploads: Vec<UploadReport>,
+    /// Compression of the current item's archive, for its report.
+    archive: Option<ArchiveReport>,
+}
+
+/// The start of a failed HTTP response's body, for the error message. An HTML
+/// error page or a proxy's gateway timeout would otherwise flood the log.
+fn error_body(response: reqwest::blocking::Response) -> String {
+    let body = response.text().unwrap_or_default();
+    let snippet: String = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match snippet.char_indices().nth(200) {
+        Some((end, _)) => format!("{}...", &snippet[..end]),
+        None if snippet.is_empty() => "(empty body)".to_string(),
+        None => snippet,
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// `{date}` unless an item sets `date_format`, so archive names stay
+/// `archive_name-YYYYMMDD`.
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d";
+
+/// Format of the `{time}` placeholder.
+const TIME_FORMAT: &str = "%H%M%S";
+
+/// Overrides the system hostname for `{hostname}`.
+const HOSTNAME_ENV: &str = "BACKUP_HOSTNAME";
+/// `{hostname}` when neither `BACKUP_HOSTNAME` nor the system has one.
+const UNKNOWN_HOST: &str = "unknown-host";
+
+static HOSTNAME: OnceLock<String> = OnceLock::new();
+
+/// The value of `{hostname}`, looked up on first use and kept for the rest
+/// of the process so every item of a run agrees on it.
+fn hostname() -> &'static str {
+    HOSTNAME.get_or_init(|| resolve_hostname(env::var(HOSTNAME_ENV).ok()))
+}
+
+/// `BACKUP_HOSTNAME` if set, else the kernel's hostname. Path separators are
+/// replaced so the result can go into a file name.
+fn resolve_hostname(configured: Option<String>) -> String {
+    let system = || {
+        ["/proc/sys/kernel/hostname", "/etc/hostname"]
+            .into_iter()
+            .find_map(|path| fs::read_to_string(path).ok())
+            .or_else(|| env::var("COMPUTERNAME").ok())
+    };
+    configured
+        .filter(|name| !name.trim().is_empty())
+        .or_else(system)
+        .map(|name| name.trim().replace(['/', '\\'], "-"))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| UNKNOWN_HOST.to_string())
+}
+
+/// The values `{date}` and `{time}` expand to, taken from a single clock
+/// reading so the two always agree, and `{hostname}`.
+struct Stamp {
+    date: String,
+    time: String,
+    hostname: &'static str,
+}
+
+impl Stamp {
+    /// `date_format` must have passed `check_date_format`; chrono panics
+    /// while formatting an invalid one.
+    fn now(date_format: &str) -> Self {
+        let now = Local::now();
+        Self {
+            date: now.format(date_format).to_string(),
+            time: now.format(TIME_FORMAT).to_string(),
+            hostname: hostname(),
+        }
+    }
+
+    /// A given `{date}`, as passed to `restore` or `verify --date`.
+    fn with_date(date: &str) -> Self {
+        Self {
+            date: date.to_string(),
+            time: Local::now().format(TIME_FORMAT).to_string(),
+            hostname: hostname(),
+        }
+    }
+
+    /// `pre_run_command`, `post_run_command` and `report_file` know `{date}`,
+    /// `{time}` and `{hostname}` but no item.
+    fn expand_run_command(&self, command: &str) -> String {
+        command
+            .replace("{date}", &self.date)
+            .replace("{time}", &self.time)
+            .replace("{hostname}", self.hostname)
+    }
+}
+
+/// Reads a `{date}` value back, so archives sort by when they were made
+/// whatever `date_format` puts first. Formats without a time of day read as
+/// midnight.
+fn parse_date(value: &str, date_format: &str) -> Option<NaiveDateTime> {
+    let mut parsed = Parsed::new();
+    chrono::format::parse(&mut parsed, value, StrftimeItems::new(date_format)).ok()?;
+    let date = parsed.to_naive_date().ok()?;
+    Some(date.and_time(parsed.to_naive_time().unwrap_or_default()))
+}
+
+/// Rejects a `date_format` that chrono cannot format, that would put a path
+/// separator into the archive name, or whose output cannot be read back to
+/// a date for ordering archives.
+fn check_date_format(date_format: &str) -> Result<(), String> {
+    if StrftimeItems::new(date_format).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("{date_format} is not a valid chrono format"));
+    }
+    let sample = Local::now().format(date_format).to_string();
+    if sample.is_empty() || sample.contains(['/', '\\']) {
+        return Err(format!(
+            "{date_format} gives {sample:?}, which cannot be part of a file name"
+        ));
+    }
+    if parse_date(&sample, date_format).is_none() {
+        return Err(format!(
+            "{date_format} must include the year, month and day (e.g. %Y-%m-%d)"
+        ));
+    }
+    Ok(())
+}
+
+fn normalize_archive_name(archive_name: &str) -> &str {
+    if archive_name.trim().is_empty() {
+        "backup"
+    } else {
+        archive_name.trim()
+    }
+}
+
+fn expand_placeholders(input: &str, stamp: &Stamp, archive_name: &str) -> String {
+    input
+        .replace("{date}", &stamp.date)
+        .replace("{time}", &stamp.time)
+        .replace("{archive_name}", archive_name)
+        .replace("{hostname}", stamp.hostname)
+}
+
+/// `archive.tar.zst` -> `archive.tar.zst.<extension>`
+fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// `tar.zst`, `tar.gz` or `tar`, plus `.age` for encrypted archives.
+fn archive_extension(format: ArchiveFormat, encrypted: bool) -> String {
+    if encrypted {
+        format!("{}.{}", format.extension(), encryption::EXTENSION)
+    } else {
+        format.extension().to_string()
+    }
+}
+
+/// `work_dir` resolved against the current directory, which is also the
+/// default.
+fn work_dir_path(work_dir: Option<&str>) -> Result<PathBuf> {
+    let cwd = env::current_dir().context("Failed to read current directory")?;
+    Ok(match work_dir {
+        Some(work_dir) => cwd.join(work_dir),
+        None => cwd,
+    })
+}
+
+/// Like `work_dir_path`, creating the directory if it is missing.
+fn prepare_work_dir(work_dir: Option<&str>) -> Result<PathBuf> {
+    let path = work_dir_path(work_dir)?;
+    fs::create_dir_all(&path)
+        .with_context(|| format!("Failed to create work_dir: {}", path.display()))?;
+    Ok(path)
+}
+
+fn build_archive_path(
+    dir: &Path,
+    naming: &ArchiveNaming,
+    date: &str,
+    extension: &str,
+) -> Result<PathBuf> {
+    let mut output_path = dir.join(naming.file_name(date, extension, 0));
+    if output_path.exists() {
+        let mut counter = 1usize;
+        loop {
+            let candidate = dir.join(naming.file_name(date, extension, counter));
+            if !candidate.exists() {
+                output_path = candidate;
+                break;
+            }
+            counter += 1;
+        }
+    }
+    Ok(output_path)
+}
+
+/// `source_path` (or `source_dir`) followed by every `source_paths` entry.
+fn resolve_source_paths(
+    item: &BackupItem,
+    stamp: &Stamp,
+    archive_name: &str,
+) -> Result<Vec<PathBuf>> {
+    let candidates: Vec<&str> = item
+        .source_path
+        .as_deref()
+        .or(item.source_dir.as_deref())
+        .into_iter()
+        .chain(item.source_paths.iter().map(String::as_str))
+        .collect();
+    if candidates.is_empty() {
+        anyhow::bail!("Missing source_path/source_dir/source_paths in backup item");
+    }
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let expanded = expand_placeholders(candidate, stamp, archive_name);
+            let trimmed = expanded.trim();
+            if trimmed.is_empty() {
+                anyhow::bail!("source_path/source_dir/source_paths cannot be empty");
+            }
+            Ok(PathBuf::from(trimmed))
+        })
+        .collect()
+}
+
+fn run_command(command: &str, workdir: Option<&str>, timeout: Option<Duration>) -> Result<()> {
+    execute_command(shell_command(command), workdir, command, timeout, None)
+}
+
+fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut command_builder = Command::new("cmd");
+        command_builder.args(["/C", command]);
+        command_builder
+    } else {
+        let mut command_builder = Command::new("sh");
+        command_builder.args(["-c", command]);
+        command_builder
+    }
+}
+
+/// Runs `argv[0]` directly with the remaining elements as its arguments, so
+/// no shell quoting or expansion is applied.
+fn argv_command(argv: &[String]) -> Result<Command> {
+    let (program, args) = argv.split_first().context("command_argv cannot be empty")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    Ok(cmd)
+}
+
+/// Runs `cmd`, capturing its output, or with `output` sending its stdout to
+/// that file. With a `timeout` the command is killed once it elapses; on
+/// Unix it then leads a process group of its own, and the whole group is
+/// killed so nothing it started is left running.
+fn execute_command(
+    mut cmd: Command,
+    workdir: Option<&str>,
+    label: &str,
+    timeout: Option<Duration>,
+    output: Option<File>,
+) -> Result<()> {
+    if let Some(dir) = workdir {
+        let dir_path = Path::new(dir);
+        if !dir_path.is_dir() {
+            anyhow::bail!("Command workdir is not a directory: {}", dir);
+        }
+        cmd.current_dir(dir_path);
+    }
+
+    cmd.stdin(Stdio::inherit())
+        .stdout(output.map_or_else(Stdio::piped, Stdio::from))
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    if timeout.is_some() {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run command: {}", label))?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let (status, stdout, stderr) = thread::scope(|scope| {
+        let stdout = scope.spawn(|| {
+            stdout
+                .map(|stdout| read_tail(stdout, COMMAND_OUTPUT_LIMIT))
+                .unwrap_or_default()
+        });
+        let stderr = scope.spawn(|| read_tail(stderr, COMMAND_OUTPUT_LIMIT));
+        let status = wait_with_timeout(&mut child, timeout);
+        let stdout = stdout.join().expect("stdout reader panicked");
+        let stderr = stderr.join().expect("stderr reader panicked");
+        (status, stdout, stderr)
+    });
+    let status = status.with_context(|| format!("Failed to run command: {}", label))?;
+    let Some(status) = status else {
+        anyhow::bail!(
+            "Command timed out after {}s and was killed: {}{}",
+            timeout.unwrap_or_default().as_secs(),
+            label,
+            format_command_output(&stdout, &stderr)
+        );
+    };
+    if !status.success() {
+        anyhow::bail!(
+            "Command failed with exit code: {}{}",
+            status,
+            format_command_output(&stdout, &stderr)
+        );
+    }
+    for (stream, output) in [("stdout", &stdout), ("stderr", &stderr)] {
+        if !output.trim().is_empty() {
+            debug!("{} {}: {}", label, stream, output.trim_end());
+        }
+    }
+    Ok(())
+}
+
+/// How often a command with a timeout is checked on.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for `child` to exit. Returns `None` if it was killed because
+/// `timeout` elapsed first.
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return child.wait().map(Some);
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            kill_command(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(COMMAND_POLL_INTERVAL);
+    }
+}
+
+/// Kills `child` and, since `execute_command` made it a process group
+/// leader, everything it started that is still in its group.
+#[cfg(unix)]
+fn kill_command(child: &mut Child) {
+    let Ok(pgid) = i32::try_from(child.id()) else {
+        let _ = child.kill();
+        return;
+    };
+    // SAFETY: kill(2) only takes integers and has no memory effects.
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+/// Windows has no process groups to kill; only the command itself is.
+#[cfg(not(unix))]
+fn kill_command(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// How much of a command's stdout and of its stderr is kept for the error
+/// and the debug log. Longer output keeps its end, where the error usually
+/// is.
+const COMMAND_OUTPUT_LIMIT: usize = 2048;
+
+/// Reads `reader` to the end, keeping only its last `limit` bytes. A read
+/// error ends the output early rather than failing the command.
+fn read_tail(mut reader: impl io::Read, limit: usize) -> String {
+    let mut tail = Vec::new();
+    let mut truncated = false;
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        tail.extend_from_slice(&buf[..read]);
+        if tail.len() > limit {
+            tail.drain(..tail.len() - limit);
+            truncated = true;
+        }
+    }
+    let text = String::from_utf8_lossy(&tail);
+    if truncated {
+        format!("...{text}")
+    } else {
+        text.into_owned()
+    }
+}
+
+/// The captured output appended to a failed command's error, stderr first.
+fn format_command_output(stdout: &str, stderr: &str) -> String {
+    [("stderr", stderr), ("stdout", stdout)]
+        .into_iter()
+        .filter(|(_, output)| !output.trim().is_empty())
+        .map(|(stream, output)| format!("\n{}:\n{}", stream, output.trim_end()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use upload::STAGING_SUFFIX;
+
+    #[test]
+    fn test_expand_placeholders() {
+        let stamp = Stamp {
+            date: "20260211".to_string(),
+            time: "020000".to_string(),
+            hostname: "web-1",
+        };
+        let result = expand_placeholders("/a/{archive_name}/{date}_{time}", &stamp, "demo");
+        assert_eq!(result, "/a/demo/20260211_020000");
+        let result = expand_placeholders("/backups/{hostname}/{archive_name}", &stamp, "demo");
+        assert_eq!(result, "/backups/web-1/demo");
+        assert_eq!(
+            stamp.expand_run_command("{hostname}-{date}"),
+            "web-1-20260211"
+        );
+        assert_eq!(resolve_hostname(Some(" db/2 ".to_string())), "db-2");
+        assert!(!resolve_hostname(Some(String::new())).is_empty());
+    }
+
+    #[test]
+    fn test_argv_command_rejects_empty() {
+        assert!(argv_command(&[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_command_reports_output() {
+        run_command("echo fine", None, None).unwrap();
+        let err =
+            run_command("echo dumped; echo 'access denied' >&2; exit 2", None, None).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("exit status: 2"), "{message}");
+        assert!(message.contains("stderr:\naccess denied"), "{message}");
+        assert!(message.contains("stdout:\ndumped"), "{message}");
+        assert_eq!(read_tail(&b"0123456789"[..], 4), "...6789");
+        assert_eq!(read_tail(&b"0123"[..], 4), "0123");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_command_timeout() {
+        let started = Instant::now();
+        // The background `sleep` keeps stdout open, so this only returns
+        // once the whole process group is gone.
+        let err = run_command(
+            "sleep 30 & echo started; wait",
+            None,
+            Some(Duration::from_millis(300)),
+        )
+        .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let message = err.to_string();
+        assert!(message.contains("timed out"), "{message}");
+        assert!(message.contains("started"), "{message}");
+        run_command("true", None, Some(Duration::from_secs(10))).unwrap();
+    }
+
+    #[test]
+    fn test_run_backup_atomic_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, remote, work) = (
+            dir.path().join("data"),
+            dir.path().join("remote"),
+            dir.path().join("work"),
+        );
+        for path in [&source, &remote, &work] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(source.join("notes.txt"), "backed up").unwrap();
+        let config_path = dir.path().join("backup.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[app]\nlocal_enabled = true\nlocal_base_dir = {:?}\nwork_dir = {:?}\n\
+                 [[backups]]\nsource_dir = {:?}\nremote_dir = \"nightly\"\narchive_name = \"data\"\n\
+                 atomic_remote = true\nkeep_archive = true\nwrite_checksum = true\n",
+                remote.display().to_string(),
+                work.display().to_string(),
+                source.display().to_string(),
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&[config_path.display().to_string()]).unwrap();
+
+        let report = run_backup(&config);
+        assert!(report.succeeded(), "{:?}", report.error);
+        let names = |dir: &Path| -> Vec<String> {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect()
+        };
+        for names in [names(&remote.join("nightly")), names(&work)] {
+            assert!(
+                names.iter().any(|name| name.ends_with(".tar.zst")),
+                "{names:?}"
+            );
+            assert!(
+                names.iter().any(|name| name.ends_with(".tar.zst.sha256")),
+                "{names:?}"
+            );
+            assert!(
+                !names.iter().any(|name| name.contains(STAGING_SUFFIX)),
+                "{names:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, remote, work) = (
+            dir.path().join("data"),
+            dir.path().join("remote"),
+            dir.path().join("work"),
+        );
+        for path in [&source, &remote, &work] {
+            fs::create_dir(path).unwrap();
+        }
+        fs::write(source.join("notes.txt"), "backed up").unwrap();
+        let config_path = dir.path().join("backup.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[app]\nlocal_enabled = true\nlocal_base_dir = {:?}\nwork_dir = {:?}\n\
+                 [[backups]]\nsource_dir = {:?}\nremote_dir = \"nightly\"\narchive_name = \"data\"\n",
+                remote.display().to_string(),
+                work.display().to_string(),
+                source.display().to_string(),
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&[config_path.display().to_string()]).unwrap();
+        assert_eq!(config.items()[0].name(), "data");
+
+        let report = run_backup(&config);
+        assert!(report.succeeded(), "{:?}", report.error);
+        assert_eq!(report.items.len(), 1);
+        assert_eq!(report.items[0].status, ItemStatus::Ok);
+        let uploaded: Vec<String> = fs::read_dir(remote.join("nightly"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(
+            uploaded.iter().any(|name| name.ends_with(".tar.zst")),
+            "{uploaded:?}"
+        );
+        assert!(Config::parse("[app]\nbackups = []").is_err());
+    }
+
+    #[test]
+    fn test_run_backup_encrypted_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, remote, work) = (
+            dir.path().join("data"),
+            dir.path().join("remote"),
+            dir.path().join("work"),
+        );
+        for path in [&source, &remote, &work] {
+            fs::create_dir(path).unwrap();
+        }
+        // Incompressible, so the archive ends up over max_part_size_mb.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..(5 << 19))
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        fs::write(source.join("noise.bin"), &noise).unwrap();
+        let identity = age::x25519::Identity::generate();
+        let config_path = dir.path().join("backup.toml");
+        fs::write(
+            &config_path,
+            format!(
+                "[app]\nlocal_enabled = true\nlocal_base_dir = {:?}\nwork_dir = {:?}\n\
+                 [app.encryption]\nrecipient = \"{}\"\n\
+                 [[backups]]\nsource_dir = {:?}\nremote_dir = \"nightly\"\narchive_name = \"data\"\n\
+                 max_part_size_mb = 1\n",
+                remote.display().to_string(),
+                work.display().to_string(),
+                identity.to_public(),
+                source.display().to_string(),
+            ),
+        )
+        .unwrap();
+        let config = Config::load(&[config_path.display().to_string()]).unwrap();
+        let report = run_backup(&config);
+        assert!(report.succeeded(), "{:?}", report.error);
+
+        let mut parts: Vec<PathBuf> = fs::read_dir(remote.join("nightly"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains(".tar.zst.age.part"))
+            .collect();
+        parts.sort();
+        assert_eq!(parts.len(), 3, "{parts:?}");
+        // Each part decrypts on its own, and together they are the archive.
+        let mut compressed = Vec::new();
+        for part in &parts {
+            assert!(fs::metadata(part).unwrap().len() <= 1 << 20);
+            age::Decryptor::new(File::open(part).unwrap())
+                .unwrap()
+                .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .unwrap()
+                .read_to_end(&mut compressed)
+                .unwrap();
+        }
+        let mut archive = tar::Archive::new(zstd::Decoder::new(&compressed[..]).unwrap());
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.header().entry_type().is_file())
+            .unwrap();
+        let mut restored = Vec::new();
+        entry.read_to_end(&mut restored).unwrap();
+        assert_eq!(restored, noise);
+    }
+
+    #[test]
+    fn test_check_date_format() {
+        assert!(check_date_format(DEFAULT_DATE_FORMAT).is_ok());
+        assert!(check_date_format("%Y-%m-%d_%H-%M").is_ok());
+        assert!(check_date_format("%Y%m%d%Q").is_err());
+        assert!(check_date_format("%Y/%m/%d").is_err());
+        assert!(check_date_format("%H%M").is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_normalize_archive_name() {
+        assert_eq!(normalize_archive_name("  "), "backup");
+        assert_eq!(normalize_archive_name(" project-a "), "project-a");
+    }
+}
@@ -0,0 +1,66 @@
+//! The lock that keeps overlapping runs from sharing a work directory.
+//!
+//! It is an advisory lock on an open file, held for the whole run, so the OS
+//! releases it when the process exits however it ends; a file left behind
+//! by a killed run is simply locked again by the next one. The file holds
+//! the PID of the run holding it, to name it in the error.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+
+/// Name of the lock file in `work_dir` unless `lock_file` is set.
+pub const DEFAULT_LOCK_FILE: &str = "backup-to-cloud.lock";
+
+/// Held until dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Locks `path`, creating it if needed. Returns `None` while another
+    /// process holds it.
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open lock file: {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .with_context(|| format!("Failed to write lock file: {}", path.display()))?;
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// PID recorded by the run holding the lock at `path`, if it can be read.
+pub fn holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEFAULT_LOCK_FILE);
+        let lock = RunLock::try_acquire(&path).unwrap().unwrap();
+        assert_eq!(holder(&path), Some(std::process::id()));
+        assert!(RunLock::try_acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(RunLock::try_acquire(&path).unwrap().is_some());
+    }
+}
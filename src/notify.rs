@@ -0,0 +1,549 @@
+//! End-of-run notifications and the report file.
+//!
+//! Every channel is best effort: the caller logs a delivery problem as a
+//! warning, and the run keeps the result its backups produced.
+
+use crate::format_bytes;
+use crate::state::ItemStatus;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{Message, SmtpTransport, Transport};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest text the Bot API accepts in one `sendMessage`.
+const TELEGRAM_MESSAGE_LIMIT: usize = 4096;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotifyConfig {
+    pub webhook_url: String,
+    /// Rendered with [`RunSummary::render`] into the payload's `text` field,
+    /// which is what Slack-compatible incoming webhooks display.
+    pub message: Option<String>,
+}
+
+/// How the SMTP connection is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// TLS from the first byte, usually on port 465.
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually on port 587. The
+    /// upgrade is required; a server that does not offer it is refused.
+    #[default]
+    Starttls,
+    /// No encryption, for a relay on localhost.
+    None,
+}
+
+impl SmtpTls {
+    fn default_port(self) -> u16 {
+        match self {
+            SmtpTls::Tls => 465,
+            SmtpTls::Starttls => 587,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to the usual port of `tls`.
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    /// Falls back to the `SMTP_PASSWORD` environment variable.
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelegramConfig {
+    /// Falls back to the `TELEGRAM_BOT_TOKEN` environment variable.
+    pub bot_token: Option<String>,
+    pub chat_id: String,
+}
+
+/// Outcome of one backup item.
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemReport {
+    pub name: String,
+    pub status: ItemStatus,
+    /// Archive size, counted only once it was uploaded everywhere.
+    pub bytes: u64,
+    pub duration_secs: u64,
+    pub failures: Vec<String>,
+    /// Absent if the item failed before its archive was complete.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveReport>,
+    /// One per uploader the item went to, in configuration order; empty if
+    /// the item failed before its upload.
+    pub uploads: Vec<UploadReport>,
+}
+
+/// How an item's archive came out.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveReport {
+    /// Regular files (or the database dump) that went in, uncompressed.
+    pub source_bytes: u64,
+    pub archive_bytes: u64,
+    /// `source_bytes / archive_bytes`, absent when either is zero.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ratio: Option<f64>,
+    pub duration_ms: u64,
+}
+
+impl ArchiveReport {
+    pub fn new(source_bytes: u64, archive_bytes: u64, duration: Duration) -> Self {
+        Self {
+            source_bytes,
+            archive_bytes,
+            ratio: (source_bytes > 0 && archive_bytes > 0)
+                .then(|| source_bytes as f64 / archive_bytes as f64),
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// Outcome of one item on one uploader.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadReport {
+    pub uploader: String,
+    pub status: ItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Including retries; `0` for a skipped uploader.
+    pub duration_ms: u64,
+    /// Archive size over `duration_ms`, for successful uploads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_sec: Option<u64>,
+    /// Skipped by `skip_if_unchanged`: the newest archive there was
+    /// identical, so nothing was uploaded.
+    pub unchanged: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub status: ItemStatus,
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub items: Vec<ItemReport>,
+    pub bytes: u64,
+    pub duration_secs: u64,
+    /// Failure messages of all items, in the order they happened.
+    pub failures: Vec<String>,
+    /// The error the run ended with, if any. It also covers problems outside
+    /// any item, such as an invalid uploader or a failed `pre_run_command`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    pub fn failed_items(&self) -> usize {
+        self.items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Failed)
+            .count()
+    }
+
+    /// Failure messages for display, falling back to the run error when no
+    /// item failed.
+    pub fn failure_lines(&self) -> Vec<&str> {
+        if self.failures.is_empty() {
+            self.error.as_deref().into_iter().collect()
+        } else {
+            self.failures.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// One-line headline, e.g. `Backup failed: 1 of 3 item(s) failed`.
+    pub fn headline(&self) -> String {
+        match self.status {
+            ItemStatus::Ok => format!("Backup ok: {} item(s)", self.items.len()),
+            ItemStatus::Failed if self.items.is_empty() => "Backup failed".to_string(),
+            ItemStatus::Failed => format!(
+                "Backup failed: {} of {} item(s) failed",
+                self.failed_items(),
+                self.items.len()
+            ),
+        }
+    }
+
+    /// Plain-text report: the headline, one line per item and the failure
+    /// messages.
+    pub fn plain_text(&self) -> String {
+        let mut text = format!(
+            "{}\n{} uploaded in {}s\n",
+            self.headline(),
+            format_bytes(self.bytes),
+            self.duration_secs
+        );
+        if !self.items.is_empty() {
+            text.push('\n');
+            for item in &self.items {
+                let status = match item.status {
+                    ItemStatus::Ok => "ok",
+                    ItemStatus::Failed => "FAILED",
+                };
+                let ratio = item
+                    .archive
+                    .as_ref()
+                    .and_then(|archive| archive.ratio)
+                    .map(|ratio| format!(", ratio {ratio:.2}"))
+                    .unwrap_or_default();
+                let unchanged: Vec<&str> = item
+                    .uploads
+                    .iter()
+                    .filter(|upload| upload.unchanged)
+                    .map(|upload| upload.uploader.as_str())
+                    .collect();
+                let unchanged = if unchanged.is_empty() {
+                    String::new()
+                } else {
+                    format!(", unchanged on {}", unchanged.join(", "))
+                };
+                text.push_str(&format!(
+                    "{:<6} {} ({}, {}s{}{})\n",
+                    status,
+                    item.name,
+                    format_bytes(item.bytes),
+                    item.duration_secs,
+                    ratio,
+                    unchanged
+                ));
+            }
+        }
+        let failures = self.failure_lines();
+        if !failures.is_empty() {
+            text.push_str("\nFailures:\n");
+            for failure in failures {
+                text.push_str(failure);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Replaces `{{status}}` (`ok` or `failed`), `{{items}}`, `{{failed}}`,
+    /// `{{bytes}}`, `{{duration}}` (seconds) and `{{failures}}` (one message
+    /// per line) in `template`.
+    pub fn render(&self, template: &str) -> String {
+        let status = match self.status {
+            ItemStatus::Ok => "ok",
+            ItemStatus::Failed => "failed",
+        };
+        template
+            .replace("{{status}}", status)
+            .replace("{{items}}", &self.items.len().to_string())
+            .replace("{{failed}}", &self.failed_items().to_string())
+            .replace("{{bytes}}", &self.bytes.to_string())
+            .replace("{{duration}}", &self.duration_secs.to_string())
+            .replace("{{failures}}", &self.failure_lines().join("\n"))
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    summary: &'a RunSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// Writes the summary as JSON to `path`, creating its directory. The report
+/// goes to a temporary file first and is renamed over `path`, so a script
+/// polling it never reads half of one.
+pub fn write_report(path: &Path, summary: &RunSummary) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    }
+    let mut json = serde_json::to_vec_pretty(summary).context("Failed to serialize report")?;
+    json.push(b'\n');
+    let partial = path.with_file_name(format!(
+        ".{}.partial",
+        path.file_name()
+            .with_context(|| format!("No file name: {}", path.display()))?
+            .to_string_lossy()
+    ));
+    fs::write(&partial, json)
+        .with_context(|| format!("Failed to write report: {}", partial.display()))?;
+    fs::rename(&partial, path)
+        .with_context(|| format!("Failed to write report: {}", path.display()))
+}
+
+/// POSTs the summary as JSON to `webhook_url`.
+pub fn send_webhook(config: &NotifyConfig, summary: &RunSummary) -> Result<()> {
+    let payload = WebhookPayload {
+        summary,
+        text: config
+            .message
+            .as_deref()
+            .map(|template| summary.render(template)),
+    };
+    let body = serde_json::to_vec(&payload).context("Failed to serialize webhook payload")?;
+    let response = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .context("Failed to create HTTP client")?
+        .post(&config.webhook_url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .with_context(|| format!("Webhook POST to {} failed", config.webhook_url))?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!(
+            "Webhook answered with HTTP {}: {}",
+            status,
+            crate::error_body(response)
+        );
+    }
+    Ok(())
+}
+
+/// Emails the plain-text report to every address in `to`.
+pub fn send_email(config: &SmtpConfig, summary: &RunSummary) -> Result<()> {
+    if config.to.is_empty() {
+        anyhow::bail!("[app.smtp] has no recipients in `to`");
+    }
+    let from: Mailbox = config
+        .from
+        .parse()
+        .with_context(|| format!("Invalid smtp from address: {}", config.from))?;
+    let mut message = Message::builder()
+        .from(from)
+        .subject(format!("[backup-to-cloud] {}", summary.headline()))
+        .header(ContentType::TEXT_PLAIN);
+    for to in &config.to {
+        let to: Mailbox = to
+            .parse()
+            .with_context(|| format!("Invalid smtp to address: {}", to))?;
+        message = message.to(to);
+    }
+    let message = message
+        .body(summary.plain_text())
+        .context("Failed to build email")?;
+
+    let tls = || {
+        TlsParameters::new(config.host.clone())
+            .with_context(|| format!("Failed to set up TLS for {}", config.host))
+    };
+    let mut transport = SmtpTransport::builder_dangerous(&config.host)
+        .port(config.port.unwrap_or(config.tls.default_port()))
+        .timeout(Some(TIMEOUT))
+        .tls(match config.tls {
+            SmtpTls::Tls => Tls::Wrapper(tls()?),
+            SmtpTls::Starttls => Tls::Required(tls()?),
+            SmtpTls::None => Tls::None,
+        });
+    if let Some(username) = &config.username {
+        let password = config
+            .password
+            .clone()
+            .or_else(|| env::var("SMTP_PASSWORD").ok())
+            .context("smtp username is set but neither password nor SMTP_PASSWORD is")?;
+        transport = transport.credentials(Credentials::new(username.clone(), password));
+    }
+    transport
+        .build()
+        .send(&message)
+        .with_context(|| format!("Failed to send email via {}", config.host))?;
+    Ok(())
+}
+
+/// Sends the plain-text report to `chat_id`, split into several messages when
+/// it exceeds Telegram's length limit.
+pub fn send_telegram(config: &TelegramConfig, summary: &RunSummary) -> Result<()> {
+    let token = config
+        .bot_token
+        .clone()
+        .or_else(|| env::var("TELEGRAM_BOT_TOKEN").ok())
+        .filter(|token| !token.trim().is_empty())
+        .context("[app.telegram] needs bot_token or TELEGRAM_BOT_TOKEN")?;
+    let icon = match summary.status {
+        ItemStatus::Ok => "\u{2705}",
+        ItemStatus::Failed => "\u{1F6A8}",
+    };
+    let text = format!("{icon} {}", summary.plain_text());
+    let client = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .context("Failed to create HTTP client")?;
+    let url = format!("https://api.telegram.org/bot{}/sendMessage", token.trim());
+    for chunk in split_message(&text, TELEGRAM_MESSAGE_LIMIT) {
+        let body = serde_json::to_vec(&serde_json::json!({
+            "chat_id": config.chat_id,
+            "text": chunk,
+        }))
+        .context("Failed to serialize Telegram message")?;
+        // The token is part of the URL, so it is kept out of error messages.
+        let response = client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .map_err(|err| err.without_url())
+            .context("Telegram sendMessage failed")?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!(
+                "Telegram sendMessage answered with HTTP {}: {}",
+                status,
+                crate::error_body(response)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Splits `text` into pieces of at most `limit` characters, breaking after a
+/// newline where possible.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        let mut line_len = line.chars().count();
+        if current_len + line_len > limit && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        while line_len > limit {
+            let split = line
+                .char_indices()
+                .nth(limit)
+                .map_or(line.len(), |(index, _)| index);
+            chunks.push(line[..split].to_string());
+            line = &line[split..];
+            line_len -= limit;
+        }
+        current.push_str(line);
+        current_len += line_len;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("short\n", 10), ["short\n"]);
+        assert_eq!(
+            split_message("first\nsecond\nthird\n", 13),
+            ["first\nsecond\n", "third\n"]
+        );
+        let long = "\u{1F6A8}".repeat(25);
+        let chunks = split_message(&long, 10);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 10));
+        assert_eq!(chunks.concat(), long);
+    }
+
+    #[test]
+    fn test_render() {
+        let summary = RunSummary {
+            status: ItemStatus::Failed,
+            started_at: Local::now(),
+            finished_at: Local::now(),
+            items: vec![
+                ItemReport {
+                    name: "app".to_string(),
+                    status: ItemStatus::Ok,
+                    bytes: 2048,
+                    duration_secs: 3,
+                    failures: Vec::new(),
+                    archive: Some(ArchiveReport::new(8192, 2048, Duration::from_millis(1500))),
+                    uploads: vec![
+                        UploadReport {
+                            uploader: "S3".to_string(),
+                            status: ItemStatus::Ok,
+                            error: None,
+                            duration_ms: 1500,
+                            bytes_per_sec: Some(1365),
+                            unchanged: false,
+                        },
+                        UploadReport {
+                            uploader: "WebDAV".to_string(),
+                            status: ItemStatus::Ok,
+                            error: None,
+                            duration_ms: 40,
+                            bytes_per_sec: None,
+                            unchanged: true,
+                        },
+                    ],
+                },
+                ItemReport {
+                    name: "db".to_string(),
+                    status: ItemStatus::Failed,
+                    bytes: 0,
+                    duration_secs: 1,
+                    failures: vec!["[db] upload failed on S3: timeout".to_string()],
+                    archive: None,
+                    uploads: vec![UploadReport {
+                        uploader: "S3".to_string(),
+                        status: ItemStatus::Failed,
+                        error: Some("timeout".to_string()),
+                        duration_ms: 30000,
+                        bytes_per_sec: None,
+                        unchanged: false,
+                    }],
+                },
+            ],
+            bytes: 2048,
+            duration_secs: 4,
+            failures: vec!["[db] upload failed on S3: timeout".to_string()],
+            error: Some("Backup finished with 1 failure(s)".to_string()),
+        };
+        assert_eq!(
+            summary.render("Backup {{status}}: {{failed}}/{{items}} failed\n{{failures}}"),
+            "Backup failed: 1/2 failed\n[db] upload failed on S3: timeout"
+        );
+
+        let payload = serde_json::to_value(WebhookPayload {
+            summary: &summary,
+            text: None,
+        })
+        .unwrap();
+        let text = summary.plain_text();
+        assert!(text.starts_with("Backup failed: 1 of 2 item(s) failed\n"));
+        assert!(text.contains("ok     app (2.0 KiB, 3s, ratio 4.00, unchanged on WebDAV)\n"));
+        assert!(text.contains("FAILED db (0 B, 1s)\n"));
+        assert!(text.ends_with("Failures:\n[db] upload failed on S3: timeout\n"));
+
+        assert_eq!(payload["status"], "failed");
+        assert_eq!(payload["items"][1]["name"], "db");
+        assert_eq!(payload["items"][1]["uploads"][0]["error"], "timeout");
+        assert!(payload["items"][0]["uploads"][0].get("error").is_none());
+        assert_eq!(payload["items"][0]["uploads"][1]["unchanged"], true);
+        assert_eq!(payload["items"][0]["archive"]["ratio"], 4.0);
+        assert!(payload["items"][1].get("archive").is_none());
+        assert!(payload.get("text").is_none());
+
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("reports/run-20260211.json");
+        write_report(&report, &summary).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+        assert_eq!(written["failures"], payload["failures"]);
+        assert_eq!(fs::read_dir(report.parent().unwrap()).unwrap().count(), 1);
+    }
+}
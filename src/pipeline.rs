@@ -0,0 +1,1380 @@
+//! The stages a backup item goes through, from its source to the archive
+//! uploaded everywhere.
+
+use crate::archive::{
+    self, ArchiveFormat, ArchiveOptions, PathFilter, SourceSummary, SymlinkMode, choose_level,
+    create_archive, create_database_archive, create_output_archive, ensure_archive_outside_source,
+    measure_source, write_archive, write_database_archive, write_output_archive,
+};
+use crate::config::BackupItem;
+use crate::database::DatabaseDump;
+use crate::dedup::{self, ChunkIndex, ChunkedArchive};
+use crate::encryption;
+use crate::gpg::SignTarget;
+use crate::notify::{ArchiveReport, UploadReport};
+use crate::retention::{prune_local_archives, with_retention};
+use crate::signing;
+use crate::split;
+use crate::state::ItemStatus;
+use crate::stream::{self, StreamDigest};
+use crate::upload::{
+    ChunkUpload, STAGING_SUFFIX, UploadOutcome, UploadPolicy, UploadTarget, skip_unchanged,
+    stage_files, unstage_files, upload_to_target, upload_with_retries,
+};
+use crate::uploader::Uploader;
+use crate::verify::verify_stream;
+use crate::{
+    RunContext, Stamp, archive_extension, argv_command, build_archive_path, execute_command,
+    expand_placeholders, format_bytes, normalize_archive_name, prepare_work_dir,
+    resolve_source_paths, run_command, shell_command, sidecar_path,
+};
+use anyhow::{Context, Result};
+use chrono::Local;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+/// The targets `item` goes to, in configuration order.
+pub fn item_targets<'a>(
+    targets: &'a mut [UploadTarget],
+    item: &BackupItem,
+) -> impl Iterator<Item = &'a mut UploadTarget> {
+    targets
+        .iter_mut()
+        .filter(|target| item.uses_uploader(target.uploader.name()))
+}
+
+/// Whether an item with `stream_upload` can be streamed this run: every
+/// backend of the item still in use has to accept a stream.
+pub fn can_stream(targets: &[UploadTarget], item: &BackupItem, base_name: &str) -> bool {
+    let mut active = targets
+        .iter()
+        .filter(|target| !target.degraded && item.uses_uploader(target.uploader.name()))
+        .peekable();
+    if active.peek().is_none() {
+        return false;
+    }
+    for target in active {
+        if !target.uploader.supports_streaming() {
+            info!(
+                "[{base_name}] {} cannot take a streamed upload, writing a local archive instead",
+                target.uploader.name()
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/// Creates the archive straight into the uploads of every backend, without a
+/// local copy. The `.sha256` and `.sig` files are made from the digests taken
+/// on the way and uploaded once the archive is complete. A stream cannot be
+/// replayed, so the archive itself is tried once whatever `upload_attempts`
+/// says. Yields the archive size, or `None` after recording a failure.
+pub fn stream_item(
+    item: &BackupItem,
+    ctx: &mut RunContext,
+    source: &ArchiveSource,
+    archive_path: &Path,
+    options: &ArchiveOptions,
+    remote_dir: &str,
+    failures: &mut Vec<String>,
+) -> Result<Option<u64>> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let work_dir = archive_path.parent().unwrap_or(Path::new("."));
+    let final_name = archive_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Archive file name is not valid UTF-8")?;
+    let atomic = item.atomic_remote.unwrap_or(false);
+    let upload_name = if atomic {
+        format!("{final_name}{STAGING_SUFFIX}")
+    } else {
+        final_name.to_string()
+    };
+
+    info!("Streaming archive: {}", final_name);
+    let started = Instant::now();
+    let active = ctx
+        .targets
+        .iter()
+        .filter(|target| !target.degraded && item.uses_uploader(target.uploader.name()))
+        .count();
+    let (tee, readers) = stream::pipe(active);
+    let mut readers = readers.into_iter();
+    let mut summary = SourceSummary::default();
+    let (created, streamed): (Result<StreamDigest>, Vec<Option<Result<()>>>) =
+        thread::scope(|scope| {
+            let handles: Vec<_> = item_targets(&mut ctx.targets, item)
+                .map(|target| {
+                    if target.degraded {
+                        return None;
+                    }
+                    let reader = readers.next().expect("one reader per active target");
+                    let uploader = target.uploader.as_mut();
+                    let upload_name = &upload_name;
+                    Some(scope.spawn(move || {
+                        info!("Uploading to {}", uploader.name());
+                        let remote_dir = uploader.normalize_remote_dir(remote_dir)?;
+                        uploader.upload_stream(Box::new(reader), upload_name, &remote_dir)
+                    }))
+                })
+                .collect();
+            // The tee is dropped before the uploads are joined, so a failed
+            // archive ends their streams with an error instead of hanging.
+            let created = {
+                let mut tee = tee;
+                let written = match source {
+                    ArchiveSource::Paths(source_paths) => {
+                        write_archive(source_paths, &mut tee, options)
+                    }
+                    ArchiveSource::Database(database) => {
+                        info!(
+                            "Dumping {} database for backup item: {}",
+                            database.engine.as_str(),
+                            base_name
+                        );
+                        write_database_archive(
+                            database,
+                            &database.entry_name(base_name),
+                            work_dir,
+                            &mut tee,
+                            options,
+                        )
+                    }
+                    ArchiveSource::CommandOutput { entry_name, stamp } => {
+                        spool_command_output(item, stamp, base_name, work_dir).and_then(|spool| {
+                            write_output_archive(spool, entry_name, &mut tee, options)
+                        })
+                    }
+                };
+                written.and_then(|archived| {
+                    summary = archived;
+                    tee.finish().context("Failed to finish archive stream")
+                })
+            };
+            let streamed = handles
+                .into_iter()
+                .map(|handle| {
+                    handle.map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow::anyhow!("upload thread panicked")))
+                    })
+                })
+                .collect();
+            (created, streamed)
+        });
+    let digest = match created {
+        Ok(digest) => {
+            ctx.archive = Some(archive_report(
+                base_name,
+                &summary,
+                digest.size,
+                started.elapsed(),
+            ));
+            digest
+        }
+        Err(err) => {
+            // Once every upload has stopped the archive fails for want of a
+            // reader, and the uploads hold the errors worth reporting.
+            let uploads_stopped = err.chain().any(|cause| {
+                cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+            });
+            if !uploads_stopped {
+                record_failure(
+                    failures,
+                    format!("[{base_name}] create archive failed: {err:#}"),
+                );
+                return Ok(None);
+            }
+            for (target, streamed) in item_targets(&mut ctx.targets, item).zip(streamed) {
+                if let Some(Err(err)) = streamed {
+                    target.record(false, &ctx.policy);
+                    record_failure(
+                        failures,
+                        format!(
+                            "[{base_name}] upload failed on {}: {}",
+                            target.uploader.name(),
+                            err
+                        ),
+                    );
+                }
+            }
+            return Ok(None);
+        }
+    };
+
+    let staged = |path: PathBuf| {
+        if atomic {
+            sidecar_path(&path, STAGING_SUFFIX.trim_start_matches('.'))
+        } else {
+            path
+        }
+    };
+    let mut sidecars = Vec::new();
+    let mut written = Ok(());
+    if item.write_checksum.unwrap_or(true) {
+        let checksum_path = staged(sidecar_path(archive_path, "sha256"));
+        written = signing::write_checksum_line(&digest.sha256, final_name, &checksum_path)
+            .context("checksum failed");
+        sidecars.push(checksum_path);
+    }
+    if let Some(signer) = &ctx.signer
+        && written.is_ok()
+    {
+        let sig_path = staged(sidecar_path(archive_path, "sig"));
+        written = signer
+            .sign_prehashed(digest.sha512.clone(), final_name, &sig_path)
+            .context("signing failed");
+        sidecars.push(sig_path);
+    }
+    // Of a streamed archive only the checksum file is on disk, so config
+    // checks only allow `target = "checksum"` here.
+    if let Some(gpg) = &ctx.gpg_signer
+        && written.is_ok()
+    {
+        let sig_path = staged(sidecar_path(archive_path, "sig"));
+        written = gpg
+            .sign_file(&staged(sidecar_path(archive_path, "sha256")), &sig_path)
+            .context("GPG signing failed");
+        sidecars.push(sig_path);
+    }
+    if let Err(err) = written {
+        remove_upload_files(&sidecars);
+        record_failure(failures, format!("[{base_name}] {err:#}"));
+        return Ok(None);
+    }
+
+    let policy = &ctx.policy;
+    let outcomes: Vec<Option<UploadOutcome>> = thread::scope(|scope| {
+        let handles: Vec<_> = item_targets(&mut ctx.targets, item)
+            .zip(streamed)
+            .map(|(target, streamed)| {
+                let streamed = streamed?;
+                let uploader = target.uploader.as_mut();
+                let (sidecars, digest, upload_name) = (&sidecars, &digest, &upload_name);
+                Some(scope.spawn(move || {
+                    let upload = streamed.and_then(|()| {
+                        finish_stream(
+                            uploader,
+                            upload_name,
+                            sidecars,
+                            remote_dir,
+                            policy,
+                            atomic,
+                            digest,
+                            work_dir,
+                        )
+                    });
+                    with_retention(uploader, upload, started.elapsed(), remote_dir, item)
+                }))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle.map(|handle| {
+                    handle.join().unwrap_or_else(|_| UploadOutcome {
+                        upload: Err(anyhow::anyhow!("upload thread panicked")),
+                        elapsed: Duration::ZERO,
+                        retention: Ok(()),
+                        unchanged: false,
+                    })
+                })
+            })
+            .collect()
+    });
+    let upload_failed = record_outcomes(
+        item_targets(&mut ctx.targets, item),
+        outcomes,
+        policy,
+        base_name,
+        digest.size,
+        &mut ctx.uploads,
+        failures,
+    );
+    remove_upload_files(&sidecars);
+    Ok((!upload_failed).then_some(digest.size))
+}
+
+/// Moves a streamed archive to its final name, verifies it and uploads its
+/// sidecar files.
+#[allow(clippy::too_many_arguments)]
+pub fn finish_stream(
+    uploader: &mut dyn Uploader,
+    upload_name: &str,
+    sidecars: &[PathBuf],
+    remote_dir: &str,
+    policy: &UploadPolicy,
+    atomic: bool,
+    digest: &StreamDigest,
+    work_dir: &Path,
+) -> Result<()> {
+    let normalized = uploader.normalize_remote_dir(remote_dir)?;
+    let remote_name = if atomic {
+        let final_name = upload_name
+            .strip_suffix(STAGING_SUFFIX)
+            .context("Staged file is missing the staging suffix")?;
+        uploader.rename(&normalized, upload_name, final_name)?;
+        final_name
+    } else {
+        upload_name
+    };
+    verify_stream(
+        uploader,
+        &normalized,
+        remote_name,
+        digest,
+        work_dir,
+        policy.verify_mode,
+    )
+    .with_context(|| format!("Verification of {} failed", remote_name))?;
+    upload_with_retries(uploader, sidecars, remote_dir, policy, atomic)
+}
+
+/// Removes files that were only written for the uploads, whatever their
+/// outcome: the sidecars of a streamed item, which has no archive to keep
+/// them with, and the parts of a split archive.
+pub fn remove_upload_files(files: &[PathBuf]) {
+    for file in files {
+        if let Err(err) = fs::remove_file(file) {
+            warn!("Failed to remove {}: {}", file.display(), err);
+        }
+    }
+}
+
+/// Records each backend's outcome of an item in configuration order, `None`
+/// standing for a degraded backend that was skipped, and adds it to
+/// `uploads` with its throughput for the `archive_size` bytes. Returns
+/// whether any upload failed.
+pub fn record_outcomes<'a>(
+    targets: impl Iterator<Item = &'a mut UploadTarget>,
+    outcomes: Vec<Option<UploadOutcome>>,
+    policy: &UploadPolicy,
+    base_name: &str,
+    archive_size: u64,
+    uploads: &mut Vec<UploadReport>,
+    failures: &mut Vec<String>,
+) -> bool {
+    let mut upload_failed = false;
+    for (target, outcome) in targets.zip(outcomes) {
+        let name = target.uploader.name().to_string();
+        let Some(outcome) = outcome else {
+            upload_failed = true;
+            info!(
+                item = base_name,
+                uploader = name.as_str(),
+                status = "skipped",
+                "[{base_name}] upload to {name} skipped"
+            );
+            uploads.push(UploadReport {
+                uploader: name.clone(),
+                status: ItemStatus::Failed,
+                error: Some("provider marked degraded".to_string()),
+                duration_ms: 0,
+                bytes_per_sec: None,
+                unchanged: false,
+            });
+            record_failure(
+                failures,
+                format!("[{base_name}] upload skipped on {name}: provider marked degraded"),
+            );
+            continue;
+        };
+        target.record(outcome.upload.is_ok(), policy);
+        let status = if outcome.upload.is_ok() {
+            ItemStatus::Ok
+        } else {
+            ItemStatus::Failed
+        };
+        let duration_ms = outcome.elapsed.as_millis() as u64;
+        if outcome.unchanged {
+            info!(
+                item = base_name,
+                uploader = name.as_str(),
+                status = "unchanged",
+                duration_ms,
+                "[{base_name}] upload to {name} unchanged, skipped"
+            );
+            uploads.push(UploadReport {
+                uploader: name,
+                status,
+                error: None,
+                duration_ms,
+                bytes_per_sec: None,
+                unchanged: true,
+            });
+            continue;
+        }
+        let bytes_per_sec = (outcome.upload.is_ok() && !outcome.elapsed.is_zero())
+            .then(|| (archive_size as f64 / outcome.elapsed.as_secs_f64()) as u64);
+        info!(
+            item = base_name,
+            uploader = name.as_str(),
+            status = status.as_str(),
+            duration_ms,
+            bytes_per_sec,
+            "[{base_name}] upload to {name} finished in {:.1}s{}",
+            outcome.elapsed.as_secs_f64(),
+            bytes_per_sec
+                .map(|rate| format!(" ({}/s)", format_bytes(rate)))
+                .unwrap_or_default()
+        );
+        uploads.push(UploadReport {
+            uploader: name.clone(),
+            status,
+            error: outcome.upload.as_ref().err().map(|err| err.to_string()),
+            duration_ms,
+            bytes_per_sec,
+            unchanged: false,
+        });
+        match outcome.upload {
+            Ok(()) => {
+                if let Err(err) = outcome.retention {
+                    record_failure(
+                        failures,
+                        format!("[{base_name}] retention failed on {}: {err:#}", name),
+                    );
+                }
+            }
+            Err(err) => {
+                upload_failed = true;
+                record_failure(
+                    failures,
+                    format!("[{base_name}] upload failed on {}: {}", name, err),
+                );
+            }
+        }
+    }
+    upload_failed
+}
+
+/// What an item archives: files and directories on disk, or a database
+/// dump.
+pub enum ArchiveSource<'a> {
+    Paths(Vec<PathBuf>),
+    Database(&'a DatabaseDump),
+    /// The stdout of the item's command, as the one entry `entry_name`. The
+    /// command is run, with `stamp` for its placeholders, as the archive is
+    /// created.
+    CommandOutput {
+        entry_name: String,
+        stamp: &'a Stamp,
+    },
+}
+
+pub fn record_failure(failures: &mut Vec<String>, message: String) {
+    error!("{}", message);
+    failures.push(message);
+}
+
+/// Runs a single backup item. Item-level problems are pushed onto `failures`
+/// so the remaining items still run; only unrecoverable errors are returned.
+/// Yields the archive size once it has been uploaded everywhere. With
+/// `modified_since` only files changed after it are archived, under an
+/// `-incr` name.
+///
+/// Each stage records its own failure and yields `None`, which ends the
+/// item: the source and archive options, then either streaming or creating
+/// the archive, its checksum and signatures, the split into parts, the
+/// uploads and pruning the local files.
+pub fn backup_item(
+    item: &BackupItem,
+    ctx: &mut RunContext,
+    modified_since: Option<SystemTime>,
+    failures: &mut Vec<String>,
+) -> Result<u64> {
+    let stamp = Stamp::now(item.date_format());
+    let base_name = normalize_archive_name(&item.archive_name);
+    let Some(source) = item_source(item, &stamp, failures)? else {
+        return Ok(0);
+    };
+    let Some(options) = archive_options(item, ctx, &source, modified_since, failures) else {
+        return Ok(0);
+    };
+    let work_dir = match prepare_work_dir(item.work_dir.as_deref().or(ctx.work_dir.as_deref())) {
+        Ok(work_dir) => work_dir,
+        Err(err) => {
+            record_failure(failures, format!("[{base_name}] {err:#}"));
+            return Ok(0);
+        }
+    };
+    let archive_path = item_archive_path(item, &work_dir, &stamp, modified_since, &options)?;
+    let remote_dir = expand_placeholders(&item.remote_dir, &stamp, base_name);
+    if item.stream_upload.unwrap_or(false) && can_stream(&ctx.targets, item, base_name) {
+        let Some(archive_size) = stream_item(
+            item,
+            ctx,
+            &source,
+            &archive_path,
+            &options,
+            &remote_dir,
+            failures,
+        )?
+        else {
+            return Ok(0);
+        };
+        finish_item(item, &source, &stamp, failures)?;
+        return Ok(archive_size);
+    }
+
+    let Some(archive) = create_item_archive(
+        item,
+        ctx,
+        &source,
+        archive_path,
+        &options,
+        &work_dir,
+        failures,
+    )?
+    else {
+        return Ok(0);
+    };
+    let Some(archive) = sign_archive(item, ctx, archive, failures) else {
+        return Ok(0);
+    };
+    let Some(mut archive) = split_archive(
+        item,
+        ctx,
+        archive,
+        &options,
+        &work_dir,
+        &remote_dir,
+        failures,
+    ) else {
+        return Ok(0);
+    };
+    if !upload_item(item, ctx, &mut archive, &remote_dir, &work_dir, failures) {
+        return Ok(0);
+    }
+    prune_item_files(item, &archive)?;
+    finish_item(item, &source, &stamp, failures)?;
+    Ok(archive.size)
+}
+
+/// What the item's archive is made of. `validate_config` rejects a database
+/// or `command_output_name` combined with a source path.
+pub fn item_source<'a>(
+    item: &'a BackupItem,
+    stamp: &'a Stamp,
+    failures: &mut Vec<String>,
+) -> Result<Option<ArchiveSource<'a>>> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    Ok(
+        match (item.database.as_ref(), item.command_output_name.as_deref()) {
+            (Some(database), _) => Some(ArchiveSource::Database(database)),
+            (None, Some(entry_name)) => Some(ArchiveSource::CommandOutput {
+                entry_name: expand_placeholders(entry_name, stamp, base_name)
+                    .trim()
+                    .to_string(),
+                stamp,
+            }),
+            (None, None) => {
+                prepare_source_paths(item, stamp, base_name, failures)?.map(ArchiveSource::Paths)
+            }
+        },
+    )
+}
+
+/// The filter, encryption and compression of the item's archive. With
+/// `auto_level` or `collect_stats`, a path source is measured first.
+pub fn archive_options(
+    item: &BackupItem,
+    ctx: &RunContext,
+    source: &ArchiveSource,
+    modified_since: Option<SystemTime>,
+    failures: &mut Vec<String>,
+) -> Option<ArchiveOptions> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let filter = match PathFilter::new(&item.include, &item.exclude) {
+        Ok(filter) => filter,
+        Err(err) => {
+            record_failure(failures, format!("[{base_name}] {err:#}"));
+            return None;
+        }
+    };
+    let recipient = match item.encryption.as_ref().or(ctx.encryption.as_ref()) {
+        Some(encryption) => match encryption.load_recipient() {
+            Ok(recipient) => Some(recipient),
+            Err(err) => {
+                record_failure(failures, format!("[{base_name}] {err:#}"));
+                return None;
+            }
+        },
+        None => None,
+    };
+    // Chunks are cut from the plain tar stream, which compression would
+    // scramble, and compressed one by one instead.
+    let format = if item.dedup.unwrap_or(false) {
+        ArchiveFormat::Tar
+    } else {
+        item.archive_format
+    };
+    let mut options = ArchiveOptions {
+        symlink_mode: item.source_is_symlink,
+        follow_symlinks: item.follow_symlinks.unwrap_or(false),
+        format,
+        level: item.compression_level.unwrap_or(match item.archive_format {
+            ArchiveFormat::Gzip => archive::DEFAULT_GZIP_LEVEL,
+            _ => ctx.default_level,
+        }),
+        // Each part of a split archive gets its own age stream, so it can be
+        // decrypted without the others.
+        segment_size: recipient
+            .as_ref()
+            .and(item.max_part_size_mb)
+            .map(|mb| encryption::segment_size(mb << 20)),
+        recipient,
+        filter,
+        modified_since,
+        threads: ctx.compression_threads,
+        owner_uid: item.owner_uid,
+        owner_gid: item.owner_gid,
+    };
+    let auto_level = item.auto_level.unwrap_or(false);
+    let collect_stats = item.collect_stats.unwrap_or(false);
+    if (auto_level || collect_stats)
+        && let ArchiveSource::Paths(source_paths) = source
+    {
+        match measure_source(source_paths, options.filter.as_ref()) {
+            Ok(summary) => {
+                if collect_stats {
+                    info!("[{base_name}] {}", format_source_stats(&summary));
+                }
+                if auto_level {
+                    let (level, reason) = choose_level(&summary);
+                    info!(
+                        "[{base_name}] auto_level picked zstd level {}: {} ({} files, {})",
+                        level,
+                        reason,
+                        summary.files,
+                        format_bytes(summary.bytes)
+                    );
+                    options.level = level;
+                }
+            }
+            Err(err) => warn!(
+                "[{base_name}] could not measure the source, using zstd level {}: {err}",
+                options.level
+            ),
+        }
+    }
+    Some(options)
+}
+
+/// Where in `work_dir` the archive goes, named for `stamp`, or as an
+/// `-incr` archive with `modified_since`.
+pub fn item_archive_path(
+    item: &BackupItem,
+    work_dir: &Path,
+    stamp: &Stamp,
+    modified_since: Option<SystemTime>,
+    options: &ArchiveOptions,
+) -> Result<PathBuf> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let date = match modified_since {
+        Some(since) => {
+            info!(
+                "[{base_name}] incremental: taking files modified after {}",
+                chrono::DateTime::<Local>::from(since).format("%Y-%m-%d %H:%M:%S")
+            );
+            format!("{}-incr", stamp.date)
+        }
+        None => stamp.date.clone(),
+    };
+    build_archive_path(
+        work_dir,
+        &item.archive_naming(),
+        &date,
+        &archive_extension(options.format, options.recipient.is_some()),
+    )
+}
+
+/// An item's local archive on its way to the uploaders, filled in stage by
+/// stage.
+pub struct ItemArchive {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The archive and its sidecars, removed once it is uploaded everywhere.
+    pub files: Vec<PathBuf>,
+    /// What is uploaded in place of the archive: its parts and manifest, or
+    /// with dedup the recipe. Empty for the archive itself.
+    pub parts: Vec<PathBuf>,
+    pub dedup: Option<DedupArchive>,
+}
+
+/// Creates the archive at `archive_path` and puts its compression into the
+/// item's report.
+pub fn create_item_archive(
+    item: &BackupItem,
+    ctx: &mut RunContext,
+    source: &ArchiveSource,
+    archive_path: PathBuf,
+    options: &ArchiveOptions,
+    work_dir: &Path,
+    failures: &mut Vec<String>,
+) -> Result<Option<ItemArchive>> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    info!("Creating archive: {}", archive_path.display());
+    let started = Instant::now();
+    let created = match source {
+        ArchiveSource::Paths(source_paths) => source_paths
+            .iter()
+            .try_for_each(|source_path| ensure_archive_outside_source(source_path, &archive_path))
+            .and_then(|()| create_archive(source_paths, &archive_path, options)),
+        ArchiveSource::Database(database) => {
+            info!(
+                "Dumping {} database for backup item: {}",
+                database.engine.as_str(),
+                base_name
+            );
+            create_database_archive(
+                database,
+                &database.entry_name(base_name),
+                &archive_path,
+                options,
+            )
+        }
+        ArchiveSource::CommandOutput { entry_name, stamp } => {
+            match spool_command_output(item, stamp, base_name, work_dir) {
+                Ok(spool) => create_output_archive(spool, entry_name, &archive_path, options),
+                Err(err) => {
+                    record_failure(failures, format!("[{base_name}] command failed: {err}"));
+                    return Ok(None);
+                }
+            }
+        }
+    };
+    let summary = match created {
+        Ok(summary) => summary,
+        Err(err) => {
+            record_failure(
+                failures,
+                format!("[{base_name}] create archive failed: {err}"),
+            );
+            return Ok(None);
+        }
+    };
+    let size = fs::metadata(&archive_path)
+        .with_context(|| {
+            format!(
+                "Failed to read archive metadata: {}",
+                archive_path.display()
+            )
+        })?
+        .len();
+    ctx.archive = Some(archive_report(base_name, &summary, size, started.elapsed()));
+    Ok(Some(ItemArchive {
+        files: vec![archive_path.clone()],
+        path: archive_path,
+        size,
+        parts: Vec::new(),
+        dedup: None,
+    }))
+}
+
+/// Writes the archive's `.sha256` checksum and `.sig` signature next to it.
+pub fn sign_archive(
+    item: &BackupItem,
+    ctx: &RunContext,
+    mut archive: ItemArchive,
+    failures: &mut Vec<String>,
+) -> Option<ItemArchive> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let archive_path = &archive.path;
+    if item.write_checksum.unwrap_or(true) {
+        let checksum_path = sidecar_path(archive_path, "sha256");
+        if let Err(err) = signing::write_checksum(archive_path, &checksum_path) {
+            record_failure(failures, format!("[{base_name}] checksum failed: {err}"));
+            return None;
+        }
+        archive.files.push(checksum_path);
+    }
+    if let Some(signer) = &ctx.signer {
+        let sig_path = sidecar_path(archive_path, "sig");
+        if let Err(err) = signer.sign_file(archive_path, &sig_path) {
+            record_failure(failures, format!("[{base_name}] signing failed: {err}"));
+            return None;
+        }
+        archive.files.push(sig_path);
+    }
+    if let Some(gpg) = &ctx.gpg_signer {
+        let signed = match gpg.target() {
+            SignTarget::Archive => archive_path.clone(),
+            SignTarget::Checksum => sidecar_path(archive_path, "sha256"),
+        };
+        let sig_path = sidecar_path(archive_path, "sig");
+        if let Err(err) = gpg.sign_file(&signed, &sig_path) {
+            record_failure(
+                failures,
+                format!("[{base_name}] GPG signing failed: {err:#}"),
+            );
+            return None;
+        }
+        archive.files.push(sig_path);
+    }
+    Some(archive)
+}
+
+/// Over `max_part_size_mb` cuts the archive into parts and their manifest,
+/// which are uploaded in place of the archive; the sidecars still describe
+/// the whole archive. An encrypted archive is cut between its age streams.
+/// With dedup the recipe stands in for the archive instead, and the chunks
+/// not yet uploaded go to their own directory first.
+pub fn split_archive(
+    item: &BackupItem,
+    ctx: &mut RunContext,
+    mut archive: ItemArchive,
+    options: &ArchiveOptions,
+    work_dir: &Path,
+    remote_dir: &str,
+    failures: &mut Vec<String>,
+) -> Option<ItemArchive> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    if let Some(max_part_size) = item.max_part_size_mb.map(|mb| mb << 20)
+        && archive.size > max_part_size
+    {
+        let split = match options.segment_size {
+            Some(_) => encryption::stream_offsets(&archive.path)
+                .and_then(|offsets| split::split_file_at(&archive.path, &offsets)),
+            None => split::split_file(&archive.path, max_part_size),
+        };
+        match split {
+            Ok(files) => {
+                info!(
+                    "[{base_name}] split {} into {} parts of up to {}",
+                    archive.path.display(),
+                    files.len() - 1,
+                    format_bytes(max_part_size)
+                );
+                archive.parts = files;
+            }
+            Err(err) => {
+                record_failure(failures, format!("[{base_name}] split failed: {err:#}"));
+                return None;
+            }
+        }
+    }
+    let dedup_target = item
+        .dedup
+        .unwrap_or(false)
+        .then(|| item_targets(&mut ctx.targets, item).next())
+        .flatten()
+        .map(|target| target.uploader.name().to_string());
+    if let Some(target) = dedup_target {
+        match chunk_archive(
+            &archive.path,
+            work_dir,
+            remote_dir,
+            target,
+            ctx,
+            options.level,
+        ) {
+            Ok(dedup_archive) => {
+                info!(
+                    "[{base_name}] dedup: {} chunks, {} of them new ({} compressed)",
+                    dedup_archive.chunked.chunks.len(),
+                    dedup_archive.upload.files.len(),
+                    format_bytes(dedup_archive.new_bytes)
+                );
+                archive.parts = vec![dedup_archive.chunked.recipe.clone()];
+                archive.dedup = Some(dedup_archive);
+            }
+            Err(err) => {
+                record_failure(failures, format!("[{base_name}] dedup failed: {err:#}"));
+                return None;
+            }
+        }
+    }
+    Some(archive)
+}
+
+/// Uploads the archive, or its parts, with the sidecars to every target of
+/// the item, and removes the parts again. Whether it is uploaded everywhere;
+/// if not, the archive is retained.
+pub fn upload_item(
+    item: &BackupItem,
+    ctx: &mut RunContext,
+    archive: &mut ItemArchive,
+    remote_dir: &str,
+    work_dir: &Path,
+    failures: &mut Vec<String>,
+) -> bool {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let upload_files: Vec<PathBuf> = if archive.parts.is_empty() {
+        archive.files.clone()
+    } else {
+        archive
+            .parts
+            .iter()
+            .chain(&archive.files[1..])
+            .cloned()
+            .collect()
+    };
+
+    // The checksum is also what skip_if_unchanged compares with the newest
+    // archive's on each backend.
+    let local_sha256 = if item.skip_if_unchanged.unwrap_or(false) {
+        let checksum_path = sidecar_path(&archive.path, "sha256");
+        match signing::read_checksum(&checksum_path) {
+            Ok(sha256) => Some(sha256),
+            Err(err) => {
+                record_failure(failures, format!("[{base_name}] checksum failed: {err:#}"));
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+
+    // With atomic_remote the files are uploaded under a staging name, so the
+    // local copies are renamed for the duration of the uploads.
+    let atomic = item.atomic_remote.unwrap_or(false);
+    let staged_files: Vec<PathBuf> = if atomic {
+        match stage_files(&upload_files) {
+            Ok(staged_files) => staged_files,
+            Err(err) => {
+                record_failure(failures, format!("[{base_name}] {err:#}"));
+                remove_upload_files(&archive.parts);
+                return false;
+            }
+        }
+    } else {
+        upload_files.clone()
+    };
+
+    // Every backend uploads from its own thread, so an item takes as long as
+    // its slowest backend instead of the sum of all of them. Results are
+    // reported afterwards in configuration order.
+    let policy = &ctx.policy;
+    let outcomes: Vec<Option<UploadOutcome>> = thread::scope(|scope| {
+        let handles: Vec<_> = item_targets(&mut ctx.targets, item)
+            .map(|target| {
+                if target.degraded {
+                    return None;
+                }
+                let uploader = target.uploader.as_mut();
+                let files = &staged_files;
+                let chunks = archive
+                    .dedup
+                    .as_ref()
+                    .map(|dedup_archive| &dedup_archive.upload);
+                let local_sha256 = local_sha256.as_deref();
+                Some(scope.spawn(move || {
+                    if let Some(sha256) = local_sha256
+                        && let Some(outcome) =
+                            skip_unchanged(uploader, remote_dir, item, sha256, work_dir)
+                    {
+                        return outcome;
+                    }
+                    upload_to_target(uploader, files, chunks, remote_dir, policy, atomic, item)
+                }))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle.map(|handle| {
+                    handle.join().unwrap_or_else(|_| UploadOutcome {
+                        upload: Err(anyhow::anyhow!("upload thread panicked")),
+                        elapsed: Duration::ZERO,
+                        retention: Ok(()),
+                        unchanged: false,
+                    })
+                })
+            })
+            .collect()
+    });
+
+    let upload_failed = record_outcomes(
+        item_targets(&mut ctx.targets, item),
+        outcomes,
+        policy,
+        base_name,
+        archive.size,
+        &mut ctx.uploads,
+        failures,
+    );
+
+    let unstaged = if atomic {
+        unstage_files(&staged_files, &upload_files)
+    } else {
+        Ok(())
+    };
+    if let Err(err) = unstaged {
+        record_failure(failures, format!("[{base_name}] {err:#}"));
+        warn!(
+            "Archive retained because its local files could not be renamed back: {}",
+            archive.path.display()
+        );
+        return false;
+    }
+
+    remove_upload_files(&archive.parts);
+    if !upload_failed
+        && let Some(dedup_archive) = &mut archive.dedup
+        && let Err(err) = dedup_archive.record()
+    {
+        // The chunks are uploaded either way; a stale index only means they
+        // are uploaded again next time.
+        warn!("[{base_name}] {err:#}");
+    }
+    if upload_failed {
+        warn!(
+            "Archive retained because one or more uploads failed: {}",
+            archive.path.display()
+        );
+        return false;
+    }
+    true
+}
+
+/// Removes the uploaded archive and its sidecars unless `keep_archive` is
+/// set, and applies `keep_local` to the directory it was in.
+pub fn prune_item_files(item: &BackupItem, archive: &ItemArchive) -> Result<()> {
+    if !item.keep_archive.unwrap_or(false) {
+        for file in &archive.files {
+            fs::remove_file(file).with_context(|| {
+                format!(
+                    "Failed to remove archive file after upload: {}",
+                    file.display()
+                )
+            })?;
+        }
+    }
+    if let Some(keep) = item.keep_local
+        && let Some(dir) = archive.path.parent()
+        && let Err(err) = prune_local_archives(dir, &item.archive_naming(), keep)
+    {
+        warn!(
+            "[{}] could not prune old local archives: {err:#}",
+            normalize_archive_name(&item.archive_name)
+        );
+    }
+    Ok(())
+}
+
+/// A `dedup` item's archive cut into chunks for its one uploader.
+pub struct DedupArchive {
+    pub target: String,
+    pub index: ChunkIndex,
+    pub chunked: ChunkedArchive,
+    /// The chunks `index` does not list for `target` yet.
+    pub upload: ChunkUpload,
+    /// Compressed size of `upload`.
+    pub new_bytes: u64,
+    /// Holds the new chunks until they are uploaded.
+    pub _chunk_dir: tempfile::TempDir,
+}
+
+impl DedupArchive {
+    /// Adds the archive's chunks to the index once `target` has them all.
+    pub fn record(&mut self) -> Result<()> {
+        self.index
+            .record(&self.target, &self.upload.remote_dir, &self.chunked.chunks)
+    }
+}
+
+/// Chunks the archive at `archive_path`, writing the chunks `target` does
+/// not have yet to a temporary directory in `work_dir`.
+pub fn chunk_archive(
+    archive_path: &Path,
+    work_dir: &Path,
+    remote_dir: &str,
+    target: String,
+    ctx: &RunContext,
+    level: i32,
+) -> Result<DedupArchive> {
+    let index = ChunkIndex::load(ctx.chunk_index_path.clone())?;
+    let chunk_remote_dir = format!("{}/{}", remote_dir.trim_end_matches('/'), dedup::CHUNK_DIR);
+    let chunk_dir = tempfile::tempdir_in(work_dir).with_context(|| {
+        format!(
+            "Failed to create a chunk directory in {}",
+            work_dir.display()
+        )
+    })?;
+    let chunked = dedup::chunk_file(archive_path, chunk_dir.path(), level, |sha256| {
+        index.contains(&target, &chunk_remote_dir, sha256)
+    })?;
+    let new_bytes = chunked
+        .new_chunks
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    Ok(DedupArchive {
+        target,
+        index,
+        upload: ChunkUpload {
+            files: chunked.new_chunks.clone(),
+            remote_dir: chunk_remote_dir,
+        },
+        chunked,
+        new_bytes,
+        _chunk_dir: chunk_dir,
+    })
+}
+
+/// What is left once an item is uploaded everywhere: removing the command
+/// output if asked to and running `post_command`.
+pub fn finish_item(
+    item: &BackupItem,
+    source: &ArchiveSource,
+    stamp: &Stamp,
+    failures: &mut Vec<String>,
+) -> Result<()> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    if let ArchiveSource::Paths(source_paths) = source
+        && item.has_command()
+        && !item.keep_command_source.unwrap_or(true)
+    {
+        for source_path in source_paths.iter().filter(|path| path.is_file()) {
+            fs::remove_file(source_path).with_context(|| {
+                format!(
+                    "Failed to remove command output file: {}",
+                    source_path.display()
+                )
+            })?;
+        }
+    }
+    if let Some(command) = &item.post_command {
+        info!("Running post-backup command for backup item: {}", base_name);
+        let workdir = item
+            .post_command_workdir
+            .as_deref()
+            .map(|dir| expand_placeholders(dir, stamp, base_name));
+        let command = expand_placeholders(command, stamp, base_name);
+        // The archive is already uploaded, so it still counts; the item is
+        // reported as failed all the same.
+        if let Err(err) = run_command(&command, workdir.as_deref(), item.command_timeout()) {
+            record_failure(
+                failures,
+                format!("[{base_name}] post_command failed: {err}"),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs the item's `command_argv`, or its `command` through the shell, with
+/// placeholders expanded. With `output`, its stdout goes to that file.
+pub fn run_item_command(
+    item: &BackupItem,
+    stamp: &Stamp,
+    base_name: &str,
+    output: Option<File>,
+) -> Result<()> {
+    let workdir = item
+        .command_workdir
+        .as_deref()
+        .map(|dir| expand_placeholders(dir, stamp, base_name));
+    match item.command_argv.as_deref() {
+        Some(argv) => {
+            if item.command.is_some() {
+                warn!("[{base_name}] command_argv is set, ignoring command");
+            }
+            let argv: Vec<String> = argv
+                .iter()
+                .map(|arg| expand_placeholders(arg, stamp, base_name))
+                .collect();
+            execute_command(
+                argv_command(&argv)?,
+                workdir.as_deref(),
+                &argv[0],
+                item.command_timeout(),
+                output,
+            )
+        }
+        None => {
+            let command = item.command.as_deref().unwrap_or_default();
+            let expanded_command = expand_placeholders(command, stamp, base_name);
+            execute_command(
+                shell_command(&expanded_command),
+                workdir.as_deref(),
+                &expanded_command,
+                item.command_timeout(),
+                output,
+            )
+        }
+    }
+}
+
+/// Runs the item's command for `command_output_name` with its stdout going
+/// to an unnamed file in `spool_dir`, gone once it is closed. tar needs an
+/// entry's size before its data, so the output cannot be archived while the
+/// command writes it; spooling it also means a failing command fails the
+/// item before any archive is written.
+pub fn spool_command_output(
+    item: &BackupItem,
+    stamp: &Stamp,
+    base_name: &str,
+    spool_dir: &Path,
+) -> Result<File> {
+    info!("Running command for backup item: {}", base_name);
+    let spool = tempfile::tempfile_in(spool_dir).with_context(|| {
+        format!(
+            "Failed to create command output spool file in: {}",
+            spool_dir.display()
+        )
+    })?;
+    let stdout = spool
+        .try_clone()
+        .context("Failed to open command output spool file")?;
+    run_item_command(item, stamp, base_name, Some(stdout))?;
+    Ok(spool)
+}
+
+/// Resolves the item's source paths, running its producer command first when
+/// one is configured. Returns `None` after recording a failure.
+pub fn prepare_source_paths(
+    item: &BackupItem,
+    stamp: &Stamp,
+    base_name: &str,
+    failures: &mut Vec<String>,
+) -> Result<Option<Vec<PathBuf>>> {
+    let source_paths = resolve_source_paths(item, stamp, base_name)?;
+    if item.has_command() {
+        info!("Running pre-backup command for backup item: {}", base_name);
+        if let Err(err) = run_item_command(item, stamp, base_name, None) {
+            record_failure(failures, format!("[{base_name}] command failed: {err}"));
+            return Ok(None);
+        }
+    }
+
+    for source_path in &source_paths {
+        let is_stored_link = item.source_is_symlink == SymlinkMode::Store
+            && fs::symlink_metadata(source_path)
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false);
+        if is_stored_link {
+            continue;
+        }
+        if !source_path.exists() {
+            record_failure(
+                failures,
+                format!(
+                    "[{base_name}] source path not found: {}",
+                    source_path.display()
+                ),
+            );
+            return Ok(None);
+        }
+        if !source_path.is_dir() && !source_path.is_file() {
+            record_failure(
+                failures,
+                format!(
+                    "[{base_name}] source path is not a file or directory: {}",
+                    source_path.display()
+                ),
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(source_paths))
+}
+
+pub fn format_source_stats(summary: &SourceSummary) -> String {
+    let mtime = |time: Option<SystemTime>| {
+        time.map(|time| {
+            chrono::DateTime::<Local>::from(time)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".to_string())
+    };
+    let largest = summary
+        .largest
+        .as_ref()
+        .map(|(path, bytes)| format!("{} ({})", path.display(), format_bytes(*bytes)))
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "source stats: {} files, {} total, largest {}, oldest mtime {}, newest mtime {}",
+        summary.files,
+        format_bytes(summary.bytes),
+        largest,
+        mtime(summary.oldest_mtime),
+        mtime(summary.newest_mtime)
+    )
+}
+
+/// Logs how far an archive compressed and how long creating it took, and
+/// returns the same numbers for the item's report.
+pub fn archive_report(
+    base_name: &str,
+    summary: &SourceSummary,
+    archive_size: u64,
+    elapsed: Duration,
+) -> ArchiveReport {
+    let report = ArchiveReport::new(summary.bytes, archive_size, elapsed);
+    info!(
+        item = base_name,
+        source_bytes = report.source_bytes,
+        archive_bytes = report.archive_bytes,
+        ratio = report.ratio,
+        duration_ms = report.duration_ms,
+        "[{base_name}] archived {} in {} file(s) into {} (ratio {}) in {:.1}s",
+        format_bytes(summary.bytes),
+        summary.files,
+        format_bytes(archive_size),
+        report
+            .ratio
+            .map_or_else(|| "-".to_string(), |ratio| format!("{ratio:.2}")),
+        elapsed.as_secs_f64()
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_DATE_FORMAT;
+    use crate::config::{Config, check_item};
+    use std::io::Read;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spool_command_output() {
+        use std::io::Seek;
+
+        let config: Config = toml::from_str(
+            r#"
+            [app]
+            [[backups]]
+            remote_dir = "/backups/db"
+            archive_name = "db"
+            command = "printf 'dump of %s' {archive_name}"
+            command_output_name = "{archive_name}.sql"
+            [[backups]]
+            remote_dir = "/backups/db"
+            archive_name = "broken"
+            command = "echo partial; echo 'no such database' >&2; exit 3"
+            command_output_name = "broken.sql"
+            "#,
+        )
+        .unwrap();
+        let mut problems = Vec::new();
+        check_item(&config.backups[0], &mut problems);
+        assert!(problems.is_empty(), "{problems:?}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let stamp = Stamp::now(DEFAULT_DATE_FORMAT);
+        let mut spool = spool_command_output(&config.backups[0], &stamp, "db", dir.path()).unwrap();
+        let mut output = String::new();
+        spool.rewind().unwrap();
+        spool.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "dump of db");
+
+        let err =
+            spool_command_output(&config.backups[1], &stamp, "broken", dir.path()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("exit status: 3"), "{message}");
+        assert!(message.contains("no such database"), "{message}");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,202 @@
+//! Unpacking a downloaded archive into a destination directory.
+
+use crate::archive::{ArchiveFormat, read_archive};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+pub struct RestoreOptions<'a> {
+    pub format: ArchiveFormat,
+    pub identity: Option<&'a age::x25519::Identity>,
+    /// Replace files that already exist under the destination.
+    pub force: bool,
+}
+
+/// Extracts the archive at `path` into `destination` and returns the number
+/// of entries written.
+///
+/// The archive is read twice: the first pass checks every entry name and,
+/// unless `force` is set, that no file would be overwritten, so a refused
+/// restore leaves the destination untouched.
+pub fn extract_archive(path: &Path, destination: &Path, options: &RestoreOptions) -> Result<u64> {
+    fs::create_dir_all(destination)
+        .with_context(|| format!("Failed to create directory: {}", destination.display()))?;
+
+    let mut conflicts: Vec<PathBuf> = Vec::new();
+    let mut archive = read_archive(path, options.format, options.identity)?;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Failed to read archive: {}", path.display()))?
+    {
+        let entry = entry.with_context(|| format!("Corrupted archive: {}", path.display()))?;
+        let name = entry
+            .path()
+            .with_context(|| format!("Invalid entry name in {}", path.display()))?;
+        let relative = safe_relative_path(&name)?;
+        let target = destination.join(&relative);
+        if !options.force
+            && !entry.header().entry_type().is_dir()
+            && fs::symlink_metadata(&target).is_ok()
+        {
+            conflicts.push(relative);
+        }
+    }
+    if !conflicts.is_empty() {
+        let shown: Vec<String> = conflicts
+            .iter()
+            .take(10)
+            .map(|path| path.display().to_string())
+            .collect();
+        anyhow::bail!(
+            "{} file(s) already exist in {} (e.g. {}); pass --force to overwrite",
+            conflicts.len(),
+            destination.display(),
+            shown.join(", ")
+        );
+    }
+
+    let mut archive = read_archive(path, options.format, options.identity)?;
+    let mut count = 0;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Failed to read archive: {}", path.display()))?
+    {
+        let mut entry = entry.with_context(|| format!("Corrupted archive: {}", path.display()))?;
+        let name = entry
+            .path()
+            .with_context(|| format!("Invalid entry name in {}", path.display()))?
+            .into_owned();
+        if !entry
+            .unpack_in(destination)
+            .with_context(|| format!("Failed to extract {}", name.display()))?
+        {
+            anyhow::bail!(
+                "Refusing to extract {} outside the destination",
+                name.display()
+            );
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Rejects entry names that are absolute or climb out with `..`.
+fn safe_relative_path(name: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!(
+                    "Archive entry {} escapes the destination directory",
+                    name.display()
+                )
+            }
+        }
+    }
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{ArchiveOptions, DEFAULT_LEVEL, create_archive};
+    use std::fs::File;
+
+    fn options(force: bool) -> RestoreOptions<'static> {
+        RestoreOptions {
+            format: ArchiveFormat::Zstd,
+            identity: None,
+            force,
+        }
+    }
+
+    #[test]
+    fn test_extract_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app");
+        fs::create_dir_all(source.join("conf")).unwrap();
+        fs::write(source.join("conf/app.toml"), b"debug = false").unwrap();
+        let output = dir.path().join("app-20260211.tar.zst");
+        let archive_options = ArchiveOptions::default();
+        create_archive(&[source], &output, &archive_options).unwrap();
+
+        let destination = dir.path().join("restore");
+        assert_eq!(
+            extract_archive(&output, &destination, &options(false)).unwrap(),
+            3
+        );
+        let restored = destination.join("app/conf/app.toml");
+        assert_eq!(fs::read(&restored).unwrap(), b"debug = false");
+
+        fs::write(&restored, b"debug = true").unwrap();
+        assert!(extract_archive(&output, &destination, &options(false)).is_err());
+        assert_eq!(fs::read(&restored).unwrap(), b"debug = true");
+        extract_archive(&output, &destination, &options(true)).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), b"debug = false");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_archive_keeps_modes_and_symlinks() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app");
+        fs::create_dir_all(source.join("bin")).unwrap();
+        let script = source.join("bin/run.sh");
+        fs::write(&script, b"#!/bin/sh\necho ok\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("bin/run.sh", source.join("run")).unwrap();
+        symlink("missing-target", source.join("dangling")).unwrap();
+        let output = dir.path().join("app-20260211.tar.zst");
+        let archive_options = ArchiveOptions::default();
+        create_archive(&[source], &output, &archive_options).unwrap();
+
+        let destination = dir.path().join("restore");
+        extract_archive(&output, &destination, &options(false)).unwrap();
+        let restored = destination.join("app");
+        let mode = fs::metadata(restored.join("bin/run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        for (link, target) in [("run", "bin/run.sh"), ("dangling", "missing-target")] {
+            let path = restored.join(link);
+            assert!(
+                fs::symlink_metadata(&path)
+                    .unwrap()
+                    .file_type()
+                    .is_symlink()
+            );
+            assert_eq!(fs::read_link(&path).unwrap(), Path::new(target));
+        }
+    }
+
+    #[test]
+    fn test_extract_archive_rejects_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("evil-20260211.tar.zst");
+        let encoder = zstd::Encoder::new(File::create(&output).unwrap(), DEFAULT_LEVEL).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        let data = b"owned";
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        // `set_path` refuses `..`, so the name is written into the header directly.
+        header.as_old_mut().name[..11].copy_from_slice(b"../evil.txt");
+        header.set_cksum();
+        builder.append(&header, &data[..]).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let destination = dir.path().join("restore");
+        assert!(extract_archive(&output, &destination, &options(true)).is_err());
+        assert!(!dir.path().join("evil.txt").exists());
+        assert!(safe_relative_path(Path::new("/etc/passwd")).is_err());
+        assert_eq!(
+            safe_relative_path(Path::new("./app/conf")).unwrap(),
+            Path::new("app/conf")
+        );
+    }
+}
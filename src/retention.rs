@@ -0,0 +1,467 @@
+//! Which remote and local files belong to an item, and pruning them to
+//! `keep_remote` and `keep_local`.
+
+use crate::archive::ArchiveFormat;
+use crate::config::BackupItem;
+use crate::dedup;
+use crate::split;
+use crate::upload::UploadOutcome;
+use crate::uploader::{RemoteEntry, Uploader};
+use crate::{archive_extension, parse_date};
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+/// Applies `keep_remote` once `upload` succeeded.
+pub fn with_retention(
+    uploader: &mut dyn Uploader,
+    upload: Result<()>,
+    elapsed: Duration,
+    remote_dir: &str,
+    item: &BackupItem,
+) -> UploadOutcome {
+    let retention = match item.keep_remote {
+        Some(keep) if upload.is_ok() => {
+            apply_retention(uploader, remote_dir, &item.archive_naming(), keep)
+        }
+        _ => Ok(()),
+    };
+    UploadOutcome {
+        upload,
+        elapsed,
+        retention,
+        unchanged: false,
+    }
+}
+
+/// Applies `keep_local` to `dir`: deletes the item's archives there beyond
+/// the newest `keep`, with their `.sha256` and `.sig` files. Other files
+/// are never touched.
+pub fn prune_local_archives(dir: &Path, naming: &ArchiveNaming, keep: usize) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("Failed to read directory: {}", dir.display()))?
+    {
+        let entry =
+            entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+        if entry.file_type().is_ok_and(|file_type| file_type.is_file())
+            && let Ok(name) = entry.file_name().into_string()
+        {
+            names.push(name);
+        }
+    }
+    for expired in expired_archives(&names, naming, keep) {
+        for name in [
+            expired.clone(),
+            format!("{expired}.sha256"),
+            format!("{expired}.sig"),
+        ] {
+            let path = dir.join(&name);
+            match fs::remove_file(&path) {
+                Ok(()) => info!("Deleted old local archive {}", path.display()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Failed to delete {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes all but the newest `keep` archives of `archive_name` in
+/// `remote_dir`, together with their sidecar files. Anything not named like
+/// one of the item's archives is left alone.
+pub fn apply_retention(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    naming: &ArchiveNaming,
+    keep: usize,
+) -> Result<()> {
+    let remote_dir = uploader.normalize_remote_dir(remote_dir)?;
+    let entries = uploader.list(&remote_dir)?;
+    let archives: Vec<String> = item_archives(entries.clone(), naming)
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    for expired in expired_archives(&archives, naming, keep) {
+        let files = entries
+            .iter()
+            .map(|entry| &entry.name)
+            .filter(|name| {
+                name.strip_prefix(expired.as_str()).is_some_and(|rest| {
+                    matches!(rest, "" | ".sig" | ".sha256")
+                        || rest.strip_prefix('.') == Some(split::MANIFEST_EXTENSION)
+                        || split::is_part_suffix(rest)
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in files {
+            uploader.delete(&remote_dir, &name)?;
+            info!(
+                "Deleted old archive {} from {} on {}",
+                name,
+                remote_dir,
+                uploader.name()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The item's archives among `names` beyond the newest `keep`, ordered by
+/// the date embedded in the name and the `-<n>` suffix added on collisions.
+pub fn expired_archives(names: &[String], naming: &ArchiveNaming, keep: usize) -> Vec<String> {
+    let mut archives: Vec<(NaiveDateTime, u64, &String)> = names
+        .iter()
+        .filter_map(|name| {
+            let (_, time, counter) = naming.date_of(name)?;
+            Some((time, counter, name))
+        })
+        .collect();
+    archives.sort();
+    let expired = archives.len().saturating_sub(keep);
+    archives
+        .into_iter()
+        .take(expired)
+        .map(|(_, _, name)| name.clone())
+        .collect()
+}
+
+/// The item's archives among `entries`, oldest first. An archive uploaded
+/// in parts is listed under its own name with the parts' total size.
+pub fn item_archives(entries: Vec<RemoteEntry>, naming: &ArchiveNaming) -> Vec<RemoteEntry> {
+    let split_archives = split_archive_entries(&entries);
+    let recipe_archives = recipe_archive_entries(&entries);
+    let mut archives: Vec<RemoteEntry> = entries
+        .into_iter()
+        .chain(split_archives)
+        .chain(recipe_archives)
+        .filter(|entry| is_item_archive(&entry.name, naming))
+        .collect();
+    archives.sort_by_key(|entry| {
+        naming
+            .date_of(&entry.name)
+            .map(|(_, time, counter)| (time, counter))
+    });
+    archives
+}
+
+/// Stand-ins for archives that only exist as parts, found by their manifest.
+pub fn split_archive_entries(entries: &[RemoteEntry]) -> Vec<RemoteEntry> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let name = entry
+                .name
+                .strip_suffix(split::MANIFEST_EXTENSION)?
+                .strip_suffix('.')?;
+            if entries.iter().any(|other| other.name == name) {
+                return None;
+            }
+            let size = entries
+                .iter()
+                .filter(|part| {
+                    part.name
+                        .strip_prefix(name)
+                        .is_some_and(split::is_part_suffix)
+                })
+                .map(|part| part.size)
+                .sum();
+            Some(RemoteEntry {
+                name: name.to_string(),
+                size,
+                md5: None,
+            })
+        })
+        .collect()
+}
+
+/// Stand-ins for `dedup` archives, found by their recipe. The size is the
+/// recipe's, which is all that is stored in `remote_dir` itself.
+pub fn recipe_archive_entries(entries: &[RemoteEntry]) -> Vec<RemoteEntry> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let name = entry
+                .name
+                .strip_suffix(dedup::RECIPE_EXTENSION)?
+                .strip_suffix('.')?;
+            Some(RemoteEntry {
+                name: name.to_string(),
+                size: entry.size,
+                md5: None,
+            })
+        })
+        .collect()
+}
+
+/// How one item's archives are named, to build the names and to pick them
+/// out of a listing again: `archive_name-<date>[-<n>].<extension>` unless the
+/// item sets `archive_filename`.
+pub struct ArchiveNaming<'a> {
+    pub archive_name: &'a str,
+    pub date_format: &'a str,
+    /// `archive_filename` with every placeholder but `{date}` expanded.
+    pub template: Option<String>,
+}
+
+impl<'a> ArchiveNaming<'a> {
+    /// The default naming.
+    pub fn new(archive_name: &'a str, date_format: &'a str) -> Self {
+        Self {
+            archive_name,
+            date_format,
+            template: None,
+        }
+    }
+
+    /// The name for `date` (which may carry `-incr`), with `-<counter>`
+    /// before the extension unless `counter` is 0. The extension is only used
+    /// by the default naming; a template brings its own, which is everything
+    /// from the first `.` after `{date}`.
+    pub fn file_name(&self, date: &str, extension: &str, counter: usize) -> String {
+        let counter = match counter {
+            0 => String::new(),
+            counter => format!("-{counter}"),
+        };
+        match &self.template {
+            Some(template) => {
+                let (prefix, suffix) = template
+                    .split_once("{date}")
+                    .unwrap_or((template.as_str(), ""));
+                let (stem, extension) = split_extension(suffix);
+                format!("{prefix}{date}{stem}{counter}{extension}")
+            }
+            None => format!("{}-{date}{counter}.{extension}", self.archive_name),
+        }
+    }
+
+    /// Date (as written and as parsed with `date_format`) and collision
+    /// counter embedded in one of the item's archive names. The default
+    /// naming matches any archive format, optionally encrypted.
+    pub fn date_of<'n>(&self, file_name: &'n str) -> Option<(&'n str, NaiveDateTime, u64)> {
+        let (rest, stem) = match &self.template {
+            Some(template) => {
+                let (prefix, suffix) = template.split_once("{date}")?;
+                let (stem, extension) = split_extension(suffix);
+                let rest = file_name.strip_prefix(prefix)?.strip_suffix(extension)?;
+                (rest, stem)
+            }
+            None => {
+                let rest = file_name
+                    .strip_prefix(self.archive_name)?
+                    .strip_prefix('-')?;
+                let rest = ArchiveFormat::ALL
+                    .iter()
+                    .flat_map(|format| {
+                        [false, true].map(|encrypted| archive_extension(*format, encrypted))
+                    })
+                    .find_map(|extension| {
+                        rest.strip_suffix(extension.as_str())?.strip_suffix('.')
+                    })?;
+                (rest, "")
+            }
+        };
+        let read_date = |rest: &'n str| {
+            let date = rest.strip_suffix(stem)?;
+            let date = date.strip_suffix("-incr").unwrap_or(date);
+            parse_date(date, self.date_format).map(|time| (date, time))
+        };
+        // A date format may itself end in `-<digits>`, so the whole rest is
+        // tried as a date before a collision counter is split off.
+        if let Some((date, time)) = read_date(rest) {
+            return Some((date, time, 0));
+        }
+        let (rest, counter) = rest.rsplit_once('-')?;
+        let counter = counter.parse().ok()?;
+        let (date, time) = read_date(rest)?;
+        Some((date, time, counter))
+    }
+}
+
+/// `_full.tar.zst` -> (`_full`, `.tar.zst`); no `.` means no extension.
+pub fn split_extension(name: &str) -> (&str, &str) {
+    match name.find('.') {
+        Some(index) => name.split_at(index),
+        None => (name, ""),
+    }
+}
+
+/// Matches the file names produced by `build_archive_path` for one item:
+/// `archive_name-<date>[-incr][-<n>].<extension>`, or its `archive_filename`
+/// with `<date>[-incr]` in place of `{date}` and the `-<n>` before the
+/// extension.
+pub fn is_item_archive(file_name: &str, naming: &ArchiveNaming) -> bool {
+    naming.date_of(file_name).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_DATE_FORMAT;
+
+    #[test]
+    fn test_is_item_archive() {
+        let naming = ArchiveNaming::new("project", DEFAULT_DATE_FORMAT);
+        assert!(is_item_archive("project-20260211.tar.zst", &naming));
+        assert!(is_item_archive("project-20260211-2.tar.zst", &naming));
+        assert!(is_item_archive("project-20260211-incr.tar.zst", &naming));
+        assert!(!is_item_archive("project-a-20260211.tar.zst", &naming));
+        assert!(!is_item_archive("project-20260211.txt", &naming));
+        assert!(is_item_archive("project-20260211.tar.gz", &naming));
+        assert!(is_item_archive("project-20260211-1.tar", &naming));
+        assert!(!is_item_archive("project-20260211.tar.zst.sig", &naming));
+        assert!(is_item_archive("project-20260211.tar.zst.age", &naming));
+        assert!(!is_item_archive(
+            "project-20260211.tar.zst.age.sig",
+            &naming
+        ));
+
+        let naming = ArchiveNaming {
+            template: Some("project_{date}_full.tzst".to_string()),
+            ..ArchiveNaming::new("project", DEFAULT_DATE_FORMAT)
+        };
+        assert_eq!(
+            naming.file_name("20260211", "tar.zst", 2),
+            "project_20260211_full-2.tzst"
+        );
+        assert_eq!(
+            naming
+                .date_of("project_20260211-incr_full-2.tzst")
+                .map(|(date, _, n)| (date, n)),
+            Some(("20260211", 2))
+        );
+        assert!(is_item_archive("project_20260211_full.tzst", &naming));
+        assert!(!is_item_archive("project-20260211.tar.zst", &naming));
+        assert!(!is_item_archive(
+            "project_20260211_full.tzst.sha256",
+            &naming
+        ));
+    }
+
+    #[test]
+    fn test_prune_local_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "project-20260209.tar.zst",
+            "project-20260209.tar.zst.sha256",
+            "project-20260210.tar.zst",
+            "project-20260211.tar.zst",
+            "project-20260211.tar.zst.sha256",
+            "project-a-20260101.tar.zst",
+            "notes.txt",
+        ] {
+            fs::write(dir.path().join(name), b"x").unwrap();
+        }
+        prune_local_archives(
+            dir.path(),
+            &ArchiveNaming::new("project", DEFAULT_DATE_FORMAT),
+            2,
+        )
+        .unwrap();
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "notes.txt",
+                "project-20260210.tar.zst",
+                "project-20260211.tar.zst",
+                "project-20260211.tar.zst.sha256",
+                "project-a-20260101.tar.zst",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expired_archives() {
+        let names: Vec<String> = [
+            "project-20260209.tar.zst",
+            "project-20260211-1.tar.zst",
+            "project-20260210.tar.gz",
+            "project-20260211.tar.zst",
+            "project-20260211.tar.zst.sig",
+            "project-notes-20260101.txt",
+            "other-20250101.tar.zst",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        assert_eq!(
+            expired_archives(
+                &names,
+                &ArchiveNaming::new("project", DEFAULT_DATE_FORMAT),
+                2
+            ),
+            ["project-20260209.tar.zst", "project-20260210.tar.gz"]
+        );
+        assert!(
+            expired_archives(
+                &names,
+                &ArchiveNaming::new("project", DEFAULT_DATE_FORMAT),
+                10
+            )
+            .is_empty()
+        );
+
+        let names: Vec<String> = [
+            "db-2026-02-11_02-00-1.tar.zst",
+            "db-2026-02-10_23-30.tar.zst",
+            "db-2026-02-11_02-00.tar.zst",
+            "db-2026-02-11_08-15.tar.zst",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        assert_eq!(
+            expired_archives(&names, &ArchiveNaming::new("db", "%Y-%m-%d_%H-%M"), 2),
+            ["db-2026-02-10_23-30.tar.zst", "db-2026-02-11_02-00.tar.zst"]
+        );
+        assert_eq!(
+            ArchiveNaming::new("db", "%d.%m.%Y")
+                .date_of("db-11.02.2026-incr-3.tar.gz")
+                .map(|(date, _, n)| (date, n)),
+            Some(("11.02.2026", 3))
+        );
+    }
+
+    #[test]
+    fn test_item_archives_with_parts_and_recipes() {
+        let entry = |name: &str, size| RemoteEntry {
+            name: name.to_string(),
+            size,
+            md5: None,
+        };
+        let entries = vec![
+            entry("project-20260211.tar.zst.part001", 1000),
+            entry("project-20260211.tar.zst.part002", 500),
+            entry("project-20260211.tar.zst.manifest", 120),
+            entry("project-20260211.tar.zst.sha256", 90),
+            entry("project-20260210.tar.zst", 1400),
+            entry("project-20260212.tar.recipe", 330),
+        ];
+        let archives: Vec<(String, u64)> =
+            item_archives(entries, &ArchiveNaming::new("project", DEFAULT_DATE_FORMAT))
+                .into_iter()
+                .map(|entry| (entry.name, entry.size))
+                .collect();
+        assert_eq!(
+            archives,
+            [
+                ("project-20260210.tar.zst".to_string(), 1400),
+                ("project-20260211.tar.zst".to_string(), 1500),
+                ("project-20260212.tar".to_string(), 330),
+            ]
+        );
+    }
+}
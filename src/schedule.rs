@@ -0,0 +1,298 @@
+//! Per-item `schedule`s for `--scheduled` runs.
+//!
+//! A schedule is either an interval since the item's last successful run
+//! (`24h`, `1d12h`, `90m`) or a five-field cron expression in local time
+//! (`30 2 * * 1-5`, or `@daily` and friends). An item is due when its
+//! interval has passed, or when the cron expression matched a minute after
+//! its last successful run; an item that never ran is always due.
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use serde::Deserialize;
+use std::fmt;
+
+/// Parsed when the config is loaded, so a bad expression fails it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    /// As written in the config, for logs.
+    source: String,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Interval(Duration),
+    Cron(Cron),
+}
+
+/// Each field as a bit mask of the values it matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day of month and day of week were both restricted, in which
+    /// case either one matching is enough, as in cron.
+    either_day: bool,
+}
+
+/// How far ahead a cron expression is searched; enough for `0 0 29 2 *`.
+const SEARCH_YEARS: i64 = 8;
+
+impl Schedule {
+    pub fn parse(value: &str) -> Result<Self> {
+        let source = value.trim().to_string();
+        let kind = if source.starts_with('@') || source.contains(char::is_whitespace) {
+            Kind::Cron(parse_cron(&source)?)
+        } else {
+            Kind::Interval(parse_interval(&source)?)
+        };
+        Ok(Self { source, kind })
+    }
+
+    /// When an item last run successfully at `last_run` is next due.
+    pub fn next_due(&self, last_run: NaiveDateTime) -> Option<NaiveDateTime> {
+        match &self.kind {
+            Kind::Interval(interval) => last_run.checked_add_signed(*interval),
+            Kind::Cron(cron) => cron.next_after(last_run),
+        }
+    }
+
+    /// Whether an item last run successfully at `last_run` is due at `now`.
+    pub fn is_due(&self, last_run: Option<NaiveDateTime>, now: NaiveDateTime) -> bool {
+        match last_run {
+            Some(last_run) => self.next_due(last_run).is_some_and(|due| due <= now),
+            None => true,
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::parse(&value)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = bit(date.day()) & self.days != 0;
+        let weekday = bit(date.weekday().num_days_from_sunday()) & self.weekdays != 0;
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// The first matching minute after `after`.
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let limit = after.checked_add_signed(Duration::days(366 * SEARCH_YEARS))?;
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        while t <= limit {
+            let date = t.date();
+            if bit(date.month()) & self.months == 0 {
+                let (year, month) = if date.month() == 12 {
+                    (date.year() + 1, 1)
+                } else {
+                    (date.year(), date.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                t = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if bit(t.hour()) & self.hours == 0 {
+                t = date.and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+            } else if bit(t.minute()) & self.minutes == 0 {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+fn bit(value: u32) -> u64 {
+    1 << value
+}
+
+/// `24h`, `7d`, `1h30m`: numbers each followed by `s`, `m`, `h`, `d` or `w`.
+fn parse_interval(value: &str) -> Result<Duration> {
+    let invalid = || {
+        format!(
+            "Invalid schedule {value:?}: expected an interval like 24h or 1d12h, or a cron expression like \"30 2 * * *\""
+        )
+    };
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let count: i64 = digits.parse().ok().with_context(invalid)?;
+        let unit = match c {
+            's' => Duration::seconds(1),
+            'm' => Duration::minutes(1),
+            'h' => Duration::hours(1),
+            'd' => Duration::days(1),
+            'w' => Duration::weeks(1),
+            _ => anyhow::bail!(invalid()),
+        };
+        total = unit
+            .checked_mul(i32::try_from(count).ok().with_context(invalid)?)
+            .and_then(|part| total.checked_add(&part))
+            .with_context(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || total <= Duration::zero() {
+        anyhow::bail!(invalid());
+    }
+    Ok(total)
+}
+
+fn parse_cron(value: &str) -> Result<Cron> {
+    let expanded = match value {
+        "@hourly" => "0 * * * *",
+        "@daily" | "@midnight" => "0 0 * * *",
+        "@weekly" => "0 0 * * 0",
+        "@monthly" => "0 0 1 * *",
+        "@yearly" | "@annually" => "0 0 1 1 *",
+        _ if value.starts_with('@') => anyhow::bail!(
+            "Unknown schedule {value}: expected @hourly, @daily, @weekly, @monthly or @yearly"
+        ),
+        _ => value,
+    };
+    let fields: Vec<&str> = expanded.split_whitespace().collect();
+    let &[minute, hour, day, month, weekday] = fields.as_slice() else {
+        anyhow::bail!(
+            "Invalid schedule {value:?}: a cron expression has five fields (minute hour day month weekday)"
+        );
+    };
+    let field = |text: &str, name: &str, min: u32, max: u32| {
+        parse_field(text, min, max)
+            .with_context(|| format!("Invalid {name} field {text:?} in schedule {value:?}"))
+    };
+    let mut weekdays = field(weekday, "weekday", 0, 7)?;
+    // 7 is Sunday too.
+    if weekdays & bit(7) != 0 {
+        weekdays = (weekdays & !bit(7)) | bit(0);
+    }
+    Ok(Cron {
+        minutes: field(minute, "minute", 0, 59)?,
+        hours: field(hour, "hour", 0, 23)?,
+        days: field(day, "day", 1, 31)?,
+        months: field(month, "month", 1, 12)?,
+        weekdays,
+        either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+    })
+}
+
+/// `*`, `5`, `1-5`, `*/15`, `10-40/10` and comma separated lists of those.
+fn parse_field(text: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().context("step is not a number")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            anyhow::bail!("step cannot be 0");
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse().context("not a number")?,
+                end.parse().context("not a number")?,
+            )
+        } else {
+            let value = range.parse().context("not a number")?;
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            anyhow::bail!("values must be within {min}-{max}");
+        }
+        for value in (start..=end).step_by(step) {
+            mask |= bit(value);
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_interval() {
+        let schedule = Schedule::parse("1d12h").unwrap();
+        assert_eq!(schedule.to_string(), "1d12h");
+        let last = at("2026-02-10 02:00");
+        assert_eq!(schedule.next_due(last), Some(at("2026-02-11 14:00")));
+        assert!(!schedule.is_due(Some(last), at("2026-02-11 13:59")));
+        assert!(schedule.is_due(Some(last), at("2026-02-11 14:00")));
+        assert!(schedule.is_due(None, last));
+        for invalid in ["", "24", "h", "0h", "3x", "-1h"] {
+            assert!(Schedule::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_cron() {
+        let weekdays = Schedule::parse("30 2 * * 1-5").unwrap();
+        // Friday 2026-02-13, so the next weekday is Monday.
+        assert_eq!(
+            weekdays.next_due(at("2026-02-13 02:30")),
+            Some(at("2026-02-16 02:30"))
+        );
+        assert!(weekdays.is_due(Some(at("2026-02-12 03:00")), at("2026-02-13 02:30")));
+        assert!(!weekdays.is_due(Some(at("2026-02-13 02:30")), at("2026-02-15 23:59")));
+
+        let quarter = Schedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(
+            quarter.next_due(at("2026-02-13 23:50")),
+            Some(at("2026-02-14 00:00"))
+        );
+        let leap = Schedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            leap.next_due(at("2026-03-01 00:00")),
+            Some(at("2028-02-29 00:00"))
+        );
+        // Day of month or day of week, as in cron: the 1st or any Sunday.
+        let either = Schedule::parse("0 3 1 * 7").unwrap();
+        assert_eq!(
+            either.next_due(at("2026-02-13 00:00")),
+            Some(at("2026-02-15 03:00"))
+        );
+        assert_eq!(
+            Schedule::parse("@daily")
+                .unwrap()
+                .next_due(at("2026-02-13 00:00")),
+            Some(at("2026-02-14 00:00"))
+        );
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "@often",
+        ] {
+            assert!(Schedule::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
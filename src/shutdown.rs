@@ -0,0 +1,67 @@
+//! SIGTERM and SIGINT handling for `--daemon`.
+//!
+//! The first signal only sets a flag: the item being backed up is finished,
+//! the remaining ones are left for the checkpoint, and the daemon exits
+//! instead of sleeping until the next cycle. The handler then restores the
+//! default action, so a second signal ends the process at once.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often a sleeping daemon looks at the flag.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+    // SAFETY: signal(2) is async-signal-safe and SIG_DFL is always valid.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
+
+#[cfg(unix)]
+pub fn install() -> Result<()> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic and calls signal(2).
+        let previous =
+            unsafe { libc::signal(signal, on_signal as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            anyhow::bail!(
+                "Failed to install a handler for signal {}: {}",
+                signal,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Without signals to catch, Ctrl+C still ends the process right away.
+#[cfg(not(unix))]
+pub fn install() -> Result<()> {
+    Ok(())
+}
+
+/// Whether a signal asked the daemon to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration` unless a stop is requested first. Returns whether
+/// the whole time was slept.
+pub fn sleep(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !requested() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(POLL_INTERVAL));
+    }
+    false
+}
@@ -0,0 +1,211 @@
+//! Detached Ed25519 signatures and SHA-256 checksums for archives.
+//!
+//! Archives are signed with Ed25519ph (SHA-512 prehash) so large files can be
+//! hashed as a stream. The `.sig` sidecar holds the 64-byte signature as hex.
+
+use anyhow::{Context, Result};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256, Sha512};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use tracing::warn;
+
+/// Ed25519ph context string, so an archive signature can't be passed off as
+/// a signature over anything else made with the same key.
+pub const SIGNATURE_CONTEXT: &[u8] = b"backup-to-cloud archive";
+
+pub struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Loads a PKCS#8 PEM private key, e.g. from
+    /// `openssl genpkey -algorithm ed25519 -out backup-signing.pem`.
+    pub fn from_pem_file(path: &Path) -> Result<Self> {
+        warn_if_accessible_by_others(path);
+        let pem = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signing key: {}", path.display()))?;
+        let key = SigningKey::from_pkcs8_pem(&pem).map_err(|err| {
+            anyhow::anyhow!(
+                "Invalid Ed25519 PKCS#8 private key in {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        Ok(Self { key })
+    }
+
+    /// Signs the file at `path` and writes the hex signature to `sig_path`.
+    pub fn sign_file(&self, path: &Path, sig_path: &Path) -> Result<()> {
+        self.sign_prehashed(hash_file(path)?, &path.display().to_string(), sig_path)
+    }
+
+    /// Like [`Signer::sign_file`], for content that has already been hashed
+    /// with [`hash_file`]'s SHA-512; `name` is only used in errors.
+    pub fn sign_prehashed(&self, prehash: Sha512, name: &str, sig_path: &Path) -> Result<()> {
+        let signature = self
+            .key
+            .sign_prehashed(prehash, Some(SIGNATURE_CONTEXT))
+            .map_err(|err| anyhow::anyhow!("Failed to sign {}: {}", name, err))?;
+        fs::write(sig_path, format!("{}\n", to_hex(&signature.to_bytes())))
+            .with_context(|| format!("Failed to write signature: {}", sig_path.display()))
+    }
+}
+
+/// Checks `.sig` files made by [`Signer`] against the matching public key.
+pub struct Verifier {
+    key: VerifyingKey,
+}
+
+impl Verifier {
+    /// Loads a PEM public key, e.g. from
+    /// `openssl pkey -in backup-signing.pem -pubout -out signing.pub.pem`.
+    pub fn from_pem_file(path: &Path) -> Result<Self> {
+        let pem = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read public key: {}", path.display()))?;
+        let key = VerifyingKey::from_public_key_pem(&pem).map_err(|err| {
+            anyhow::anyhow!("Invalid Ed25519 public key in {}: {}", path.display(), err)
+        })?;
+        Ok(Self { key })
+    }
+
+    /// Fails unless `sig_path` holds a valid signature of the file at `path`.
+    pub fn verify_file(&self, path: &Path, sig_path: &Path) -> Result<()> {
+        let hex = fs::read_to_string(sig_path)
+            .with_context(|| format!("Failed to read signature: {}", sig_path.display()))?;
+        let bytes = from_hex(hex.trim())
+            .with_context(|| format!("Malformed signature file: {}", sig_path.display()))?;
+        let signature = Signature::from_slice(&bytes).map_err(|err| {
+            anyhow::anyhow!("Malformed signature {}: {}", sig_path.display(), err)
+        })?;
+        self.key
+            .verify_prehashed(hash_file(path)?, Some(SIGNATURE_CONTEXT), &signature)
+            .map_err(|_| anyhow::anyhow!("Signature does not match {}", path.display()))
+    }
+}
+
+/// Writes a `sha256sum`-compatible line (`<hex>  <file name>`) for the file
+/// at `path` to `checksum_path`, so `sha256sum -c` works next to the file.
+pub fn write_checksum(path: &Path, checksum_path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("No file name: {}", path.display()))?
+        .to_string_lossy();
+    write_checksum_line(&sha256_file(path)?, &name, checksum_path)
+}
+
+/// SHA-256 of the file at `path` as lowercase hex, read in chunks.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// The digest from a checksum file like [`write_checksum`] writes, lowercase.
+pub fn read_checksum(checksum_path: &Path) -> Result<String> {
+    let content = fs::read_to_string(checksum_path)
+        .with_context(|| format!("Failed to read checksum: {}", checksum_path.display()))?;
+    content
+        .split_whitespace()
+        .next()
+        .filter(|hex| hex.len() == 64 && from_hex(hex).is_some())
+        .map(|hex| hex.to_ascii_lowercase())
+        .with_context(|| format!("Malformed checksum file: {}", checksum_path.display()))
+}
+
+/// Writes the line [`write_checksum`] would for a file named `name` whose
+/// SHA-256 is `sha256` (lowercase hex).
+pub fn write_checksum_line(sha256: &str, name: &str, checksum_path: &Path) -> Result<()> {
+    fs::write(checksum_path, format!("{sha256}  {name}\n"))
+        .with_context(|| format!("Failed to write checksum: {}", checksum_path.display()))
+}
+
+pub fn hash_file(path: &Path) -> Result<Sha512> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut hasher = Sha512::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(hasher)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(unix)]
+fn warn_if_accessible_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        warn!(
+            "Signing key {} is accessible by other users; restrict it with chmod 600",
+            path.display()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_accessible_by_others(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("demo-20260211.tar.zst");
+        let checksum = dir.path().join("demo-20260211.tar.zst.sha256");
+        fs::write(&archive, b"abc").unwrap();
+        write_checksum(&archive, &checksum).unwrap();
+        assert_eq!(
+            fs::read_to_string(&checksum).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  demo-20260211.tar.zst\n"
+        );
+        assert_eq!(
+            read_checksum(&checksum).unwrap(),
+            sha256_file(&archive).unwrap()
+        );
+        fs::write(&checksum, "not a digest\n").unwrap();
+        assert!(read_checksum(&checksum).is_err());
+    }
+
+    #[test]
+    fn test_signature_verifies_with_public_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("demo-20260211.tar.zst");
+        let sig_path = dir.path().join("demo-20260211.tar.zst.sig");
+        fs::write(&archive, b"archive bytes").unwrap();
+
+        let signer = Signer {
+            key: SigningKey::from_bytes(&[7u8; 32]),
+        };
+        signer.sign_file(&archive, &sig_path).unwrap();
+
+        let verifier = Verifier {
+            key: signer.key.verifying_key(),
+        };
+        verifier.verify_file(&archive, &sig_path).unwrap();
+
+        fs::write(&archive, b"archive bytez").unwrap();
+        assert!(verifier.verify_file(&archive, &sig_path).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! Splitting archives over `max_part_size` into numbered parts.
+//!
+//! `x.tar.zst` becomes `x.tar.zst.part001`, `x.tar.zst.part002`, ... cut at
+//! byte boundaries, plus `x.tar.zst.manifest` listing every part with its
+//! SHA-256 in `sha256sum` format. The numbers are zero-padded to the same
+//! width, so the parts sort in order and `cat x.tar.zst.part* > x.tar.zst`
+//! reassembles the archive after `sha256sum -c x.tar.zst.manifest`.
+//!
+//! Encrypted archives are cut where one of their age streams ends instead,
+//! so each part is one stream (see [`crate::encryption`]).
+
+use crate::signing::to_hex;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_EXTENSION: &str = "manifest";
+const PART_PREFIX: &str = ".part";
+/// Part numbers are padded to at least this many digits.
+const MIN_PART_DIGITS: usize = 3;
+
+/// One entry of a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub name: String,
+    /// Lowercase hex.
+    pub sha256: String,
+}
+
+pub fn manifest_name(archive_name: &str) -> String {
+    format!("{archive_name}.{MANIFEST_EXTENSION}")
+}
+
+/// Whether `suffix` (what follows the archive name) is `.part<digits>`.
+pub fn is_part_suffix(suffix: &str) -> bool {
+    suffix.strip_prefix(PART_PREFIX).is_some_and(|number| {
+        number.len() >= MIN_PART_DIGITS && number.bytes().all(|byte| byte.is_ascii_digit())
+    })
+}
+
+fn part_name(archive_name: &str, number: u64, digits: usize) -> String {
+    format!("{archive_name}{PART_PREFIX}{number:0digits$}")
+}
+
+/// Splits the file at `path` into parts of at most `max_part_size` bytes
+/// next to it and writes the manifest. Returns the part paths in order,
+/// followed by the manifest; the original file is left in place. Nothing is
+/// left behind if it fails.
+pub fn split_file(path: &Path, max_part_size: u64) -> Result<Vec<PathBuf>> {
+    let archive_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Archive file name is not valid UTF-8")?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    split_file_into(path, archive_name, dir, max_part_size)
+}
+
+/// Like [`split_file`], writing the parts and manifest into `dir` with
+/// names made from `archive_name` instead of the file's own.
+pub fn split_file_into(
+    path: &Path,
+    archive_name: &str,
+    dir: &Path,
+    max_part_size: u64,
+) -> Result<Vec<PathBuf>> {
+    let size = fs::metadata(path)
+        .with_context(|| format!("Failed to read file size: {}", path.display()))?
+        .len();
+    let offsets: Vec<u64> = (0..size.div_ceil(max_part_size).max(1))
+        .map(|number| number * max_part_size)
+        .collect();
+    split_at(path, archive_name, dir, &offsets)
+}
+
+/// Like [`split_file`], cutting the file where `offsets` say each part
+/// starts, the first at `0`, rather than every `max_part_size` bytes.
+pub fn split_file_at(path: &Path, offsets: &[u64]) -> Result<Vec<PathBuf>> {
+    let archive_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Archive file name is not valid UTF-8")?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    split_at(path, archive_name, dir, offsets)
+}
+
+fn split_at(path: &Path, archive_name: &str, dir: &Path, offsets: &[u64]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let result = write_parts(path, archive_name, dir, offsets, &mut files);
+    if result.is_err() {
+        for file in &files {
+            let _ = fs::remove_file(file);
+        }
+    }
+    result.map(|()| files)
+}
+
+fn write_parts(
+    path: &Path,
+    archive_name: &str,
+    dir: &Path,
+    offsets: &[u64],
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let mut input =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    let size = input
+        .metadata()
+        .with_context(|| format!("Failed to read file size: {}", path.display()))?
+        .len();
+    let ends = offsets.iter().skip(1).copied().chain(std::iter::once(size));
+    let digits = MIN_PART_DIGITS.max(offsets.len().to_string().len());
+
+    let mut manifest = String::new();
+    for (number, (start, end)) in (1..).zip(offsets.iter().copied().zip(ends)) {
+        let name = part_name(archive_name, number, digits);
+        let part_path = dir.join(&name);
+        files.push(part_path.clone());
+        let mut output = HashingWriter::new(
+            File::create(&part_path)
+                .with_context(|| format!("Failed to create part: {}", part_path.display()))?,
+        );
+        let length = end
+            .checked_sub(start)
+            .with_context(|| format!("Split offsets out of order: {} after {}", end, start))?;
+        let copied = io::copy(&mut Read::by_ref(&mut input).take(length), &mut output)
+            .with_context(|| format!("Failed to write part: {}", part_path.display()))?;
+        if copied != length {
+            anyhow::bail!("Archive shrank while splitting: {}", path.display());
+        }
+        manifest.push_str(&format!("{}  {}\n", output.finish(), name));
+    }
+    let manifest_path = dir.join(manifest_name(archive_name));
+    files.push(manifest_path.clone());
+    fs::write(&manifest_path, manifest)
+        .with_context(|| format!("Failed to write manifest: {}", manifest_path.display()))
+}
+
+/// Reads a manifest written by [`split_file`] for `archive_name`. The parts
+/// have to be numbered from 1 without gaps, in order.
+pub fn read_manifest(path: &Path, archive_name: &str) -> Result<Vec<Part>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest: {}", path.display()))?;
+    let mut parts = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (sha256, name) = line
+            .split_once("  ")
+            .with_context(|| format!("Malformed manifest line in {}: {}", path.display(), line))?;
+        let number = name
+            .strip_prefix(archive_name)
+            .filter(|suffix| is_part_suffix(suffix))
+            .and_then(|suffix| suffix[PART_PREFIX.len()..].parse::<usize>().ok())
+            .with_context(|| format!("{} lists a foreign part: {}", path.display(), name))?;
+        if number != parts.len() + 1 {
+            anyhow::bail!(
+                "{} lists part {} where part {} was expected",
+                path.display(),
+                name,
+                parts.len() + 1
+            );
+        }
+        parts.push(Part {
+            name: name.to_string(),
+            sha256: sha256.to_ascii_lowercase(),
+        });
+    }
+    if parts.is_empty() {
+        anyhow::bail!("Manifest lists no parts: {}", path.display());
+    }
+    Ok(parts)
+}
+
+/// Reassembles `parts` into `output`. `fetch(name, path)` has to put each
+/// part at `path`; every part is checked against the manifest and removed
+/// once appended, so only one is on disk at a time.
+pub fn join_parts(
+    parts: &[Part],
+    output: &Path,
+    mut fetch: impl FnMut(&str, &Path) -> Result<()>,
+) -> Result<()> {
+    let dir = output.parent().unwrap_or(Path::new(""));
+    let mut joined = File::create(output)
+        .with_context(|| format!("Failed to create file: {}", output.display()))?;
+    for part in parts {
+        let part_path = dir.join(&part.name);
+        fetch(&part.name, &part_path)?;
+        let appended = append_part(&mut joined, &part_path, part);
+        let _ = fs::remove_file(&part_path);
+        appended?;
+    }
+    joined
+        .sync_all()
+        .with_context(|| format!("Failed to flush file: {}", output.display()))
+}
+
+fn append_part(joined: &mut File, part_path: &Path, part: &Part) -> Result<()> {
+    let mut input = File::open(part_path)
+        .with_context(|| format!("Failed to open part: {}", part_path.display()))?;
+    let mut output = HashingWriter::new(joined);
+    io::copy(&mut input, &mut output)
+        .with_context(|| format!("Failed to append part: {}", part.name))?;
+    let sha256 = output.finish();
+    if sha256 != part.sha256 {
+        anyhow::bail!(
+            "Part {} has SHA-256 {}, the manifest lists {}",
+            part.name,
+            sha256,
+            part.sha256
+        );
+    }
+    Ok(())
+}
+
+/// Passes writes through while hashing them.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> String {
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("data-20260211.tar.zst");
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&archive, &content).unwrap();
+
+        let files = split_file(&archive, 1000).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "data-20260211.tar.zst.part001",
+                "data-20260211.tar.zst.part002",
+                "data-20260211.tar.zst.part003",
+                "data-20260211.tar.zst.manifest",
+            ]
+        );
+        assert_eq!(fs::metadata(&files[2]).unwrap().len(), 500);
+        let parts = read_manifest(&files[3], "data-20260211.tar.zst").unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(is_part_suffix(".part002"));
+        assert!(!is_part_suffix(".part2") && !is_part_suffix(".sha256"));
+
+        // Parts come from a separate "remote" directory, like a download.
+        let remote = tempfile::tempdir().unwrap();
+        for file in &files[..3] {
+            fs::rename(file, remote.path().join(file.file_name().unwrap())).unwrap();
+        }
+        let joined = dir.path().join("joined.tar.zst");
+        let fetch = |name: &str, path: &Path| {
+            fs::copy(remote.path().join(name), path)?;
+            Ok(())
+        };
+        join_parts(&parts, &joined, fetch).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), content);
+
+        fs::write(remote.path().join(&parts[1].name), b"tampered").unwrap();
+        assert!(join_parts(&parts, &joined, fetch).is_err());
+
+        let manifest = fs::read_to_string(&files[3]).unwrap();
+        let reordered: String = manifest
+            .lines()
+            .rev()
+            .map(|line| format!("{line}\n"))
+            .collect();
+        fs::write(&files[3], reordered).unwrap();
+        assert!(read_manifest(&files[3], "data-20260211.tar.zst").is_err());
+    }
+
+    #[test]
+    fn test_split_file_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("data-20260211.tar.zst.age");
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&archive, &content).unwrap();
+
+        let files = split_file_at(&archive, &[0, 700, 1900]).unwrap();
+        let sizes: Vec<u64> = files[..3]
+            .iter()
+            .map(|file| fs::metadata(file).unwrap().len())
+            .collect();
+        assert_eq!(sizes, [700, 1200, 600]);
+        let parts = read_manifest(&files[3], "data-20260211.tar.zst.age").unwrap();
+        let joined = dir.path().join("joined");
+        join_parts(&parts, &joined, |_, _| Ok(())).unwrap();
+        assert_eq!(fs::read(&joined).unwrap(), content);
+
+        assert!(split_file_at(&archive, &[0, 3000]).is_err());
+        assert!(
+            !dir.path()
+                .join("data-20260211.tar.zst.age.part001")
+                .exists()
+        );
+    }
+}
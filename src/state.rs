@@ -0,0 +1,275 @@
+//! Per-item results of recent runs, kept next to the config file so an
+//! interrupted run can be resumed.
+//!
+//! Besides each item's last outcome, the file holds the checkpoint of the
+//! run in progress: the items it completed so far. A run that reaches the
+//! end of the item list clears it, so one that is still there on the next
+//! start belongs to a run that died partway, and the items it names are
+//! skipped when it is from the same day.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    Ok,
+    Failed,
+}
+
+impl ItemStatus {
+    /// As written to the state file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemState {
+    /// Run date (`YYYYMMDD`) the status belongs to.
+    pub date: String,
+    pub status: ItemStatus,
+}
+
+/// Items completed by a run that has not reached the end of its item list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Run date (`YYYYMMDD`) of that run.
+    date: String,
+    items: BTreeSet<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateData {
+    #[serde(default)]
+    items: BTreeMap<String, ItemState>,
+    /// Unix time at which the last successful backup of an `incremental`
+    /// item started.
+    #[serde(default)]
+    incremental: BTreeMap<String, u64>,
+    /// Unix time at which the last successful backup of each item started,
+    /// for `schedule`s.
+    #[serde(default)]
+    last_run: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checkpoint: Option<Checkpoint>,
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    data: StateData,
+}
+
+impl StateFile {
+    /// `backup.toml` -> `backup.state.json` in the same directory.
+    pub fn path_for_config(config_path: &Path) -> PathBuf {
+        let stem = config_path
+            .file_stem()
+            .map(|stem| stem.to_os_string())
+            .unwrap_or_else(|| "backup".into());
+        let mut name = stem;
+        name.push(".state.json");
+        config_path.with_file_name(name)
+    }
+
+    pub fn empty(path: PathBuf) -> Self {
+        Self {
+            path,
+            data: StateData::default(),
+        }
+    }
+
+    /// Loads the state file, treating a missing file as empty.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::empty(path)),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read state file: {}", path.display()));
+            }
+        };
+        let data = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse state file: {}", path.display()))?;
+        Ok(Self { path, data })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether `item` was backed up successfully on `date`. Entries from other
+    /// dates are stale and never count.
+    pub fn succeeded_on(&self, item: &str, date: &str) -> bool {
+        self.data
+            .items
+            .get(item)
+            .is_some_and(|state| state.date == date && state.status == ItemStatus::Ok)
+    }
+
+    /// Whether an interrupted run on `date` already completed `item`.
+    pub fn checkpointed(&self, item: &str, date: &str) -> bool {
+        self.data
+            .checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.date == date && checkpoint.items.contains(item))
+    }
+
+    /// Records the outcome of `item` and writes the file right away, so the
+    /// state survives the process dying on a later item. A success is also
+    /// added to the checkpoint, which starts over when it was from another
+    /// day.
+    pub fn record(&mut self, item: &str, date: &str, status: ItemStatus) -> Result<()> {
+        self.data.items.insert(
+            item.to_string(),
+            ItemState {
+                date: date.to_string(),
+                status,
+            },
+        );
+        if status == ItemStatus::Ok {
+            let checkpoint = self.data.checkpoint.get_or_insert_with(|| Checkpoint {
+                date: date.to_string(),
+                items: BTreeSet::new(),
+            });
+            if checkpoint.date != date {
+                checkpoint.date = date.to_string();
+                checkpoint.items.clear();
+            }
+            checkpoint.items.insert(item.to_string());
+        }
+        self.save()
+    }
+
+    /// Drops the checkpoint, once every item was run or with `--restart`.
+    pub fn clear_checkpoint(&mut self) -> Result<()> {
+        if self.data.checkpoint.take().is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Start of the last successful backup of `item` in incremental mode;
+    /// files modified after it go into the next incremental archive.
+    pub fn incremental_since(&self, item: &str) -> Option<SystemTime> {
+        self.data
+            .incremental
+            .get(item)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs))
+    }
+
+    /// Records `started` as the new incremental base of `item`. It is
+    /// truncated to whole seconds, which can only make the next archive
+    /// include a file twice, never miss one.
+    pub fn record_incremental(&mut self, item: &str, started: SystemTime) -> Result<()> {
+        let secs = started
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.data.incremental.insert(item.to_string(), secs);
+        self.save()
+    }
+
+    /// Start of the last successful backup of `item`, whatever its mode.
+    pub fn last_run(&self, item: &str) -> Option<SystemTime> {
+        self.data
+            .last_run
+            .get(item)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs))
+    }
+
+    /// Records `started` as the start of the last successful backup of
+    /// `item`, in whole seconds.
+    pub fn record_last_run(&mut self, item: &str, started: SystemTime) -> Result<()> {
+        let secs = started
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.data.last_run.insert(item.to_string(), secs);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let content =
+            serde_json::to_string_pretty(&self.data).context("Failed to serialize state")?;
+        let mut tmp_name = self.path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        fs::write(&tmp_path, content)
+            .with_context(|| format!("Failed to write state file: {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace state file: {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_for_config() {
+        assert_eq!(
+            StateFile::path_for_config(Path::new("/etc/backup/nightly.toml")),
+            Path::new("/etc/backup/nightly.state.json")
+        );
+    }
+
+    #[test]
+    fn test_record_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.state.json");
+        let mut state = StateFile::load(path.clone()).unwrap();
+        state.record("app", "20260210", ItemStatus::Ok).unwrap();
+        state.record("db", "20260211", ItemStatus::Failed).unwrap();
+        state.record("etc", "20260211", ItemStatus::Ok).unwrap();
+
+        let state = StateFile::load(path).unwrap();
+        assert!(!state.succeeded_on("app", "20260211"));
+        assert!(!state.succeeded_on("db", "20260211"));
+        assert!(state.succeeded_on("etc", "20260211"));
+        assert!(!state.succeeded_on("missing", "20260211"));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.state.json");
+        let mut state = StateFile::load(path.clone()).unwrap();
+        state.record("app", "20260210", ItemStatus::Ok).unwrap();
+        state.record("app", "20260211", ItemStatus::Ok).unwrap();
+        state.record("db", "20260211", ItemStatus::Failed).unwrap();
+
+        let mut state = StateFile::load(path.clone()).unwrap();
+        assert!(state.checkpointed("app", "20260211"));
+        assert!(!state.checkpointed("app", "20260212"));
+        assert!(!state.checkpointed("db", "20260211"));
+        state.clear_checkpoint().unwrap();
+
+        let state = StateFile::load(path).unwrap();
+        assert!(!state.checkpointed("app", "20260211"));
+        assert!(state.succeeded_on("app", "20260211"));
+    }
+
+    #[test]
+    fn test_record_incremental() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.state.json");
+        let mut state = StateFile::load(path.clone()).unwrap();
+        assert_eq!(state.incremental_since("app"), None);
+        let started = UNIX_EPOCH + Duration::from_millis(1_770_000_000_750);
+        state.record_incremental("app", started).unwrap();
+
+        let state = StateFile::load(path).unwrap();
+        assert_eq!(
+            state.incremental_since("app"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_770_000_000))
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! An in-memory pipe from the archive writer to uploads that read it as a
+//! stream, so an archive can reach its backends without a local copy.
+//!
+//! The writer hands each chunk to every reader and blocks once a reader is
+//! `CHANNEL_CHUNKS` behind, so memory use stays bounded. A reader only sees
+//! the end of the stream after [`StreamTee::finish`]; if the writer is
+//! dropped before that, reads fail instead of passing off a truncated
+//! archive as complete.
+
+use crate::signing::to_hex;
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+const CHUNK_SIZE: usize = 1 << 20;
+/// Chunks queued per reader before the writer waits for it.
+const CHANNEL_CHUNKS: usize = 8;
+
+enum Message {
+    Data(Arc<[u8]>),
+    End,
+}
+
+/// What is known about a finished stream, in place of the file it would
+/// otherwise have been read from.
+#[derive(Clone)]
+pub struct StreamDigest {
+    pub size: u64,
+    /// Lowercase hex.
+    pub sha256: String,
+    /// Lowercase hex.
+    pub md5: String,
+    /// The Ed25519ph prehash, as [`crate::signing::hash_file`] computes it.
+    pub sha512: Sha512,
+}
+
+/// The write end, fanning out to every [`StreamReader`].
+pub struct StreamTee {
+    senders: Vec<SyncSender<Message>>,
+    buffer: Vec<u8>,
+    size: u64,
+    sha256: Sha256,
+    md5: Md5,
+    sha512: Sha512,
+}
+
+pub struct StreamReader {
+    receiver: Receiver<Message>,
+    chunk: Arc<[u8]>,
+    offset: usize,
+    ended: bool,
+}
+
+/// Creates a pipe with `readers` read ends.
+pub fn pipe(readers: usize) -> (StreamTee, Vec<StreamReader>) {
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..readers)
+        .map(|_| mpsc::sync_channel(CHANNEL_CHUNKS))
+        .unzip();
+    let tee = StreamTee {
+        senders,
+        buffer: Vec::with_capacity(CHUNK_SIZE),
+        size: 0,
+        sha256: Sha256::new(),
+        md5: Md5::new(),
+        sha512: Sha512::new(),
+    };
+    let readers = receivers
+        .into_iter()
+        .map(|receiver| StreamReader {
+            receiver,
+            chunk: Arc::from(Vec::new()),
+            offset: 0,
+            ended: false,
+        })
+        .collect();
+    (tee, readers)
+}
+
+impl StreamTee {
+    /// Sends to every reader still listening. A reader whose upload failed
+    /// has dropped its end and is left behind; the stream only fails once
+    /// no reader is left.
+    fn send(&mut self, message: impl Fn() -> Message) -> io::Result<()> {
+        self.senders.retain(|sender| sender.send(message()).is_ok());
+        if self.senders.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "every upload of the stream has stopped",
+            ));
+        }
+        Ok(())
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk: Arc<[u8]> =
+            mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE)).into();
+        self.send(|| Message::Data(chunk.clone()))
+    }
+
+    /// Ends the stream for every reader and returns its digests.
+    pub fn finish(mut self) -> io::Result<StreamDigest> {
+        self.send_buffer()?;
+        self.send(|| Message::End)?;
+        Ok(StreamDigest {
+            size: self.size,
+            sha256: to_hex(&self.sha256.finalize()),
+            md5: to_hex(&self.md5.finalize()),
+            sha512: self.sha512,
+        })
+    }
+}
+
+impl Write for StreamTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sha256.update(buf);
+        self.md5.update(buf);
+        self.sha512.update(buf);
+        self.size += buf.len() as u64;
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Chunks are only sent once full, so the encoders flushing along the
+    /// way do not fragment the stream.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.offset < self.chunk.len() {
+                let len = buf.len().min(self.chunk.len() - self.offset);
+                buf[..len].copy_from_slice(&self.chunk[self.offset..self.offset + len]);
+                self.offset += len;
+                return Ok(len);
+            }
+            if self.ended {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Message::Data(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Ok(Message::End) => self.ended = true,
+                Err(_) => {
+                    return Err(io::Error::other(
+                        "archive creation stopped before the end of the stream",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_pipe() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        let (mut tee, readers) = pipe(2);
+        let handles: Vec<_> = readers
+            .into_iter()
+            .map(|mut reader| {
+                thread::spawn(move || {
+                    let mut read = Vec::new();
+                    reader.read_to_end(&mut read).map(|_| read)
+                })
+            })
+            .collect();
+        tee.write_all(&data).unwrap();
+        let digest = tee.finish().unwrap();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), data);
+        }
+        assert_eq!(digest.size, data.len() as u64);
+        assert_eq!(digest.sha256, to_hex(&Sha256::digest(&data)));
+        assert_eq!(digest.md5, to_hex(&Md5::digest(&data)));
+
+        let (mut tee, mut readers) = pipe(2);
+        let mut reader = readers.pop().unwrap();
+        drop(readers);
+        let handle = thread::spawn(move || {
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).map(|_| read.len())
+        });
+        tee.write_all(&data).unwrap();
+        drop(tee);
+        assert!(handle.join().unwrap().is_err());
+    }
+}
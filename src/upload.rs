@@ -0,0 +1,541 @@
+//! Uploading an item's files to one backend: retries, staging names for
+//! `atomic_remote`, splitting over the backend's size limit and the
+//! health of each backend over a run.
+
+use crate::config::{AppConfig, BackupItem};
+use crate::retention::{item_archives, with_retention};
+use crate::signing;
+use crate::split;
+use crate::uploader::Uploader;
+use crate::verify::{VerifyMode, verify_upload};
+use crate::{format_bytes, normalize_archive_name, sidecar_path};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Retry and circuit-breaker settings shared by every upload in a run.
+pub struct UploadPolicy {
+    pub attempts: u32,
+    pub retry_delay: Duration,
+    pub failure_threshold: Option<usize>,
+    pub verify_mode: VerifyMode,
+}
+
+impl UploadPolicy {
+    pub fn from_app(app: &AppConfig) -> Self {
+        Self {
+            attempts: app.upload_attempts.unwrap_or(1).max(1),
+            retry_delay: Duration::from_secs(app.upload_retry_delay_secs.unwrap_or(10)),
+            failure_threshold: app
+                .provider_failure_threshold
+                .filter(|threshold| *threshold > 0),
+            verify_mode: app.verify_mode,
+        }
+    }
+}
+
+/// An uploader plus its health for the current run. Once a provider has failed
+/// `provider_failure_threshold` items in a row it is marked degraded and
+/// skipped for the remaining items.
+pub struct UploadTarget {
+    pub uploader: Box<dyn Uploader>,
+    pub consecutive_failures: usize,
+    pub degraded: bool,
+}
+
+impl UploadTarget {
+    pub fn new(uploader: Box<dyn Uploader>) -> Self {
+        Self {
+            uploader,
+            consecutive_failures: 0,
+            degraded: false,
+        }
+    }
+
+    pub fn record(&mut self, succeeded: bool, policy: &UploadPolicy) {
+        if succeeded {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures += 1;
+        if let Some(threshold) = policy.failure_threshold
+            && !self.degraded
+            && self.consecutive_failures >= threshold
+        {
+            self.degraded = true;
+            warn!(
+                "{} failed {} item(s) in a row, skipping it for the rest of this run",
+                self.uploader.name(),
+                self.consecutive_failures
+            );
+        }
+    }
+}
+
+/// Uploads `files` in order, retrying each one up to `policy.attempts` times.
+/// With `atomic`, the files carry the `.uploading` staging suffix and each one
+/// is renamed to its final name on the remote once it is fully uploaded. A
+/// file over the backend's single-file limit goes up in parts of that size
+/// with their manifest instead, as `max_part_size_mb` would cut it.
+pub fn upload_with_retries(
+    uploader: &mut dyn Uploader,
+    files: &[PathBuf],
+    remote_dir: &str,
+    policy: &UploadPolicy,
+    atomic: bool,
+) -> Result<()> {
+    let remote_dir = uploader.normalize_remote_dir(remote_dir)?;
+    // Split up front so a file that cannot be split does not leave the
+    // smaller ones it belongs with half uploaded. The parts are removed with
+    // their directories once this returns.
+    let mut split_dirs = Vec::new();
+    let mut upload_files = Vec::with_capacity(files.len());
+    for file in files {
+        let size = fs::metadata(file)
+            .with_context(|| format!("Failed to read file size: {}", file.display()))?
+            .len();
+        match uploader.max_file_size() {
+            Some(limit) if size > limit => {
+                let (dir, parts) = split_for_upload(file, limit, atomic).with_context(|| {
+                    format!(
+                        "{} is {}, over the {} single-file limit of {}, and could not be split",
+                        file.display(),
+                        format_bytes(size),
+                        uploader.name(),
+                        format_bytes(limit)
+                    )
+                })?;
+                info!(
+                    "{} is over the {} single-file limit of {}, uploading it in {} parts",
+                    file.display(),
+                    uploader.name(),
+                    format_bytes(limit),
+                    parts.len() - 1
+                );
+                split_dirs.push(dir);
+                upload_files.extend(parts);
+            }
+            _ => upload_files.push(file.clone()),
+        }
+    }
+    for file in &upload_files {
+        upload_file_with_retries(uploader, file, &remote_dir, policy)?;
+        let uploaded_name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Uploaded file name is not valid UTF-8")?;
+        let remote_name = if atomic {
+            let final_name = uploaded_name
+                .strip_suffix(STAGING_SUFFIX)
+                .context("Staged file is missing the staging suffix")?;
+            uploader.rename(&remote_dir, uploaded_name, final_name)?;
+            final_name
+        } else {
+            uploaded_name
+        };
+        verify_upload(uploader, &remote_dir, remote_name, file, policy.verify_mode)
+            .with_context(|| format!("Verification of {} failed", remote_name))?;
+    }
+    Ok(())
+}
+
+pub const STAGING_SUFFIX: &str = ".uploading";
+
+/// Cuts `file` into parts of at most `limit` bytes and their manifest, in a
+/// directory of their own next to it that is removed when dropped. Parts are
+/// named after the file's final name, and with `atomic` carry the staging
+/// suffix like the file itself.
+pub fn split_for_upload(
+    file: &Path,
+    limit: u64,
+    atomic: bool,
+) -> Result<(tempfile::TempDir, Vec<PathBuf>)> {
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Archive file name is not valid UTF-8")?;
+    let final_name = if atomic {
+        name.strip_suffix(STAGING_SUFFIX)
+            .context("Staged file is missing the staging suffix")?
+    } else {
+        name
+    };
+    let parent = file.parent().unwrap_or(Path::new("."));
+    let dir = tempfile::Builder::new()
+        .prefix(".split-")
+        .tempdir_in(parent)
+        .with_context(|| {
+            format!(
+                "Failed to create a directory for parts in {}",
+                parent.display()
+            )
+        })?;
+    let parts = split::split_file_into(file, final_name, dir.path(), limit)?;
+    let parts = if atomic { stage_files(&parts)? } else { parts };
+    Ok((dir, parts))
+}
+
+/// Renames `files` to their staging names for `atomic_remote` and returns
+/// those. If one cannot be renamed, the ones before it get their names back.
+pub fn stage_files(files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut staged_files = Vec::with_capacity(files.len());
+    for file in files {
+        let staged = sidecar_path(file, STAGING_SUFFIX.trim_start_matches('.'));
+        if let Err(err) = fs::rename(file, &staged) {
+            if let Err(restore_err) = unstage_files(&staged_files, files) {
+                warn!("{restore_err:#}");
+            }
+            return Err(err)
+                .with_context(|| format!("Failed to stage file for upload: {}", file.display()));
+        }
+        staged_files.push(staged);
+    }
+    Ok(staged_files)
+}
+
+/// Gives the `staged` files their names from `files` back, trying every one
+/// before reporting those that failed.
+pub fn unstage_files(staged: &[PathBuf], files: &[PathBuf]) -> Result<()> {
+    let failed: Vec<String> = staged
+        .iter()
+        .zip(files)
+        .filter_map(|(staged, file)| {
+            fs::rename(staged, file)
+                .err()
+                .map(|err| format!("{}: {err}", staged.display()))
+        })
+        .collect();
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "Failed to restore staged file names:\n{}",
+            failed.join("\n")
+        );
+    }
+    Ok(())
+}
+
+pub fn upload_file_with_retries(
+    uploader: &mut dyn Uploader,
+    file: &Path,
+    remote_dir: &str,
+    policy: &UploadPolicy,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let label = format!("Uploading {} to {}", file.display(), uploader.name());
+        let mut progress = progress_logger(label);
+        match uploader.upload_with_progress(file, remote_dir, &mut progress) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < policy.attempts => {
+                warn!(
+                    "Upload to {} failed (attempt {}/{}), retrying in {}s: {}",
+                    uploader.name(),
+                    attempt,
+                    policy.attempts,
+                    policy.retry_delay.as_secs(),
+                    err
+                );
+                thread::sleep(policy.retry_delay);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Logs upload progress whenever another 10% of the file has been sent.
+pub fn progress_logger(label: String) -> impl FnMut(u64, u64) {
+    let mut next_percent = 10;
+    move |sent, total| {
+        let percent = sent.saturating_mul(100).checked_div(total).unwrap_or(100);
+        if percent >= next_percent {
+            info!(
+                "{}: {}% ({} of {})",
+                label,
+                percent,
+                format_bytes(sent),
+                format_bytes(total)
+            );
+            next_percent = (percent / 10 + 1) * 10;
+        }
+    }
+}
+
+/// Result of uploading one item to one backend.
+pub struct UploadOutcome {
+    pub upload: Result<()>,
+    /// How long the upload took, retries included.
+    pub elapsed: Duration,
+    /// Only attempted once the upload succeeded.
+    pub retention: Result<()>,
+    /// Nothing was uploaded: the newest archive there was identical.
+    pub unchanged: bool,
+}
+
+/// New chunks of a `dedup` item, uploaded to their own directory before the
+/// recipe that refers to them.
+pub struct ChunkUpload {
+    pub files: Vec<PathBuf>,
+    pub remote_dir: String,
+}
+
+/// Uploads an item's files to one backend and, if that worked and
+/// `keep_remote` is set, prunes its older archives there. Runs on a thread of
+/// its own, one per backend.
+pub fn upload_to_target(
+    uploader: &mut dyn Uploader,
+    files: &[PathBuf],
+    chunks: Option<&ChunkUpload>,
+    remote_dir: &str,
+    policy: &UploadPolicy,
+    atomic: bool,
+    item: &BackupItem,
+) -> UploadOutcome {
+    info!("Uploading to {}", uploader.name());
+    let started = Instant::now();
+    let all_files: Vec<PathBuf> = chunks
+        .iter()
+        .flat_map(|chunks| chunks.files.iter())
+        .chain(files)
+        .cloned()
+        .collect();
+    let upload = check_available_space(uploader, &all_files)
+        .and_then(|()| match chunks {
+            Some(chunks) => {
+                upload_with_retries(uploader, &chunks.files, &chunks.remote_dir, policy, false)
+            }
+            None => Ok(()),
+        })
+        .and_then(|()| upload_with_retries(uploader, files, remote_dir, policy, atomic));
+    with_retention(uploader, upload, started.elapsed(), remote_dir, item)
+}
+
+/// Refuses `files` up front when the backend reports less free space than
+/// they need, instead of failing part way through. A quota that cannot be
+/// queried is only warned about.
+pub fn check_available_space(uploader: &mut dyn Uploader, files: &[PathBuf]) -> Result<()> {
+    let available = match uploader.available_space() {
+        Ok(Some(available)) => available,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            warn!(
+                "Could not query free space on {}: {:#}",
+                uploader.name(),
+                err
+            );
+            return Ok(());
+        }
+    };
+    let mut needed = 0;
+    for file in files {
+        needed += fs::metadata(file)
+            .with_context(|| format!("Failed to read file size: {}", file.display()))?
+            .len();
+    }
+    if needed > available {
+        anyhow::bail!(
+            "Insufficient cloud space on {}: {} free, {} needed",
+            uploader.name(),
+            format_bytes(available),
+            format_bytes(needed)
+        );
+    }
+    Ok(())
+}
+
+/// With `skip_if_unchanged`, skips the upload when the `.sha256` of the
+/// item's newest archive on the backend lists `sha256`, since uploading would
+/// only store a second copy. If that cannot be found out, a warning is logged
+/// and the archive is uploaded.
+pub fn skip_unchanged(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    item: &BackupItem,
+    sha256: &str,
+    work_dir: &Path,
+) -> Option<UploadOutcome> {
+    let base_name = normalize_archive_name(&item.archive_name);
+    let started = Instant::now();
+    match newest_remote_checksum(uploader, remote_dir, item, work_dir) {
+        Ok(Some((name, remote))) if remote == sha256 => {
+            info!(
+                "[{base_name}] identical to {} on {}: unchanged, skipped",
+                name,
+                uploader.name()
+            );
+            Some(UploadOutcome {
+                upload: Ok(()),
+                elapsed: started.elapsed(),
+                retention: Ok(()),
+                unchanged: true,
+            })
+        }
+        Ok(_) => None,
+        Err(err) => {
+            warn!(
+                "[{base_name}] could not compare with the newest archive on {}, uploading: {err:#}",
+                uploader.name()
+            );
+            None
+        }
+    }
+}
+
+/// Name and SHA-256 of the item's newest archive in `remote_dir`, read from
+/// its `.sha256`; `None` if there is no archive or it has no `.sha256`.
+pub fn newest_remote_checksum(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    item: &BackupItem,
+    work_dir: &Path,
+) -> Result<Option<(String, String)>> {
+    let remote_dir = uploader.normalize_remote_dir(remote_dir)?;
+    let entries = uploader.list(&remote_dir)?;
+    let Some(newest) = item_archives(entries.clone(), &item.archive_naming()).pop() else {
+        return Ok(None);
+    };
+    let checksum_name = format!("{}.sha256", newest.name);
+    if !entries.iter().any(|entry| entry.name == checksum_name) {
+        return Ok(None);
+    }
+    let download_dir =
+        tempfile::tempdir_in(work_dir).context("Failed to create a download directory")?;
+    let checksum_path = download_dir.path().join(&checksum_name);
+    uploader.download(&remote_dir, &checksum_name, &checksum_path)?;
+    Ok(Some((newest.name, signing::read_checksum(&checksum_path)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uploader;
+
+    /// The local uploader with a single-file limit, like a Baidu account's.
+    struct LimitedUploader(uploader::local::LocalUploader, u64);
+
+    impl Uploader for LimitedUploader {
+        fn name(&self) -> &str {
+            "limited"
+        }
+
+        fn upload_with_progress(
+            &mut self,
+            local_path: &Path,
+            remote_dir: &str,
+            progress: &mut dyn FnMut(u64, u64),
+        ) -> Result<()> {
+            let size = fs::metadata(local_path)?.len();
+            if size > self.1 {
+                anyhow::bail!("{} is over the limit", local_path.display());
+            }
+            self.0
+                .upload_with_progress(local_path, remote_dir, progress)
+        }
+
+        fn max_file_size(&self) -> Option<u64> {
+            Some(self.1)
+        }
+
+        fn supports_rename(&self) -> bool {
+            true
+        }
+
+        fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+            self.0.rename(remote_dir, from, to)
+        }
+    }
+
+    #[test]
+    fn test_upload_with_retries_splits_oversized_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (work, remote) = (dir.path().join("work"), dir.path().join("remote"));
+        fs::create_dir_all(remote.join("nightly")).unwrap();
+        fs::create_dir(&work).unwrap();
+        let archive = work.join("data-20260211.tar.zst");
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&archive, &content).unwrap();
+        let checksum = work.join("data-20260211.tar.zst.sha256");
+        fs::write(&checksum, "checksum").unwrap();
+        let policy = UploadPolicy {
+            attempts: 1,
+            retry_delay: Duration::ZERO,
+            failure_threshold: None,
+            verify_mode: VerifyMode::None,
+        };
+        let names = |dir: &Path| -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+
+        for atomic in [false, true] {
+            let mut uploader = LimitedUploader(
+                uploader::local::LocalUploader::new(&remote, false).unwrap(),
+                1000,
+            );
+            let files = if atomic {
+                stage_files(&[archive.clone(), checksum.clone()]).unwrap()
+            } else {
+                vec![archive.clone(), checksum.clone()]
+            };
+            upload_with_retries(&mut uploader, &files, "nightly", &policy, atomic).unwrap();
+            if atomic {
+                unstage_files(&files, &[archive.clone(), checksum.clone()]).unwrap();
+            }
+            assert_eq!(
+                names(&remote.join("nightly")),
+                [
+                    "data-20260211.tar.zst.manifest",
+                    "data-20260211.tar.zst.part001",
+                    "data-20260211.tar.zst.part002",
+                    "data-20260211.tar.zst.part003",
+                    "data-20260211.tar.zst.sha256",
+                ]
+            );
+            // The parts only lived for the upload.
+            assert_eq!(
+                names(&work),
+                ["data-20260211.tar.zst", "data-20260211.tar.zst.sha256"]
+            );
+            let joined: Vec<u8> = (1..=3)
+                .flat_map(|part| {
+                    fs::read(remote.join(format!("nightly/data-20260211.tar.zst.part00{part}")))
+                        .unwrap()
+                })
+                .collect();
+            assert_eq!(joined, content);
+            fs::remove_dir_all(remote.join("nightly")).unwrap();
+            fs::create_dir(remote.join("nightly")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stage_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("data-20260211.tar.zst");
+        let checksum = dir.path().join("data-20260211.tar.zst.sha256");
+        fs::write(&archive, "archive").unwrap();
+        fs::write(&checksum, "checksum").unwrap();
+        let files = [archive.clone(), checksum.clone()];
+
+        let staged = stage_files(&files).unwrap();
+        assert!(!archive.exists());
+        assert!(staged[0].ends_with("data-20260211.tar.zst.uploading"));
+        unstage_files(&staged, &files).unwrap();
+        assert!(archive.exists() && checksum.exists());
+
+        // The missing file fails the staging, and the archive staged before
+        // it gets its name back.
+        let missing = dir.path().join("data-20260211.tar.zst.sig");
+        let err = stage_files(&[archive.clone(), missing]).unwrap_err();
+        assert!(format!("{err:#}").starts_with("Failed to stage file for upload"));
+        assert!(archive.exists());
+        assert!(!staged[0].exists());
+    }
+}
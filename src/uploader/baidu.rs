@@ -0,0 +1,157 @@
+//! Baidu Netdisk helpers around estan's `BaiduPanUploader`.
+//!
+//! estan reports xpan API failures with the bare `errno` the API returned.
+//! The common ones are looked up here and added to the error, in English
+//! and in the Chinese of Baidu's own documentation, so a failure can be
+//! acted on without searching for the code.
+
+/// Known xpan `errno` values.
+const ERRNOS: &[(i64, &str)] = &[
+    (
+        -6,
+        "authentication failed, the access token is invalid or expired; run `backup-to-cloud auth` again (身份验证失败)",
+    ),
+    (
+        -7,
+        "file or directory name is invalid or not accessible (文件或目录名错误或无权访问)",
+    ),
+    (-8, "file or directory already exists (文件或目录已存在)"),
+    (-9, "file or directory does not exist (文件或目录不存在)"),
+    (-10, "cloud storage is full (云端容量已满)"),
+    (2, "invalid parameter (参数错误)"),
+    (
+        6,
+        "the app is not allowed to access user data; check its permissions in the Baidu developer console (不允许接入用户数据)",
+    ),
+    (10, "creating the file failed (创建文件失败)"),
+    (
+        111,
+        "the access token is invalid or expired; run `backup-to-cloud auth` again (access token 失效)",
+    ),
+    (31024, "no permission to access (没有访问权限)"),
+    (
+        31034,
+        "rate limited, too many requests for this account (命中接口频控)",
+    ),
+    (31061, "file already exists (文件已存在)"),
+    (31062, "invalid file name (文件名无效)"),
+    (
+        31064,
+        "upload path not allowed; remote_dir must be below /apps/<app name> (上传路径错误)",
+    ),
+    (
+        31190,
+        "file does not exist, the upload session may have expired (文件不存在)",
+    ),
+    (
+        31299,
+        "the first block is too small (第一个分片的大小小于4MB)",
+    ),
+    (
+        31363,
+        "block missing, a part did not reach the server before the file was created (分片缺失)",
+    ),
+    (
+        31364,
+        "a part exceeds the block size limit (超出分片大小限制)",
+    ),
+];
+
+/// Description of a known xpan `errno`.
+pub fn describe_errno(errno: i64) -> Option<&'static str> {
+    ERRNOS
+        .iter()
+        .find(|(code, _)| *code == errno)
+        .map(|(_, description)| *description)
+}
+
+/// The xpan `errno` for hitting the rate limit (频控).
+pub const RATE_LIMITED: i64 = 31034;
+
+/// Whether `err` is Baidu refusing requests because the account sent too
+/// many, which retrying right away only prolongs.
+pub fn is_rate_limited(err: &anyhow::Error) -> bool {
+    let message = format!("{err:#}");
+    find_errno(&message) == Some(RATE_LIMITED) || message.to_lowercase().contains("flow limit")
+}
+
+/// Non-zero `errno`s that a fresh try of the whole upload can get past:
+/// the rate limit, which has its own backoff, and the ones for an upload
+/// session that broke halfway.
+const TRANSIENT_ERRNOS: &[i64] = &[RATE_LIMITED, 10, 31190, 31363];
+
+/// Whether `err` carries an `errno` that retrying will not change, such as
+/// an expired token or a full or forbidden destination.
+pub fn is_permanent(err: &anyhow::Error) -> bool {
+    find_errno(&format!("{err:#}"))
+        .is_some_and(|errno| errno != 0 && !TRANSIENT_ERRNOS.contains(&errno))
+}
+
+/// The first `errno <n>` (also `errno: <n>` or `errno=<n>`) in `message`.
+pub fn find_errno(message: &str) -> Option<i64> {
+    message.match_indices("errno").find_map(|(at, word)| {
+        let rest = message[at + word.len()..].trim_start_matches([' ', ':', '=', '"']);
+        let end = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+            .map_or(rest.len(), |(i, _)| i);
+        rest[..end].parse().ok()
+    })
+}
+
+/// Adds what the `errno` in `err` means, or that it is not a known code.
+/// Errors without one are returned as they are.
+pub fn explain(err: anyhow::Error) -> anyhow::Error {
+    let Some(errno) = find_errno(&format!("{err:#}")) else {
+        return err;
+    };
+    match describe_errno(errno) {
+        Some(description) => err.context(format!("Baidu errno {errno}: {description}")),
+        None => err.context(format!(
+            "Baidu errno {errno} (not a known code, see the xpan API documentation)"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(find_errno("Pre-upload failed: errno 31034"), Some(31034));
+        assert_eq!(find_errno(r#"{"errno":-6,"request_id":1}"#), Some(-6));
+        assert_eq!(find_errno("errno=111, token"), Some(111));
+        assert_eq!(find_errno("no errno here"), None);
+        assert_eq!(find_errno("connection reset"), None);
+
+        let err = explain(anyhow::anyhow!("Create failed: errno 31363"));
+        assert!(format!("{err:#}").starts_with("Baidu errno 31363: block missing"));
+        let err = explain(anyhow::anyhow!("errno 424242"));
+        assert!(format!("{err:#}").contains("not a known code"));
+        let err = explain(anyhow::anyhow!("timed out"));
+        assert_eq!(format!("{err:#}"), "timed out");
+
+        assert!(is_rate_limited(&anyhow::anyhow!(
+            "Upload failed: errno 31034"
+        )));
+        assert!(is_rate_limited(&anyhow::anyhow!("Flow limit exceeded")));
+        assert!(!is_rate_limited(&anyhow::anyhow!("errno -6")));
+    }
+
+    #[test]
+    fn test_is_permanent() {
+        assert!(is_permanent(&anyhow::anyhow!(
+            "Pre-upload failed: errno -6"
+        )));
+        assert!(is_permanent(&anyhow::Error::msg(r#"{"errno":31064}"#)));
+        assert!(!is_permanent(&anyhow::anyhow!(
+            "Upload failed: errno 31034"
+        )));
+        assert!(!is_permanent(&anyhow::anyhow!(
+            "Create failed: errno 31363"
+        )));
+        assert!(!is_permanent(&anyhow::anyhow!("errno 0")));
+        assert!(!is_permanent(&anyhow::anyhow!("connection reset by peer")));
+    }
+}
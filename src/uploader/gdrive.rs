@@ -0,0 +1,771 @@
+//! Google Drive through the Drive v3 REST API.
+//!
+//! Authorization uses OAuth's device flow with the `drive.file` scope: the
+//! first time, a URL and a code are printed to be confirmed in a browser;
+//! the tokens are then kept in the `gdrive_config` file and the access token
+//! is refreshed silently whenever it is about to expire. With that scope the
+//! tool only sees what it created itself, so `remote_dir` is a path of
+//! folders below My Drive that are created as needed. Archives go up as
+//! resumable uploads in `CHUNK_SIZE` pieces, and a chunk whose request
+//! failed is resent from wherever Drive says it stopped.
+
+use super::{
+    HttpConfig, RemoteEntry, StorageUsage, Throttle, ThrottledReader, Uploader, check_remote_dir,
+    send_watched,
+};
+use anyhow::{Context, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use reqwest::redirect::Policy;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const SCOPE: &str = "https://www.googleapis.com/auth/drive.file";
+const FILES_URL: &str = "https://www.googleapis.com/drive/v3/files";
+const UPLOAD_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
+const ABOUT_URL: &str = "https://www.googleapis.com/drive/v3/about";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+/// Drive wants every chunk but the last to be a multiple of 256 KiB.
+const CHUNK_SIZE: u64 = 32 << 20;
+/// Times a failed chunk is resent before the upload gives up.
+const CHUNK_RETRIES: u32 = 3;
+/// The access token is refreshed this long before it expires.
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+pub struct GDriveConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the tokens are kept; written by the first authorization.
+    pub token_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    /// Unix time in seconds.
+    expires_at: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct OAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DriveFile {
+    id: String,
+    name: String,
+    /// int64 values are sent as strings; folders have none.
+    size: Option<String>,
+    md5_checksum: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    #[serde(default)]
+    files: Vec<DriveFile>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct About {
+    storage_quota: StorageQuota,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    user: Option<User>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct User {
+    email_address: Option<String>,
+}
+
+/// Like file sizes, the numbers are strings. Unlimited accounts have no
+/// `limit`.
+#[derive(Deserialize)]
+struct StorageQuota {
+    limit: Option<String>,
+    usage: Option<String>,
+}
+
+/// Where a resumable upload stands.
+#[derive(Debug, PartialEq, Eq)]
+enum SessionState {
+    /// Drive has this many bytes.
+    Incomplete(u64),
+    Complete,
+}
+
+pub struct GDriveUploader {
+    client: Client,
+    config: GDriveConfig,
+    token: Token,
+    /// Folder IDs by path below My Drive.
+    folders: HashMap<String, String>,
+    throttle: Option<Throttle>,
+}
+
+impl GDriveUploader {
+    /// Loads the saved tokens, or runs the device authorization when there
+    /// are none yet, which prints a URL and a code and waits until they have
+    /// been confirmed in a browser.
+    pub fn new(config: GDriveConfig, http: &HttpConfig) -> Result<Self> {
+        let client = build_client(http)?;
+        let token = match load_token(&config.token_path)? {
+            Some(token) => token,
+            None => {
+                let token = authorize(&client, &config)?;
+                save_token(&config.token_path, &token)?;
+                token
+            }
+        };
+        Ok(Self {
+            client,
+            config,
+            token,
+            folders: HashMap::new(),
+            throttle: None,
+        })
+    }
+
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    fn refresh_if_expired(&mut self) -> Result<()> {
+        if self.token.expires_at > unix_now() + EXPIRY_MARGIN_SECS {
+            return Ok(());
+        }
+        let response = self
+            .client
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("refresh_token", self.token.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ])
+            .send()
+            .context("Google token refresh failed")?;
+        if !response.status().is_success() {
+            let error = oauth_error(response);
+            anyhow::bail!(
+                "Google Drive refused to refresh the access token ({}); delete {} and run \
+                 `backup-to-cloud auth <config>` to authorize again",
+                error,
+                self.config.token_path.display()
+            );
+        }
+        let refreshed: TokenResponse = parse(response, "token refresh")?;
+        self.token.access_token = refreshed.access_token;
+        self.token.expires_at = unix_now() + refreshed.expires_in;
+        if let Some(refresh_token) = refreshed.refresh_token {
+            self.token.refresh_token = refresh_token;
+        }
+        save_token(&self.config.token_path, &self.token)
+    }
+
+    fn request(&mut self, method: Method, url: &str) -> Result<RequestBuilder> {
+        self.refresh_if_expired()?;
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.token.access_token))
+    }
+
+    fn quota(&mut self) -> Result<StorageUsage> {
+        let response = self
+            .request(Method::GET, ABOUT_URL)?
+            .query(&[("fields", "storageQuota(limit,usage)")])
+            .send()
+            .context("Google Drive quota request failed")?;
+        let about: About = parse(check(response, "quota")?, "quota")?;
+        let used = match about.storage_quota.usage {
+            Some(usage) => usage.parse().context("Invalid Google Drive quota usage")?,
+            None => 0,
+        };
+        let limit = match about.storage_quota.limit {
+            Some(limit) => Some(limit.parse().context("Invalid Google Drive quota limit")?),
+            None => None,
+        };
+        Ok(StorageUsage { used, limit })
+    }
+
+    /// Every file matching the search `query`, over as many pages as it
+    /// takes.
+    fn search(&mut self, query: &str) -> Result<Vec<DriveFile>> {
+        let mut files = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut params = vec![
+                ("q", query.to_string()),
+                (
+                    "fields",
+                    "nextPageToken,files(id,name,size,md5Checksum)".to_string(),
+                ),
+                ("pageSize", "1000".to_string()),
+            ];
+            if let Some(page_token) = page_token.take() {
+                params.push(("pageToken", page_token));
+            }
+            let response = self
+                .request(Method::GET, FILES_URL)?
+                .query(&params)
+                .send()
+                .context("Google Drive file listing failed")?;
+            let page: FileList = parse(check(response, "list")?, "file listing")?;
+            files.extend(page.files);
+            match page.next_page_token {
+                Some(next) => page_token = Some(next),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    /// The first non-trashed child of `parent` called `name`, either a
+    /// folder or a file.
+    fn find(&mut self, parent: &str, name: &str, folder: bool) -> Result<Option<DriveFile>> {
+        let query = format!(
+            "name = {} and {} in parents and mimeType {} {} and trashed = false",
+            query_literal(name),
+            query_literal(parent),
+            if folder { "=" } else { "!=" },
+            query_literal(FOLDER_MIME_TYPE)
+        );
+        Ok(self.search(&query)?.into_iter().next())
+    }
+
+    fn create_folder(&mut self, parent: &str, name: &str) -> Result<String> {
+        let metadata = serde_json::json!({
+            "name": name,
+            "mimeType": FOLDER_MIME_TYPE,
+            "parents": [parent],
+        });
+        let response = self
+            .request(Method::POST, FILES_URL)?
+            .query(&[("fields", "id")])
+            .header(CONTENT_TYPE, "application/json; charset=UTF-8")
+            .body(metadata.to_string())
+            .send()
+            .with_context(|| format!("Google Drive folder creation failed: {}", name))?;
+        #[derive(Deserialize)]
+        struct Created {
+            id: String,
+        }
+        let created: Created = parse(check(response, "create folder")?, "folder creation")?;
+        Ok(created.id)
+    }
+
+    /// ID of the folder at `remote_dir`, creating missing folders one
+    /// segment at a time if `create` is set; `None` if it does not exist
+    /// otherwise.
+    fn folder_id(&mut self, remote_dir: &str, create: bool) -> Result<Option<String>> {
+        let mut parent = "root".to_string();
+        let mut path = String::new();
+        for segment in remote_dir.split('/').filter(|segment| !segment.is_empty()) {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(segment);
+            if let Some(id) = self.folders.get(&path) {
+                parent = id.clone();
+                continue;
+            }
+            let id = match self.find(&parent, segment, true)? {
+                Some(folder) => folder.id,
+                None if create => self.create_folder(&parent, segment)?,
+                None => return Ok(None),
+            };
+            self.folders.insert(path.clone(), id.clone());
+            parent = id;
+        }
+        Ok(Some(parent))
+    }
+
+    fn existing_folder(&mut self, remote_dir: &str) -> Result<String> {
+        self.folder_id(remote_dir, false)?
+            .with_context(|| format!("Google Drive has no folder {}", remote_dir))
+    }
+
+    fn file_id(&mut self, remote_dir: &str, name: &str) -> Result<String> {
+        let folder = self.existing_folder(remote_dir)?;
+        let file = self
+            .find(&folder, name, false)?
+            .with_context(|| format!("Google Drive has no file {}/{}", remote_dir, name))?;
+        Ok(file.id)
+    }
+
+    fn delete_file(&mut self, id: &str) -> Result<()> {
+        let response = self
+            .request(Method::DELETE, &format!("{}/{}", FILES_URL, id))?
+            .send()
+            .context("Google Drive delete failed")?;
+        check(response, "delete")?;
+        Ok(())
+    }
+
+    /// Opens a resumable upload session and returns its URL. A file that
+    /// already has the name gets a new revision instead of a duplicate,
+    /// since Drive allows several files with the same name in a folder.
+    fn start_session(
+        &mut self,
+        folder: &str,
+        name: &str,
+        existing: Option<String>,
+        total: u64,
+    ) -> Result<String> {
+        let (method, url, metadata) = match existing {
+            Some(id) => (
+                Method::PATCH,
+                format!("{}/{}", UPLOAD_URL, id),
+                serde_json::json!({}),
+            ),
+            None => (
+                Method::POST,
+                UPLOAD_URL.to_string(),
+                serde_json::json!({ "name": name, "parents": [folder] }),
+            ),
+        };
+        let response = self
+            .request(method, &url)?
+            .query(&[("uploadType", "resumable")])
+            .header(CONTENT_TYPE, "application/json; charset=UTF-8")
+            .header("X-Upload-Content-Type", "application/octet-stream")
+            .header("X-Upload-Content-Length", total)
+            .body(metadata.to_string())
+            .send()
+            .with_context(|| format!("Google Drive upload of {} failed to start", name))?;
+        let response = check(response, "start upload")?;
+        response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .context("Google Drive did not return an upload session URL")
+    }
+
+    /// Asks the session how much it received, after a chunk failed.
+    fn session_state(&self, session: &str, total: u64) -> Result<SessionState> {
+        let response = self
+            .client
+            .put(session)
+            .header(CONTENT_RANGE, format!("bytes */{}", total))
+            .body(Vec::new())
+            .send()
+            .context("Google Drive upload status query failed")?;
+        parse_session_state(response)
+    }
+
+    fn send_chunk(
+        &self,
+        session: &str,
+        file: &File,
+        offset: u64,
+        total: u64,
+    ) -> Result<SessionState> {
+        let len = CHUNK_SIZE.min(total - offset);
+        let mut chunk = file.try_clone().context("Failed to reopen archive")?;
+        chunk
+            .seek(SeekFrom::Start(offset))
+            .context("Failed to seek in archive")?;
+        let (body, watchdog) =
+            ThrottledReader::new(chunk.take(len), self.throttle.clone()).sized_body(len);
+        let request = self
+            .client
+            .put(session)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+            )
+            .body(body);
+        let response =
+            send_watched(request, watchdog).context("Google Drive chunk upload failed")?;
+        parse_session_state(response)
+    }
+}
+
+impl Uploader for GDriveUploader {
+    fn name(&self) -> &str {
+        "GDrive"
+    }
+
+    /// Progress is reported after every chunk.
+    fn upload_with_progress(
+        &mut self,
+        local_path: &Path,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Archive file name is not valid UTF-8")?;
+        let file = File::open(local_path)
+            .with_context(|| format!("Failed to open archive: {}", local_path.display()))?;
+        let total = file
+            .metadata()
+            .with_context(|| format!("Failed to read file size: {}", local_path.display()))?
+            .len();
+        let folder = self
+            .folder_id(remote_dir, true)?
+            .context("Google Drive folder was not created")?;
+        let existing = self.find(&folder, name, false)?.map(|file| file.id);
+        // The session URL carries its own authorization, so a long upload
+        // does not depend on the access token staying valid.
+        let session = self.start_session(&folder, name, existing, total)?;
+        if total == 0 {
+            if self.session_state(&session, 0)? != SessionState::Complete {
+                anyhow::bail!("Google Drive did not finish the empty upload of {}", name);
+            }
+            progress(0, 0);
+            return Ok(());
+        }
+
+        let mut offset = 0;
+        let mut retries = 0;
+        while offset < total {
+            match self.send_chunk(&session, &file, offset, total) {
+                Ok(SessionState::Incomplete(received)) => {
+                    offset = received;
+                    retries = 0;
+                }
+                Ok(SessionState::Complete) => offset = total,
+                Err(err) if retries < CHUNK_RETRIES => {
+                    retries += 1;
+                    warn!(
+                        "Google Drive chunk at byte {} of {} failed, resending ({}/{}): {:#}",
+                        offset, name, retries, CHUNK_RETRIES, err
+                    );
+                    thread::sleep(Duration::from_secs(u64::from(retries)));
+                    offset = match self.session_state(&session, total)? {
+                        SessionState::Incomplete(received) => received,
+                        SessionState::Complete => total,
+                    };
+                }
+                Err(err) => return Err(err),
+            }
+            progress(offset, total);
+        }
+        Ok(())
+    }
+
+    /// Paths are below My Drive, without leading or trailing `/`.
+    fn normalize_remote_dir(&self, remote_dir: &str) -> Result<String> {
+        Ok(check_remote_dir(remote_dir)?.trim_matches('/').to_string())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    /// Replaces an existing `to`, as the other backends do.
+    fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+        let id = self.file_id(remote_dir, from)?;
+        let folder = self.existing_folder(remote_dir)?;
+        if let Some(existing) = self.find(&folder, to, false)? {
+            self.delete_file(&existing.id)?;
+        }
+        let response = self
+            .request(Method::PATCH, &format!("{}/{}", FILES_URL, id))?
+            .header(CONTENT_TYPE, "application/json; charset=UTF-8")
+            .body(serde_json::json!({ "name": to }).to_string())
+            .send()
+            .with_context(|| format!("Google Drive rename failed: {}", from))?;
+        check(response, "rename")?;
+        Ok(())
+    }
+
+    fn list(&mut self, remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+        let folder = self.existing_folder(remote_dir)?;
+        let query = format!(
+            "{} in parents and mimeType != {} and trashed = false",
+            query_literal(&folder),
+            query_literal(FOLDER_MIME_TYPE)
+        );
+        Ok(self
+            .search(&query)?
+            .into_iter()
+            .map(|file| RemoteEntry {
+                size: file.size.and_then(|size| size.parse().ok()).unwrap_or(0),
+                md5: file.md5_checksum,
+                name: file.name,
+            })
+            .collect())
+    }
+
+    fn check_access(&mut self) -> Result<Option<String>> {
+        let response = self
+            .request(Method::GET, ABOUT_URL)?
+            .query(&[("fields", "user(emailAddress)")])
+            .send()
+            .context("Google Drive account request failed")?;
+        let account: Account = parse(check(response, "account")?, "account")?;
+        Ok(account
+            .user
+            .and_then(|user| user.email_address)
+            .map(|email| format!("signed in as {email}")))
+    }
+
+    fn available_space(&mut self) -> Result<Option<u64>> {
+        let usage = self.quota()?;
+        Ok(usage.limit.map(|limit| limit.saturating_sub(usage.used)))
+    }
+
+    fn storage_usage(&mut self) -> Result<Option<StorageUsage>> {
+        self.quota().map(Some)
+    }
+
+    fn download(&mut self, remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+        let id = self.file_id(remote_dir, name)?;
+        let response = self
+            .request(Method::GET, &format!("{}/{}", FILES_URL, id))?
+            .query(&[("alt", "media")])
+            .send()
+            .with_context(|| format!("Google Drive download failed: {}", name))?;
+        let mut response = check(response, "download")?;
+        let mut file = File::create(local_path)
+            .with_context(|| format!("Failed to create file: {}", local_path.display()))?;
+        io::copy(&mut response, &mut file)
+            .with_context(|| format!("Failed to download {}", name))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to flush file: {}", local_path.display()))
+    }
+
+    /// Deletes for good, without going through the trash.
+    fn delete(&mut self, remote_dir: &str, name: &str) -> Result<()> {
+        let id = self.file_id(remote_dir, name)?;
+        self.delete_file(&id)
+    }
+}
+
+/// Upload sessions answer `308` while incomplete, which must not be taken
+/// for a redirect.
+fn build_client(http: &HttpConfig) -> Result<Client> {
+    http.client_builder()?
+        .redirect(Policy::custom(|attempt| {
+            if attempt.status() == StatusCode::PERMANENT_REDIRECT || attempt.previous().len() > 10 {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+        .context("Failed to create HTTP client")
+}
+
+/// Runs the OAuth device flow until the code was confirmed, denied or
+/// expired.
+fn authorize(client: &Client, config: &GDriveConfig) -> Result<Token> {
+    let response = client
+        .post(DEVICE_CODE_URL)
+        .form(&[("client_id", config.client_id.as_str()), ("scope", SCOPE)])
+        .send()
+        .context("Google device authorization failed")?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Google device authorization failed: {}",
+            oauth_error(response)
+        );
+    }
+    let device: DeviceCode = parse(response, "device authorization")?;
+    println!(
+        "To authorize Google Drive, open {} and enter the code {}",
+        device.verification_url, device.user_code
+    );
+
+    let mut interval = device.interval.unwrap_or(5);
+    let deadline = unix_now() + device.expires_in;
+    while unix_now() < deadline {
+        thread::sleep(Duration::from_secs(interval));
+        let response = client
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+                ("device_code", device.device_code.as_str()),
+                ("grant_type", DEVICE_GRANT_TYPE),
+            ])
+            .send()
+            .context("Google token request failed")?;
+        if response.status().is_success() {
+            let granted: TokenResponse = parse(response, "token request")?;
+            return Ok(Token {
+                access_token: granted.access_token,
+                refresh_token: granted
+                    .refresh_token
+                    .context("Google did not return a refresh token")?,
+                expires_at: unix_now() + granted.expires_in,
+            });
+        }
+        let body = response.text().unwrap_or_default();
+        match serde_json::from_str::<OAuthError>(&body) {
+            Ok(error) if error.error == "authorization_pending" => {}
+            Ok(error) if error.error == "slow_down" => interval += 5,
+            Ok(error) => anyhow::bail!(
+                "Google Drive authorization failed: {}",
+                describe_oauth_error(&error)
+            ),
+            Err(_) => anyhow::bail!("Google Drive authorization failed: {}", body.trim()),
+        }
+    }
+    anyhow::bail!("Google Drive authorization code expired before it was entered")
+}
+
+fn load_token(path: &Path) -> Result<Option<Token>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Invalid Google Drive token file: {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)
+            .with_context(|| format!("Failed to read Google Drive token file: {}", path.display())),
+    }
+}
+
+/// Written through a temporary file, so a crash never leaves half a token
+/// file behind; on Unix it is created with mode 600.
+fn save_token(path: &Path, token: &Token) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let partial = path.with_extension("partial");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let json = serde_json::to_vec_pretty(token).context("Failed to encode Google Drive token")?;
+    options
+        .open(&partial)
+        .and_then(|mut file| file.write_all(&json))
+        .with_context(|| format!("Failed to write token file: {}", partial.display()))?;
+    fs::rename(&partial, path)
+        .with_context(|| format!("Failed to write token file: {}", path.display()))
+}
+
+fn check(response: Response, action: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    anyhow::bail!(
+        "Google Drive {} failed with HTTP {}: {}",
+        action,
+        status,
+        crate::error_body(response)
+    )
+}
+
+fn parse<T: DeserializeOwned>(response: Response, what: &str) -> Result<T> {
+    let body = response
+        .text()
+        .with_context(|| format!("Failed to read Google {} response", what))?;
+    serde_json::from_str(&body).with_context(|| format!("Invalid Google {} response", what))
+}
+
+/// The OAuth `error` of a failed token request, or its status and body
+/// when it has none.
+fn oauth_error(response: Response) -> String {
+    let status = response.status();
+    let body = response.text().unwrap_or_default();
+    serde_json::from_str::<OAuthError>(&body)
+        .map(|error| describe_oauth_error(&error))
+        .unwrap_or_else(|_| format!("HTTP {}: {}", status, body.trim()))
+}
+
+fn describe_oauth_error(error: &OAuthError) -> String {
+    match &error.error_description {
+        Some(description) => format!("{}: {}", error.error, description),
+        None => error.error.clone(),
+    }
+}
+
+/// `200`/`201` once Drive has the whole file, `308` with the received range
+/// (`bytes=0-N`, absent before the first byte) while it does not.
+fn parse_session_state(response: Response) -> Result<SessionState> {
+    match response.status() {
+        StatusCode::PERMANENT_REDIRECT => Ok(SessionState::Incomplete(
+            response
+                .headers()
+                .get(RANGE)
+                .and_then(|value| value.to_str().ok())
+                .map_or(Ok(0), received_bytes)?,
+        )),
+        _ => check(response, "upload").map(|_| SessionState::Complete),
+    }
+}
+
+fn received_bytes(range: &str) -> Result<u64> {
+    range
+        .strip_prefix("bytes=0-")
+        .and_then(|last| last.parse::<u64>().ok())
+        .map(|last| last + 1)
+        .with_context(|| format!("Unexpected upload range from Google Drive: {}", range))
+}
+
+/// A string literal for a Drive search query.
+fn query_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_literal_and_range() {
+        assert_eq!(query_literal("app"), "'app'");
+        assert_eq!(query_literal(r"it's a\b"), r"'it\'s a\\b'");
+        assert_eq!(received_bytes("bytes=0-33554431").unwrap(), 32 << 20);
+        assert!(received_bytes("bytes=5-9").is_err());
+    }
+}
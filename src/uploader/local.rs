@@ -0,0 +1,326 @@
+//! A directory on the local filesystem, typically an NFS or SMB mount.
+//!
+//! `remote_dir` is resolved below `local_base_dir`. Files are copied to a
+//! hidden `.partial` name first and renamed into place once complete, so a
+//! crash never leaves a truncated archive under its final name.
+
+use super::{RemoteEntry, Throttle, ThrottledReader, Uploader, check_remote_dir};
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+pub struct LocalUploader {
+    base_dir: PathBuf,
+    fsync: bool,
+    throttle: Option<Throttle>,
+}
+
+impl LocalUploader {
+    pub fn new(base_dir: impl Into<PathBuf>, fsync: bool) -> Result<Self> {
+        let base_dir = base_dir.into();
+        if !base_dir.is_dir() {
+            anyhow::bail!("local_base_dir is not a directory: {}", base_dir.display());
+        }
+        Ok(Self {
+            base_dir,
+            fsync,
+            throttle: None,
+        })
+    }
+
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    fn dir(&self, remote_dir: &str) -> PathBuf {
+        self.base_dir.join(remote_dir)
+    }
+
+    fn path(&self, remote_dir: &str, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name == ".." {
+            anyhow::bail!("Invalid remote file name: {}", name);
+        }
+        Ok(self.dir(remote_dir).join(name))
+    }
+
+    fn copy_with_progress(
+        &self,
+        source: &Path,
+        destination: &Path,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let file = File::open(source)
+            .with_context(|| format!("Failed to open archive: {}", source.display()))?;
+        let total = file
+            .metadata()
+            .with_context(|| format!("Failed to read file size: {}", source.display()))?
+            .len();
+        let sent = self.copy(
+            file,
+            &source.display().to_string(),
+            destination,
+            &mut |sent| progress(sent.min(total), total),
+        )?;
+        if sent != total {
+            anyhow::bail!(
+                "Archive changed size while copying: {} (expected {} bytes, copied {})",
+                source.display(),
+                total,
+                sent
+            );
+        }
+        if total == 0 {
+            progress(0, 0);
+        }
+        Ok(())
+    }
+
+    /// Copies `input` to `destination`, fsyncing it if configured, and
+    /// returns the number of bytes written. `label` names the input in
+    /// errors.
+    fn copy(
+        &self,
+        input: impl Read,
+        label: &str,
+        destination: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<u64> {
+        let mut input = ThrottledReader::new(input, self.throttle.clone());
+        let mut output = File::create(destination)
+            .with_context(|| format!("Failed to create file: {}", destination.display()))?;
+        let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+        let mut sent = 0u64;
+        loop {
+            let read = input
+                .read(&mut buffer)
+                .with_context(|| format!("Failed to read archive: {}", label))?;
+            if read == 0 {
+                break;
+            }
+            output
+                .write_all(&buffer[..read])
+                .with_context(|| format!("Failed to write file: {}", destination.display()))?;
+            sent += read as u64;
+            progress(sent);
+        }
+        if self.fsync {
+            output
+                .sync_all()
+                .with_context(|| format!("Failed to fsync file: {}", destination.display()))?;
+        }
+        Ok(sent)
+    }
+
+    /// Writes `name` under a hidden `.partial` name with `copy` and renames it
+    /// into place once that succeeded.
+    fn place(
+        &self,
+        remote_dir: &str,
+        name: &str,
+        copy: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<()> {
+        let dir = self.dir(remote_dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        let destination = self.path(remote_dir, name)?;
+        let partial = dir.join(format!(".{name}.partial"));
+
+        let copied = copy(&partial).and_then(|()| {
+            fs::rename(&partial, &destination).with_context(|| {
+                format!("Failed to move file into place: {}", destination.display())
+            })
+        });
+        if copied.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        copied?;
+        self.sync_dir(&dir)
+    }
+
+    /// Flushes the directory entry after a rename, so the new name survives a
+    /// power loss. Directories cannot be opened for syncing on Windows.
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        if self.fsync && cfg!(unix) {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("Failed to fsync directory: {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Uploader for LocalUploader {
+    fn name(&self) -> &str {
+        "Local"
+    }
+
+    fn upload_with_progress(
+        &mut self,
+        local_path: &Path,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Archive file name is not valid UTF-8")?;
+        self.place(remote_dir, name, |partial| {
+            self.copy_with_progress(local_path, partial, progress)
+        })
+    }
+
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    fn upload_stream(
+        &mut self,
+        input: Box<dyn Read + Send>,
+        name: &str,
+        remote_dir: &str,
+    ) -> Result<()> {
+        self.place(remote_dir, name, |partial| {
+            self.copy(input, name, partial, &mut |_| {}).map(|_| ())
+        })
+    }
+
+    /// Paths are relative to `local_base_dir`, without leading or trailing
+    /// `/`.
+    fn normalize_remote_dir(&self, remote_dir: &str) -> Result<String> {
+        Ok(check_remote_dir(remote_dir)?.trim_matches('/').to_string())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+        let source = self.path(remote_dir, from)?;
+        let destination = self.path(remote_dir, to)?;
+        fs::rename(&source, &destination).with_context(|| {
+            format!(
+                "Failed to rename {} to {}",
+                source.display(),
+                destination.display()
+            )
+        })?;
+        self.sync_dir(&self.dir(remote_dir))
+    }
+
+    fn list(&mut self, remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+        let dir = self.dir(remote_dir);
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        {
+            let entry =
+                entry.with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+            let metadata = entry
+                .metadata()
+                .with_context(|| format!("Failed to read metadata: {}", entry.path().display()))?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                entries.push(RemoteEntry {
+                    name: name.to_string(),
+                    size: metadata.len(),
+                    md5: None,
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn download(&mut self, remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+        let source = self.path(remote_dir, name)?;
+        fs::copy(&source, local_path).with_context(|| {
+            format!(
+                "Failed to copy {} to {}",
+                source.display(),
+                local_path.display()
+            )
+        })?;
+        Ok(())
+    }
+
+    fn delete(&mut self, remote_dir: &str, name: &str) -> Result<()> {
+        let path = self.path(remote_dir, name)?;
+        fs::remove_file(&path).with_context(|| format!("Failed to delete: {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_uploader_round_trip() {
+        let work = tempfile::tempdir().unwrap();
+        let base = tempfile::tempdir().unwrap();
+        let archive = work.path().join("demo-20260211.tar.zst");
+        let content = vec![7u8; COPY_BUFFER_SIZE + 123];
+        fs::write(&archive, &content).unwrap();
+
+        let mut uploader = LocalUploader::new(base.path(), true).unwrap();
+        let remote_dir = uploader.normalize_remote_dir("/backups/demo/").unwrap();
+        let mut reports = Vec::new();
+        uploader
+            .upload_with_progress(&archive, &remote_dir, &mut |sent, total| {
+                reports.push((sent, total))
+            })
+            .unwrap();
+        let total = content.len() as u64;
+        assert_eq!(reports.last(), Some(&(total, total)));
+        assert!(reports.len() >= 2);
+
+        let listed = uploader.list(&remote_dir).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "demo-20260211.tar.zst");
+        assert_eq!(listed[0].size, total);
+
+        uploader
+            .rename(&remote_dir, "demo-20260211.tar.zst", "demo-final.tar.zst")
+            .unwrap();
+        let downloaded = work.path().join("downloaded");
+        uploader
+            .download(&remote_dir, "demo-final.tar.zst", &downloaded)
+            .unwrap();
+        assert_eq!(fs::read(&downloaded).unwrap(), content);
+
+        uploader.delete(&remote_dir, "demo-final.tar.zst").unwrap();
+
+        let (mut tee, mut readers) = crate::stream::pipe(1);
+        tee.write_all(&content).unwrap();
+        tee.finish().unwrap();
+        uploader
+            .upload_stream(
+                Box::new(readers.remove(0)),
+                "demo-stream.tar.zst",
+                &remote_dir,
+            )
+            .unwrap();
+        uploader
+            .download(&remote_dir, "demo-stream.tar.zst", &downloaded)
+            .unwrap();
+        assert_eq!(fs::read(&downloaded).unwrap(), content);
+        uploader.delete(&remote_dir, "demo-stream.tar.zst").unwrap();
+
+        // A stream that ends without `finish` leaves nothing behind.
+        let (mut tee, mut readers) = crate::stream::pipe(1);
+        tee.write_all(b"truncated").unwrap();
+        drop(tee);
+        assert!(
+            uploader
+                .upload_stream(Box::new(readers.remove(0)), "demo-cut.tar.zst", &remote_dir)
+                .is_err()
+        );
+        assert!(uploader.list(&remote_dir).unwrap().is_empty());
+        assert!(uploader.delete(&remote_dir, "../escape").is_err());
+    }
+}
@@ -0,0 +1,718 @@
+//! OneDrive through Microsoft Graph.
+//!
+//! Authorization uses OAuth's device flow against the Microsoft identity
+//! platform, like Google Drive: the first time, a URL and a code are printed
+//! to be confirmed in a browser; the tokens are then kept in the
+//! `onedrive_config` file and the access token is refreshed silently
+//! whenever it is about to expire. `remote_dir` is a path of folders below
+//! the drive root, created as needed. Files up to `SIMPLE_UPLOAD_LIMIT` go
+//! up in a single PUT, larger ones through an upload session in
+//! `CHUNK_SIZE` pieces, and a chunk whose request failed is resent from
+//! wherever the session says it stopped.
+
+use super::{
+    HttpConfig, RemoteEntry, StorageUsage, Throttle, ThrottledReader, Uploader, check_remote_dir,
+    percent_encode, send_watched,
+};
+use anyhow::{Context, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const LOGIN_URL: &str = "https://login.microsoftonline.com";
+const DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// `offline_access` is what gets a refresh token.
+const SCOPE: &str = "Files.ReadWrite offline_access";
+const DRIVE_URL: &str = "https://graph.microsoft.com/v1.0/me/drive";
+/// Tenant used unless `onedrive_tenant` is set: any personal or work account.
+pub const DEFAULT_TENANT: &str = "common";
+/// Graph takes files up to this size in a single PUT.
+const SIMPLE_UPLOAD_LIMIT: u64 = 4 << 20;
+/// Upload session chunks must be multiples of 320 KiB.
+const CHUNK_SIZE: u64 = 32 * (320 << 10);
+/// Times a failed chunk is resent before the upload gives up.
+const CHUNK_RETRIES: u32 = 3;
+/// The access token is refreshed this long before it expires.
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+pub struct OneDriveConfig {
+    pub client_id: String,
+    /// Only for apps registered as confidential clients; device flow apps
+    /// usually have none.
+    pub client_secret: Option<String>,
+    pub tenant: String,
+    /// Where the tokens are kept; written by the first authorization.
+    pub token_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    /// Unix time in seconds.
+    expires_at: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct OAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DriveItem {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    /// Only files have it; folders have a `folder` facet instead.
+    file: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Children {
+    #[serde(default)]
+    value: Vec<DriveItem>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Drive {
+    quota: Option<Quota>,
+    owner: Option<IdentitySet>,
+}
+
+#[derive(Deserialize)]
+struct IdentitySet {
+    user: Option<Identity>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Identity {
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Quota {
+    used: Option<i64>,
+    total: Option<i64>,
+    remaining: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadSession {
+    upload_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionStatus {
+    #[serde(default)]
+    next_expected_ranges: Vec<String>,
+}
+
+/// Where an upload session stands.
+#[derive(Debug, PartialEq, Eq)]
+enum SessionState {
+    /// The session wants the file from this byte on.
+    Incomplete(u64),
+    Complete,
+}
+
+pub struct OneDriveUploader {
+    client: Client,
+    config: OneDriveConfig,
+    token: Token,
+    /// Folders known to exist, by path below the drive root.
+    folders: HashSet<String>,
+    throttle: Option<Throttle>,
+}
+
+impl OneDriveUploader {
+    /// Loads the saved tokens, or runs the device authorization when there
+    /// are none yet, which prints a URL and a code and waits until they have
+    /// been confirmed in a browser.
+    pub fn new(config: OneDriveConfig, http: &HttpConfig) -> Result<Self> {
+        let client = http.http_client()?;
+        let token = match load_token(&config.token_path)? {
+            Some(token) => token,
+            None => {
+                let token = authorize(&client, &config)?;
+                save_token(&config.token_path, &token)?;
+                token
+            }
+        };
+        Ok(Self {
+            client,
+            config,
+            token,
+            folders: HashSet::new(),
+            throttle: None,
+        })
+    }
+
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    fn refresh_if_expired(&mut self) -> Result<()> {
+        if self.token.expires_at > unix_now() + EXPIRY_MARGIN_SECS {
+            return Ok(());
+        }
+        let mut form = vec![
+            ("client_id", self.config.client_id.as_str()),
+            ("refresh_token", self.token.refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+            ("scope", SCOPE),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = self
+            .client
+            .post(token_url(&self.config.tenant))
+            .form(&form)
+            .send()
+            .context("Microsoft token refresh failed")?;
+        if !response.status().is_success() {
+            let error = oauth_error(response);
+            anyhow::bail!(
+                "OneDrive refused to refresh the access token ({}); delete {} and run \
+                 `backup-to-cloud auth <config>` to authorize again",
+                error,
+                self.config.token_path.display()
+            );
+        }
+        let refreshed: TokenResponse = parse(response, "token refresh")?;
+        self.token.access_token = refreshed.access_token;
+        self.token.expires_at = unix_now() + refreshed.expires_in;
+        if let Some(refresh_token) = refreshed.refresh_token {
+            self.token.refresh_token = refresh_token;
+        }
+        save_token(&self.config.token_path, &self.token)
+    }
+
+    fn request(&mut self, method: Method, url: &str) -> Result<RequestBuilder> {
+        self.refresh_if_expired()?;
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.token.access_token))
+    }
+
+    fn quota(&mut self) -> Result<Option<Quota>> {
+        let response = self
+            .request(Method::GET, DRIVE_URL)?
+            .query(&[("$select", "quota")])
+            .send()
+            .context("OneDrive quota request failed")?;
+        let drive: Drive = parse(check(response, "quota")?, "quota")?;
+        Ok(drive.quota)
+    }
+
+    /// Creates every segment of `remote_dir` that is not known to exist; a
+    /// folder that is already there answers `409`.
+    fn ensure_folders(&mut self, remote_dir: &str) -> Result<()> {
+        let mut parent = String::new();
+        for segment in remote_dir.split('/').filter(|segment| !segment.is_empty()) {
+            let path = join_path(&parent, segment);
+            if !self.folders.contains(&path) {
+                let body = serde_json::json!({
+                    "name": segment,
+                    "folder": {},
+                    "@microsoft.graph.conflictBehavior": "fail",
+                });
+                let response = self
+                    .request(Method::POST, &item_url(&parent, "/children"))?
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .with_context(|| format!("OneDrive folder creation failed: {}", path))?;
+                if response.status() != StatusCode::CONFLICT {
+                    check(response, "create folder")?;
+                }
+                self.folders.insert(path.clone());
+            }
+            parent = path;
+        }
+        Ok(())
+    }
+
+    fn simple_upload(&mut self, path: &str, file: File, total: u64) -> Result<()> {
+        let url = item_url(path, "/content");
+        let (body, watchdog) = ThrottledReader::new(file, self.throttle.clone()).sized_body(total);
+        let request = self
+            .request(Method::PUT, &url)?
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(body);
+        let response = send_watched(request, watchdog)
+            .with_context(|| format!("OneDrive upload failed: {}", path))?;
+        check(response, "upload")?;
+        Ok(())
+    }
+
+    /// Opens an upload session that replaces an existing file of the same
+    /// name, and returns its URL.
+    fn start_session(&mut self, path: &str) -> Result<String> {
+        let body = serde_json::json!({
+            "item": { "@microsoft.graph.conflictBehavior": "replace" },
+        });
+        let response = self
+            .request(Method::POST, &item_url(path, "/createUploadSession"))?
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .with_context(|| format!("OneDrive upload of {} failed to start", path))?;
+        let session: UploadSession = parse(check(response, "start upload")?, "upload session")?;
+        Ok(session.upload_url)
+    }
+
+    /// Asks the session which bytes it still wants, after a chunk failed.
+    fn session_state(&self, session: &str) -> Result<SessionState> {
+        let response = self
+            .client
+            .get(session)
+            .send()
+            .context("OneDrive upload status query failed")?;
+        let status: SessionStatus = parse(check(response, "upload status")?, "upload status")?;
+        match status.next_expected_ranges.first() {
+            Some(range) => next_expected(range).map(SessionState::Incomplete),
+            None => Ok(SessionState::Complete),
+        }
+    }
+
+    /// The session URL carries its own authorization, and Graph rejects
+    /// chunks that come with an access token.
+    fn send_chunk(
+        &self,
+        session: &str,
+        file: &File,
+        offset: u64,
+        total: u64,
+    ) -> Result<SessionState> {
+        let len = CHUNK_SIZE.min(total - offset);
+        let mut chunk = file.try_clone().context("Failed to reopen archive")?;
+        chunk
+            .seek(SeekFrom::Start(offset))
+            .context("Failed to seek in archive")?;
+        let (body, watchdog) =
+            ThrottledReader::new(chunk.take(len), self.throttle.clone()).sized_body(len);
+        let request = self
+            .client
+            .put(session)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+            )
+            .body(body);
+        let response = send_watched(request, watchdog).context("OneDrive chunk upload failed")?;
+        // `202` while more is expected, `200`/`201` with the item once the
+        // last byte is in.
+        if response.status() != StatusCode::ACCEPTED {
+            return check(response, "upload").map(|_| SessionState::Complete);
+        }
+        let status: SessionStatus = parse(response, "chunk upload")?;
+        match status.next_expected_ranges.first() {
+            Some(range) => next_expected(range).map(SessionState::Incomplete),
+            None => Ok(SessionState::Incomplete(offset + len)),
+        }
+    }
+}
+
+impl Uploader for OneDriveUploader {
+    fn name(&self) -> &str {
+        "OneDrive"
+    }
+
+    /// Progress is reported after every chunk.
+    fn upload_with_progress(
+        &mut self,
+        local_path: &Path,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Archive file name is not valid UTF-8")?;
+        let file = File::open(local_path)
+            .with_context(|| format!("Failed to open archive: {}", local_path.display()))?;
+        let total = file
+            .metadata()
+            .with_context(|| format!("Failed to read file size: {}", local_path.display()))?
+            .len();
+        self.ensure_folders(remote_dir)?;
+        let path = join_path(remote_dir, name);
+        if total <= SIMPLE_UPLOAD_LIMIT {
+            self.simple_upload(&path, file, total)?;
+            progress(total, total);
+            return Ok(());
+        }
+
+        let session = self.start_session(&path)?;
+        let mut offset = 0;
+        let mut retries = 0;
+        while offset < total {
+            match self.send_chunk(&session, &file, offset, total) {
+                Ok(SessionState::Incomplete(next)) => {
+                    offset = next;
+                    retries = 0;
+                }
+                Ok(SessionState::Complete) => offset = total,
+                Err(err) if retries < CHUNK_RETRIES => {
+                    retries += 1;
+                    warn!(
+                        "OneDrive chunk at byte {} of {} failed, resending ({}/{}): {:#}",
+                        offset, name, retries, CHUNK_RETRIES, err
+                    );
+                    thread::sleep(Duration::from_secs(u64::from(retries)));
+                    offset = match self.session_state(&session)? {
+                        SessionState::Incomplete(next) => next,
+                        SessionState::Complete => total,
+                    };
+                }
+                Err(err) => return Err(err),
+            }
+            progress(offset, total);
+        }
+        Ok(())
+    }
+
+    /// Paths are below the drive root, without leading or trailing `/`.
+    fn normalize_remote_dir(&self, remote_dir: &str) -> Result<String> {
+        Ok(check_remote_dir(remote_dir)?.trim_matches('/').to_string())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    /// Replaces an existing `to`, as the other backends do.
+    fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+        let url = item_url(&join_path(remote_dir, from), "");
+        let body = serde_json::json!({ "name": to });
+        let response = self
+            .request(Method::PATCH, &url)?
+            .query(&[("@microsoft.graph.conflictBehavior", "replace")])
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .with_context(|| format!("OneDrive rename failed: {}", from))?;
+        check(response, "rename")?;
+        Ok(())
+    }
+
+    fn list(&mut self, remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+        let mut entries = Vec::new();
+        let mut url = item_url(remote_dir, "/children");
+        loop {
+            let response = self
+                .request(Method::GET, &url)?
+                .send()
+                .context("OneDrive listing failed")?;
+            let page: Children = parse(check(response, "list")?, "listing")?;
+            entries.extend(
+                page.value
+                    .into_iter()
+                    .filter(|item| item.file.is_some())
+                    .map(|item| RemoteEntry {
+                        name: item.name,
+                        size: item.size,
+                        md5: None,
+                    }),
+            );
+            match page.next_link {
+                Some(next) => url = next,
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    fn check_access(&mut self) -> Result<Option<String>> {
+        let response = self
+            .request(Method::GET, DRIVE_URL)?
+            .query(&[("$select", "owner")])
+            .send()
+            .context("OneDrive account request failed")?;
+        let drive: Drive = parse(check(response, "account")?, "account")?;
+        Ok(drive
+            .owner
+            .and_then(|owner| owner.user)
+            .and_then(|user| user.display_name)
+            .map(|name| format!("drive of {name}")))
+    }
+
+    fn available_space(&mut self) -> Result<Option<u64>> {
+        Ok(self
+            .quota()?
+            .and_then(|quota| quota.remaining)
+            .and_then(|remaining| u64::try_from(remaining).ok()))
+    }
+
+    fn storage_usage(&mut self) -> Result<Option<StorageUsage>> {
+        Ok(self.quota()?.and_then(|quota| {
+            let used = u64::try_from(quota.used?).ok()?;
+            let limit = quota.total.and_then(|total| u64::try_from(total).ok());
+            Some(StorageUsage { used, limit })
+        }))
+    }
+
+    /// Graph redirects to a pre-authenticated download URL, which reqwest
+    /// follows without the access token.
+    fn download(&mut self, remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+        let url = item_url(&join_path(remote_dir, name), "/content");
+        let response = self
+            .request(Method::GET, &url)?
+            .send()
+            .with_context(|| format!("OneDrive download failed: {}", name))?;
+        let mut response = check(response, "download")?;
+        let mut file = File::create(local_path)
+            .with_context(|| format!("Failed to create file: {}", local_path.display()))?;
+        io::copy(&mut response, &mut file)
+            .with_context(|| format!("Failed to download {}", name))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to flush file: {}", local_path.display()))
+    }
+
+    /// Deleted files go to the OneDrive recycle bin, where they count
+    /// against the quota until it is emptied.
+    fn delete(&mut self, remote_dir: &str, name: &str) -> Result<()> {
+        let url = item_url(&join_path(remote_dir, name), "");
+        let response = self
+            .request(Method::DELETE, &url)?
+            .send()
+            .with_context(|| format!("OneDrive delete failed: {}", name))?;
+        check(response, "delete")?;
+        Ok(())
+    }
+}
+
+fn token_url(tenant: &str) -> String {
+    format!("{LOGIN_URL}/{tenant}/oauth2/v2.0/token")
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    let dir = dir.trim_matches('/');
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// The Graph URL of the item at `path` below the drive root, followed by
+/// `suffix` (`/children`, `/content`, ...). The root itself has no path.
+fn item_url(path: &str, suffix: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        format!("{DRIVE_URL}/root{suffix}")
+    } else {
+        format!("{DRIVE_URL}/root:/{}:{suffix}", percent_encode(path, false))
+    }
+}
+
+/// Runs the OAuth device flow until the code was confirmed, denied or
+/// expired.
+fn authorize(client: &Client, config: &OneDriveConfig) -> Result<Token> {
+    let response = client
+        .post(format!(
+            "{LOGIN_URL}/{}/oauth2/v2.0/devicecode",
+            config.tenant
+        ))
+        .form(&[("client_id", config.client_id.as_str()), ("scope", SCOPE)])
+        .send()
+        .context("Microsoft device authorization failed")?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Microsoft device authorization failed: {}",
+            oauth_error(response)
+        );
+    }
+    let device: DeviceCode = parse(response, "device authorization")?;
+    println!(
+        "To authorize OneDrive, open {} and enter the code {}",
+        device.verification_uri, device.user_code
+    );
+
+    let mut interval = device.interval.unwrap_or(5);
+    let deadline = unix_now() + device.expires_in;
+    while unix_now() < deadline {
+        thread::sleep(Duration::from_secs(interval));
+        let mut form = vec![
+            ("client_id", config.client_id.as_str()),
+            ("device_code", device.device_code.as_str()),
+            ("grant_type", DEVICE_GRANT_TYPE),
+        ];
+        if let Some(secret) = &config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = client
+            .post(token_url(&config.tenant))
+            .form(&form)
+            .send()
+            .context("Microsoft token request failed")?;
+        if response.status().is_success() {
+            let granted: TokenResponse = parse(response, "token request")?;
+            return Ok(Token {
+                access_token: granted.access_token,
+                refresh_token: granted
+                    .refresh_token
+                    .context("Microsoft did not return a refresh token")?,
+                expires_at: unix_now() + granted.expires_in,
+            });
+        }
+        let body = response.text().unwrap_or_default();
+        match serde_json::from_str::<OAuthError>(&body) {
+            Ok(error) if error.error == "authorization_pending" => {}
+            Ok(error) if error.error == "slow_down" => interval += 5,
+            Ok(error) => anyhow::bail!(
+                "OneDrive authorization failed: {}",
+                describe_oauth_error(&error)
+            ),
+            Err(_) => anyhow::bail!("OneDrive authorization failed: {}", body.trim()),
+        }
+    }
+    anyhow::bail!("OneDrive authorization code expired before it was entered")
+}
+
+fn load_token(path: &Path) -> Result<Option<Token>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Invalid OneDrive token file: {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err)
+            .with_context(|| format!("Failed to read OneDrive token file: {}", path.display())),
+    }
+}
+
+/// Written through a temporary file, so a crash never leaves half a token
+/// file behind; on Unix it is created with mode 600.
+fn save_token(path: &Path, token: &Token) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let partial = path.with_extension("partial");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let json = serde_json::to_vec_pretty(token).context("Failed to encode OneDrive token")?;
+    options
+        .open(&partial)
+        .and_then(|mut file| file.write_all(&json))
+        .with_context(|| format!("Failed to write token file: {}", partial.display()))?;
+    fs::rename(&partial, path)
+        .with_context(|| format!("Failed to write token file: {}", path.display()))
+}
+
+fn check(response: Response, action: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    anyhow::bail!(
+        "OneDrive {} failed with HTTP {}: {}",
+        action,
+        status,
+        crate::error_body(response)
+    )
+}
+
+fn parse<T: DeserializeOwned>(response: Response, what: &str) -> Result<T> {
+    let body = response
+        .text()
+        .with_context(|| format!("Failed to read OneDrive {} response", what))?;
+    serde_json::from_str(&body).with_context(|| format!("Invalid OneDrive {} response", what))
+}
+
+/// The OAuth `error` of a failed token request, or its status and body
+/// when it has none.
+fn oauth_error(response: Response) -> String {
+    let status = response.status();
+    let body = response.text().unwrap_or_default();
+    serde_json::from_str::<OAuthError>(&body)
+        .map(|error| describe_oauth_error(&error))
+        .unwrap_or_else(|_| format!("HTTP {}: {}", status, body.trim()))
+}
+
+fn describe_oauth_error(error: &OAuthError) -> String {
+    match &error.error_description {
+        Some(description) => format!("{}: {}", error.error, description),
+        None => error.error.clone(),
+    }
+}
+
+/// The start of a `nextExpectedRanges` entry, `12345-` or `12345-67890`.
+fn next_expected(range: &str) -> Result<u64> {
+    range
+        .split('-')
+        .next()
+        .and_then(|start| start.parse().ok())
+        .with_context(|| format!("Unexpected upload range from OneDrive: {}", range))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_item_url_and_range() {
+        assert_eq!(
+            item_url("", "/children"),
+            format!("{DRIVE_URL}/root/children")
+        );
+        assert_eq!(
+            item_url("backups/my app/x.tar.zst", "/content"),
+            format!("{DRIVE_URL}/root:/backups/my%20app/x.tar.zst:/content")
+        );
+        assert_eq!(join_path("", "x"), "x");
+        assert_eq!(join_path("backups/", "x"), "backups/x");
+        assert_eq!(next_expected("33554432-").unwrap(), 32 << 20);
+        assert_eq!(next_expected("26-2047").unwrap(), 26);
+        assert!(next_expected("bytes").is_err());
+        assert_eq!(CHUNK_SIZE % (320 << 10), 0);
+    }
+}
@@ -0,0 +1,458 @@
+//! SFTP over SSH, for a VPS or any host with an SSH login.
+//!
+//! `remote_dir` is used as given: absolute paths start at `/`, relative ones
+//! at the login directory. The session is opened on first use and kept for
+//! the rest of the run.
+
+use super::{
+    RemoteEntry, Throttle, ThrottledReader, Timeouts, Uploader, check_remote_dir, watched,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+const COPY_BUFFER_SIZE: usize = 256 << 10;
+
+/// What to do with a host whose key is not in `known_hosts` yet. A key that
+/// differs from the recorded one is always rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyPolicy {
+    #[default]
+    Strict,
+    /// Trust the key on first connection and append it to `known_hosts`.
+    AcceptNew,
+}
+
+pub enum SftpAuth {
+    Password(String),
+    PrivateKey {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub auth: SftpAuth,
+    pub known_hosts: PathBuf,
+    pub host_key_policy: HostKeyPolicy,
+}
+
+pub struct SftpUploader {
+    config: SftpConfig,
+    /// Kept alongside the SFTP channel so the connection stays open.
+    /// With a clone of the socket, which the stall watchdog shuts down to
+    /// break off a write that no longer returns.
+    session: Option<(Session, Sftp, TcpStream)>,
+    throttle: Option<Throttle>,
+    timeouts: Timeouts,
+}
+
+impl SftpUploader {
+    pub fn new(config: SftpConfig) -> Self {
+        Self {
+            config,
+            session: None,
+            throttle: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: &Timeouts) -> Self {
+        self.timeouts = *timeouts;
+        self
+    }
+
+    fn sftp(&mut self) -> Result<&Sftp> {
+        if self.session.is_none() {
+            self.session = Some(self.connect()?);
+        }
+        Ok(&self.session.as_ref().expect("session just opened").1)
+    }
+
+    fn connect(&self) -> Result<(Session, Sftp, TcpStream)> {
+        let config = &self.config;
+        let address = format!("{}:{}", config.host, config.port);
+        let socket = address
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {}", address))?
+            .next()
+            .with_context(|| format!("{} resolved to no address", address))?;
+        let tcp = TcpStream::connect_timeout(&socket, self.timeouts.connect)
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        let socket = tcp.try_clone().context("Failed to clone the SSH socket")?;
+        let mut session = Session::new().context("Failed to create SSH session")?;
+        // Every blocking call gives up after this, e.g. each write of an
+        // upload, so a stalled server fails the attempt instead of hanging.
+        session.set_timeout(
+            self.timeouts
+                .request
+                .as_millis()
+                .try_into()
+                .unwrap_or(u32::MAX),
+        );
+        session.set_tcp_stream(tcp);
+        session
+            .handshake()
+            .with_context(|| format!("SSH handshake with {} failed", address))?;
+        self.check_host_key(&session)?;
+
+        match &config.auth {
+            SftpAuth::Password(password) => {
+                session
+                    .userauth_password(&config.username, password)
+                    .with_context(|| format!("SSH password login as {} failed", config.username))?
+            }
+            SftpAuth::PrivateKey { path, passphrase } => session
+                .userauth_pubkey_file(&config.username, None, path, passphrase.as_deref())
+                .with_context(|| {
+                    format!(
+                        "SSH key login as {} with {} failed",
+                        config.username,
+                        path.display()
+                    )
+                })?,
+        }
+        if !session.authenticated() {
+            anyhow::bail!("SSH login as {} was not accepted", config.username);
+        }
+        let sftp = session
+            .sftp()
+            .with_context(|| format!("Failed to start SFTP on {}", address))?;
+        Ok((session, sftp, socket))
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let config = &self.config;
+        let (key, key_type) = session.host_key().context("Server sent no host key")?;
+        let mut known_hosts = session
+            .known_hosts()
+            .context("Failed to init known_hosts")?;
+        let file_exists = config.known_hosts.exists();
+        if file_exists {
+            known_hosts
+                .read_file(&config.known_hosts, KnownHostFileKind::OpenSSH)
+                .with_context(|| {
+                    format!(
+                        "Failed to read known_hosts: {}",
+                        config.known_hosts.display()
+                    )
+                })?;
+        }
+        match known_hosts.check_port(&config.host, config.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => anyhow::bail!(
+                "Host key for {} does not match {}; refusing to connect",
+                config.host,
+                config.known_hosts.display()
+            ),
+            CheckResult::Failure => anyhow::bail!("Failed to check host key for {}", config.host),
+            CheckResult::NotFound => match config.host_key_policy {
+                HostKeyPolicy::Strict => anyhow::bail!(
+                    "Host {} is not in {}; add it with ssh-keyscan or set \
+                     sftp_host_key_policy = \"accept_new\"",
+                    config.host,
+                    config.known_hosts.display()
+                ),
+                HostKeyPolicy::AcceptNew => {
+                    known_hosts
+                        .add(
+                            &known_hosts_pattern(&config.host, config.port),
+                            key,
+                            "added by backup-to-cloud",
+                            key_type.into(),
+                        )
+                        .with_context(|| {
+                            format!("Failed to record host key for {}", config.host)
+                        })?;
+                    if !file_exists && let Some(parent) = config.known_hosts.parent() {
+                        std::fs::create_dir_all(parent).with_context(|| {
+                            format!("Failed to create directory: {}", parent.display())
+                        })?;
+                    }
+                    known_hosts
+                        .write_file(&config.known_hosts, KnownHostFileKind::OpenSSH)
+                        .with_context(|| {
+                            format!(
+                                "Failed to write known_hosts: {}",
+                                config.known_hosts.display()
+                            )
+                        })?;
+                    tracing::warn!(
+                        "Trusted new host key for {} and saved it to {}",
+                        config.host,
+                        config.known_hosts.display()
+                    );
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    /// Creates every missing directory of `remote_dir`, parents first.
+    fn ensure_dirs(&mut self, remote_dir: &str) -> Result<()> {
+        let sftp = self.sftp()?;
+        let mut current = PathBuf::from(if remote_dir.starts_with('/') { "/" } else { "" });
+        for segment in remote_dir.split('/').filter(|segment| !segment.is_empty()) {
+            current.push(segment);
+            if sftp.stat(&current).is_ok_and(|stat| stat.is_dir()) {
+                continue;
+            }
+            sftp.mkdir(&current, 0o755).with_context(|| {
+                format!("Failed to create remote directory: {}", current.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Copies `input` to `remote_dir/name`; `label` names the input in errors.
+    /// After a failure, e.g. a timeout, the session may be stuck mid-packet,
+    /// so the next attempt reconnects.
+    fn write_remote(
+        &mut self,
+        input: impl Read,
+        label: &str,
+        name: &str,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<()> {
+        let result = self.copy_to_remote(input, label, name, remote_dir, progress);
+        if result.is_err() {
+            self.session = None;
+        }
+        result
+    }
+
+    fn copy_to_remote(
+        &mut self,
+        input: impl Read,
+        label: &str,
+        name: &str,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<()> {
+        let mut input = ThrottledReader::new(input, self.throttle.clone());
+        self.ensure_dirs(remote_dir)?;
+
+        let path = remote_path(remote_dir, name);
+        let mut output = self
+            .sftp()?
+            .create(&path)
+            .with_context(|| format!("Failed to create remote file: {}", path.display()))?;
+        let socket = self
+            .session
+            .as_ref()
+            .expect("session opened by sftp()")
+            .2
+            .try_clone()
+            .context("Failed to clone the SSH socket")?;
+        let abort = move || {
+            let _ = socket.shutdown(Shutdown::Both);
+        };
+        watched(input.watchdog(), abort, || {
+            let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+            let mut sent = 0u64;
+            loop {
+                let read = input
+                    .read(&mut buffer)
+                    .with_context(|| format!("Failed to read archive: {}", label))?;
+                if read == 0 {
+                    break;
+                }
+                output
+                    .write_all(&buffer[..read])
+                    .with_context(|| format!("Failed to write remote file: {}", path.display()))?;
+                sent += read as u64;
+                progress(sent);
+            }
+            output
+                .flush()
+                .with_context(|| format!("Failed to write remote file: {}", path.display()))
+        })
+    }
+}
+
+/// Host entry as OpenSSH writes it: bare for port 22, `[host]:port` otherwise.
+fn known_hosts_pattern(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+fn remote_path(remote_dir: &str, name: &str) -> PathBuf {
+    Path::new(remote_dir).join(name)
+}
+
+impl Uploader for SftpUploader {
+    fn name(&self) -> &str {
+        "SFTP"
+    }
+
+    fn upload_with_progress(
+        &mut self,
+        local_path: &Path,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Archive file name is not valid UTF-8")?;
+        let file = File::open(local_path)
+            .with_context(|| format!("Failed to open archive: {}", local_path.display()))?;
+        let total = file
+            .metadata()
+            .with_context(|| format!("Failed to read file size: {}", local_path.display()))?
+            .len();
+        self.write_remote(
+            file,
+            &local_path.display().to_string(),
+            name,
+            remote_dir,
+            &mut |sent| progress(sent.min(total), total),
+        )?;
+        if total == 0 {
+            progress(0, 0);
+        }
+        Ok(())
+    }
+
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    /// A stream that breaks off leaves the partial remote file behind, as an
+    /// interrupted file upload does; `atomic_remote` keeps it off the final
+    /// name.
+    fn upload_stream(
+        &mut self,
+        input: Box<dyn Read + Send>,
+        name: &str,
+        remote_dir: &str,
+    ) -> Result<()> {
+        self.write_remote(input, name, name, remote_dir, &mut |_| {})
+    }
+
+    /// Keeps a leading `/` (absolute path) and drops trailing ones.
+    fn normalize_remote_dir(&self, remote_dir: &str) -> Result<String> {
+        let remote_dir = check_remote_dir(remote_dir)?;
+        let trimmed = remote_dir.trim_end_matches('/');
+        Ok(if trimmed.is_empty() { "/" } else { trimmed }.to_string())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+        let source = remote_path(remote_dir, from);
+        let destination = remote_path(remote_dir, to);
+        self.sftp()?
+            .rename(&source, &destination, None)
+            .with_context(|| {
+                format!(
+                    "Failed to rename {} to {}",
+                    source.display(),
+                    destination.display()
+                )
+            })
+    }
+
+    /// Lists the login directory, which every account has.
+    fn check_access(&mut self) -> Result<Option<String>> {
+        self.list(".")?;
+        Ok(None)
+    }
+
+    fn list(&mut self, remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+        let entries = self
+            .sftp()?
+            .readdir(Path::new(remote_dir))
+            .with_context(|| format!("Failed to list remote directory: {}", remote_dir))?;
+        let mut listed: Vec<RemoteEntry> = entries
+            .into_iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_str()?.to_string();
+                Some(RemoteEntry {
+                    name,
+                    size: stat.size.unwrap_or(0),
+                    md5: None,
+                })
+            })
+            .collect();
+        listed.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(listed)
+    }
+
+    fn download(&mut self, remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+        let path = remote_path(remote_dir, name);
+        let mut input = self
+            .sftp()?
+            .open(&path)
+            .with_context(|| format!("Failed to open remote file: {}", path.display()))?;
+        let mut file = File::create(local_path)
+            .with_context(|| format!("Failed to create file: {}", local_path.display()))?;
+        io::copy(&mut input, &mut file)
+            .with_context(|| format!("Failed to download {}", path.display()))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to flush file: {}", local_path.display()))
+    }
+
+    fn delete(&mut self, remote_dir: &str, name: &str) -> Result<()> {
+        let path = remote_path(remote_dir, name);
+        self.sftp()?
+            .unlink(&path)
+            .with_context(|| format!("Failed to delete remote file: {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_hosts_pattern() {
+        assert_eq!(
+            known_hosts_pattern("vps.example.com", 22),
+            "vps.example.com"
+        );
+        assert_eq!(
+            known_hosts_pattern("vps.example.com", 2222),
+            "[vps.example.com]:2222"
+        );
+    }
+
+    #[test]
+    fn test_normalize_remote_dir() {
+        let uploader = SftpUploader::new(SftpConfig {
+            host: "vps.example.com".to_string(),
+            port: 22,
+            username: "backup".to_string(),
+            auth: SftpAuth::Password("secret".to_string()),
+            known_hosts: PathBuf::from("/nonexistent/known_hosts"),
+            host_key_policy: HostKeyPolicy::Strict,
+        });
+        assert_eq!(
+            uploader.normalize_remote_dir("/srv/backups/").unwrap(),
+            "/srv/backups"
+        );
+        assert_eq!(uploader.normalize_remote_dir("backups").unwrap(), "backups");
+        assert_eq!(uploader.normalize_remote_dir("/").unwrap(), "/");
+        assert!(uploader.normalize_remote_dir("/srv/../etc").is_err());
+    }
+}
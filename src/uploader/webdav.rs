@@ -0,0 +1,478 @@
+//! WebDAV servers such as Nextcloud, ownCloud or Apache mod_dav.
+//!
+//! `remote_dir` is resolved below the configured base URL (for Nextcloud,
+//! `https://<host>/remote.php/dav/files/<user>`). Missing collections are
+//! created one segment at a time with MKCOL before the archive is PUT.
+
+use super::{
+    HttpConfig, RemoteEntry, StorageUsage, Throttle, ThrottledReader, Uploader, check_remote_dir,
+    percent_encode, send_watched,
+};
+use anyhow::{Context, Result};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::{Method, StatusCode};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub struct WebDavUploader {
+    client: Client,
+    base_url: String,
+    username: String,
+    password: String,
+    throttle: Option<Throttle>,
+}
+
+impl WebDavUploader {
+    pub fn new(base_url: &str, username: String, password: String) -> Result<Self> {
+        let base_url = base_url.trim().trim_end_matches('/');
+        reqwest::Url::parse(base_url)
+            .with_context(|| format!("Invalid webdav_url: {}", base_url))?;
+        Ok(Self {
+            client: Client::new(),
+            base_url: base_url.to_string(),
+            username,
+            password,
+            throttle: None,
+        })
+    }
+
+    pub fn with_throttle(mut self, throttle: Option<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn with_http(mut self, http: &HttpConfig) -> Result<Self> {
+        self.client = http.http_client()?;
+        Ok(self)
+    }
+
+    /// URL of `remote_dir`, or of `name` inside it.
+    fn url(&self, remote_dir: &str, name: Option<&str>) -> String {
+        let mut url = self.base_url.clone();
+        if !remote_dir.is_empty() {
+            url.push('/');
+            url.push_str(&percent_encode(remote_dir, false));
+        }
+        if let Some(name) = name {
+            url.push('/');
+            url.push_str(&percent_encode(name, true));
+        }
+        url
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    /// MKCOLs every segment of `remote_dir`; existing collections answer
+    /// 405 Method Not Allowed and are left alone.
+    fn ensure_collections(&self, remote_dir: &str) -> Result<()> {
+        let mut current = String::new();
+        for segment in remote_dir.split('/').filter(|segment| !segment.is_empty()) {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(segment);
+            let url = self.url(&current, None);
+            let mkcol = Method::from_bytes(b"MKCOL").expect("valid method");
+            let response = self
+                .request(mkcol, &url)
+                .send()
+                .with_context(|| format!("WebDAV MKCOL failed: {}", url))?;
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check(response, "MKCOL")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn quota(&mut self) -> Result<Quota> {
+        let url = self.url("", None);
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND").expect("valid method"), &url)
+            .header("Depth", "0")
+            .header("Content-Type", "application/xml")
+            .body(
+                r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:"><d:prop><d:quota-used-bytes/><d:quota-available-bytes/></d:prop></d:propfind>"#,
+            )
+            .send()
+            .with_context(|| format!("WebDAV PROPFIND failed: {}", url))?;
+        let body = check(response, "PROPFIND")?.text()?;
+        parse_quota(&body)
+    }
+}
+
+impl Uploader for WebDavUploader {
+    fn name(&self) -> &str {
+        "WebDAV"
+    }
+
+    /// The file is streamed to the server in a single PUT, so progress is
+    /// only reported once it finished.
+    fn upload_with_progress(
+        &mut self,
+        local_path: &Path,
+        remote_dir: &str,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        let name = local_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Archive file name is not valid UTF-8")?;
+        self.ensure_collections(remote_dir)?;
+
+        let file = File::open(local_path)
+            .with_context(|| format!("Failed to open archive: {}", local_path.display()))?;
+        let total = file
+            .metadata()
+            .with_context(|| format!("Failed to read file size: {}", local_path.display()))?
+            .len();
+        let url = self.url(remote_dir, Some(name));
+        let (body, watchdog) = ThrottledReader::new(file, self.throttle.clone()).sized_body(total);
+        let response = send_watched(self.request(Method::PUT, &url).body(body), watchdog)
+            .with_context(|| format!("WebDAV PUT failed: {}", url))?;
+        check(response, "PUT")?;
+        progress(total, total);
+        Ok(())
+    }
+
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    /// Sent as a chunked PUT. Servers discard a body that breaks off, though
+    /// some keep the partial file; `atomic_remote` keeps it off the final
+    /// name.
+    fn upload_stream(
+        &mut self,
+        input: Box<dyn Read + Send>,
+        name: &str,
+        remote_dir: &str,
+    ) -> Result<()> {
+        self.ensure_collections(remote_dir)?;
+        let url = self.url(remote_dir, Some(name));
+        let input = ThrottledReader::new(input, self.throttle.clone());
+        let watchdog = input.watchdog();
+        let response = send_watched(
+            self.request(Method::PUT, &url).body(Body::new(input)),
+            watchdog,
+        )
+        .with_context(|| format!("WebDAV PUT failed: {}", url))?;
+        check(response, "PUT")?;
+        Ok(())
+    }
+
+    /// Paths are relative to the base URL, without leading or trailing `/`.
+    fn normalize_remote_dir(&self, remote_dir: &str) -> Result<String> {
+        Ok(check_remote_dir(remote_dir)?.trim_matches('/').to_string())
+    }
+
+    fn supports_rename(&self) -> bool {
+        true
+    }
+
+    fn rename(&mut self, remote_dir: &str, from: &str, to: &str) -> Result<()> {
+        let source = self.url(remote_dir, Some(from));
+        let destination = self.url(remote_dir, Some(to));
+        let response = self
+            .request(Method::from_bytes(b"MOVE").expect("valid method"), &source)
+            .header("Destination", &destination)
+            .header("Overwrite", "T")
+            .send()
+            .with_context(|| format!("WebDAV MOVE failed: {}", source))?;
+        check(response, "MOVE")?;
+        Ok(())
+    }
+
+    fn list(&mut self, remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+        let url = self.url(remote_dir, None);
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND").expect("valid method"), &url)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(
+                r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#,
+            )
+            .send()
+            .with_context(|| format!("WebDAV PROPFIND failed: {}", url))?;
+        let body = check(response, "PROPFIND")?.text()?;
+        parse_propfind(&body)
+    }
+
+    /// Asks for `quota-available-bytes` (RFC 4331) on the base URL; servers
+    /// without quotas leave it out or answer a negative number.
+    fn available_space(&mut self) -> Result<Option<u64>> {
+        Ok(self.quota()?.available)
+    }
+
+    /// The limit is what is used plus what is still available, as RFC 4331
+    /// has no property for the quota itself.
+    fn storage_usage(&mut self) -> Result<Option<StorageUsage>> {
+        let quota = self.quota()?;
+        Ok(quota.used.map(|used| StorageUsage {
+            used,
+            limit: quota
+                .available
+                .map(|available| used.saturating_add(available)),
+        }))
+    }
+
+    fn download(&mut self, remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+        let url = self.url(remote_dir, Some(name));
+        let response = self
+            .request(Method::GET, &url)
+            .send()
+            .with_context(|| format!("WebDAV GET failed: {}", url))?;
+        let mut response = check(response, "GET")?;
+        let mut file = File::create(local_path)
+            .with_context(|| format!("Failed to create file: {}", local_path.display()))?;
+        io::copy(&mut response, &mut file)
+            .with_context(|| format!("Failed to download {}", url))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to flush file: {}", local_path.display()))
+    }
+
+    fn delete(&mut self, remote_dir: &str, name: &str) -> Result<()> {
+        let url = self.url(remote_dir, Some(name));
+        let response = self
+            .request(Method::DELETE, &url)
+            .send()
+            .with_context(|| format!("WebDAV DELETE failed: {}", url))?;
+        check(response, "DELETE")?;
+        Ok(())
+    }
+}
+
+fn check(response: Response, action: &str) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().to_string();
+    anyhow::bail!(
+        "WebDAV {} {} failed with HTTP {}: {}",
+        action,
+        url,
+        status,
+        crate::error_body(response)
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Field {
+    None,
+    Href,
+    Length,
+}
+
+/// Collects the non-collection members of a `Depth: 1` PROPFIND multistatus.
+/// Elements are matched by local name since servers pick their own namespace
+/// prefixes.
+fn parse_propfind(body: &str) -> Result<Vec<RemoteEntry>> {
+    let mut reader = Reader::from_str(body);
+    let mut entries = Vec::new();
+    let mut href: Option<String> = None;
+    let mut size = 0u64;
+    let mut is_collection = false;
+    let mut field = Field::None;
+    loop {
+        match reader.read_event().context("Invalid PROPFIND response")? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"response" => {
+                    href = None;
+                    size = 0;
+                    is_collection = false;
+                }
+                b"href" => field = Field::Href,
+                b"getcontentlength" => field = Field::Length,
+                b"collection" => is_collection = true,
+                _ => {}
+            },
+            Event::Empty(element) if element.local_name().as_ref() == b"collection" => {
+                is_collection = true;
+            }
+            Event::Text(text) => {
+                let text = text.unescape().context("Invalid PROPFIND response")?;
+                match field {
+                    Field::Href => href = Some(text.trim().to_string()),
+                    Field::Length => size = text.trim().parse().unwrap_or(0),
+                    Field::None => {}
+                }
+            }
+            Event::End(element) => {
+                field = Field::None;
+                if element.local_name().as_ref() == b"response"
+                    && !is_collection
+                    && let Some(name) = href.take().as_deref().and_then(href_file_name)
+                {
+                    entries.push(RemoteEntry {
+                        name,
+                        size,
+                        md5: None,
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// The RFC 4331 quota properties. Servers report a negative available count
+/// for unlimited or unknown quotas, which reads as `None`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Quota {
+    used: Option<u64>,
+    available: Option<u64>,
+}
+
+/// Reads `quota-used-bytes` and `quota-available-bytes` out of a `Depth: 0`
+/// PROPFIND multistatus.
+fn parse_quota(body: &str) -> Result<Quota> {
+    let mut reader = Reader::from_str(body);
+    let mut quota = Quota::default();
+    let mut property: Option<String> = None;
+    loop {
+        match reader.read_event().context("Invalid PROPFIND response")? {
+            Event::Start(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).into_owned();
+                property =
+                    (name == "quota-used-bytes" || name == "quota-available-bytes").then_some(name);
+            }
+            Event::Text(text) if property.is_some() => {
+                let name = property.take().expect("checked above");
+                let text = text.unescape().context("Invalid PROPFIND response")?;
+                let value: i64 = text
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid {}: {}", name, text))?;
+                let value = u64::try_from(value).ok();
+                if name == "quota-used-bytes" {
+                    quota.used = value;
+                } else {
+                    quota.available = value;
+                }
+            }
+            Event::End(_) => property = None,
+            Event::Eof => return Ok(quota),
+            _ => {}
+        }
+    }
+}
+
+/// Last path segment of an `href`, percent-decoded.
+fn href_file_name(href: &str) -> Option<String> {
+    let segment = href.trim_end_matches('/').rsplit('/').next()?;
+    if segment.is_empty() {
+        return None;
+    }
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && let Some(hex) = segment.get(index + 1..index + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_propfind() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/backups/app/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/backups/app/app-20260211.tar.zst</d:href>
+    <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>1048576</d:getcontentlength></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/backups/app/my%20notes.txt</d:href>
+    <d:propstat><d:prop><d:resourcetype/><d:getcontentlength>12</d:getcontentlength></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/backups/app/old/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let entries = parse_propfind(body).unwrap();
+        let listed: Vec<(&str, u64)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.size))
+            .collect();
+        assert_eq!(
+            listed,
+            [("app-20260211.tar.zst", 1048576), ("my notes.txt", 12)]
+        );
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/</d:href>
+    <d:propstat><d:prop><d:quota-used-bytes>1073741824</d:quota-used-bytes><d:quota-available-bytes>5368709120</d:quota-available-bytes></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            parse_quota(body).unwrap(),
+            Quota {
+                used: Some(1073741824),
+                available: Some(5368709120),
+            }
+        );
+        let unlimited = body.replace("5368709120", "-3");
+        assert_eq!(parse_quota(&unlimited).unwrap().available, None);
+        let missing = body.replace(
+            "<d:quota-available-bytes>5368709120</d:quota-available-bytes>",
+            "",
+        );
+        assert_eq!(
+            parse_quota(&missing).unwrap(),
+            Quota {
+                used: Some(1073741824),
+                available: None,
+            }
+        );
+        assert_eq!(parse_quota("<d:multistatus/>").unwrap(), Quota::default());
+    }
+
+    #[test]
+    fn test_url() {
+        let uploader = WebDavUploader::new(
+            "https://cloud.example.com/remote.php/dav/files/alice/",
+            "alice".to_string(),
+            "token".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            uploader.url("backups/my app", Some("app-20260211.tar.zst")),
+            "https://cloud.example.com/remote.php/dav/files/alice/backups/my%20app/app-20260211.tar.zst"
+        );
+        assert_eq!(
+            uploader.url("", None),
+            "https://cloud.example.com/remote.php/dav/files/alice"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Post-upload checks selected with `verify_mode`.
+
+use crate::signing::{hash_file, to_hex};
+use crate::stream::StreamDigest;
+use crate::uploader::{RemoteEntry, Uploader};
+use anyhow::{Context, Result};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyMode {
+    #[default]
+    None,
+    /// The listed size must match the local file.
+    Size,
+    /// Size plus the MD5 the provider reports for the stored file.
+    RemoteHash,
+    /// Downloads the stored file and compares its content hash.
+    DownloadHash,
+}
+
+/// What an uploaded file is checked against.
+enum Expected<'a> {
+    /// The local file it was uploaded from.
+    File(&'a Path),
+    /// The digests taken while it was streamed; a `download_hash` copy is
+    /// fetched into `download_dir`.
+    Stream {
+        digest: &'a StreamDigest,
+        download_dir: &'a Path,
+    },
+}
+
+impl Expected<'_> {
+    fn describe(&self) -> &'static str {
+        match self {
+            Expected::File(_) => "local file",
+            Expected::Stream { .. } => "streamed archive",
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self {
+            Expected::File(path) => Ok(fs::metadata(path)
+                .with_context(|| format!("Failed to read file size: {}", path.display()))?
+                .len()),
+            Expected::Stream { digest, .. } => Ok(digest.size),
+        }
+    }
+
+    fn md5(&self) -> Result<String> {
+        match self {
+            Expected::File(path) => md5_file(path),
+            Expected::Stream { digest, .. } => Ok(digest.md5.clone()),
+        }
+    }
+
+    fn sha512(&self) -> Result<Vec<u8>> {
+        match self {
+            Expected::File(path) => Ok(hash_file(path)?.finalize().to_vec()),
+            Expected::Stream { digest, .. } => Ok(digest.sha512.clone().finalize().to_vec()),
+        }
+    }
+
+    fn download_dir(&self) -> &Path {
+        match self {
+            Expected::File(path) => path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+            Expected::Stream { download_dir, .. } => download_dir,
+        }
+    }
+}
+
+/// Checks that `remote_dir/remote_name` matches `local_path` as strictly as
+/// `mode` asks for.
+pub fn verify_upload(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    remote_name: &str,
+    local_path: &Path,
+    mode: VerifyMode,
+) -> Result<()> {
+    check(
+        uploader,
+        remote_dir,
+        remote_name,
+        &Expected::File(local_path),
+        mode,
+    )
+}
+
+/// Like [`verify_upload`], for an archive that was streamed and has no local
+/// copy to compare with.
+pub fn verify_stream(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    remote_name: &str,
+    digest: &StreamDigest,
+    download_dir: &Path,
+    mode: VerifyMode,
+) -> Result<()> {
+    check(
+        uploader,
+        remote_dir,
+        remote_name,
+        &Expected::Stream {
+            digest,
+            download_dir,
+        },
+        mode,
+    )
+}
+
+fn check(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    remote_name: &str,
+    expected: &Expected,
+    mode: VerifyMode,
+) -> Result<()> {
+    match mode {
+        VerifyMode::None => Ok(()),
+        VerifyMode::Size => {
+            find_entry(uploader, remote_dir, remote_name, expected)?;
+            Ok(())
+        }
+        VerifyMode::RemoteHash => {
+            let entry = find_entry(uploader, remote_dir, remote_name, expected)?;
+            let remote_md5 = entry
+                .md5
+                .with_context(|| format!("{} does not report file hashes", uploader.name()))?;
+            let local_md5 = expected.md5()?;
+            if !remote_md5.eq_ignore_ascii_case(&local_md5) {
+                anyhow::bail!(
+                    "{} reports MD5 {} for {}, {} has {}",
+                    uploader.name(),
+                    remote_md5,
+                    remote_name,
+                    expected.describe(),
+                    local_md5
+                );
+            }
+            Ok(())
+        }
+        VerifyMode::DownloadHash => {
+            let download_dir = expected.download_dir();
+            let downloaded = tempfile::NamedTempFile::new_in(download_dir).with_context(|| {
+                format!(
+                    "Failed to create download file in: {}",
+                    download_dir.display()
+                )
+            })?;
+            uploader.download(remote_dir, remote_name, downloaded.path())?;
+            let remote_hash = hash_file(downloaded.path())?.finalize().to_vec();
+            if remote_hash != expected.sha512()? {
+                anyhow::bail!(
+                    "Downloaded copy of {} from {} does not match the {}",
+                    remote_name,
+                    uploader.name(),
+                    expected.describe()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Lists `remote_dir` and returns the entry for `remote_name` once its size
+/// matches what was uploaded.
+fn find_entry(
+    uploader: &mut dyn Uploader,
+    remote_dir: &str,
+    remote_name: &str,
+    expected: &Expected,
+) -> Result<RemoteEntry> {
+    let local_size = expected.size()?;
+    let entry = uploader
+        .list(remote_dir)?
+        .into_iter()
+        .find(|entry| entry.name == remote_name)
+        .with_context(|| {
+            format!(
+                "{} is not listed in {} on {}",
+                remote_name,
+                remote_dir,
+                uploader.name()
+            )
+        })?;
+    if entry.size != local_size {
+        anyhow::bail!(
+            "{} lists {} as {} bytes, {} has {}",
+            uploader.name(),
+            remote_name,
+            entry.size,
+            expected.describe(),
+            local_size
+        );
+    }
+    Ok(entry)
+}
+
+fn md5_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut hasher = Md5::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Stores uploads in memory and can report them with a different size or
+    /// content than what was uploaded.
+    #[derive(Default)]
+    struct MemoryUploader {
+        files: HashMap<String, Vec<u8>>,
+        reported_size: Option<u64>,
+    }
+
+    impl Uploader for MemoryUploader {
+        fn name(&self) -> &str {
+            "memory"
+        }
+
+        fn upload_with_progress(
+            &mut self,
+            local_path: &Path,
+            _remote_dir: &str,
+            progress: &mut dyn FnMut(u64, u64),
+        ) -> Result<()> {
+            let name = local_path.file_name().unwrap().to_str().unwrap();
+            let content = fs::read(local_path).unwrap();
+            let total = content.len() as u64;
+            self.files.insert(name.to_string(), content);
+            progress(total, total);
+            Ok(())
+        }
+
+        fn list(&mut self, _remote_dir: &str) -> Result<Vec<RemoteEntry>> {
+            Ok(self
+                .files
+                .iter()
+                .map(|(name, content)| RemoteEntry {
+                    name: name.clone(),
+                    size: self.reported_size.unwrap_or(content.len() as u64),
+                    md5: Some(to_hex(&Md5::digest(content))),
+                })
+                .collect())
+        }
+
+        fn download(&mut self, _remote_dir: &str, name: &str, local_path: &Path) -> Result<()> {
+            fs::write(local_path, &self.files[name])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_verify_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("demo-20260211.tar.zst");
+        fs::write(&archive, b"archive bytes").unwrap();
+        let name = "demo-20260211.tar.zst";
+
+        let mut uploader = MemoryUploader::default();
+        uploader.upload(&archive, "/backups").unwrap();
+        for mode in [
+            VerifyMode::Size,
+            VerifyMode::RemoteHash,
+            VerifyMode::DownloadHash,
+        ] {
+            verify_upload(&mut uploader, "/backups", name, &archive, mode).unwrap();
+        }
+        let (mut tee, _readers) = crate::stream::pipe(1);
+        io::Write::write_all(&mut tee, b"archive bytes").unwrap();
+        let digest = tee.finish().unwrap();
+        for mode in [VerifyMode::RemoteHash, VerifyMode::DownloadHash] {
+            verify_stream(&mut uploader, "/backups", name, &digest, dir.path(), mode).unwrap();
+        }
+
+        // Stored truncated while the listing still reports the right size.
+        uploader
+            .files
+            .insert(name.to_string(), b"archive byt".to_vec());
+        uploader.reported_size = Some(13);
+        verify_upload(&mut uploader, "/backups", name, &archive, VerifyMode::Size).unwrap();
+        assert!(
+            verify_upload(
+                &mut uploader,
+                "/backups",
+                name,
+                &archive,
+                VerifyMode::RemoteHash
+            )
+            .is_err()
+        );
+        assert!(
+            verify_upload(
+                &mut uploader,
+                "/backups",
+                name,
+                &archive,
+                VerifyMode::DownloadHash
+            )
+            .is_err()
+        );
+
+        assert!(
+            verify_stream(
+                &mut uploader,
+                "/backups",
+                name,
+                &digest,
+                dir.path(),
+                VerifyMode::DownloadHash
+            )
+            .is_err()
+        );
+
+        uploader.reported_size = Some(11);
+        assert!(
+            verify_upload(&mut uploader, "/backups", name, &archive, VerifyMode::Size).is_err()
+        );
+    }
+}